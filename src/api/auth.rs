@@ -0,0 +1,256 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Node API authentication.
+//!
+//! When an API keypair is configured the administration routes (`/admin/...`)
+//! only accept the TCP requests signed with it. The signature covers the method,
+//! the path with the sorted query string, a timestamp and the body:
+//!
+//! ```text
+//! <METHOD>\n<path>[?<key>=<value>&...]\n<timestamp>\n<body>
+//! ```
+//!
+//! and is sent hex encoded in the `X-Trinci-Signature` header, along with the
+//! timestamp (seconds since the epoch) in `X-Trinci-Timestamp`. Requests out
+//! of the allowed clock skew or already seen are refused.
+//!
+//! The keypair file is loaded again when modified, to rotate the key while
+//! the node is running.
+
+use crate::api::{Request, Response};
+use crate::utils::{self, Signer};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use trinci_core::{base::Mutex, PublicKey};
+
+/// Request timestamp header.
+pub const TIMESTAMP_HEADER: &str = "x-trinci-timestamp";
+
+/// Request signature header.
+pub const SIGNATURE_HEADER: &str = "x-trinci-signature";
+
+/// Max distance in seconds between the request timestamp and the node clock.
+const MAX_CLOCK_SKEW: u64 = 60;
+
+/// Min interval between two checks of the keypair file modification time.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Signed data of a request.
+fn signed_data(method: &str, target: &str, timestamp: u64, body: &[u8]) -> Vec<u8> {
+    let mut data = format!("{}\n{}\n{}\n", method, target, timestamp).into_bytes();
+    data.extend_from_slice(body);
+    data
+}
+
+/// Request target with the query parameters sorted by name.
+pub fn canonical_target(path: &str, query: &HashMap<String, String>) -> String {
+    let mut pairs: Vec<_> = query.iter().collect();
+    pairs.sort();
+    let query: Vec<String> = pairs
+        .into_iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    if query.is_empty() {
+        path.to_string()
+    } else {
+        format!("{}?{}", path, query.join("&"))
+    }
+}
+
+/// Authentication headers of a request to send.
+pub fn sign(
+    keypair: &dyn Signer,
+    method: &str,
+    target: &str,
+    body: &[u8],
+) -> Result<Vec<(&'static str, String)>, String> {
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, crate::api::parse_query(query)),
+        None => (target, HashMap::new()),
+    };
+    let timestamp = now();
+    let data = signed_data(method, &canonical_target(path, &query), timestamp, body);
+    let signature = keypair.sign(&data).map_err(|err| err.to_string())?;
+    Ok(vec![
+        (TIMESTAMP_HEADER, timestamp.to_string()),
+        (SIGNATURE_HEADER, hex::encode(signature)),
+    ])
+}
+
+struct State {
+    key: PublicKey,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+    /// Signatures seen within the clock skew window, with their timestamp.
+    seen: VecDeque<(u64, Vec<u8>)>,
+}
+
+pub struct ApiAuth {
+    path: String,
+    state: Mutex<State>,
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+impl ApiAuth {
+    /// Loads the API keypair.
+    pub fn open(path: &str) -> Result<Self, String> {
+        let modified = modified(path);
+        let key = utils::load_signer(Some(path.to_string()))
+            .map_err(|err| format!("API keypair {}: {}", path, err))?
+            .public_key();
+        Ok(Self::with_key(path, key, modified))
+    }
+
+    fn with_key(path: &str, key: PublicKey, modified: Option<SystemTime>) -> Self {
+        ApiAuth {
+            path: path.to_string(),
+            state: Mutex::new(State {
+                key,
+                modified,
+                last_check: Instant::now(),
+                seen: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Account identifier of the current key.
+    pub fn account_id(&self) -> String {
+        self.state.lock().key.to_account_id()
+    }
+
+    // Loads the keypair again if the file has been modified.
+    fn refresh(&self, state: &mut State) {
+        if state.last_check.elapsed() < RELOAD_INTERVAL {
+            return;
+        }
+        state.last_check = Instant::now();
+        let modified = modified(&self.path);
+        if modified == state.modified {
+            return;
+        }
+        state.modified = modified;
+        match utils::load_signer(Some(self.path.clone())) {
+            Ok(keypair) => {
+                state.key = keypair.public_key();
+                state.seen.clear();
+                info!("[api] API key rotated, now {}", state.key.to_account_id());
+            }
+            Err(err) => error!("[api] error reloading {}: {}", self.path, err),
+        }
+    }
+
+    /// Checks the signature of an administration request.
+    pub fn check(&self, req: &Request) -> Result<(), Response> {
+        let unauthorized = |reason: &str| Err(Response::error(401, reason));
+        let timestamp = match req
+            .headers
+            .get(TIMESTAMP_HEADER)
+            .and_then(|value| value.parse::<u64>().ok())
+        {
+            Some(timestamp) => timestamp,
+            None => return unauthorized("missing or invalid request timestamp"),
+        };
+        let signature = match req
+            .headers
+            .get(SIGNATURE_HEADER)
+            .and_then(|value| hex::decode(value).ok())
+        {
+            Some(signature) => signature,
+            None => return unauthorized("missing or invalid request signature"),
+        };
+        let now = now();
+        if now.abs_diff(timestamp) > MAX_CLOCK_SKEW {
+            return unauthorized("request timestamp out of the allowed skew");
+        }
+
+        let mut state = self.state.lock();
+        self.refresh(&mut state);
+        let target = canonical_target(&req.path, &req.query);
+        let data = signed_data(&req.method, &target, timestamp, &req.body);
+        if !state.key.verify(&data, &signature) {
+            return unauthorized("bad request signature");
+        }
+        while let Some((seen, _)) = state.seen.front() {
+            if now.abs_diff(*seen) <= MAX_CLOCK_SKEW {
+                break;
+            }
+            state.seen.pop_front();
+        }
+        if state.seen.iter().any(|(_, seen)| *seen == signature) {
+            return unauthorized("request already served");
+        }
+        state.seen.push_back((timestamp, signature));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trinci_core::crypto::{ed25519, KeyPair};
+
+    fn request(target: &str, headers: Vec<(&'static str, String)>) -> Request {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Request {
+            method: "POST".to_string(),
+            path: path.to_string(),
+            query: crate::api::parse_query(query),
+            params: HashMap::new(),
+            headers: headers
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+            body: b"{}".to_vec(),
+            peer: "127.0.0.1:1234".parse().unwrap(),
+            admin_socket: false,
+        }
+    }
+
+    #[test]
+    fn signed_requests() {
+        let keypair = KeyPair::Ed25519(ed25519::KeyPair::from_random());
+        let auth = ApiAuth::with_key("", keypair.public_key(), None);
+
+        let target = "/admin/node/p2p?active=false";
+        let headers = sign(&keypair, "POST", target, b"{}").unwrap();
+        assert!(auth.check(&request(target, headers.clone())).is_ok());
+        // Replayed.
+        assert!(auth.check(&request(target, headers.clone())).is_err());
+        // Tampered query.
+        let tampered = "/admin/node/p2p?active=true";
+        assert!(auth.check(&request(tampered, headers)).is_err());
+        assert!(auth.check(&request(target, vec![])).is_err());
+
+        let other = KeyPair::Ed25519(ed25519::KeyPair::from_random());
+        let headers = sign(&other, "POST", target, b"{}").unwrap();
+        assert!(auth.check(&request(target, headers)).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Minimal node API client, used by the subcommands to drive a running node.
+//!
+//! Addresses containing a `/` are admin socket paths.
+
+use crate::api::auth;
+use crate::utils::Signer;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+/// Socket read/write timeout.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sends a request to the node API at `addr`, returns the response status and
+/// body.
+pub fn request(
+    addr: &str,
+    method: &str,
+    path: &str,
+    body: Option<&[u8]>,
+) -> io::Result<(u16, Vec<u8>)> {
+    request_signed(addr, method, path, body, None)
+}
+
+/// Whether the address is an admin socket path.
+pub fn is_socket(addr: &str) -> bool {
+    addr.contains('/')
+}
+
+/// Splits a plain HTTP URL in the address, with the default port if missing,
+/// and the path.
+pub fn split_url(url: &str) -> Option<(String, String)> {
+    let rest = url.strip_prefix("http://")?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let addr = match host.rsplit_once(':') {
+        Some((_, port)) if !port.ends_with(']') => host.to_string(),
+        _ => format!("{}:80", host),
+    };
+    Some((addr, path.to_string()))
+}
+
+// Writes the request, returns the raw response.
+fn exchange<S: Read + Write>(mut stream: S, head: &[u8], body: &[u8]) -> io::Result<Vec<u8>> {
+    stream.write_all(head)?;
+    stream.write_all(body)?;
+    // The server closes the connection after the response.
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+// Exchanges the request on the admin socket.
+#[cfg(unix)]
+fn socket_exchange(path: &str, head: &[u8], body: &[u8]) -> io::Result<Vec<u8>> {
+    let stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    exchange(stream, head, body)
+}
+
+#[cfg(not(unix))]
+fn socket_exchange(_path: &str, _head: &[u8], _body: &[u8]) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "admin sockets are supported on Unix only",
+    ))
+}
+
+/// Sends a request signed with the API keypair, if any (see the `auth`
+/// module).
+pub fn request_signed(
+    addr: &str,
+    method: &str,
+    path: &str,
+    body: Option<&[u8]>,
+    keypair: Option<&dyn Signer>,
+) -> io::Result<(u16, Vec<u8>)> {
+    let body = body.unwrap_or_default();
+    let mut headers = String::new();
+    if let Some(keypair) = keypair {
+        let auth = auth::sign(keypair, method, path, body).map_err(io::Error::other)?;
+        for (name, value) in auth {
+            headers.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+        {}Content-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        path,
+        if is_socket(addr) { "localhost" } else { addr },
+        headers,
+        body.len()
+    );
+    let buf = if is_socket(addr) {
+        socket_exchange(addr, head.as_bytes(), body)?
+    } else {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        exchange(stream, head.as_bytes(), body)?
+    };
+
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed response");
+    let split = buf
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(invalid)?;
+    let status = std::str::from_utf8(&buf[..split])
+        .ok()
+        .and_then(|head| head.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(invalid)?;
+    Ok((status, buf[split + 4..].to_vec()))
+}
@@ -0,0 +1,434 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Node local HTTP API.
+//!
+//! Small HTTP/1.1 server exposing node-side information and administrative
+//! operations. The blockchain REST API is served by the core REST service,
+//! this one only carries what is owned by the node.
+//!
+//! The administration routes (`/admin/...`) are never served unauthenticated
+//! over TCP. With an admin socket configured they are served on that Unix
+//! socket only, guarded by the file permissions; otherwise TCP requests must be
+//! signed with the API keypair (see the `auth` module). With neither of them
+//! configured the administration routes are refused.
+
+pub mod auth;
+pub mod client;
+pub mod service;
+mod worker;
+
+use auth::ApiAuth;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use trinci_core::base::serialize::rmp_serialize;
+
+/// HTTP request.
+pub struct Request {
+    /// Method, uppercase.
+    pub method: String,
+    /// Path, without the query string.
+    pub path: String,
+    /// Query string parameters.
+    pub query: HashMap<String, String>,
+    /// Path parameters, captured by the `:name` route segments.
+    pub params: HashMap<String, String>,
+    /// Headers, names are lowercase.
+    pub headers: HashMap<String, String>,
+    /// Request body.
+    pub body: Vec<u8>,
+    /// Client address, loopback for the admin socket clients.
+    pub peer: SocketAddr,
+    /// Received on the admin Unix socket.
+    pub admin_socket: bool,
+}
+
+impl Request {
+    /// Path parameter parsed to the requested type.
+    pub fn param<T: std::str::FromStr>(&self, name: &str) -> Option<T> {
+        self.params.get(name).and_then(|value| value.parse().ok())
+    }
+
+    /// Query parameter parsed to the requested type.
+    pub fn query<T: std::str::FromStr>(&self, name: &str) -> Option<T> {
+        self.query.get(name).and_then(|value| value.parse().ok())
+    }
+
+    /// Whether the client asks for msgpack rather than JSON (`Accept` header).
+    pub fn accepts_msgpack(&self) -> bool {
+        self.headers
+            .get("accept")
+            .map(|accept| {
+                accept.split(',').any(|media| {
+                    let media = media.split(';').next().unwrap_or_default().trim();
+                    media == "application/msgpack" || media == "application/x-msgpack"
+                })
+            })
+            .unwrap_or(false)
+    }
+
+    /// Deserializes the JSON body.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, Response> {
+        match self.headers.get("content-type") {
+            Some(content_type) if !content_type.starts_with("application/json") => {
+                return Err(Response::error(415, "Unsupported Media Type"));
+            }
+            _ => (),
+        }
+        serde_json::from_slice(&self.body)
+            .map_err(|err| Response::error(400, format!("bad request body: {}", err)))
+    }
+}
+
+/// HTTP response.
+pub struct Response {
+    /// Status code.
+    pub status: u16,
+    /// Content type.
+    pub content_type: &'static str,
+    /// Response body.
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Plain text response.
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into().into_bytes(),
+        }
+    }
+
+    /// JSON response.
+    pub fn json<T: Serialize>(value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Response {
+                status: 200,
+                content_type: "application/json",
+                body,
+            },
+            Err(err) => Response::text(500, format!("serialization error: {}", err)),
+        }
+    }
+
+    /// msgpack response.
+    pub fn msgpack<T: Serialize>(value: &T) -> Self {
+        match rmp_serialize(value) {
+            Ok(body) => Response {
+                status: 200,
+                content_type: "application/msgpack",
+                body,
+            },
+            Err(err) => Response::text(500, format!("serialization error: {}", err)),
+        }
+    }
+
+    /// JSON or msgpack response, as asked by the client.
+    pub fn negotiate<T: Serialize>(req: &Request, value: &T) -> Self {
+        if req.accepts_msgpack() {
+            Response::msgpack(value)
+        } else {
+            Response::json(value)
+        }
+    }
+
+    /// Empty successful response.
+    pub fn ok() -> Self {
+        Response::text(200, "OK")
+    }
+
+    /// Error response.
+    pub fn error(status: u16, message: impl Into<String>) -> Self {
+        Response::text(status, message)
+    }
+}
+
+/// Route handler.
+pub type Handler = Box<dyn Fn(&Request) -> Response + Send + Sync>;
+
+struct Route {
+    method: &'static str,
+    segments: Vec<&'static str>,
+    handler: Handler,
+}
+
+impl Route {
+    // Returns the captured path parameters if the path matches.
+    fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        if segments.len() != self.segments.len() {
+            return None;
+        }
+        let mut params = HashMap::new();
+        for (pattern, segment) in self.segments.iter().zip(segments) {
+            match pattern.strip_prefix(':') {
+                Some(name) => {
+                    params.insert(name.to_string(), segment.to_string());
+                }
+                None if *pattern == segment => (),
+                None => return None,
+            }
+        }
+        Some(params)
+    }
+}
+
+// Decodes the `%XX` escapes and the `+` spaces of a query string component,
+// malformed escapes are kept as they are.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => match bytes.get(i + 1..i + 3) {
+                Some(hex) if hex.iter().all(u8::is_ascii_hexdigit) => {
+                    let hex = std::str::from_utf8(hex).unwrap_or_default();
+                    decoded.push(u8::from_str_radix(hex, 16).unwrap_or_default());
+                    i += 2;
+                }
+                _ => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Absolute path without empty, `.` or `..` segments, a trailing slash
+// allowed. The routes ignore the empty segments, the other paths could dodge
+// the administration check.
+fn is_canonical(path: &str) -> bool {
+    let path = match path.strip_prefix('/') {
+        Some(path) => path.strip_suffix('/').unwrap_or(path),
+        None => return false,
+    };
+    path.is_empty()
+        || path
+            .split('/')
+            .all(|segment| !segment.is_empty() && segment != "." && segment != "..")
+}
+
+/// Query string parameters, percent-decoded.
+pub(crate) fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (percent_decode(key), percent_decode(value)),
+            None => (percent_decode(pair), String::new()),
+        })
+        .collect()
+}
+
+/// Routes table.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    /// Administration routes authentication.
+    auth: Option<Arc<ApiAuth>>,
+    /// Administration routes served on the admin socket only.
+    admin_socket_only: bool,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    /// Adds a route. Path segments starting with `:` capture a parameter.
+    pub fn add<F>(&mut self, method: &'static str, path: &'static str, handler: F)
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            method,
+            segments: path.trim_matches('/').split('/').collect(),
+            handler: Box::new(handler),
+        });
+    }
+
+    /// Requires the administration requests to be signed.
+    pub fn set_auth(&mut self, auth: Arc<ApiAuth>) {
+        self.auth = Some(auth);
+    }
+
+    /// Serves the administration routes on the admin socket only.
+    pub fn set_admin_socket_only(&mut self) {
+        self.admin_socket_only = true;
+    }
+
+    /// Dispatches the request to the matching route.
+    pub fn dispatch(&self, mut req: Request) -> Response {
+        if !is_canonical(&req.path) {
+            return Response::error(400, "Bad Request");
+        }
+        let admin = req.path.trim_matches('/').split('/').next() == Some("admin");
+        // The admin socket clients are authenticated by the file permissions.
+        if admin && !req.admin_socket {
+            if self.admin_socket_only {
+                return Response::error(403, "administration on the admin socket only");
+            }
+            let auth = match &self.auth {
+                Some(auth) => auth,
+                None => {
+                    return Response::error(
+                        403,
+                        "administration requires `admin-socket` or `api-keypair`",
+                    )
+                }
+            };
+            if let Err(res) = auth.check(&req) {
+                debug!(
+                    "[api] {} {} from {} refused",
+                    req.method, req.path, req.peer
+                );
+                return res;
+            }
+        }
+        let mut allowed = false;
+        for route in &self.routes {
+            if let Some(params) = route.matches(&req.path) {
+                if route.method == req.method {
+                    req.params = params;
+                    return (route.handler)(&req);
+                }
+                allowed = true;
+            }
+        }
+        if allowed {
+            Response::error(405, "Method Not Allowed")
+        } else {
+            Response::error(404, "Not Found")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: HashMap::new(),
+            params: HashMap::new(),
+            headers: HashMap::new(),
+            body: Vec::new(),
+            peer: "127.0.0.1:1234".parse().unwrap(),
+            admin_socket: false,
+        }
+    }
+
+    #[test]
+    fn router_dispatch() {
+        let mut router = Router::new();
+        router.add("GET", "/api/v1/block/:height", |req| {
+            Response::text(200, req.param::<u64>("height").unwrap().to_string())
+        });
+
+        let res = router.dispatch(request("GET", "/api/v1/block/42"));
+        assert_eq!(res.status, 200);
+        assert_eq!(res.body, b"42");
+
+        let res = router.dispatch(request("POST", "/api/v1/block/42"));
+        assert_eq!(res.status, 405);
+
+        let res = router.dispatch(request("GET", "/api/v1/block"));
+        assert_eq!(res.status, 404);
+    }
+
+    #[test]
+    fn admin_unauthenticated() {
+        let mut router = Router::new();
+        router.add("GET", "/admin/node", |_| Response::ok());
+        router.add("GET", "/api/v1/stats", |_| Response::ok());
+
+        let res = router.dispatch(request("GET", "/admin/node"));
+        assert_eq!(res.status, 403);
+        assert_eq!(router.dispatch(request("GET", "/api/v1/stats")).status, 200);
+
+        let mut req = request("GET", "/admin/node");
+        req.admin_socket = true;
+        assert_eq!(router.dispatch(req).status, 200);
+    }
+
+    #[test]
+    fn admin_non_canonical_path() {
+        let mut router = Router::new();
+        router.add("GET", "/admin/node", |_| Response::ok());
+
+        for path in [
+            "//admin/node",
+            "/./admin/node",
+            "/api/../admin/node",
+            "admin/node",
+        ] {
+            assert_eq!(
+                router.dispatch(request("GET", path)).status,
+                400,
+                "{}",
+                path
+            );
+        }
+        assert_eq!(router.dispatch(request("GET", "/admin/node/")).status, 403);
+        assert_eq!(router.dispatch(request("GET", "/")).status, 404);
+    }
+
+    #[test]
+    fn admin_socket_only() {
+        let mut router = Router::new();
+        router.add("GET", "/admin/node", |_| Response::ok());
+        router.set_admin_socket_only();
+
+        let res = router.dispatch(request("GET", "/admin/node"));
+        assert_eq!(res.status, 403);
+
+        let mut req = request("GET", "/admin/node");
+        req.admin_socket = true;
+        assert_eq!(router.dispatch(req).status, 200);
+    }
+
+    #[test]
+    fn query_decoding() {
+        let query = parse_query("path=%2Ftmp%2Fa%20b%26c&filter=p2p+debug&bad=%zz%4");
+        assert_eq!(query["path"], "/tmp/a b&c");
+        assert_eq!(query["filter"], "p2p debug");
+        assert_eq!(query["bad"], "%zz%4");
+    }
+
+    #[test]
+    fn content_negotiation() {
+        let mut req = request("GET", "/api/v1/blocks");
+        assert_eq!(
+            Response::negotiate(&req, &42).content_type,
+            "application/json"
+        );
+
+        req.headers.insert(
+            "accept".to_string(),
+            "application/json;q=0.5, application/msgpack".to_string(),
+        );
+        let res = Response::negotiate(&req, &42);
+        assert_eq!(res.content_type, "application/msgpack");
+        assert_eq!(res.body, [42]);
+    }
+}
@@ -0,0 +1,158 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::api::{
+    worker::{ApiWorker, Listener},
+    Router,
+};
+use crate::utils;
+#[cfg(unix)]
+use std::os::unix::{
+    fs::PermissionsExt,
+    net::{UnixListener, UnixStream},
+};
+use std::{
+    fs,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// Node API service configuration.
+pub struct ApiConfig {
+    /// Binding address.
+    pub addr: String,
+    /// Listening port.
+    pub port: u16,
+    /// Admin Unix socket path.
+    pub socket: Option<String>,
+}
+
+pub struct ApiService {
+    /// Service configuration.
+    config: ApiConfig,
+    /// Routes, shared with the worker.
+    router: Arc<Router>,
+    /// Bound address, known once started.
+    local_addr: Option<SocketAddr>,
+    /// Worker threads handlers
+    handlers: Vec<JoinHandle<()>>,
+    /// Worker stop flag.
+    stop: Arc<AtomicBool>,
+    /// To check if the worker still alive
+    canary: Arc<()>,
+}
+
+impl ApiService {
+    pub fn new(config: ApiConfig, router: Router) -> Self {
+        ApiService {
+            config,
+            router: Arc::new(router),
+            local_addr: None,
+            handlers: Vec::new(),
+            stop: Arc::new(AtomicBool::new(false)),
+            canary: Arc::new(()),
+        }
+    }
+
+    // Runs a worker on the listener.
+    fn spawn(&mut self, listener: Listener) {
+        let mut worker = ApiWorker::new(listener, self.router.clone(), self.stop.clone());
+        let mut canary = Arc::clone(&self.canary);
+        let handle = thread::spawn(move || {
+            let _ = Arc::get_mut(&mut canary);
+            worker.run();
+        });
+        self.handlers.push(handle);
+    }
+
+    // Binds the admin socket, only accessible by the node user.
+    #[cfg(unix)]
+    fn bind_socket(path: &str) -> std::io::Result<UnixListener> {
+        // Left over by a previous run.
+        if UnixStream::connect(path).is_err() {
+            let _ = fs::remove_file(path);
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        Ok(listener)
+    }
+
+    /// Start API service if not already running
+    pub fn start(&mut self) {
+        debug!("Starting API service");
+        if !self.handlers.is_empty() {
+            warn!("Service was already running");
+            return;
+        }
+
+        let addr = utils::host_port(&self.config.addr, self.config.port);
+        let listener = match TcpListener::bind(&addr) {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("[api] unable to bind {}: {}", addr, err);
+                return;
+            }
+        };
+        self.local_addr = listener.local_addr().ok();
+        info!("[api] listening on {}", addr);
+
+        self.stop.store(false, Ordering::Relaxed);
+        self.spawn(Listener::Tcp(listener));
+        #[cfg(unix)]
+        if let Some(path) = self.config.socket.clone() {
+            match Self::bind_socket(&path) {
+                Ok(listener) => {
+                    info!("[api] admin socket {}", path);
+                    self.spawn(Listener::Unix(listener));
+                }
+                Err(err) => error!("[api] unable to bind the admin socket {}: {}", path, err),
+            }
+        }
+    }
+
+    /// Stop API service
+    pub fn stop(&mut self) {
+        debug!("Stopping API service");
+        if self.handlers.is_empty() {
+            return;
+        }
+        self.stop.store(true, Ordering::Relaxed);
+        // Wake up the workers blocked on accept.
+        if let Some(addr) = self.local_addr {
+            let _ = TcpStream::connect(addr);
+        }
+        #[cfg(unix)]
+        if let Some(path) = &self.config.socket {
+            let _ = UnixStream::connect(path);
+        }
+        for handle in self.handlers.drain(..) {
+            let _ = handle.join();
+        }
+        if let Some(path) = &self.config.socket {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// Check if API service is running
+    pub fn is_running(&self) -> bool {
+        !self.handlers.is_empty() && Arc::strong_count(&self.canary) == 1 + self.handlers.len()
+    }
+}
@@ -0,0 +1,267 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::api::{parse_query, Request, Response, Router};
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{net::Ipv4Addr, os::unix::net::UnixListener};
+
+/// Max size of the request line plus headers.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Max size of a request body.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Socket read/write timeout.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Max connections served at once by a listener, the others are refused.
+const MAX_CONNECTIONS: usize = 64;
+
+/// Listening socket.
+pub enum Listener {
+    Tcp(TcpListener),
+    /// Admin socket.
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+pub struct ApiWorker {
+    listener: Listener,
+    router: Arc<Router>,
+    stop: Arc<AtomicBool>,
+    /// Connections being served.
+    active: Arc<AtomicUsize>,
+}
+
+// Connection slot, released on drop.
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn acquire(active: &Arc<AtomicUsize>) -> Option<Self> {
+        active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < MAX_CONNECTIONS).then_some(count + 1)
+            })
+            .ok()
+            .map(|_| Slot(active.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+// Reads a request from the stream, the error is the response to send back.
+fn read_request<S: Read>(
+    stream: &mut S,
+    peer: SocketAddr,
+    admin_socket: bool,
+) -> Result<Request, Response> {
+    let mut reader = BufReader::new(stream.take((MAX_HEAD_SIZE + MAX_BODY_SIZE) as u64));
+    let bad_request = || Response::error(400, "Bad Request");
+
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|_| bad_request())?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_uppercase(), target.to_string()),
+        _ => return Err(bad_request()),
+    };
+
+    let mut head_size = line.len();
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).map_err(|_| bad_request())?;
+        head_size += line.len();
+        if head_size > MAX_HEAD_SIZE {
+            return Err(Response::error(413, "Payload Too Large"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let len = headers
+        .get("content-length")
+        .map(|len| len.parse::<usize>())
+        .transpose()
+        .map_err(|_| bad_request())?
+        .unwrap_or(0);
+    if len > MAX_BODY_SIZE {
+        return Err(Response::error(413, "Payload Too Large"));
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).map_err(|_| bad_request())?;
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), parse_query(query)),
+        None => (target, HashMap::new()),
+    };
+
+    Ok(Request {
+        method,
+        path,
+        query,
+        params: HashMap::new(),
+        headers,
+        body,
+        peer,
+        admin_socket,
+    })
+}
+
+fn write_response<S: Write>(stream: &mut S, res: &Response) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        res.status,
+        status_text(res.status),
+        res.content_type,
+        res.body.len()
+    )?;
+    stream.write_all(&res.body)?;
+    stream.flush()
+}
+
+fn handle_connection<S: Read + Write>(
+    mut stream: S,
+    peer: SocketAddr,
+    admin_socket: bool,
+    router: Arc<Router>,
+) {
+    let res = match read_request(&mut stream, peer, admin_socket) {
+        Ok(req) => {
+            let summary = format!("{} {} {}", req.peer, req.method, req.path);
+            let res = router.dispatch(req);
+            debug!("[api] {} -> {}", summary, res.status);
+            res
+        }
+        Err(res) => res,
+    };
+    if let Err(err) = write_response(&mut stream, &res) {
+        debug!("[api] error writing response to {}: {}", peer, err);
+    }
+}
+
+impl ApiWorker {
+    pub fn new(listener: Listener, router: Arc<Router>, stop: Arc<AtomicBool>) -> Self {
+        ApiWorker {
+            listener,
+            router,
+            stop,
+            active: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    // Serves the connection on a dedicated thread, or refuses it when
+    // `MAX_CONNECTIONS` are already being served.
+    fn serve<S: Read + Write + Send + 'static>(
+        &self,
+        mut stream: S,
+        peer: SocketAddr,
+        admin_socket: bool,
+    ) {
+        let slot = match Slot::acquire(&self.active) {
+            Some(slot) => slot,
+            None => {
+                debug!(
+                    "[api] connection from {} refused, too many connections",
+                    peer
+                );
+                let res = Response::error(503, "Service Unavailable");
+                let _ = write_response(&mut stream, &res);
+                return;
+            }
+        };
+        let router = self.router.clone();
+        thread::spawn(move || {
+            let _slot = slot;
+            handle_connection(stream, peer, admin_socket, router)
+        });
+    }
+
+    /// Accepts connections until the stop flag is raised.
+    /// Each connection is served by a dedicated thread, up to
+    /// `MAX_CONNECTIONS` at once.
+    pub fn run(&mut self) {
+        let stopped = || self.stop.load(Ordering::Relaxed);
+        loop {
+            match &self.listener {
+                Listener::Tcp(listener) => match listener.accept() {
+                    Ok((stream, peer)) => {
+                        if stopped() {
+                            break;
+                        }
+                        let _ = stream.set_read_timeout(Some(IO_TIMEOUT));
+                        let _ = stream.set_write_timeout(Some(IO_TIMEOUT));
+                        self.serve(stream, peer, false);
+                    }
+                    Err(err) => warn!("[api] accept error: {}", err),
+                },
+                #[cfg(unix)]
+                Listener::Unix(listener) => match listener.accept() {
+                    Ok((stream, _)) => {
+                        if stopped() {
+                            break;
+                        }
+                        let _ = stream.set_read_timeout(Some(IO_TIMEOUT));
+                        let _ = stream.set_write_timeout(Some(IO_TIMEOUT));
+                        let peer = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+                        self.serve(stream, peer, true);
+                    }
+                    Err(err) => warn!("[api] admin socket accept error: {}", err),
+                },
+            }
+        }
+    }
+}
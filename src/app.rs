@@ -15,20 +15,84 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::config::DEFAULT_BOOTSTRAP_REPLICANT_PATH;
+use crate::api::{
+    auth::ApiAuth,
+    service::{ApiConfig, ApiService},
+    Router,
+};
+use crate::bootstrap_reader::{self, BootstrapReader};
+use crate::catchup::{self, CatchUp};
+#[cfg(feature = "chaos")]
+use crate::chaos::Chaos;
+use crate::checkpoints::{self, Checkpoints};
+use crate::clock::Clock;
+use crate::config::{self, ConfigLoader, DEFAULT_BOOTSTRAP_REPLICANT_PATH, DEFAULT_NETWORK_ID};
+use crate::control::{self, NodeControl};
+use crate::correlation::Correlation;
+use crate::crash::CrashReporter;
+use crate::denylist::Denylist;
+use crate::download;
+use crate::explorer::Explorer;
+use crate::forks::ForkDetector;
+use crate::gateway::admission::{Admission, AdmissionConfig};
+use crate::gateway::dedup::GossipDedup;
+use crate::gateway::journal::TxJournal;
+use crate::gateway::lanes::{self, Lanes};
+use crate::gateway::service::{GatewayContext, GatewayService};
+use crate::guard::{Guard, GuardConfig, Protocol};
+use crate::integrity;
+use crate::lock::DbLock;
+use crate::mdns::{self, Mdns};
+use crate::metrics::{Metrics, MetricsSource};
 #[cfg(feature = "monitor")]
-use crate::monitor::{self, service::MonitorService, worker::MonitorConfig};
+use crate::monitor::{
+    self,
+    alert::{AlertConfig, Alerter},
+    history::{History, HistoryConfig},
+    service::MonitorService,
+    status::MonitorConfig,
+};
+use crate::nat::{self, Nat, NatConfig};
+use crate::notify::Notifier;
+#[cfg(feature = "otel")]
+use crate::otel::{Otel, OtelConfig};
+use crate::p2p::{self, P2pService};
+use crate::pacer::{self, Pacer};
+use crate::peers::{self, PeerFilter};
+use crate::replica::{self, Replica};
+use crate::reputation::{self, Reputation, ReputationConfig};
+use crate::resources::{self, ResourceConfig, ResourceGuard};
+use crate::service_contract::{self, ServiceContract};
+use crate::snapshots::SnapshotServer;
+use crate::state_diff::StateTracker;
+use crate::stats::{self, CoreStats};
+use crate::tasks::Tasks;
+use crate::tracer::Tracer;
+use crate::traffic::Traffic;
 use crate::utils;
+use crate::version::{self, PeerVersions};
+use crate::visa::{self, Endpoints, NodeVisa, Visa};
+use crate::watchdog::{Facts, Verdict, Watchdog, WatchdogConfig};
+use crate::wm_cache::{self, NodeWm, WmCache, WmPool};
+use crate::ws::{WsConfig, WsService};
 use crate::{config::Config, config::SERVICE_ACCOUNT_ID};
+use crate::{logfile, logfilter};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 use trinci_core::base::BlockchainSettings;
 use trinci_core::crypto::drand::SeedSource;
 use trinci_core::crypto::{Hash, HashAlgorithm, Hashable};
 use trinci_core::db::DbFork;
 use trinci_core::rest::service::NodeInfo;
 
-use trinci_core::{wm::MAX_FUEL, Account, Error, VERSION};
+use trinci_core::{Account, Error, VERSION};
 
 use version_compare::Cmp;
 
@@ -39,33 +103,287 @@ use trinci_core::{
     },
     blockchain::{BlockConfig, BlockRequestSender, BlockService, Event, Message},
     bridge::{BridgeConfig, BridgeService},
-    crypto::{ed25519::KeyPair as Ed25519KeyPair, ed25519::PublicKey as Ed25519PublicKey, KeyPair},
+    crypto::{ed25519::PublicKey as Ed25519PublicKey, KeyPair},
     db::{Db, RocksDb, RocksDbFork},
-    p2p::{service::PeerConfig, PeerService},
+    p2p::service::PeerConfig,
     rest::{RestConfig, RestService},
-    wm::{Wm, WmLocal},
     ErrorKind, Transaction,
 };
 
 #[cfg(feature = "kafka")]
 use trinci_core::kafka::{KafkaConfig, KafkaService};
 
+/// Node startup failure.
+#[derive(Debug, PartialEq, Eq)]
+pub enum StartupError {
+    /// The core version is below the blockchain `min_node_version`.
+    UpgradeRequired { found: String, need: String },
+    /// Versions not comparable.
+    InvalidVersion { found: String, need: String },
+    /// Database integrity check failure.
+    CorruptedDb(String),
+    /// Database folder in use or not accessible.
+    DbLocked(String),
+    /// Public listener guard setup failure.
+    Guard(String),
+    /// Keypair file not loadable.
+    Keypair(String),
+    /// Bootstrap file not available.
+    Bootstrap(String),
+    /// Stored chain conflicting with a trusted checkpoint.
+    Checkpoint(String),
+}
+
+impl std::fmt::Display for StartupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StartupError::UpgradeRequired { found, need } => {
+                write!(f, "upgrade required, found core {}, need {}", found, need)
+            }
+            StartupError::InvalidVersion { found, need } => write!(
+                f,
+                "unable to compare the core version {} with the required {}",
+                found, need
+            ),
+            StartupError::CorruptedDb(err) => write!(
+                f,
+                "database corrupted, {} (restore a backup or resync the node with `--autorepl`)",
+                err
+            ),
+            StartupError::DbLocked(err) => write!(f, "{}", err),
+            StartupError::Guard(err) => write!(f, "{}", err),
+            StartupError::Keypair(err) => write!(f, "{}", err),
+            StartupError::Bootstrap(err) => write!(f, "{}", err),
+            StartupError::Checkpoint(err) => {
+                write!(
+                    f,
+                    "{} (the stored chain is on a fork, resync the node)",
+                    err
+                )
+            }
+        }
+    }
+}
+
+/// Checks the core version against the blockchain minimum, a failure is only
+/// logged when `force` is set.
+fn check_min_version(found: &str, need: &str, force: bool) -> Result<(), StartupError> {
+    let err = match version_compare::compare(found, need) {
+        Ok(Cmp::Lt) => StartupError::UpgradeRequired {
+            found: found.to_string(),
+            need: need.to_string(),
+        },
+        Ok(_) => return Ok(()),
+        Err(_) => StartupError::InvalidVersion {
+            found: found.to_string(),
+            need: need.to_string(),
+        },
+    };
+    if force {
+        warn!("{} (overridden by `force-version-override`)", err);
+        Ok(())
+    } else {
+        Err(err)
+    }
+}
+
+/// Puts a guard in front of a public listener when any limit or additional
+/// address is configured.
+/// Returns the guard and the address the core service has to bind.
+fn guard_listener(
+    name: &'static str,
+    protocol: Protocol,
+    config: GuardConfig,
+    addr: &str,
+    extra_addrs: &[String],
+    port: u16,
+    metrics: &Metrics,
+) -> Result<(Option<Arc<Guard>>, String, u16), StartupError> {
+    // The IPv6 addresses are bound by the guard, the core services
+    // expecting IPv4 ones.
+    if !config.is_enabled() && extra_addrs.is_empty() && !utils::is_ipv6(addr) {
+        return Ok((None, addr.to_string(), port));
+    }
+    let addrs = std::iter::once(addr)
+        .chain(extra_addrs.iter().map(String::as_str))
+        .map(|addr| utils::host_port(addr, port))
+        .collect();
+    let guard = Guard::loopback(name, protocol, config, addrs).map_err(|err| {
+        StartupError::Guard(format!(
+            "no loopback port for the {} service: {}",
+            name, err
+        ))
+    })?;
+    let upstream = guard.upstream();
+    let guard = Arc::new(guard);
+    metrics.register(guard.clone());
+    Ok((Some(guard), upstream.ip().to_string(), upstream.port()))
+}
+
+/// Relays the P2P additional addresses to the core service, which binds the
+/// main address only, IPv4.
+fn p2p_relay(
+    addr: &str,
+    extra_addrs: &[String],
+    port: u16,
+    metrics: &Metrics,
+) -> Result<Option<Arc<Guard>>, StartupError> {
+    if utils::is_ipv6(addr) {
+        return Err(StartupError::Guard(format!(
+            "P2P address {} not supported, the IPv6 addresses go in `p2p-extra-addrs`",
+            addr
+        )));
+    }
+    if extra_addrs.is_empty() {
+        return Ok(None);
+    }
+    if port == 0 {
+        return Err(StartupError::Guard(
+            "`p2p-extra-addrs` requires a fixed `p2p-port`".to_string(),
+        ));
+    }
+    let ip = addr
+        .parse::<Ipv4Addr>()
+        .map_err(|_| StartupError::Guard(format!("invalid P2P address {}", addr)))?;
+    let ip = if ip.is_unspecified() {
+        Ipv4Addr::LOCALHOST
+    } else {
+        ip
+    };
+    let addrs = extra_addrs
+        .iter()
+        .map(|addr| utils::host_port(addr, port))
+        .collect();
+    let guard = Arc::new(Guard::new(
+        "p2p",
+        Protocol::Stream,
+        GuardConfig::default(),
+        addrs,
+        SocketAddr::from((ip, port)),
+    ));
+    metrics.register(guard.clone());
+    Ok(Some(guard))
+}
+
+// Frees the loopback port kept by a guard, before its core service start.
+fn release_upstream(guard: &Option<Arc<Guard>>) {
+    if let Some(guard) = guard {
+        guard.release_upstream();
+    }
+}
+
+// Keeps the loopback port of a stopped core service.
+fn hold_upstream(guard: &Option<Arc<Guard>>) {
+    if let Some(guard) = guard {
+        guard.hold_upstream();
+    }
+}
+
+fn start_guard(guard: &Option<Arc<Guard>>) {
+    if let Some(guard) = guard {
+        if let Err(err) = Guard::start(guard.clone()) {
+            error!("[guard] {} listener: {}", guard.name(), err);
+        }
+    }
+}
+
 /// Application context.
 pub struct App {
     /// Block service context.
-    pub block_svc: Arc<Mutex<BlockService<RocksDb, WmLocal>>>,
+    pub block_svc: Arc<Mutex<BlockService<RocksDb, NodeWm>>>,
     /// Rest service context.
     pub rest_svc: RestService,
+    /// Rest listener limits.
+    pub rest_guard: Option<Arc<Guard>>,
     /// Peer2Peer service context.
-    pub p2p_svc: Arc<Mutex<PeerService>>,
+    pub p2p_svc: Arc<Mutex<P2pService>>,
+    /// Peer2Peer additional addresses relay.
+    pub p2p_guard: Option<Arc<Guard>>,
     /// Bridge service context.
     pub bridge_svc: BridgeService,
+    /// Bridge listener limits.
+    pub bridge_guard: Option<Arc<Guard>>,
+    /// Gateway service context.
+    pub gateway_svc: GatewayService,
+    /// Client transactions priority lanes.
+    pub lanes: Arc<Lanes>,
+    /// Unconfirmed transactions journal.
+    pub journal: Option<Arc<TxJournal>>,
+    /// Process resources guard.
+    pub resources: Arc<ResourceGuard>,
+    /// Stalled chain watchdog.
+    pub watchdog: Watchdog,
+    /// Node API service context.
+    pub api_svc: ApiService,
+    /// WebSocket events service context.
+    pub ws_svc: WsService,
+    /// Primary blocks follower, in replica role.
+    pub replica: Option<Arc<Replica>>,
+    /// Pipelined catch up from a trusted node, in full and api roles.
+    pub catchup_peer: Option<Arc<Replica>>,
+    /// Competing blocks detection.
+    pub forks: Arc<ForkDetector>,
+    /// Trusted checkpoints.
+    pub checkpoints: Arc<Checkpoints>,
+    /// Chain catch up progress.
+    pub catchup: Arc<CatchUp>,
+    /// Supervised node workers.
+    pub tasks: Tasks,
+    /// Crash reports of the threads panics.
+    pub crash: Arc<CrashReporter>,
+    /// Node lifecycle control.
+    pub control: Arc<NodeControl>,
+    /// Smart contracts cache.
+    pub wm_cache: Arc<WmCache>,
+    /// Wasm machines serving the node-initiated calls.
+    pub wm_pool: Arc<WmPool>,
+    /// Smart contracts to load at startup.
+    pub wm_preload: Vec<Hash>,
+    /// Blocks throughput metrics.
+    pub tracer: Arc<Tracer>,
+    /// Receipt push notifications.
+    pub notifier: Arc<Notifier>,
+    /// Requests tracing.
+    pub correlation: Option<Arc<Correlation>>,
+    /// P2P reachability detection.
+    pub nat: Arc<Nat>,
+    /// Local network peers discovery.
+    pub mdns: Option<Mdns>,
+    /// P2P peers reputation.
+    pub reputation: Arc<Reputation>,
+    /// Peers versions.
+    pub versions: Arc<RwLock<PeerVersions>>,
+    /// Service contract upgrades.
+    pub service_contract: Arc<ServiceContract>,
+    /// Core stats sampling.
+    pub stats: Arc<CoreStats>,
+    /// Genesis block threshold and timeout.
+    pub bootstrap_block: (usize, u16),
+    /// Blocks production pacing, when the idle skip is enabled.
+    pub pacer: Option<Arc<Pacer>>,
+    /// Start even if the core version is below the blockchain minimum.
+    pub force_version_override: bool,
+    /// Validator check configuration.
+    pub validators: ValidatorConfig,
+    /// Node role.
+    pub role: NodeRole,
     /// Monitor service context.
     #[cfg(feature = "monitor")]
     pub monitor_svc: Option<MonitorService>,
+    /// Monitor alerts.
+    #[cfg(feature = "monitor")]
+    pub alerter: Arc<Alerter>,
     /// Kafka service TODO: make it optional
     #[cfg(feature = "kafka")]
     pub kafka_svc: KafkaService,
+    /// OpenTelemetry exporter.
+    #[cfg(feature = "otel")]
+    pub otel: Option<Arc<Otel>>,
+    /// Fault injection.
+    #[cfg(feature = "chaos")]
+    pub chaos: Arc<Chaos>,
+    /// Node timers clock, virtual in simulation mode.
+    pub clock: Clock,
     /// Keypair placeholder.
     pub keypair: Arc<KeyPair>,
     /// p2p Keypair placeholder
@@ -74,6 +392,8 @@ pub struct App {
     pub bootstrap_path: String,
     /// Seed
     pub seed: Arc<SeedSource>,
+    /// Database folder lock, held until the node exits.
+    _db_lock: DbLock,
 }
 
 // If this panics, it panics early at node boot. Not a big deal.
@@ -97,23 +417,190 @@ fn is_service_present(chan: &BlockRequestSender) -> bool {
 
 use trinci_core::blockchain::IsValidator;
 
+/// Block timeouts waited at most for the pending transactions at handover.
+const HANDOVER_BLOCK_TIMEOUTS: u64 = 3;
+
+/// Max time waited for the node workers to end at shutdown.
+const TASKS_SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+/// Time given to the alerts sent before stopping the node.
+#[cfg(feature = "monitor")]
+const ALERT_DELIVERY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
 // All nodes are validator for the first block
 fn is_validator_function_temporary(value: bool) -> impl IsValidator {
     move |_account_id| Ok(value)
 }
 
-/// Method to check if the node is a current validator
-fn is_validator_function_call(
-    wm: Arc<Mutex<dyn Wm>>,
+/// How the node checks if an account is a current validator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidatorMode {
+    /// Ask the service contract `is_validator` method.
+    Contract,
+    /// Accounts listed in the `validators` configuration.
+    Static,
+    /// Every account (development only).
+    Always,
+}
+
+impl std::str::FromStr for ValidatorMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "contract" => Ok(ValidatorMode::Contract),
+            "static" => Ok(ValidatorMode::Static),
+            "always" => Ok(ValidatorMode::Always),
+            _ => Err(format!(
+                "invalid validator mode `{}` (expected contract, static or always)",
+                value
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for ValidatorMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mode = match self {
+            ValidatorMode::Contract => "contract",
+            ValidatorMode::Static => "static",
+            ValidatorMode::Always => "always",
+        };
+        write!(f, "{}", mode)
+    }
+}
+
+/// Node role within the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeRole {
+    /// Block production when validator, plus all the services.
+    Full,
+    /// Read traffic only: the node executes and serves the blocks, but is
+    /// never a validator and doesn't need a node keypair.
+    Api,
+    /// P2P connectivity only: gossip relay and peers discovery, the
+    /// blockchain service (contracts execution) and REST, bridge and
+    /// WebSocket services are never started.
+    Relay,
+    /// Read traffic only, without P2P: the blocks are streamed from the
+    /// `replica-primary` node.
+    Replica,
+}
+
+impl std::str::FromStr for NodeRole {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "full" => Ok(NodeRole::Full),
+            "api" => Ok(NodeRole::Api),
+            "relay" => Ok(NodeRole::Relay),
+            "replica" => Ok(NodeRole::Replica),
+            _ => Err(format!(
+                "invalid node role `{}` (expected full, api, relay or replica)",
+                value
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for NodeRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let role = match self {
+            NodeRole::Full => "full",
+            NodeRole::Api => "api",
+            NodeRole::Relay => "relay",
+            NodeRole::Replica => "replica",
+        };
+        write!(f, "{}", role)
+    }
+}
+
+/// Strategy used to check if an account is a current validator.
+pub trait ValidatorStrategy: Send + Sync {
+    fn is_validator(&self, account_id: &str) -> trinci_core::Result<bool>;
+}
+
+/// Node-initiated contract calls (not part of a transaction), limits and
+/// accounting.
+#[derive(Debug)]
+pub struct InternalCalls {
+    /// Max fuel spent by a single call.
+    pub fuel: u64,
+    /// Call depth the calls start from, it bounds the nested calls.
+    pub depth: u16,
+    /// Account acting as origin and caller.
+    pub origin: String,
+    calls: AtomicU64,
+    errors: AtomicU64,
+    burned_fuel: AtomicU64,
+}
+
+impl InternalCalls {
+    pub fn new(fuel: u64, depth: u16, origin: String) -> Self {
+        InternalCalls {
+            fuel,
+            depth,
+            origin,
+            calls: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            burned_fuel: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn account(&self, burned_fuel: u64, error: bool) {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.burned_fuel.fetch_add(burned_fuel, Ordering::Relaxed);
+        if error {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl MetricsSource for InternalCalls {
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# TYPE trinci_internal_calls_total counter");
+        let _ = writeln!(
+            out,
+            "trinci_internal_calls_total {}",
+            self.calls.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "# TYPE trinci_internal_call_errors_total counter");
+        let _ = writeln!(
+            out,
+            "trinci_internal_call_errors_total {}",
+            self.errors.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "# TYPE trinci_internal_call_fuel_total counter");
+        let _ = writeln!(
+            out,
+            "trinci_internal_call_fuel_total {}",
+            self.burned_fuel.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Calls the service contract `is_validator` method.
+pub struct ContractValidator {
+    wm: Arc<WmPool>,
     db: Arc<RwLock<dyn Db<DbForkType = RocksDbFork>>>,
     seed: Arc<SeedSource>,
-    block_timestamp: u64,
-) -> impl IsValidator {
-    move |account_id: String| {
+    calls: Arc<InternalCalls>,
+}
+
+impl ValidatorStrategy for ContractValidator {
+    fn is_validator(&self, account_id: &str) -> trinci_core::Result<bool> {
         let args = rmp_serialize(&account_id)?;
 
-        let seed = seed.clone();
-        let mut fork = db.write().fork_create();
+        // Not yet stored during the bootstrap.
+        let network = self
+            .db
+            .read()
+            .load_configuration("blockchain:settings")
+            .and_then(|buf| rmp_deserialize::<BlockchainSettings>(&buf).ok())
+            .and_then(|config| config.network_name)
+            .unwrap_or_default();
+
+        let mut fork = self.db.write().fork_create();
 
         let account = fork
             .load_account(SERVICE_ACCOUNT_ID)
@@ -126,29 +613,162 @@ fn is_validator_function_call(
             )
         })?;
 
-        let (_, res) = wm.lock().call(
-            &mut fork,
-            42,
-            "skynet",
-            SERVICE_ACCOUNT_ID,
-            SERVICE_ACCOUNT_ID,
-            SERVICE_ACCOUNT_ID,
-            contract,
-            "is_validator",
-            &args,
-            seed,
-            &mut Vec::new(),
-            #[cfg(feature = "indexer")]
-            &mut Vec::new(),
-            MAX_FUEL,
-            block_timestamp,
-        );
+        let calls = &self.calls;
+        let (burned_fuel, res) = self.wm.with(|wm| {
+            wm.call(
+                &mut fork,
+                calls.depth,
+                &network,
+                &calls.origin,
+                SERVICE_ACCOUNT_ID,
+                &calls.origin,
+                contract,
+                "is_validator",
+                &args,
+                self.seed.clone(),
+                &mut Vec::new(),
+                #[cfg(feature = "indexer")]
+                &mut Vec::new(),
+                calls.fuel,
+                0,
+            )
+        });
+        calls.account(burned_fuel, res.is_err());
         let res = res?;
 
         rmp_deserialize(&res)
     }
 }
 
+/// Accounts listed in the configuration.
+pub struct StaticValidators(HashSet<String>);
+
+impl ValidatorStrategy for StaticValidators {
+    fn is_validator(&self, account_id: &str) -> trinci_core::Result<bool> {
+        Ok(self.0.contains(account_id))
+    }
+}
+
+/// Every account is a validator.
+pub struct AlwaysValidator;
+
+impl ValidatorStrategy for AlwaysValidator {
+    fn is_validator(&self, _account_id: &str) -> trinci_core::Result<bool> {
+        Ok(true)
+    }
+}
+
+/// Keeps the results of another strategy until a new block is executed.
+pub struct CachedValidator<S> {
+    inner: S,
+    db: Arc<RwLock<dyn Db<DbForkType = RocksDbFork>>>,
+    cache: Mutex<(Option<u64>, HashMap<String, bool>)>,
+}
+
+impl<S: ValidatorStrategy> CachedValidator<S> {
+    pub fn new(inner: S, db: Arc<RwLock<dyn Db<DbForkType = RocksDbFork>>>) -> Self {
+        CachedValidator {
+            inner,
+            db,
+            cache: Mutex::new((None, HashMap::new())),
+        }
+    }
+}
+
+impl<S: ValidatorStrategy> ValidatorStrategy for CachedValidator<S> {
+    fn is_validator(&self, account_id: &str) -> trinci_core::Result<bool> {
+        let height = self
+            .db
+            .read()
+            .load_block(u64::MAX)
+            .map(|block| block.data.height);
+        {
+            let mut cache = self.cache.lock();
+            if cache.0 != height {
+                *cache = (height, HashMap::new());
+            }
+            if let Some(result) = cache.1.get(account_id) {
+                return Ok(*result);
+            }
+        }
+        // Errors are not cached, the next check tries again.
+        let result = self.inner.is_validator(account_id)?;
+        let mut cache = self.cache.lock();
+        if cache.0 == height {
+            cache.1.insert(account_id.to_string(), result);
+        }
+        Ok(result)
+    }
+}
+
+/// Validator check configuration.
+#[derive(Debug, Clone)]
+pub struct ValidatorConfig {
+    pub mode: ValidatorMode,
+    /// Validators in `static` mode.
+    pub validators: Vec<String>,
+    /// Limits of the service contract calls.
+    pub calls: Arc<InternalCalls>,
+    /// Account never reported as validator: the node itself in `api` role.
+    pub excluded: Option<String>,
+}
+
+impl ValidatorConfig {
+    /// Builds the configured strategy.
+    pub fn strategy(
+        &self,
+        wm: Arc<WmPool>,
+        db: Arc<RwLock<dyn Db<DbForkType = RocksDbFork>>>,
+        seed: Arc<SeedSource>,
+    ) -> Box<dyn ValidatorStrategy> {
+        match self.mode {
+            ValidatorMode::Contract => {
+                let contract = ContractValidator {
+                    wm,
+                    db: db.clone(),
+                    seed,
+                    calls: self.calls.clone(),
+                };
+                Box::new(CachedValidator::new(contract, db))
+            }
+            ValidatorMode::Static => {
+                Box::new(StaticValidators(self.validators.iter().cloned().collect()))
+            }
+            ValidatorMode::Always => Box::new(AlwaysValidator),
+        }
+    }
+
+    /// Method to check if the node is a current validator
+    pub fn is_validator_function(
+        &self,
+        wm: Arc<WmPool>,
+        db: Arc<RwLock<dyn Db<DbForkType = RocksDbFork>>>,
+        seed: Arc<SeedSource>,
+    ) -> impl IsValidator {
+        let strategy = self.strategy(wm, db, seed);
+        let excluded = self.excluded.clone();
+        move |account_id: String| {
+            if excluded.as_ref() == Some(&account_id) {
+                return Ok(false);
+            }
+            strategy.is_validator(&account_id)
+        }
+    }
+}
+
+// REST addresses of the P2P bootstrap peers, assumed on the local REST port.
+fn bootstrap_rest_hosts(config: &Config) -> Vec<String> {
+    config
+        .p2p_bootstrap_addrs
+        .iter()
+        .filter_map(|addr| peers::address_endpoint(addr))
+        .filter_map(|endpoint| {
+            let (host, _) = endpoint.rsplit_once(':')?;
+            Some(format!("{}:{}", host, config.rest_port))
+        })
+        .collect()
+}
+
 fn bootstrap_monitor(chan: BlockRequestSender) {
     debug!("Bootstrap procedure started");
 
@@ -180,34 +800,21 @@ fn bootstrap_monitor(chan: BlockRequestSender) {
     .unwrap();
 }
 
-// Calculate the network name from the bootstrap hash
-fn calculate_network_name(data: &[u8]) -> String {
+/// Calculates the network name from the bootstrap hash.
+pub(crate) fn calculate_network_name(data: &[u8]) -> String {
     let hash = Hash::from_data(HashAlgorithm::Sha256, data);
     bs58::encode(hash).into_string()
 }
 
-// Load the bootstrap struct from file, panic if something goes wrong
-fn load_bootstrap_struct_from_file(path: &str) -> (String, Vec<u8>, Vec<Transaction>) {
-    println!("path: {}", path);
-    let mut bootstrap_file = std::fs::File::open(path).expect("bootstrap file not found");
-
-    let mut buf = Vec::new();
-    std::io::Read::read_to_end(&mut bootstrap_file, &mut buf).expect("loading bootstrap");
-
-    match rmp_deserialize::<Bootstrap>(&buf) {
-        Ok(bs) => (calculate_network_name(&buf), bs.bin, bs.txs),
-        Err(_) => panic!("Invalid bootstrap file format!"), // If the bootstrap is not valid should panic!
-    }
-}
 #[derive(Serialize, Deserialize)]
-struct Bootstrap {
+pub(crate) struct Bootstrap {
     // Binary bootstrap.wasm
     #[serde(with = "serde_bytes")]
-    bin: Vec<u8>,
+    pub bin: Vec<u8>,
     // Vec of transaction for the genesis block
-    txs: Vec<Transaction>,
+    pub txs: Vec<Transaction>,
     // Random string to generate unique file
-    nonce: String,
+    pub nonce: String,
 }
 
 // If this panics, it panics early at node boot. Not a big deal.
@@ -239,8 +846,28 @@ pub(crate) fn load_config_from_service(chan: &BlockRequestSender) -> BlockchainS
 
 impl App {
     /// Create a new Application instance.
-    pub fn new(mut config: Config, keypair: KeyPair) -> Self {
-        let wm = WmLocal::new(config.wm_cache_max);
+    pub fn new(
+        mut config: Config,
+        keypair: KeyPair,
+        loader: ConfigLoader,
+    ) -> Result<Self, StartupError> {
+        // The settings as loaded, before the bootstrap updates.
+        let settings = config.settings();
+        let config_hash = config::settings_hash(&settings);
+        let wm_cache = Arc::new(WmCache::new(config.wm_cache_max));
+        let wm = NodeWm::new(wm_cache.clone());
+        let wm_pool = Arc::new(WmPool::new(config.wm_pool_size, config.wm_cache_max));
+        let wm_preload = config
+            .wm_preload
+            .iter()
+            .filter_map(|hex| match Hash::from_hex(hex) {
+                Ok(hash) => Some(hash),
+                Err(_) => {
+                    warn!("[wm] invalid preload contract hash {}", hex);
+                    None
+                }
+            })
+            .collect();
 
         // In case the autoreplicant setting is enbled,
         // recover the needed info from the bootstrap node.
@@ -248,10 +875,10 @@ impl App {
             Some(bootstrap_node_address) => {
                 // Collect bootstrap infos.
                 let visa = utils::get_visa(&bootstrap_node_address).unwrap();
-                config.p2p_bootstrap_addr = Some(format!(
-                    "{}@/ip4/{}/tcp/{}",
-                    visa.p2p_account_id, visa.public_ip, visa.p2p_port
-                ));
+                config.p2p_bootstrap_addrs.insert(
+                    0,
+                    peers::peer_address(&visa.p2p_account_id, &visa.public_ip, visa.p2p_port),
+                );
 
                 // Retrieve bootstrap transactions.
                 let bootstrap_path = DEFAULT_BOOTSTRAP_REPLICANT_PATH;
@@ -262,20 +889,52 @@ impl App {
                 config.db_path = format!("db/{}", bootstrap_hash);
 
                 // Check if local version is coherent to the remote version.
-                utils::check_version(
-                    (
-                        env!("CARGO_PKG_VERSION").to_string(),
-                        trinci_core::VERSION.to_string(),
-                    ),
-                    visa.node_version,
-                );
+                for warning in version::compare(&version::local(), &visa.node_version) {
+                    warn!("[autorepl] bootstrap node: {}", warning);
+                }
             }
             _ => (),
         }
 
         // If in replication mode, path specified by nw name,
         // otherwise the config file path will be used.
-        let db = RocksDb::new(&config.db_path);
+        let db_lock = DbLock::acquire(&config.db_path).map_err(StartupError::DbLocked)?;
+        let mut db = RocksDb::new(&config.db_path);
+        integrity::verify(&mut db, config.db_verify).map_err(StartupError::CorruptedDb)?;
+        let checkpoints = Checkpoints::new(
+            config
+                .checkpoints
+                .iter()
+                .filter_map(|entry| checkpoints::parse(entry).ok())
+                .collect(),
+        );
+        checkpoints.refresh(&db);
+        checkpoints.verify(&db).map_err(StartupError::Checkpoint)?;
+
+        // First start without the bootstrap file, as for a new node: fetched
+        // from the REST service of the bootstrap peers.
+        if config.bootstrap_fetch
+            && db.load_block(u64::MAX).is_none()
+            && !Path::new(&config.bootstrap_path).exists()
+        {
+            if config.network == DEFAULT_NETWORK_ID {
+                return Err(StartupError::Bootstrap(format!(
+                    "{} not found, set `network` to the network name to fetch it from the bootstrap peers",
+                    config.bootstrap_path
+                )));
+            }
+            let hosts = bootstrap_rest_hosts(&config);
+            download::fetch_bootstrap(&hosts, Some(&config.network), &config.bootstrap_path)
+                .map_err(StartupError::Bootstrap)?;
+        }
+
+        // The service contract is never evicted from the cache.
+        if let Some(contract) = db
+            .load_account(SERVICE_ACCOUNT_ID)
+            .and_then(|account| account.contract)
+        {
+            wm_cache.pin(contract);
+        }
 
         let keypair = Arc::new(keypair);
 
@@ -329,8 +988,12 @@ impl App {
             debug!("[p2p] keypair loaded from file");
             (p2p_keypair.public_key(), p2p_keypair)
         } else {
-            let p2p_keypair = Ed25519KeyPair::from_random();
-            debug!("[p2p] keypair randomly generated");
+            // A random node keypair changes at every start, nothing to endorse.
+            let node_keypair = (config.keypair_path.is_some() && config.role == NodeRole::Full)
+                .then_some(&*keypair as &dyn utils::Signer);
+            let p2p_keypair = utils::load_p2p_identity(&config.db_path, node_keypair)
+                .map_err(|err| StartupError::Keypair(format!("P2P identity: {}", err)))?;
+            debug!("[p2p] keypair loaded from the database folder");
             (p2p_keypair.public_key(), p2p_keypair)
         };
 
@@ -347,47 +1010,385 @@ impl App {
         );
         let chan = block_svc.request_channel();
 
+        let replica = (config.role == NodeRole::Replica).then(|| {
+            Arc::new(
+                Replica::new(
+                    config.replica_primary.clone(),
+                    block_svc.db_arc(),
+                    chan.clone(),
+                )
+                .with_threads(config.replay_threads),
+            )
+        });
+        // Without a trusted peer the P2P synchronization is pipelined from
+        // the first bootstrap peer.
+        let catchup_peer = match (&config.catchup_peer, config.role) {
+            (Some(peer), _) => Some(peer.clone()),
+            (None, NodeRole::Full | NodeRole::Api) if !config.offline => {
+                bootstrap_rest_hosts(&config).into_iter().next()
+            }
+            _ => None,
+        };
+        let catchup_peer = catchup_peer.map(|peer| {
+            Arc::new(
+                Replica::new(peer, block_svc.db_arc(), chan.clone())
+                    .with_threads(config.replay_threads),
+            )
+        });
+
+        // Requests from REST, bridge and P2P pass through the gateway.
+        let denylist = Arc::new(Mutex::new(Denylist::open(&config.db_path)));
+        let metrics = Arc::new(Metrics::new());
+        metrics.register(wm_cache.clone());
+        let tracer = Arc::new(Tracer::open(&config.db_path));
+        metrics.register(tracer.clone());
+        let traffic = Arc::new(Traffic::new());
+        metrics.register(traffic.clone());
+        let control = Arc::new(NodeControl::new(block_svc.db_arc(), config.offline));
+        let crash = Arc::new(CrashReporter::new(
+            &config.db_path,
+            keypair.public_key().to_account_id(),
+            config_hash.clone(),
+            block_svc.db_arc(),
+        ));
+        let block_svc = Arc::new(Mutex::new(block_svc));
+        let validators = ValidatorConfig {
+            mode: config.validator_mode,
+            validators: config.validators.clone(),
+            calls: Arc::new(InternalCalls::new(
+                config.internal_call_fuel,
+                config.internal_call_depth,
+                config.internal_call_origin.clone(),
+            )),
+            excluded: matches!(config.role, NodeRole::Api | NodeRole::Replica)
+                .then(|| keypair.public_key().to_account_id()),
+        };
+        metrics.register(validators.calls.clone());
+        let service_contract = Arc::new(ServiceContract::new(
+            block_svc.clone(),
+            wm_cache.clone(),
+            wm_pool.clone(),
+            seed.clone(),
+            validators.clone(),
+        ));
+        let peers = Arc::new(RwLock::new(PeerFilter::new(
+            config.p2p_allowed_peers.clone(),
+            config.p2p_blocked_peers.clone(),
+        )));
+        let reputation = Arc::new(
+            Reputation::new(ReputationConfig {
+                ban_score: config.p2p_ban_score,
+                ban_duration: config.p2p_ban_duration,
+            })
+            .with_db(block_svc.lock().db_arc()),
+        );
+        let tasks = Tasks::default();
+        // A relay node never runs the blockchain service.
+        let gateway_chan = match config.role {
+            NodeRole::Relay => {
+                crate::gateway::service::sink(&tasks, "relay node, no blockchain data")
+            }
+            _ => chan.clone(),
+        };
+        let journal = (config.tx_journal && config.role != NodeRole::Relay)
+            .then(|| TxJournal::open(&config.db_path))
+            .and_then(|journal| match journal {
+                Ok(journal) => {
+                    info!("[journal] {} unconfirmed transactions", journal.len());
+                    Some(Arc::new(journal))
+                }
+                Err(err) => {
+                    error!("[journal] unable to open the transactions journal: {}", err);
+                    None
+                }
+            });
+        let resources = Arc::new(ResourceGuard::new(
+            ResourceConfig {
+                max_memory: config.resource_max_memory,
+                max_open_files: config.resource_max_open_files,
+                min_disk_free: config.resource_min_disk_free,
+                critical_disk_free: config.resource_critical_disk_free,
+            },
+            &config.db_path,
+        ));
+        let clock = if config.simulation {
+            warn!("Simulation mode, the node timers follow the virtual clock");
+            Clock::simulated()
+        } else {
+            Clock::default()
+        };
+        let watchdog = Watchdog::new(WatchdogConfig {
+            factor: config.stall_factor,
+            recover: config.stall_recover,
+            block_timeout: config.block_timeout,
+        });
+        let admission = Arc::new(
+            Admission::new(AdmissionConfig {
+                max_tx_size: config.admission_max_tx_size,
+                max_pending: config.admission_max_pending,
+                min_fuel: config.admission_min_fuel,
+                allowed_accounts: config.admission_allowed_accounts.iter().cloned().collect(),
+                blocked_accounts: config.admission_blocked_accounts.iter().cloned().collect(),
+                max_pool: config.admission_max_pool,
+                retry_after: config.block_timeout as u64,
+            })
+            .with_resources(resources.clone())
+            .with_pool(gateway_chan.clone()),
+        );
+        let lanes = Arc::new(Lanes::new(config.pool_priority, config.block_threshold));
+        #[cfg(feature = "chaos")]
+        let chaos = Arc::new(Chaos::new());
+        metrics.register(lanes.clone());
+        let dedup = Arc::new(GossipDedup::new(config.p2p_gossip_cache));
+        metrics.register(dedup.clone());
+        let forks = Arc::new(ForkDetector::new().with_db(block_svc.lock().db_arc()));
+        metrics.register(forks.clone());
+        let checkpoints = Arc::new(checkpoints.with_db(block_svc.lock().db_arc()));
+        let catchup = CatchUp::new(block_svc.lock().db_arc(), traffic.clone());
+        let catchup = Arc::new(match replica.as_ref().or(catchup_peer.as_ref()) {
+            Some(replica) => catchup.with_replica(replica.clone()),
+            None => catchup,
+        });
+        let trace_endpoint = config.trace_otlp_endpoint.clone();
+        #[cfg(feature = "otel")]
+        let trace_endpoint = trace_endpoint.or_else(|| config.otel_endpoint.clone());
+        let correlation = config
+            .trace_requests
+            .then(|| Arc::new(Correlation::new(trace_endpoint)));
+        #[cfg(feature = "otel")]
+        let otel = config.otel_endpoint.clone().map(|endpoint| {
+            Arc::new(Otel::new(
+                OtelConfig {
+                    endpoint,
+                    interval: std::time::Duration::from_secs(config.otel_interval.max(1)),
+                },
+                tracer.clone(),
+                wm_cache.clone(),
+                lanes.clone(),
+            ))
+        });
+        let gateway_svc = GatewayService::new(
+            gateway_chan,
+            GatewayContext {
+                denylist: denylist.clone(),
+                metrics: metrics.clone(),
+                control: control.clone(),
+                peers: peers.clone(),
+                reputation: reputation.clone(),
+                admission,
+                lanes: lanes.clone(),
+                journal: journal.clone(),
+                traffic: traffic.clone(),
+                dedup,
+                forks: forks.clone(),
+                checkpoints: checkpoints.clone(),
+                correlation: correlation.clone(),
+                #[cfg(feature = "chaos")]
+                chaos: chaos.clone(),
+            },
+            tasks.clone(),
+        );
+
+        let nat = Arc::new(Nat::new(NatConfig {
+            public_ip: config.public_ip.clone(),
+            local_ip: config.local_ip.clone(),
+            p2p_port: config.p2p_port,
+            probe: config.nat_probe.clone(),
+            fallback: config.nat_fallback,
+            upnp_tool: config.nat_upnp_tool.clone(),
+        }));
+
+        let mut router = Router::new();
+        if let Some(path) = &config.api_keypair {
+            let auth = ApiAuth::open(path).map_err(StartupError::Keypair)?;
+            info!(
+                "[api] administration requests signed by {}",
+                auth.account_id()
+            );
+            router.set_auth(Arc::new(auth));
+        }
+        let explorer = Arc::new(Explorer::new(block_svc.lock().db_arc()));
+        Explorer::routes(explorer, &mut router);
+        if let Some(replica) = &replica {
+            Replica::routes(replica.clone(), &mut router);
+        }
+        let snapshots = Arc::new(SnapshotServer::new(block_svc.lock().db_arc()));
+        SnapshotServer::routes(snapshots, &mut router);
+        let node_visa = Visa {
+            node_version: env!("CARGO_PKG_VERSION").to_string(),
+            core_version: trinci_core::VERSION.to_string(),
+            network: None,
+            role: config.role.to_string(),
+            features: visa::features(),
+            public_key: keypair.public_key(),
+            account_id: keypair.public_key().to_account_id(),
+            p2p_account_id: p2p_public_key.to_account_id(),
+            endpoints: Endpoints {
+                public_ip: config.public_ip.clone(),
+                p2p_port: config.p2p_port,
+                rest_port: config.rest_port,
+                bridge_port: config.bridge_port,
+                ws_port: config.ws_port,
+                api_port: config.api_port,
+            },
+            time: 0,
+        };
+        let node_visa = NodeVisa::new(node_visa, block_svc.lock().db_arc(), keypair.clone());
+        NodeVisa::routes(Arc::new(node_visa), &mut router);
+        Denylist::routes(denylist, &mut router);
+        #[cfg(feature = "chaos")]
+        Chaos::routes(chaos.clone(), &mut router);
+        Clock::routes(clock.clone(), &mut router);
+        Metrics::routes(metrics.clone(), &mut router);
+        WmCache::routes(wm_cache.clone(), &mut router);
+        NodeControl::routes(control.clone(), &mut router);
+        ResourceGuard::routes(resources.clone(), &mut router);
+        Nat::routes(nat.clone(), &mut router);
+        PeerFilter::routes(peers.clone(), &mut router);
+        Reputation::routes(reputation.clone(), &mut router);
+        ForkDetector::routes(forks.clone(), &mut router);
+        Checkpoints::routes(checkpoints.clone(), &mut router);
+        Tasks::routes(tasks.clone(), &mut router);
+        logfilter::routes(&mut router);
+        logfile::routes(&mut router);
+        config::routes(settings, loader, &mut router);
+        if config.admin_socket.is_some() {
+            router.set_admin_socket_only();
+        } else if config.api_keypair.is_none() {
+            warn!("[api] administration routes disabled, set `admin-socket` or `api-keypair`");
+        }
+        ServiceContract::routes(service_contract.clone(), &mut router);
+        let pacer = (config.block_idle_skip && config.role == NodeRole::Full).then(|| {
+            Arc::new(Pacer::new(
+                block_svc.clone(),
+                chan.clone(),
+                config.block_idle_timeout,
+                clock.clone(),
+            ))
+        });
+        let stats = Arc::new(CoreStats::new(
+            chan.clone(),
+            config.stats_history,
+            traffic.clone(),
+            clock.clone(),
+        ));
+        CoreStats::routes(stats.clone(), &mut router);
+        Tracer::routes(tracer.clone(), &mut router);
+        let notifier = Arc::new(Notifier::new(chan.clone()));
+        Notifier::routes(notifier.clone(), &mut router);
+        let ws_svc = WsService::new(
+            WsConfig {
+                addr: config.ws_addr.clone(),
+                port: config.ws_port,
+            },
+            chan.clone(),
+            config
+                .ws_state_diff
+                .then(|| StateTracker::new(block_svc.lock().db_arc())),
+        );
+
+        let mdns = if config.p2p_mdns && !config.offline {
+            Mdns::start(&p2p_public_key.to_account_id(), config.p2p_port)
+        } else {
+            None
+        };
+        if let Some(mdns) = &mdns {
+            let discovered = mdns.discover(mdns::DISCOVERY_WINDOW);
+            for addr in discovered {
+                if !config.p2p_bootstrap_addrs.contains(&addr) {
+                    config.p2p_bootstrap_addrs.push(addr);
+                }
+            }
+        }
+        let p2p_bootstrap_addr = if config.offline {
+            config.p2p_bootstrap_addrs.first().cloned()
+        } else {
+            peers::select_bootstrap(&config.p2p_bootstrap_addrs)
+        };
         let p2p_config = PeerConfig {
             addr: config.p2p_addr.clone(),
             port: config.p2p_port.clone(),
             network: Mutex::new(config.network.clone()),
-            bootstrap_addr: config.p2p_bootstrap_addr.clone(),
+            bootstrap_addr: p2p_bootstrap_addr.clone(),
             p2p_keypair: Some(p2p_keypair),
             active: !config.offline,
         };
-        let p2p_svc = PeerService::new(p2p_config, chan.clone());
-
+        let p2p_svc = P2pService::new(p2p_config, gateway_svc.request_channel("p2p"))
+            .with_dns_refresh(std::time::Duration::from_secs(config.p2p_dns_refresh));
+        let p2p_guard = p2p_relay(
+            &config.p2p_addr,
+            &config.p2p_extra_addrs,
+            config.p2p_port,
+            &metrics,
+        )?;
+        let versions = Arc::new(RwLock::new(PeerVersions::new(
+            peers,
+            config.p2p_bootstrap_addrs.clone(),
+            config.rest_port,
+        )));
+        PeerVersions::routes(versions.clone(), &mut router);
+
+        let (bridge_guard, bridge_addr, bridge_port) = guard_listener(
+            "bridge",
+            Protocol::Stream,
+            GuardConfig {
+                max_connections: config.bridge_max_connections,
+                max_connections_per_ip: config.bridge_max_connections_per_ip,
+                rate: config.bridge_rate_limit,
+                max_request_size: 0,
+            },
+            &config.bridge_addr,
+            &config.bridge_extra_addrs,
+            config.bridge_port,
+            &metrics,
+        )?;
         let bridge_config = BridgeConfig {
-            addr: config.bridge_addr,
-            port: config.bridge_port,
+            addr: bridge_addr,
+            port: bridge_port,
         };
-        let bridge_svc = BridgeService::new(bridge_config, chan.clone());
+        let bridge_svc = BridgeService::new(bridge_config, gateway_svc.request_channel("bridge"));
 
         // block chain monitor
         #[cfg(feature = "monitor")]
+        let alerter = Arc::new(Alerter::new(
+            AlertConfig {
+                no_block_secs: config.alert_no_block,
+                pool_size: config.alert_pool_size,
+                webhook: config.alert_webhook.clone(),
+                exec: config.alert_exec.clone(),
+            },
+            keypair.public_key().to_account_id(),
+        ));
+        #[cfg(feature = "monitor")]
         let monitor_svc = {
             let nw_public_key = p2p_public_key.to_account_id();
 
-            let node_status = monitor::worker::Status {
+            let node_status = monitor::status::Status {
                 public_key: keypair.public_key().to_account_id(), // check if ok
                 nw_public_key,
-                role: monitor::worker::NodeRole::Ordinary, // FIXME
-                nw_config: monitor::worker::NetworkConfig {
+                role: monitor::status::NodeRole::Ordinary, // FIXME
+                nw_config: monitor::status::NetworkConfig {
                     name: config.network.clone(),
                     block_threshold: config.block_threshold,
                     block_timeout: config.block_timeout,
                 },
                 core_version: trinci_core::VERSION.to_string(),
+                config_hash,
                 last_block: None,
                 unconfirmed_pool: None,
-                p2p_info: monitor::worker::P2pInfo {
+                p2p_info: monitor::status::P2pInfo {
                     p2p_addr: config.p2p_addr,
                     p2p_port: config.p2p_port,
-                    p2p_bootstrap_addr: config.p2p_bootstrap_addr.clone(),
+                    p2p_bootstrap_addr,
+                    p2p_bootstrap_peers: config.p2p_bootstrap_addrs.clone(),
+                    port_mapping: None,
                 },
                 ip_endpoint: config.local_ip,
                 pub_ip: config.public_ip.clone(),
                 seed: seed_value,
+                throughput: None,
+                p2p_traffic: None,
+                sync: None,
             };
 
             let monitor_config = MonitorConfig {
@@ -395,8 +1396,37 @@ impl App {
                 data: node_status,
             };
 
-            MonitorService::new(monitor_config, chan.clone(), config.offline)
+            let history = (config.telemetry != crate::telemetry::Telemetry::Off
+                && !config.monitor_history.is_empty())
+            .then(|| {
+                History::new(HistoryConfig {
+                    path: config.monitor_history.clone().into(),
+                    max_size: config.monitor_history_max_size,
+                    files: config.monitor_history_files,
+                })
+            });
+
+            MonitorService::new(
+                monitor_config,
+                chan.clone(),
+                config.telemetry_redact.clone(),
+                tracer.clone(),
+                traffic.clone(),
+                history,
+                alerter.clone(),
+                clock.clone(),
+            )
         };
+        #[cfg(feature = "monitor")]
+        monitor::page::routes(monitor_svc.status(), &mut router);
+        let api_svc = ApiService::new(
+            ApiConfig {
+                addr: config.api_addr.clone(),
+                port: config.api_port,
+                socket: config.admin_socket.clone(),
+            },
+            router,
+        );
 
         // Collect data to initialize the file that contains informations about the node.
         let public_ip = if config.public_ip.is_some() {
@@ -417,12 +1447,26 @@ impl App {
             ),
         };
 
+        let (rest_guard, rest_addr, rest_port) = guard_listener(
+            "rest",
+            Protocol::Http,
+            GuardConfig {
+                max_connections: config.rest_max_connections,
+                max_connections_per_ip: config.rest_max_connections_per_ip,
+                rate: config.rest_rate_limit,
+                max_request_size: config.rest_max_request_size,
+            },
+            &config.rest_addr,
+            &config.rest_extra_addrs,
+            config.rest_port,
+            &metrics,
+        )?;
         let rest_config = RestConfig {
-            addr: config.rest_addr.clone(),
-            port: config.rest_port,
+            addr: rest_addr,
+            port: rest_port,
             node_info,
         };
-        let rest_svc = RestService::new(rest_config, chan.clone());
+        let rest_svc = RestService::new(rest_config, gateway_svc.request_channel("rest"));
 
         #[cfg(feature = "kafka")]
         let kafka_service = {
@@ -435,30 +1479,84 @@ impl App {
             )
         };
 
-        App {
-            block_svc: Arc::new(Mutex::new(block_svc)),
+        Ok(App {
+            block_svc,
             rest_svc,
+            rest_guard,
             p2p_svc: Arc::new(Mutex::new(p2p_svc)),
+            p2p_guard,
             bridge_svc,
+            bridge_guard,
+            gateway_svc,
+            lanes,
+            journal,
+            resources,
+            watchdog,
+            api_svc,
+            ws_svc,
+            replica,
+            catchup_peer,
+            forks,
+            checkpoints,
+            catchup,
+            tasks,
+            crash,
+            control,
+            wm_cache,
+            wm_pool,
+            wm_preload,
+            tracer,
+            notifier,
+            correlation,
+            nat,
+            mdns,
+            reputation,
+            versions,
+            service_contract,
+            stats,
+            bootstrap_block: (
+                config.bootstrap_block_threshold,
+                config.bootstrap_block_timeout,
+            ),
+            pacer,
+            force_version_override: config.force_version_override,
+            validators,
+            role: config.role,
             p2p_public_key,
             bootstrap_path: config.bootstrap_path,
             keypair,
             #[cfg(feature = "monitor")]
             monitor_svc: Some(monitor_svc),
+            #[cfg(feature = "monitor")]
+            alerter,
             seed,
             #[cfg(feature = "kafka")]
             kafka_svc: kafka_service,
-        }
+            #[cfg(feature = "otel")]
+            otel,
+            #[cfg(feature = "chaos")]
+            chaos,
+            clock,
+            _db_lock: db_lock,
+        })
     }
 
-    // Set the block service config
+    // Set the block service config, the priority lanes follow the block
+    // threshold and the release policy of the service account settings
     fn set_block_service_config(&mut self, config: BlockchainSettings) {
+        self.lanes.set_block_threshold(config.block_threshold);
+        let db = self.block_svc.lock().db_arc();
+        if let Some(policy) = lanes::settings_policy(&db.read()) {
+            self.lanes.set_policy(policy);
+        }
         self.block_svc.lock().stop();
         self.block_svc.lock().set_block_config(
             config.network_name.unwrap(), // If this fails is at the very beginning
             config.block_threshold,
             config.block_timeout,
         );
+        self.service_contract
+            .set_burn_method(config.burning_fuel_method.clone());
         self.block_svc
             .lock()
             .set_burn_fuel_method(config.burning_fuel_method);
@@ -466,7 +1564,7 @@ impl App {
     }
 
     // Load the config from the DB
-    fn set_config_from_db(&mut self) -> String {
+    fn set_config_from_db(&mut self) -> Result<String, StartupError> {
         let block_svc = self.block_svc.clone();
         let db = block_svc.lock().db_arc();
         let buf = db.read().load_configuration("blockchain:settings").unwrap(); // If this fails is at the very beginning
@@ -474,22 +1572,17 @@ impl App {
         let config = rmp_deserialize::<BlockchainSettings>(&buf).unwrap(); // If this fails is at the very beginning
 
         // Check core version
-        let version = VERSION;
-        match version_compare::compare(version, config.min_node_version.clone()) {
-            Ok(Cmp::Lt) => {
-                panic!(
-                    "Error: The core version is lower than the minumum accepted by the bootstrap"
-                )
-            }
-            Ok(_) => (),
-            Err(_) => panic!("Error: Version comparing failure"),
-        }
+        check_min_version(
+            VERSION,
+            &config.min_node_version,
+            self.force_version_override,
+        )?;
 
         let network_name = config.network_name.clone().unwrap(); // If this fails is at the very beginning
         info!("network name: {:?}", network_name);
         self.set_block_service_config(config);
 
-        network_name
+        Ok(network_name)
     }
 
     // Store the blockchain config in the DB
@@ -506,10 +1599,30 @@ impl App {
     }
 
     // Insert the initial transactions in the pool
-    fn put_txs_in_the_pool(&mut self, txs: Vec<Transaction>) {
+    // Feeds the genesis transactions to the pool in batches, as they are read
+    // and verified.
+    fn put_bootstrap_txs(&mut self, bootstrap: &mut BootstrapReader) -> Result<(), StartupError> {
+        let total = bootstrap.txs_count();
+        let mut logged = 0;
         self.block_svc.lock().stop();
-        self.block_svc.lock().put_txs(txs);
+        let result = loop {
+            match bootstrap.next_batch(bootstrap_reader::BATCH_SIZE) {
+                Ok(batch) if batch.is_empty() => break Ok(()),
+                Ok(batch) => self.block_svc.lock().put_txs(batch),
+                Err(err) => break Err(StartupError::Bootstrap(err)),
+            }
+            // Every tenth of the transactions.
+            let read = bootstrap.txs_read();
+            if (read - logged) * 10 >= total || read == total {
+                info!(
+                    "[bootstrap] {}/{} genesis transactions verified",
+                    read, total
+                );
+                logged = read;
+            }
+        };
         self.block_svc.lock().start();
+        result
     }
 
     // Store manually the service Account on the DB
@@ -520,6 +1633,7 @@ impl App {
     ) {
         let mut fork = db.write().fork_create();
         let hash = Hash::from_data(HashAlgorithm::Sha256, &bootstrap_bin);
+        self.wm_cache.pin(hash);
         fork.store_account(Account::new(SERVICE_ACCOUNT_ID, Some(hash)));
         let mut key = String::from("contracts:code:");
         key.push_str(&hex::encode(&hash));
@@ -527,47 +1641,123 @@ impl App {
         db.write().fork_merge(fork).unwrap();
     }
 
+    // Loads the service contract and the configured ones into the wasm
+    // machine cache, to spare the first calls the compilation time.
+    fn preload_contracts(&self) {
+        let (wm, db) = {
+            let block_svc = self.block_svc.lock();
+            (block_svc.wm_arc(), block_svc.db_arc())
+        };
+        let service = db
+            .read()
+            .load_account(SERVICE_ACCOUNT_ID)
+            .and_then(|account| account.contract);
+        let mut contracts: Vec<Hash> = service.into_iter().collect();
+        for hash in &self.wm_preload {
+            if !contracts.contains(hash) {
+                contracts.push(*hash);
+            }
+        }
+        wm_cache::preload(&wm, &db, self.seed.clone(), SERVICE_ACCOUNT_ID, &contracts);
+        // The pool machines only call the service contract.
+        self.wm_pool.preload(
+            &db,
+            self.seed.clone(),
+            SERVICE_ACCOUNT_ID,
+            service.as_slice(),
+        );
+    }
+
     /// Starts the blockchain service to receive messages from the bootstrap procedure.
     /// Spawn a temporary thread that takes care of "service" account creation.
     /// Once that the service account is created, the thread takes care to set the
     /// main smart contracts loader within the wasm machine.
-    pub fn start(&mut self, _file: Option<String>, _addr: Option<String>) {
-        let p2p_start;
+    // Starts the P2P service with the peers versions exchange and the
+    // reachability detection.
+    fn start_p2p(&mut self) {
+        self.p2p_svc.lock().start();
+        let versions = self.versions.clone();
+        let min_node_version = self.control.status(None).min_node_version;
+        versions.write().set_min_node_version(min_node_version);
+        self.tasks
+            .spawn("versions", move || version::exchange(versions));
+        let nat = self.nat.clone();
+        #[cfg(feature = "monitor")]
+        let monitor_status = self.monitor_svc.as_ref().map(|monitor| monitor.status());
+        self.tasks.spawn("nat", move || {
+            nat::run(nat, |_status| {
+                #[cfg(feature = "monitor")]
+                if let Some(monitor_status) = &monitor_status {
+                    monitor_status.write().data.p2p_info.port_mapping =
+                        _status.mapping_method.clone();
+                }
+            })
+        });
+    }
+
+    // Relay role: the network name comes from the bootstrap file, the P2P
+    // requests are answered by the gateway.
+    fn start_relay(&mut self) -> Result<(), StartupError> {
+        let network_name = BootstrapReader::open(&self.bootstrap_path)
+            .and_then(BootstrapReader::finish)
+            .map_err(StartupError::Bootstrap)?;
+        info!("Starting the relay services, network {}", network_name);
+        self.gateway_svc.start();
+        self.api_svc.start();
+        self.p2p_svc.lock().set_network_name(network_name);
+        self.start_p2p();
+        let p2p_svc = self.p2p_svc.clone();
+        self.tasks.spawn("p2p", move || p2p::run(p2p_svc));
+        Ok(())
+    }
+
+    pub fn start(&mut self, _addr: Option<String>) -> Result<(), StartupError> {
+        if self.role == NodeRole::Relay {
+            return self.start_relay();
+        }
+        let mut p2p_start;
 
         self.block_svc.lock().start();
+        self.gateway_svc.start();
 
         let db = self.block_svc.lock().db_arc();
 
         let chan = self.block_svc.lock().request_channel();
         if is_service_present(&chan) {
-            let network_name = self.set_config_from_db();
+            let network_name = self.set_config_from_db()?;
 
-            let wm = self.block_svc.lock().wm_arc();
+            let wm = self.wm_pool.clone();
 
-            let is_validator = is_validator_function_call(wm, db, self.seed.clone(), 0);
+            let is_validator = self
+                .validators
+                .is_validator_function(wm, db, self.seed.clone());
 
             self.set_block_service_is_validator(is_validator);
 
             self.p2p_svc.lock().set_network_name(network_name);
+            if let Some(journal) = &self.journal {
+                journal.replay(&chan);
+            }
             p2p_start = true;
         } else {
             // Load the Bootstrap Struct from file
-            let (good_network_name, bootstrap_bin, bootstrap_txs) =
-                load_bootstrap_struct_from_file(&self.bootstrap_path);
+            let mut bootstrap =
+                BootstrapReader::open(&self.bootstrap_path).map_err(StartupError::Bootstrap)?;
 
             // Store the service account on the DB
-            self.store_service_account(db, bootstrap_bin);
+            self.store_service_account(db, bootstrap.take_bin());
 
-            let block_threshold = if bootstrap_txs.is_empty() {
-                42
+            let txs_count = bootstrap.txs_count();
+            let block_threshold = if txs_count == 0 {
+                self.bootstrap_block.0
             } else {
-                bootstrap_txs.len()
+                txs_count
             };
 
             self.set_block_service_config(BlockchainSettings {
                 accept_broadcast: false,
                 block_threshold,
-                block_timeout: 2, // The genesis block will be executed after this timeout and not with block_threshold transactions in the pool // FIXME
+                block_timeout: self.bootstrap_block.1, // The genesis block will be executed after this timeout and not with block_threshold transactions in the pool
                 burning_fuel_method: String::new(),
                 network_name: Some("bootstrap".to_string()),
                 min_node_version: String::from("0.2.7"),
@@ -576,13 +1766,18 @@ impl App {
 
             let block_svc = self.block_svc.clone();
             let p2p_svc = self.p2p_svc.clone();
+            let replica = self.replica.is_some();
 
-            if bootstrap_txs.is_empty() {
-                let wm = self.block_svc.lock().wm_arc();
+            if txs_count == 0 {
+                let good_network_name = bootstrap.finish().map_err(StartupError::Bootstrap)?;
+                let wm = self.wm_pool.clone();
                 let db = self.block_svc.lock().db_arc();
                 let seed = self.seed.clone();
+                let validators = self.validators.clone();
+                let lanes = self.lanes.clone();
+                let service_contract = self.service_contract.clone();
 
-                std::thread::spawn(move || {
+                self.tasks.spawn("bootstrap", move || {
                     bootstrap_monitor(chan.clone());
 
                     let mut bs = block_svc.lock();
@@ -599,23 +1794,31 @@ impl App {
                         config.block_threshold,
                         config.block_timeout,
                     );
+                    lanes.set_block_threshold(config.block_threshold);
+                    if let Some(policy) = crate::gateway::lanes::settings_policy(&db.read()) {
+                        lanes.set_policy(policy);
+                    }
 
                     // Set the burn fuel method name
                     bs.set_burn_fuel_method(config.burning_fuel_method.clone());
+                    service_contract.set_burn_method(config.burning_fuel_method.clone());
 
                     // Store the configuration on the DB
                     bs.store_config_into_db(config);
 
-                    let is_validator = is_validator_function_call(wm.clone(), db.clone(), seed, 0);
+                    let is_validator = validators.is_validator_function(wm, db, seed);
                     bs.set_validator(is_validator);
 
                     bs.start();
                     p2p_svc.lock().set_network_name(net_name);
-                    p2p_svc.lock().start();
+                    if !replica {
+                        p2p_svc.lock().start();
+                    }
                 });
                 p2p_start = false;
             } else {
-                self.put_txs_in_the_pool(bootstrap_txs);
+                self.put_bootstrap_txs(&mut bootstrap)?;
+                let good_network_name = bootstrap.finish().map_err(StartupError::Bootstrap)?;
 
                 bootstrap_monitor(chan.clone()); // Blocking function
 
@@ -626,12 +1829,14 @@ impl App {
                 // Store the configuration on the DB
                 self.store_config_into_db(config);
 
-                let network_name = self.set_config_from_db();
+                let network_name = self.set_config_from_db()?;
 
-                let wm = self.block_svc.lock().wm_arc();
+                let wm = self.wm_pool.clone();
                 let db = self.block_svc.lock().db_arc();
 
-                let is_validator = is_validator_function_call(wm, db, self.seed.clone(), 0);
+                let is_validator = self
+                    .validators
+                    .is_validator_function(wm, db, self.seed.clone());
 
                 self.set_block_service_is_validator(is_validator);
 
@@ -640,36 +1845,327 @@ impl App {
             }
         }
 
+        self.preload_contracts();
+
+        // A replica gets the blocks from its primary only.
+        p2p_start &= self.replica.is_none();
+
         info!("Starting the services");
 
+        release_upstream(&self.rest_guard);
         self.rest_svc.start();
+        start_guard(&self.rest_guard);
+        self.api_svc.start();
+        self.ws_svc.start();
         if p2p_start {
-            self.p2p_svc.lock().start();
+            self.start_p2p();
         }
+        if self.replica.is_none() {
+            start_guard(&self.p2p_guard);
+        }
+        release_upstream(&self.bridge_guard);
         self.bridge_svc.start();
+        start_guard(&self.bridge_guard);
+
+        if let Some(replica) = self.replica.clone() {
+            self.tasks.spawn("replica", move || replica::run(replica));
+        }
+        if let Some(peer) = self.catchup_peer.clone() {
+            self.tasks
+                .spawn("catchup_peer", move || replica::catch_up(peer));
+        }
+
+        let checkpoints = self.checkpoints.clone();
+        self.tasks
+            .spawn("checkpoints", move || checkpoints::run(checkpoints));
+
+        let catchup = self.catchup.clone();
+        let control = self.control.clone();
+        #[cfg(feature = "monitor")]
+        let monitor_status = self.monitor_svc.as_ref().map(|monitor| monitor.status());
+        self.tasks.spawn("catchup", move || {
+            catchup::run(catchup, |sync| {
+                control.set_sync(sync.clone());
+                #[cfg(feature = "monitor")]
+                if let Some(monitor_status) = &monitor_status {
+                    monitor_status.write().data.sync = Some(sync.clone());
+                }
+            })
+        });
+
+        let service_contract = self.service_contract.clone();
+        self.tasks.spawn("service_contract", move || {
+            service_contract::run(service_contract)
+        });
+
+        let stats = self.stats.clone();
+        self.tasks.spawn("stats", move || stats::run(stats));
+
+        let reputation = self.reputation.clone();
+        self.tasks
+            .spawn("reputation", move || reputation::run(reputation));
+
+        let p2p_svc = self.p2p_svc.clone();
+        self.tasks.spawn("p2p", move || p2p::run(p2p_svc));
+
+        if let Some(pacer) = self.pacer.clone() {
+            self.tasks.spawn("pacer", move || pacer::run(pacer));
+        }
 
         #[cfg(feature = "monitor")]
         {
             let addr: String = _addr.unwrap();
-            let file: String = _file.unwrap();
-            self.monitor_svc.as_mut().unwrap().start(addr, file);
+            self.monitor_svc.as_mut().unwrap().start(addr, &self.tasks);
         }
 
         #[cfg(feature = "kafka")]
         {
             self.kafka_svc.start();
         }
+
+        #[cfg(feature = "monitor")]
+        self.alerter.event(
+            "node_started",
+            format!("node {} started", env!("CARGO_PKG_VERSION")),
+        );
+        Ok(())
+    }
+
+    // Starts or stops a service switchable via the node API.
+    fn switch_service(&mut self, name: &str, active: bool) {
+        match (name, active) {
+            ("rest", true) => {
+                release_upstream(&self.rest_guard);
+                self.rest_svc.start();
+            }
+            ("rest", false) => {
+                self.rest_svc.stop();
+                hold_upstream(&self.rest_guard);
+            }
+            ("bridge", true) => {
+                release_upstream(&self.bridge_guard);
+                self.bridge_svc.start();
+            }
+            ("bridge", false) => {
+                self.bridge_svc.stop();
+                hold_upstream(&self.bridge_guard);
+            }
+            ("ws", true) => self.ws_svc.start(),
+            ("ws", false) => self.ws_svc.stop(),
+            _ => (),
+        }
+    }
+
+    // Block timeout of the blockchain settings, the configured one before the
+    // genesis block.
+    fn block_timeout(&self) -> u16 {
+        let db = self.block_svc.lock().db_arc();
+        let settings = db
+            .read()
+            .load_configuration("blockchain:settings")
+            .and_then(|buf| rmp_deserialize::<BlockchainSettings>(&buf).ok());
+        settings
+            .map(|settings| settings.block_timeout)
+            .unwrap_or_else(|| self.watchdog.block_timeout())
+    }
+
+    // Retires the validator ahead of the shutdown: the pending transactions
+    // are given a few block timeouts to be executed, then the node stops
+    // proposing blocks and leaves the network. The core P2P protocol has no
+    // departure message, the peers see the connections closed.
+    fn handover(&mut self) {
+        warn!("Validator handover, waiting for the pending transactions execution");
+        let wait = HANDOVER_BLOCK_TIMEOUTS * self.block_timeout().max(1) as u64;
+        let deadline = self.clock.now() + std::time::Duration::from_secs(wait);
+        while let Some(sample) = self.stats.sample() {
+            if sample.pool_size == 0 {
+                break;
+            }
+            if self.clock.now() >= deadline {
+                warn!(
+                    "{} pending transactions left to the other validators",
+                    sample.pool_size
+                );
+                break;
+            }
+            self.clock.sleep(std::time::Duration::from_secs(1));
+        }
+        // The block service stop lets the block in progress complete.
+        self.set_block_service_is_validator(is_validator_function_temporary(false));
+        info!("Block proposals stopped, leaving the network");
+        if let Some(mdns) = &self.mdns {
+            mdns.stop();
+        }
+        self.p2p_svc.lock().stop();
+        #[cfg(feature = "monitor")]
+        self.alerter.deliver(
+            "validator_handover",
+            format!(
+                "validator {} retired",
+                self.keypair.public_key().to_account_id()
+            ),
+            ALERT_DELIVERY_TIMEOUT,
+        );
+    }
+
+    // Checks for a stalled chain, the diagnosis is logged and alerted.
+    fn check_stall(&mut self) {
+        // Blockchain service busy or stopped, checked on the next round.
+        let sample = match self.stats.sample() {
+            Some(sample) => sample,
+            None => return,
+        };
+        let facts = Facts {
+            height: sample.last_block.map(|block| block.height),
+            pool_size: sample.pool_size,
+            p2p_received: sample.p2p.bytes_received,
+            p2p_active: self.control.is_p2p_active(),
+        };
+        let block_timeout = self.block_timeout();
+        let db = self.block_svc.lock().db_arc();
+        let validator = || {
+            if self.role != NodeRole::Full {
+                return Ok(false);
+            }
+            let wm = self.wm_pool.clone();
+            let is_validator = self
+                .validators
+                .is_validator_function(wm, db, self.seed.clone());
+            is_validator(self.keypair.public_key().to_account_id()).map_err(|err| err.to_string())
+        };
+        let verdict = self
+            .watchdog
+            .check(&facts, block_timeout, self.clock.now(), validator);
+        match verdict {
+            Some(Verdict::Stalled { age, diagnosis }) => {
+                error!("No new block for {} seconds, chain stalled:", age);
+                for line in &diagnosis {
+                    error!("  - {}", line);
+                }
+                #[cfg(feature = "monitor")]
+                self.alerter.condition("stalled", true, || {
+                    format!("no new block for {} seconds: {}", age, diagnosis.join("; "))
+                });
+                if self.watchdog.recover() && facts.p2p_active {
+                    warn!("Restarting the P2P service to recover from the stall");
+                    let mut p2p_svc = self.p2p_svc.lock();
+                    p2p_svc.stop();
+                    p2p_svc.start();
+                }
+            }
+            Some(Verdict::Idle { age }) => {
+                info!("No new block for {} seconds, unconfirmed pool empty", age);
+            }
+            Some(Verdict::Resumed) => {
+                info!("New blocks after a stall, chain resumed");
+                #[cfg(feature = "monitor")]
+                self.alerter
+                    .condition("stalled", false, || "chain resumed".to_string());
+            }
+            None => (),
+        }
+    }
+
+    // Stops a service as if it died, on fault injection request.
+    #[cfg(feature = "chaos")]
+    fn crash(&mut self, service: &str) {
+        warn!("[chaos] crashing the {} service", service);
+        match service {
+            "blockchain" => self.block_svc.lock().stop(),
+            "rest" => self.rest_svc.stop(),
+            "bridge" => self.bridge_svc.stop(),
+            "gateway" => self.gateway_svc.stop(),
+            "api" => self.api_svc.stop(),
+            "ws" => self.ws_svc.stop(),
+            _ => (),
+        }
     }
 
-    pub fn park(&mut self) {
+    /// Supervises the services until the shutdown, returns `false` if stopped
+    /// by a failure.
+    pub fn park(&mut self) -> bool {
+        let mut p2p_active = self.control.is_p2p_active();
+        // Only the gateway and the node API run in relay role.
+        let relay = self.role == NodeRole::Relay;
+        let mut active = control::SERVICES.map(|_| true);
+        let mut writes_blocked = false;
+        let mut last_check = self.clock.now() - resources::CHECK_INTERVAL;
+        #[cfg(feature = "monitor")]
+        let mut forks = 0;
         loop {
-            std::thread::sleep(std::time::Duration::from_secs(1));
-            let mut stop = false;
-            if !self.block_svc.lock().is_running() {
+            self.clock.sleep(std::time::Duration::from_secs(1));
+            #[cfg(feature = "chaos")]
+            if let Some(service) = self.chaos.take_crash() {
+                self.crash(service);
+            }
+            if self.clock.now() - last_check >= resources::CHECK_INTERVAL {
+                last_check = self.clock.now();
+                let _alerts = self.resources.check();
+                #[cfg(feature = "monitor")]
+                for (kind, raised, message) in _alerts {
+                    self.alerter.condition(kind, raised, || message);
+                }
+                // The blockchain service is the database writer.
+                if self.resources.writes_blocked() != writes_blocked && !relay {
+                    writes_blocked = !writes_blocked;
+                    if writes_blocked {
+                        error!("Database disk almost full, stopping the blockchain service");
+                        self.block_svc.lock().stop();
+                    } else {
+                        info!("Database disk space recovered, starting the blockchain service");
+                        self.block_svc.lock().start();
+                    }
+                }
+                if !relay {
+                    self.check_stall();
+                }
+                #[cfg(feature = "monitor")]
+                {
+                    let rejected = self.service_contract.rejected_burn_method();
+                    self.alerter
+                        .condition("burn_method", rejected.is_some(), || match rejected {
+                            Some((method, err)) => format!(
+                                "burning fuel method `{}` not exposed by the service contract: {}",
+                                method, err
+                            ),
+                            None => "burning fuel method switched".to_string(),
+                        });
+                    if self.forks.total() != forks {
+                        forks = self.forks.total();
+                        if let Some(report) = self.forks.last_report() {
+                            self.alerter.event("fork", report.to_string());
+                        }
+                    }
+                }
+            }
+            if self.control.is_p2p_active() != p2p_active {
+                p2p_active = !p2p_active;
+                if p2p_active {
+                    self.p2p_svc.lock().start();
+                } else {
+                    self.p2p_svc.lock().stop();
+                }
+            }
+            for (name, active) in control::SERVICES.iter().zip(active.iter_mut()) {
+                let requested = self.control.is_service_active(name);
+                if requested != *active && !relay {
+                    *active = requested;
+                    self.switch_service(name, requested);
+                }
+            }
+            // The handover ends with the node shutdown.
+            let handover = self.control.is_handover_requested();
+            if handover && !relay {
+                self.handover();
+            }
+            let running = |name| !relay && self.control.is_service_active(name);
+            let shutdown = handover || self.control.is_shutdown_requested();
+            let mut stop = shutdown;
+            if !relay && !writes_blocked && !self.block_svc.lock().is_running() {
                 error!("Blockchain service is not running");
                 stop = true;
             }
-            if !self.rest_svc.is_running() {
+            if running("rest") && !self.rest_svc.is_running() {
                 error!("Rest service is not running");
                 stop = true;
             }
@@ -677,35 +2173,256 @@ impl App {
             //     error!("P2P service is not running");
             //     stop = true;
             // }
-            if !self.bridge_svc.is_running() {
+            if running("bridge") && !self.bridge_svc.is_running() {
                 error!("Bridge service is not running");
                 stop = true;
             }
+            if !self.gateway_svc.is_running() {
+                error!("Gateway service is not running");
+                stop = true;
+            }
+            if !self.api_svc.is_running() {
+                error!("API service is not running");
+                stop = true;
+            }
+            if running("ws") && !self.ws_svc.is_running() {
+                error!("WS service is not running");
+                stop = true;
+            }
+            for (task, message) in self.tasks.take_panics() {
+                error!("Worker {} failed: {}", task, message);
+                stop = true;
+            }
+            if let Some(summary) = self.crash.take() {
+                error!("Node crashed: {}", summary);
+                #[cfg(feature = "monitor")]
+                self.alerter
+                    .deliver("crash", summary, ALERT_DELIVERY_TIMEOUT);
+                stop = true;
+            }
             #[cfg(feature = "monitor")]
             {
-                if !self.monitor_svc.as_mut().unwrap().is_running() {
+                if !relay && !self.monitor_svc.as_mut().unwrap().is_running() {
                     error!("Monitor service is not running");
                     stop = true;
                 }
             }
             if stop {
+                #[cfg(feature = "monitor")]
+                if !shutdown {
+                    self.alerter.deliver(
+                        "service_down",
+                        "node services stopped".to_string(),
+                        ALERT_DELIVERY_TIMEOUT,
+                    );
+                }
                 self.block_svc.lock().stop();
                 self.rest_svc.stop();
                 self.p2p_svc.lock().stop();
                 self.bridge_svc.stop();
+                self.gateway_svc.stop();
+                self.api_svc.stop();
+                self.ws_svc.stop();
+                if let Some(mdns) = &self.mdns {
+                    mdns.stop();
+                }
                 #[cfg(feature = "monitor")]
                 self.monitor_svc.as_mut().unwrap().stop();
+                let running = self.tasks.shutdown(TASKS_SHUTDOWN_TIMEOUT);
+                if !running.is_empty() {
+                    warn!("Workers still running at shutdown: {}", running.join(", "));
+                }
+                if shutdown {
+                    info!("Shutdown completed");
+                    return true;
+                }
                 break;
             }
         }
         println!("Something bad happened, stopping the application");
+        false
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::app::Bootstrap;
-    use trinci_core::base::serialize::rmp_deserialize;
+    use crate::app::{calculate_network_name, check_min_version, App, Bootstrap, StartupError};
+    use crate::config::{Config, ConfigLoader, SERVICE_ACCOUNT_ID};
+    use crate::telemetry::Telemetry;
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+    use tempfile::TempDir;
+    use trinci_core::{
+        base::{
+            serialize::{rmp_deserialize, rmp_serialize},
+            BlockchainSettings,
+        },
+        blockchain::Message,
+        crypto::{ed25519, Hash, HashAlgorithm, KeyPair},
+        db::{Db, DbFork, RocksDb},
+        Account,
+    };
+
+    // Test network bootstrap: service contract and two genesis transactions.
+    fn offline_bootstrap() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("data/offline-bootstrap.bin")
+    }
+
+    // Node configuration on local ports picked by the services, without P2P.
+    fn test_config(dir: &Path, bootstrap_path: &Path) -> Config {
+        let local = "127.0.0.1".to_string();
+        Config {
+            db_path: dir.join("db").to_string_lossy().into_owned(),
+            bootstrap_path: bootstrap_path.to_string_lossy().into_owned(),
+            rest_addr: local.clone(),
+            rest_port: 0,
+            bridge_addr: local.clone(),
+            bridge_port: 0,
+            api_addr: local.clone(),
+            api_port: 0,
+            ws_addr: local.clone(),
+            ws_port: 0,
+            p2p_addr: local,
+            p2p_port: 0,
+            monitor_file: dir.join("monitor.json").to_string_lossy().into_owned(),
+            telemetry: Telemetry::Off,
+            offline: true,
+            p2p_mdns: false,
+            ip_discovery: false,
+            bootstrap_block_threshold: 2,
+            ..Default::default()
+        }
+    }
+
+    fn test_app(config: Config) -> App {
+        let loader: ConfigLoader = {
+            let config = config.clone();
+            Arc::new(move || Ok(config.clone()))
+        };
+        App::new(
+            config,
+            KeyPair::Ed25519(ed25519::KeyPair::from_random()),
+            loader,
+        )
+        .unwrap()
+    }
+
+    // Blockchain settings stored in the database.
+    fn stored_settings(app: &App) -> Option<BlockchainSettings> {
+        let db = app.block_svc.lock().db_arc();
+        let buf = db.read().load_configuration("blockchain:settings")?;
+        rmp_deserialize(&buf).ok()
+    }
+
+    fn has_genesis(app: &App) -> bool {
+        let db = app.block_svc.lock().db_arc();
+        let block = db.read().load_block(0);
+        block.is_some()
+    }
+
+    // Database of an already initialized node.
+    fn initialized_db(config: &Config, network_name: &str, min_node_version: &str) {
+        let bootstrap =
+            rmp_deserialize::<Bootstrap>(&fs::read(offline_bootstrap()).unwrap()).unwrap();
+        let hash = Hash::from_data(HashAlgorithm::Sha256, &bootstrap.bin);
+        let settings = BlockchainSettings {
+            accept_broadcast: true,
+            block_threshold: 42,
+            block_timeout: 3,
+            burning_fuel_method: String::new(),
+            network_name: Some(network_name.to_string()),
+            is_production: false,
+            min_node_version: min_node_version.to_string(),
+        };
+        let mut db = RocksDb::new(&config.db_path);
+        let mut fork = db.fork_create();
+        fork.store_account(Account::new(SERVICE_ACCOUNT_ID, Some(hash)));
+        let key = format!("contracts:code:{}", hex::encode(hash));
+        fork.store_account_data(SERVICE_ACCOUNT_ID, &key, bootstrap.bin);
+        fork.store_configuration("blockchain:settings", rmp_serialize(&settings).unwrap());
+        db.fork_merge(fork).unwrap();
+    }
+
+    #[test]
+    fn start_with_existing_db() {
+        let dir = TempDir::new().unwrap();
+        let config = test_config(dir.path(), &offline_bootstrap());
+        initialized_db(&config, "QmExistingNetwork", "0.2.7");
+        let mut app = test_app(config);
+
+        app.start(Some(String::new())).unwrap();
+
+        // No genesis replay, the stored configuration is kept.
+        assert!(!has_genesis(&app));
+        let settings = stored_settings(&app).unwrap();
+        assert_eq!(settings.network_name.as_deref(), Some("QmExistingNetwork"));
+        assert_eq!(settings.block_threshold, 42);
+    }
+
+    #[test]
+    fn start_with_existing_db_refused() {
+        let dir = TempDir::new().unwrap();
+        let config = test_config(dir.path(), &offline_bootstrap());
+        initialized_db(&config, "QmExistingNetwork", "99.0.0");
+        let mut app = test_app(config);
+
+        let res = app.start(Some(String::new()));
+
+        assert!(matches!(res, Err(StartupError::UpgradeRequired { .. })));
+    }
+
+    #[test]
+    fn start_with_bootstrap_txs() {
+        let dir = TempDir::new().unwrap();
+        let mut app = test_app(test_config(dir.path(), &offline_bootstrap()));
+
+        // Returns once the genesis block is executed.
+        app.start(Some(String::new())).unwrap();
+
+        assert!(has_genesis(&app));
+        let network_name = calculate_network_name(&fs::read(offline_bootstrap()).unwrap());
+        let settings = stored_settings(&app).unwrap();
+        assert_eq!(settings.network_name, Some(network_name));
+    }
+
+    #[test]
+    fn start_with_empty_bootstrap() {
+        let dir = TempDir::new().unwrap();
+        let fixture =
+            rmp_deserialize::<Bootstrap>(&fs::read(offline_bootstrap()).unwrap()).unwrap();
+        let bootstrap = Bootstrap {
+            bin: fixture.bin,
+            txs: vec![],
+            nonce: fixture.nonce,
+        };
+        let buf = rmp_serialize(&bootstrap).unwrap();
+        let bootstrap_path = dir.path().join("bootstrap.bin");
+        fs::write(&bootstrap_path, &buf).unwrap();
+        let mut app = test_app(test_config(dir.path(), &bootstrap_path));
+
+        // Returns at once, the genesis transactions come later on.
+        app.start(Some(String::new())).unwrap();
+        assert!(!has_genesis(&app));
+
+        let chan = app.block_svc.lock().request_channel();
+        for tx in fixture.txs {
+            chan.send_sync(Message::PutTransactionRequest { confirm: false, tx })
+                .unwrap();
+        }
+
+        // The validator switch thread stores the final configuration.
+        let network_name = Some(calculate_network_name(&buf));
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while stored_settings(&app).and_then(|settings| settings.network_name) != network_name {
+            assert!(Instant::now() < deadline, "validator switch not done");
+            std::thread::sleep(Duration::from_millis(200));
+        }
+        assert!(has_genesis(&app));
+    }
 
     #[ignore = "use this to check a boostrap file"]
     #[test]
@@ -721,4 +2438,17 @@ mod tests {
         println!("{} Transactions", &bootstrap.txs.len());
         println!("nonce: `{}`", &bootstrap.nonce);
     }
+
+    #[test]
+    fn min_version_refusal() {
+        assert_eq!(check_min_version("0.2.7", "0.2.7", false), Ok(()));
+        assert_eq!(
+            check_min_version("0.2.6", "0.2.7", false),
+            Err(StartupError::UpgradeRequired {
+                found: "0.2.6".to_string(),
+                need: "0.2.7".to_string(),
+            })
+        );
+        assert_eq!(check_min_version("0.2.6", "0.2.7", true), Ok(()));
+    }
 }
@@ -0,0 +1,233 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Bootstrap file streaming.
+//!
+//! The bootstrap file is read sequentially instead of being loaded at once:
+//! the service contract first, then the genesis transactions in batches.
+//! The signatures of a batch are verified in parallel, one worker per CPU,
+//! before it is handed to the pool. The network name, the hash of
+//! the whole file, is computed along the way and known once the file is
+//! finished. Memory stays bounded by the contract size plus one batch, the
+//! transactions being only held by the unconfirmed pool.
+
+use ring::digest;
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+    thread,
+};
+use trinci_core::Transaction;
+
+/// Genesis transactions fed to the pool at once.
+pub const BATCH_SIZE: usize = 1024;
+
+/// Reader hashing the data read through it.
+struct HashReader<R> {
+    inner: R,
+    context: digest::Context,
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.context.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+fn invalid<E: std::fmt::Display>(err: E) -> String {
+    format!("invalid bootstrap file: {}", err)
+}
+
+/// Checks the transactions integrity (signatures included) in parallel,
+/// `first` is the index of the first one within the file.
+fn verify(txs: &[Transaction], first: usize) -> Result<(), String> {
+    let workers = thread::available_parallelism().map_or(1, |workers| workers.get());
+    let chunk_size = txs.len().div_ceil(workers).max(1);
+    thread::scope(|scope| {
+        let workers: Vec<_> = txs
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(chunk, txs)| {
+                scope.spawn(move || {
+                    txs.iter().enumerate().try_for_each(|(i, tx)| {
+                        tx.check_integrity().map_err(|err| {
+                            invalid(format!(
+                                "transaction {}: {}",
+                                first + chunk * chunk_size + i,
+                                err
+                            ))
+                        })
+                    })
+                })
+            })
+            .collect();
+        // First failure in the file order.
+        workers.into_iter().try_for_each(|worker| {
+            worker
+                .join()
+                .unwrap_or_else(|_| Err(invalid("verification panicked")))
+        })
+    })
+}
+
+/// Sequential reader of a bootstrap file (see `app::Bootstrap`).
+pub struct BootstrapReader {
+    reader: HashReader<BufReader<File>>,
+    bin: Vec<u8>,
+    nonce: Option<String>,
+    /// Fields left after the transactions.
+    fields: u32,
+    txs: usize,
+    /// Transactions read so far.
+    read: usize,
+}
+
+impl BootstrapReader {
+    /// Opens the file and reads up to the genesis transactions.
+    pub fn open(path: &str) -> Result<Self, String> {
+        let file = File::open(path).map_err(|err| format!("{}: {}", path, err))?;
+        let mut bootstrap = BootstrapReader {
+            reader: HashReader {
+                inner: BufReader::new(file),
+                context: digest::Context::new(&digest::SHA256),
+            },
+            bin: Vec::new(),
+            nonce: None,
+            fields: 0,
+            txs: 0,
+            read: 0,
+        };
+        let mut fields = rmp::decode::read_map_len(&mut bootstrap.reader).map_err(invalid)?;
+        while fields > 0 {
+            fields -= 1;
+            if bootstrap.read_field()? {
+                bootstrap.fields = fields;
+                return Ok(bootstrap);
+            }
+        }
+        Err(invalid("genesis transactions not found"))
+    }
+
+    // Reads a field, returns `true` at the transactions one, leaving them to
+    // `next_batch`.
+    fn read_field(&mut self) -> Result<bool, String> {
+        let mut name = [0; 8];
+        let name = rmp::decode::read_str(&mut self.reader, &mut name).map_err(invalid)?;
+        match name {
+            "bin" => {
+                let len = rmp::decode::read_bin_len(&mut self.reader).map_err(invalid)?;
+                self.bin = vec![0; len as usize];
+                self.reader.read_exact(&mut self.bin).map_err(invalid)?;
+            }
+            "txs" => {
+                self.txs = rmp::decode::read_array_len(&mut self.reader).map_err(invalid)? as usize;
+                return Ok(true);
+            }
+            "nonce" => {
+                let len = rmp::decode::read_str_len(&mut self.reader).map_err(invalid)?;
+                let mut nonce = vec![0; len as usize];
+                self.reader.read_exact(&mut nonce).map_err(invalid)?;
+                self.nonce = Some(String::from_utf8(nonce).map_err(invalid)?);
+            }
+            name => return Err(invalid(format!("unexpected field `{}`", name))),
+        }
+        Ok(false)
+    }
+
+    /// Service contract binary, empty once taken.
+    pub fn take_bin(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.bin)
+    }
+
+    /// Number of genesis transactions.
+    pub fn txs_count(&self) -> usize {
+        self.txs
+    }
+
+    /// Genesis transactions read so far.
+    pub fn txs_read(&self) -> usize {
+        self.read
+    }
+
+    /// Next genesis transactions, at most `max`, empty at the end.
+    /// Every transaction integrity is checked.
+    pub fn next_batch(&mut self, max: usize) -> Result<Vec<Transaction>, String> {
+        let first = self.read;
+        let count = max.min(self.txs - first);
+        let mut batch = Vec::with_capacity(count);
+        for index in first..first + count {
+            let tx: Transaction = rmp_serde::decode::from_read(&mut self.reader)
+                .map_err(|err| invalid(format!("transaction {}: {}", index, err)))?;
+            batch.push(tx);
+        }
+        verify(&batch, first)?;
+        self.read += count;
+        Ok(batch)
+    }
+
+    /// Reads the rest of the file, returns the network name.
+    pub fn finish(mut self) -> Result<String, String> {
+        // Transactions not fed to the pool.
+        while !self.next_batch(BATCH_SIZE)?.is_empty() {}
+        for _ in 0..self.fields {
+            if self.read_field()? {
+                return Err(invalid("duplicated transactions"));
+            }
+        }
+        if self.nonce.is_none() {
+            return Err(invalid("nonce not found"));
+        }
+        // Trailing data is part of the hash.
+        io::copy(&mut self.reader, &mut io::sink()).map_err(invalid)?;
+        let mut hash = vec![0x12, 0x20];
+        hash.extend_from_slice(self.reader.context.finish().as_ref());
+        Ok(bs58::encode(hash).into_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{calculate_network_name, Bootstrap};
+    use tempfile::TempDir;
+    use trinci_core::base::serialize::rmp_serialize;
+
+    #[test]
+    fn stream_bootstrap() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("bootstrap.bin");
+        let buf = rmp_serialize(&Bootstrap {
+            bin: b"\0asm".to_vec(),
+            txs: Vec::new(),
+            nonce: "nonce".to_string(),
+        })
+        .unwrap();
+        std::fs::write(&path, &buf).unwrap();
+
+        let mut bootstrap = BootstrapReader::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(bootstrap.take_bin(), b"\0asm");
+        assert_eq!(bootstrap.txs_count(), 0);
+        assert!(bootstrap.next_batch(BATCH_SIZE).unwrap().is_empty());
+        assert_eq!(bootstrap.finish().unwrap(), calculate_network_name(&buf));
+
+        std::fs::write(&path, &buf[..buf.len() - 2]).unwrap();
+        let bootstrap = BootstrapReader::open(path.to_str().unwrap()).unwrap();
+        assert!(bootstrap.finish().is_err());
+    }
+}
@@ -0,0 +1,237 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Chain catch up progress.
+//!
+//! After a downtime the node replays the blocks produced meanwhile, which can
+//! take a while. The local height is sampled against the target height, the
+//! highest block seen from the peers (or the primary height in `replica`
+//! role, the `catchup-peer` height when set), and the progress (blocks per second, estimated time left) is
+//! logged, served by the node status and shown by the monitor.
+//!
+//! The core does not expose the heights advertised by the peers: the target
+//! is only known once a block gossiped by the network is received.
+
+use crate::replica::Replica;
+use crate::tasks;
+use crate::traffic::Traffic;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use trinci_core::{
+    base::{Mutex, RwLock},
+    db::{Db, RocksDb},
+};
+
+/// Interval between two samples of the local height.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Window the replay rate is computed over, in milliseconds.
+const RATE_WINDOW_MS: u64 = 60_000;
+
+/// Min interval between two progress log lines, in milliseconds.
+const LOG_INTERVAL_MS: u64 = 30_000;
+
+/// Blocks behind the target the node is considered in sync.
+const SYNC_LAG: u64 = 2;
+
+/// Catch up progress, as reported by the node status and the monitor.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SyncStatus {
+    /// Catching up with the target height.
+    pub syncing: bool,
+    /// Local height.
+    pub height: Option<u64>,
+    /// Highest height known.
+    pub target: Option<u64>,
+    /// Blocks replayed per second over the last minute.
+    pub blocks_per_sec: f64,
+    /// Estimated seconds to reach the target.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eta_secs: Option<u64>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// (time in milliseconds, local height) samples over the rate window.
+    samples: VecDeque<(u64, u64)>,
+    /// Catch up start (time in milliseconds, height).
+    started: Option<(u64, u64)>,
+    last_log: u64,
+}
+
+pub struct CatchUp {
+    db: Arc<RwLock<RocksDb>>,
+    traffic: Arc<Traffic>,
+    replica: Option<Arc<Replica>>,
+    inner: Mutex<Inner>,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl CatchUp {
+    pub fn new(db: Arc<RwLock<RocksDb>>, traffic: Arc<Traffic>) -> Self {
+        CatchUp {
+            db,
+            traffic,
+            replica: None,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Targets the primary height, in replica role, or the catch up peer
+    /// height.
+    pub fn with_replica(mut self, replica: Arc<Replica>) -> Self {
+        self.replica = Some(replica);
+        self
+    }
+
+    /// Samples the local and the target heights.
+    pub fn sample(&self) -> SyncStatus {
+        let height = self
+            .db
+            .read()
+            .load_block(u64::MAX)
+            .map(|block| block.data.height);
+        let target = match &self.replica {
+            Some(replica) => replica.status().primary_height,
+            None => self.traffic.peers_height(),
+        };
+        self.inner.lock().update(height, target, now_ms())
+    }
+}
+
+impl Inner {
+    // Accounts a sample taken at `now` (milliseconds), logging the progress.
+    fn update(&mut self, height: Option<u64>, target: Option<u64>, now: u64) -> SyncStatus {
+        if let Some(height) = height {
+            self.samples.push_back((now, height));
+        }
+        while self
+            .samples
+            .front()
+            .is_some_and(|(time, _)| *time + RATE_WINDOW_MS < now)
+        {
+            self.samples.pop_front();
+        }
+        let blocks_per_sec = match (self.samples.front(), self.samples.back()) {
+            (Some((start, from)), Some((end, to))) if end > start => {
+                to.saturating_sub(*from) as f64 * 1000.0 / (end - start) as f64
+            }
+            _ => 0.0,
+        };
+
+        let target = target.max(height);
+        let current = height.unwrap_or_default();
+        let remaining = target.unwrap_or_default().saturating_sub(current);
+        let syncing = remaining > SYNC_LAG;
+        let eta_secs = (syncing && blocks_per_sec > 0.0)
+            .then(|| (remaining as f64 / blocks_per_sec).ceil() as u64);
+
+        match (syncing, self.started) {
+            (true, None) => {
+                info!(
+                    "[sync] catching up from height {} to {}",
+                    current,
+                    current + remaining
+                );
+                self.started = Some((now, current));
+                self.last_log = now;
+            }
+            (true, Some(_)) if now >= self.last_log + LOG_INTERVAL_MS => {
+                let target = current + remaining;
+                info!(
+                    "[sync] height {}/{} ({:.1}%), {:.1} blocks/s, ETA {}",
+                    current,
+                    target,
+                    current as f64 * 100.0 / target.max(1) as f64,
+                    blocks_per_sec,
+                    eta_secs.map_or("unknown".to_string(), |eta| format!("{} s", eta))
+                );
+                self.last_log = now;
+            }
+            (false, Some((start, from))) => {
+                info!(
+                    "[sync] caught up at height {}, {} blocks in {} s",
+                    current,
+                    current.saturating_sub(from),
+                    now.saturating_sub(start) / 1000
+                );
+                self.started = None;
+            }
+            _ => (),
+        }
+
+        SyncStatus {
+            syncing,
+            height,
+            target,
+            blocks_per_sec,
+            eta_secs,
+        }
+    }
+}
+
+/// Samples the catch up progress every `SAMPLE_INTERVAL`, handing it to
+/// `on_update`.
+pub fn run<F>(catchup: Arc<CatchUp>, on_update: F)
+where
+    F: Fn(&SyncStatus),
+{
+    loop {
+        on_update(&catchup.sample());
+        if !tasks::sleep(SAMPLE_INTERVAL) {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catch_up_progress() {
+        let mut inner = Inner::default();
+
+        let sync = inner.update(Some(100), Some(1100), 0);
+        assert!(sync.syncing);
+        assert_eq!(sync.eta_secs, None);
+
+        let sync = inner.update(Some(200), Some(1100), 10_000);
+        assert_eq!(sync.blocks_per_sec, 10.0);
+        assert_eq!(sync.eta_secs, Some(90));
+
+        // The oldest sample leaves the rate window.
+        let sync = inner.update(Some(800), Some(1100), 70_000);
+        assert_eq!(sync.blocks_per_sec, 10.0);
+        assert_eq!(sync.eta_secs, Some(30));
+
+        let sync = inner.update(Some(1099), Some(1100), 75_000);
+        assert!(!sync.syncing);
+        assert_eq!(sync.eta_secs, None);
+        assert!(inner.started.is_none());
+    }
+}
@@ -0,0 +1,203 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Fault injection (`chaos` feature), to test the network resilience in
+//! staging. Never enable it on production nodes.
+//!
+//! Driven through the node API administration routes:
+//! - `GET|POST /admin/chaos`: faults settings, e.g.
+//!   `{"p2p_drop": 10, "block_delay_ms": 2000, "seed": 42}`:
+//!   - `p2p_drop`: percentage of the P2P requests dropped by the gateway, the
+//!     peer gets no response;
+//!   - `block_delay_ms`: delay of the blocks proposed by the peers before
+//!     they reach the blockchain service;
+//!   - `seed`: random generator seed, the same seed drops the same sequence
+//!     of requests;
+//! - `POST /admin/chaos/crash/:service`: stops a service as if it died, the
+//!   node health check then stops the node as it does on real failures.
+
+use crate::api::{Request, Response, Router};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use trinci_core::{
+    base::{serialize::rmp_deserialize, Mutex, RwLock},
+    blockchain::Message,
+};
+
+/// Services that can be crashed on demand.
+pub const CRASHABLE: [&str; 6] = ["blockchain", "rest", "bridge", "gateway", "api", "ws"];
+
+/// Faults settings, all disabled by default.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChaosSettings {
+    /// Percentage of the P2P requests dropped.
+    #[serde(default)]
+    pub p2p_drop: u8,
+    /// Delay of the peers blocks, in milliseconds.
+    #[serde(default)]
+    pub block_delay_ms: u64,
+    /// Random generator seed.
+    #[serde(default)]
+    pub seed: u64,
+}
+
+pub struct Chaos {
+    settings: RwLock<ChaosSettings>,
+    rng: Mutex<StdRng>,
+    /// Service to crash, taken by the node health check.
+    crash: Mutex<Option<&'static str>>,
+}
+
+// Whether the message carries a block.
+fn carries_block(msg: &Message) -> bool {
+    match msg {
+        Message::GetBlockResponse { .. } => true,
+        Message::Packed { buf } => match rmp_deserialize::<Message>(buf) {
+            Ok(msg) => carries_block(&msg),
+            Err(_) => rmp_deserialize::<Vec<Message>>(buf)
+                .is_ok_and(|msgs| msgs.iter().any(carries_block)),
+        },
+        _ => false,
+    }
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Chaos {
+    pub fn new() -> Self {
+        warn!("[chaos] fault injection available, not meant for production nodes");
+        Chaos {
+            settings: RwLock::new(ChaosSettings::default()),
+            rng: Mutex::new(StdRng::seed_from_u64(0)),
+            crash: Mutex::new(None),
+        }
+    }
+
+    pub fn settings(&self) -> ChaosSettings {
+        self.settings.read().clone()
+    }
+
+    /// Replaces the settings, restarting the random sequence.
+    pub fn set(&self, settings: ChaosSettings) -> Result<(), String> {
+        if settings.p2p_drop > 100 {
+            return Err("`p2p_drop` is a percentage".to_string());
+        }
+        warn!("[chaos] faults set to {:?}", settings);
+        *self.rng.lock() = StdRng::seed_from_u64(settings.seed);
+        *self.settings.write() = settings;
+        Ok(())
+    }
+
+    /// Whether to drop a P2P request.
+    pub fn drop_p2p(&self) -> bool {
+        let drop = self.settings.read().p2p_drop;
+        drop > 0 && self.rng.lock().gen_range(0..100) < drop
+    }
+
+    /// Delay to apply to a P2P request.
+    pub fn p2p_delay(&self, msg: &Message) -> Option<Duration> {
+        let delay = self.settings.read().block_delay_ms;
+        (delay > 0 && carries_block(msg)).then(|| Duration::from_millis(delay))
+    }
+
+    /// Requests a service crash, returns `false` for an unknown service.
+    pub fn request_crash(&self, service: &str) -> bool {
+        match CRASHABLE.iter().find(|name| **name == service) {
+            Some(name) => {
+                *self.crash.lock() = Some(name);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Service to crash, if requested.
+    pub fn take_crash(&self) -> Option<&'static str> {
+        self.crash.lock().take()
+    }
+
+    /// Registers the fault injection routes within the node API.
+    pub fn routes(chaos: Arc<Self>, router: &mut Router) {
+        let faults = chaos.clone();
+        router.add("GET", "/admin/chaos", move |_: &Request| {
+            Response::json(&faults.settings())
+        });
+        let faults = chaos.clone();
+        router.add("POST", "/admin/chaos", move |req: &Request| {
+            let settings = match req.json::<ChaosSettings>() {
+                Ok(settings) => settings,
+                Err(res) => return res,
+            };
+            match faults.set(settings) {
+                Ok(()) => Response::ok(),
+                Err(err) => Response::error(400, err),
+            }
+        });
+        router.add(
+            "POST",
+            "/admin/chaos/crash/:service",
+            move |req: &Request| match req.param::<String>("service") {
+                Some(service) if chaos.request_crash(&service) => Response::ok(),
+                _ => Response::error(
+                    400,
+                    format!("unknown service, expected one of {}", CRASHABLE.join(", ")),
+                ),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_faults() {
+        let chaos = Chaos::new();
+        assert!(!chaos.drop_p2p());
+        assert!(chaos
+            .set(ChaosSettings {
+                p2p_drop: 101,
+                ..Default::default()
+            })
+            .is_err());
+
+        let settings = ChaosSettings {
+            p2p_drop: 30,
+            block_delay_ms: 500,
+            seed: 7,
+        };
+        chaos.set(settings.clone()).unwrap();
+        let first: Vec<bool> = (0..100).map(|_| chaos.drop_p2p()).collect();
+        chaos.set(settings).unwrap();
+        let second: Vec<bool> = (0..100).map(|_| chaos.drop_p2p()).collect();
+
+        assert_eq!(first, second);
+        assert!(first.iter().any(|drop| *drop) && first.iter().any(|drop| !drop));
+        assert_eq!(chaos.p2p_delay(&Message::GetCoreStatsRequest), None);
+
+        assert!(!chaos.request_crash("kernel"));
+        assert!(chaos.request_crash("rest"));
+        assert_eq!(chaos.take_crash(), Some("rest"));
+        assert_eq!(chaos.take_crash(), None);
+    }
+}
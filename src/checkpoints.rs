@@ -0,0 +1,309 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Trusted checkpoints.
+//!
+//! A checkpoint pins the hash of the block at a height, protecting the node
+//! against long-range forks. Checkpoints come from the `checkpoints`
+//! setting (`"<height>:<block hash>"` entries) and from the service account
+//! data `blockchain:checkpoints`, a msgpack array of `[height, hash]` pairs,
+//! reloaded periodically. The configured ones win on the same height.
+//!
+//! The stored chain is checked at startup, the node refuses to start on a
+//! conflict. The blocks received from the peers that conflict with a
+//! checkpoint, or that would rewrite the chain below the last checkpoint
+//! reached, are refused before reaching the blockchain service and their
+//! peer is banned.
+
+use crate::api::{Request, Response, Router};
+use crate::config::SERVICE_ACCOUNT_ID;
+use crate::tasks;
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use trinci_core::{
+    base::{serialize::rmp_deserialize, RwLock},
+    crypto::Hashable,
+    db::{Db, RocksDb},
+    Block, Hash, Message,
+};
+
+/// Service account data key of the on-chain checkpoints.
+pub const SERVICE_CHECKPOINTS_KEY: &str = "blockchain:checkpoints";
+
+/// Seconds between two reloads of the on-chain checkpoints.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Checkpoint origin.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Config,
+    Service,
+}
+
+/// Checkpoint, as reported by the node API.
+#[derive(Serialize, Debug, PartialEq)]
+struct CheckpointItem {
+    height: u64,
+    hash: String,
+    source: Source,
+    /// Whether the stored chain reached the checkpoint.
+    reached: bool,
+}
+
+pub struct Checkpoints {
+    configured: BTreeMap<u64, Hash>,
+    points: RwLock<BTreeMap<u64, (Hash, Source)>>,
+    db: Option<Arc<RwLock<RocksDb>>>,
+}
+
+/// Parses a `<height>:<block hash>` checkpoint, the hash with or without the
+/// multihash prefix.
+pub fn parse(entry: &str) -> Result<(u64, Hash), String> {
+    let invalid = || format!("invalid checkpoint `{}`, expected <height>:<hash>", entry);
+    let (height, hash) = entry.split_once(':').ok_or_else(invalid)?;
+    let height = height.trim().parse().map_err(|_| invalid())?;
+    let hash = hash.trim().to_lowercase();
+    let hash = if hash.len() == 64 {
+        format!("1220{}", hash)
+    } else {
+        hash
+    };
+    let hash = Hash::from_hex(&hash).map_err(|_| invalid())?;
+    Ok((height, hash))
+}
+
+// Checkpoints stored in the service account.
+fn service_points(db: &RocksDb) -> Vec<(u64, Hash)> {
+    let buf = match db.load_account_data(SERVICE_ACCOUNT_ID, SERVICE_CHECKPOINTS_KEY) {
+        Some(buf) => buf,
+        None => return Vec::new(),
+    };
+    rmp_deserialize(&buf).unwrap_or_else(|err| {
+        warn!("[checkpoints] invalid service account checkpoints: {}", err);
+        Vec::new()
+    })
+}
+
+// Stored block at `height`, the core answers the last block for the heights
+// above it.
+fn stored_block(db: &RocksDb, height: u64) -> Option<Block> {
+    db.load_block(height)
+        .filter(|block| block.data.height == height)
+}
+
+impl Checkpoints {
+    pub fn new(configured: BTreeMap<u64, Hash>) -> Self {
+        let points = configured
+            .iter()
+            .map(|(height, hash)| (*height, (*hash, Source::Config)))
+            .collect();
+        Checkpoints {
+            configured,
+            points: RwLock::new(points),
+            db: None,
+        }
+    }
+
+    /// Reloads the service account checkpoints from `db` periodically.
+    pub fn with_db(mut self, db: Arc<RwLock<RocksDb>>) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Merges the configured and the service account checkpoints.
+    pub fn refresh(&self, db: &RocksDb) {
+        let mut points = BTreeMap::new();
+        for (height, hash) in service_points(db) {
+            match self.configured.get(&height) {
+                Some(configured) if *configured != hash => warn!(
+                    "[checkpoints] service account checkpoint at height {} overridden by the configuration",
+                    height
+                ),
+                _ => (),
+            }
+            points.insert(height, (hash, Source::Service));
+        }
+        for (height, hash) in &self.configured {
+            points.insert(*height, (*hash, Source::Config));
+        }
+        *self.points.write() = points;
+    }
+
+    /// Checks the stored chain against the checkpoints.
+    pub fn verify(&self, db: &RocksDb) -> Result<(), String> {
+        for (height, (hash, _)) in self.points.read().iter() {
+            if let Some(block) = stored_block(db, *height) {
+                if block.data.primary_hash() != *hash {
+                    return Err(format!(
+                        "stored block {} hash {} conflicts with the checkpoint {}",
+                        height,
+                        hex::encode(block.data.primary_hash().as_bytes()),
+                        hex::encode(hash.as_bytes())
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Conflict of a block with the checkpoints, `local` gives the stored
+    // block at a height.
+    fn conflict<F>(&self, block: &Block, local: F) -> Option<String>
+    where
+        F: Fn(u64) -> Option<Block>,
+    {
+        let points = self.points.read();
+        let height = block.data.height;
+        if let Some((hash, _)) = points.get(&height) {
+            return (block.data.primary_hash() != *hash)
+                .then(|| format!("block {} conflicts with the checkpoint", height));
+        }
+        if let Some((hash, _)) = height.checked_sub(1).and_then(|prev| points.get(&prev)) {
+            if block.data.prev_hash != *hash {
+                return Some(format!(
+                    "block {} does not follow the checkpoint at {}",
+                    height,
+                    height - 1
+                ));
+            }
+        }
+        // Below the last checkpoint reached the stored chain is final.
+        let (checkpoint, _) = points.range(height..).next()?;
+        let stored = local(height)?;
+        local(*checkpoint)?;
+        (stored.data.primary_hash() != block.data.primary_hash()).then(|| {
+            format!(
+                "block {} rewrites the chain below the checkpoint at {}",
+                height, checkpoint
+            )
+        })
+    }
+
+    /// Checks the blocks carried by a p2p message, packed messages included.
+    /// Returns the reason of the refusal.
+    pub fn check(&self, msg: &Message) -> Option<String> {
+        match msg {
+            Message::GetBlockResponse { block, .. } => self.conflict(block, |height| {
+                let db = self.db.as_ref()?.read();
+                stored_block(&db, height)
+            }),
+            Message::Packed { buf } => {
+                if let Ok(msg) = rmp_deserialize::<Message>(buf) {
+                    self.check(&msg)
+                } else if let Ok(msgs) = rmp_deserialize::<Vec<Message>>(buf) {
+                    msgs.iter().find_map(|msg| self.check(msg))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn items(&self) -> Vec<CheckpointItem> {
+        let last = self
+            .db
+            .as_ref()
+            .and_then(|db| db.read().load_block(u64::MAX))
+            .map(|block| block.data.height);
+        self.points
+            .read()
+            .iter()
+            .map(|(height, (hash, source))| CheckpointItem {
+                height: *height,
+                hash: hex::encode(hash.as_bytes()),
+                source: *source,
+                reached: last >= Some(*height),
+            })
+            .collect()
+    }
+
+    /// Registers the checkpoints routes within the node API.
+    pub fn routes(checkpoints: Arc<Self>, router: &mut Router) {
+        router.add("GET", "/admin/checkpoints", move |_: &Request| {
+            Response::json(&checkpoints.items())
+        });
+    }
+}
+
+/// Reloads the service account checkpoints every `REFRESH_INTERVAL`.
+pub fn run(checkpoints: Arc<Checkpoints>) {
+    let db = match &checkpoints.db {
+        Some(db) => db.clone(),
+        None => return,
+    };
+    while tasks::sleep(REFRESH_INTERVAL) {
+        checkpoints.refresh(&db.read());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trinci_core::{BlockData, HashAlgorithm};
+
+    fn block(height: u64, prev_hash: Hash, seed: &[u8]) -> Block {
+        Block {
+            data: BlockData {
+                validator: None,
+                height,
+                size: 0,
+                prev_hash,
+                txs_hash: Hash::from_data(HashAlgorithm::Sha256, seed),
+                rxs_hash: Hash::default(),
+                state_hash: Hash::default(),
+                timestamp: 0,
+            },
+            signature: vec![],
+        }
+    }
+
+    #[test]
+    fn conflicting_blocks() {
+        let trusted = block(10, Hash::default(), b"trusted");
+        let hash = trusted.data.primary_hash();
+        let entry = format!("10:{}", hex::encode(&hash.as_bytes()[2..]));
+        assert_eq!(parse(&entry), Ok((10, hash)));
+        assert!(parse("10").is_err());
+        let checkpoints = Checkpoints::new([(10, hash)].into_iter().collect());
+        let stored = block(5, Hash::default(), b"stored");
+        let local = |height| match height {
+            5 => Some(stored.clone()),
+            10 => Some(trusted.clone()),
+            _ => None,
+        };
+
+        assert!(checkpoints.conflict(&trusted, local).is_none());
+        assert!(checkpoints
+            .conflict(&block(10, Hash::default(), b"fork"), local)
+            .is_some());
+        assert!(checkpoints
+            .conflict(&block(11, hash, b"next"), local)
+            .is_none());
+        assert!(checkpoints
+            .conflict(&block(11, Hash::default(), b"next"), local)
+            .is_some());
+        assert!(checkpoints.conflict(&stored, local).is_none());
+        assert!(checkpoints
+            .conflict(&block(5, Hash::default(), b"fork"), local)
+            .is_some());
+        assert!(checkpoints
+            .conflict(&block(5, Hash::default(), b"fork"), |_| None)
+            .is_none());
+    }
+}
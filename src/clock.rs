@@ -0,0 +1,162 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Node clock.
+//!
+//! Real by default. In simulation mode the time is virtual: it only moves
+//! forward when advanced, through the node API or by the tests, so that the
+//! node timers (blocks pacing, stall detection, resources checks, statistics
+//! sampling and monitor cycles) run in milliseconds instead of minutes.
+//! The blocks timeout of the core keeps the real time.
+
+use crate::api::{Request, Response, Router};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+struct Virtual {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+    tick: Condvar,
+}
+
+/// Clock advance request, via node API.
+#[derive(Deserialize)]
+struct AdvanceRequest {
+    secs: u64,
+}
+
+#[derive(Serialize)]
+struct ClockStatus {
+    simulated: bool,
+    /// Virtual seconds since the node start.
+    elapsed: u64,
+}
+
+#[derive(Clone, Default)]
+pub struct Clock {
+    virtual_time: Option<Arc<Virtual>>,
+}
+
+impl Clock {
+    /// Virtual clock, stopped until advanced.
+    pub fn simulated() -> Self {
+        Clock {
+            virtual_time: Some(Arc::new(Virtual {
+                start: Instant::now(),
+                elapsed: Mutex::new(Duration::ZERO),
+                tick: Condvar::new(),
+            })),
+        }
+    }
+
+    pub fn is_simulated(&self) -> bool {
+        self.virtual_time.is_some()
+    }
+
+    pub fn now(&self) -> Instant {
+        match &self.virtual_time {
+            Some(time) => time.start + *time.elapsed.lock().unwrap(),
+            None => Instant::now(),
+        }
+    }
+
+    /// Blocks the current thread for `duration`, of virtual time in simulation.
+    pub fn sleep(&self, duration: Duration) {
+        match &self.virtual_time {
+            Some(_) => self.sleep_until(self.now() + duration),
+            None => thread::sleep(duration),
+        }
+    }
+
+    /// Blocks the current thread until `deadline`.
+    pub fn sleep_until(&self, deadline: Instant) {
+        let time = match &self.virtual_time {
+            Some(time) => time,
+            None => return thread::sleep(deadline.saturating_duration_since(Instant::now())),
+        };
+        let mut elapsed = time.elapsed.lock().unwrap();
+        while time.start + *elapsed < deadline {
+            elapsed = time.tick.wait(elapsed).unwrap();
+        }
+    }
+
+    /// Moves the virtual time forward, waking up the expired sleeps.
+    /// No effect on the real clock.
+    pub fn advance(&self, duration: Duration) {
+        if let Some(time) = &self.virtual_time {
+            *time.elapsed.lock().unwrap() += duration;
+            time.tick.notify_all();
+        }
+    }
+
+    fn status(&self) -> ClockStatus {
+        ClockStatus {
+            simulated: self.is_simulated(),
+            elapsed: match &self.virtual_time {
+                Some(time) => time.elapsed.lock().unwrap().as_secs(),
+                None => 0,
+            },
+        }
+    }
+
+    /// Registers the clock administration routes within the node API.
+    pub fn routes(clock: Clock, router: &mut Router) {
+        let status = clock.clone();
+        router.add("GET", "/admin/clock", move |_: &Request| {
+            Response::json(&status.status())
+        });
+        router.add("POST", "/admin/clock/advance", move |req: &Request| {
+            if !clock.is_simulated() {
+                return Response::error(409, "node not in simulation mode");
+            }
+            let advance = match req.json::<AdvanceRequest>() {
+                Ok(advance) => advance,
+                Err(res) => return res,
+            };
+            clock.advance(Duration::from_secs(advance.secs));
+            Response::json(&clock.status())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn virtual_sleep() {
+        let clock = Clock::simulated();
+        let start = clock.now();
+        let (tx, rx) = mpsc::channel();
+        let sleeper = clock.clone();
+        thread::spawn(move || {
+            sleeper.sleep_until(start + Duration::from_secs(60));
+            tx.send(()).unwrap();
+        });
+
+        clock.advance(Duration::from_secs(30));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        clock.advance(Duration::from_secs(30));
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert_eq!(clock.now() - start, Duration::from_secs(60));
+    }
+}
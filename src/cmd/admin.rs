@@ -0,0 +1,75 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! `admin` subcommand: running node administration through the admin socket,
+//! the administration routes being never exposed over TCP.
+
+use crate::api::client;
+use clap::ArgMatches;
+
+pub fn run(matches: &ArgMatches, sub_matches: &ArgMatches) -> i32 {
+    let config = match super::node_config(matches) {
+        Some(config) => config,
+        None => return 1,
+    };
+    let socket = match config.admin_socket {
+        Some(socket) => socket,
+        None => {
+            eprintln!("Error: `admin-socket` not configured");
+            return 1;
+        }
+    };
+
+    let (method, path) = match sub_matches.subcommand() {
+        Some(("status", _)) => ("GET", "/admin/node".to_string()),
+        Some(("stats", _)) => ("GET", "/api/v1/stats".to_string()),
+        Some((command @ ("stop" | "start"), matches)) => {
+            let active = command == "start";
+            match matches.value_of("service").unwrap_or_default() {
+                "p2p" => ("POST", format!("/admin/node/p2p?active={}", active)),
+                service => (
+                    "POST",
+                    format!("/admin/node/services/{}?active={}", service, active),
+                ),
+            }
+        }
+        Some(("reload", _)) => ("POST", "/admin/config/reload".to_string()),
+        Some(("rotate-logs", _)) => ("POST", "/admin/log/rotate".to_string()),
+        Some(("drain", _)) => ("POST", "/admin/node/drain".to_string()),
+        Some(("handover", _)) => ("POST", "/admin/node/handover".to_string()),
+        Some(("shutdown", _)) => ("POST", "/admin/node/shutdown".to_string()),
+        _ => return 2,
+    };
+
+    match client::request(&socket, method, &path, None) {
+        Ok((200, body)) => {
+            let body = String::from_utf8_lossy(&body);
+            if body != "OK" {
+                println!("{}", body);
+            }
+            0
+        }
+        Ok((status, body)) => {
+            eprintln!("Error: {} {}", status, String::from_utf8_lossy(&body));
+            1
+        }
+        Err(err) => {
+            eprintln!("Error: admin socket {}: {}", socket, err);
+            1
+        }
+    }
+}
@@ -0,0 +1,359 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! `backup` subcommand: node database and identity backup.
+//!
+//! A backup folder holds:
+//! - `db/`: copy of the database folder, the persisted P2P identity only
+//!   along with the keys;
+//! - `config.toml`: the node configuration file, if any;
+//! - `keys/`: the keypair files, only if explicitly requested;
+//! - `manifest.json`: metadata, last block height and state hash, and SHA-256
+//!   of all the files above.
+//!
+//! The core does not expose the RocksDB checkpoints, so the database is
+//! copied file by file with the node stopped and the database folder locked:
+//! the closed database files are consistent. The copy is then opened and
+//! verified (last block state hash and recent blocks linkage) before being
+//! hashed. The restore checks the files against the manifest, and the
+//! restored database against the recorded height and state hash.
+
+use crate::api::client;
+use crate::config::{Config, DEFAULT_CONFIG_FILE};
+use crate::integrity::{self, DbVerify};
+use crate::lock::{DbLock, LOCK_FILE};
+use crate::utils::{self, copy_dir};
+use clap::ArgMatches;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use trinci_core::db::{Db, RocksDb};
+
+const MANIFEST_FILE: &str = "manifest.json";
+const DB_DIR: &str = "db";
+const KEYS_DIR: &str = "keys";
+const CONFIG_FILE: &str = "config.toml";
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    /// Version of the node that created the backup.
+    node_version: String,
+    /// Creation time, seconds since the epoch.
+    created: u64,
+    network: String,
+    db_path: String,
+    keypair_path: Option<String>,
+    p2p_keypair: Option<String>,
+    api_keypair: Option<String>,
+    /// Keypair files copied within the backup.
+    keys_included: bool,
+    /// Last block height, missing for an empty database.
+    height: Option<u64>,
+    /// Last block state hash (hex).
+    state_hash: Option<String>,
+    /// SHA-256 (hex) of the files, by path relative to the backup folder.
+    files: BTreeMap<String, String>,
+}
+
+pub fn run(matches: &ArgMatches, sub_matches: &ArgMatches) -> i32 {
+    let config = match super::node_config(matches) {
+        Some(config) => config,
+        None => return 1,
+    };
+    let config_file = matches.value_of("config").unwrap_or(DEFAULT_CONFIG_FILE);
+
+    let addr = super::admin_addr(&config);
+    if client::request(&addr, "GET", "/admin/node", None).is_ok() {
+        eprintln!("Error: the node is running, stop it first");
+        return 1;
+    }
+
+    let result = match sub_matches.subcommand() {
+        Some(("create", matches)) => create(
+            &config,
+            Path::new(config_file),
+            Path::new(matches.value_of("dir").unwrap_or_default()),
+            matches.is_present("include-keys"),
+        )
+        .map(|manifest| format!("Backup created, {} files", manifest.files.len())),
+        Some(("restore", matches)) => restore(
+            &config,
+            Path::new(config_file),
+            Path::new(matches.value_of("dir").unwrap_or_default()),
+            matches.is_present("force"),
+        )
+        .map(|_| format!("Backup restored into {}", config.db_path)),
+        _ => return 2,
+    };
+    match result {
+        Ok(message) => {
+            println!("{}", message);
+            0
+        }
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            1
+        }
+    }
+}
+
+// Last block height and state hash of the database at `path`, verified
+// against the current state: a truncated or corrupted copy is refused.
+fn db_state(path: &Path) -> Result<(Option<u64>, Option<String>), String> {
+    let mut db = RocksDb::new(path);
+    integrity::verify(&mut db, DbVerify::Quick)
+        .map_err(|err| format!("database {}: {}", path.display(), err))?;
+    Ok(match db.load_block(u64::MAX) {
+        Some(block) => (
+            Some(block.data.height),
+            Some(hex::encode(block.data.state_hash.as_bytes())),
+        ),
+        None => (None, None),
+    })
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            len => ctx.update(&buf[..len]),
+        }
+    }
+    Ok(hex::encode(ctx.finish()))
+}
+
+// Hashes all the files below `dir`, keyed by path relative to `base`.
+fn hash_dir(base: &Path, dir: &Path, files: &mut BTreeMap<String, String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            hash_dir(base, &path, files)?;
+        } else if let Ok(name) = path.strip_prefix(base) {
+            files.insert(name.to_string_lossy().into_owned(), hash_file(&path)?);
+        }
+    }
+    Ok(())
+}
+
+// Keypair files that can be copied, TPM keys never leave the device.
+fn key_files(config: &Config) -> Vec<&str> {
+    [
+        &config.keypair_path,
+        &config.p2p_keypair,
+        &config.api_keypair,
+    ]
+    .into_iter()
+    .flatten()
+    .map(String::as_str)
+    .filter(|path| !path.contains("/tpm"))
+    .collect()
+}
+
+fn create(
+    config: &Config,
+    config_file: &Path,
+    dir: &Path,
+    include_keys: bool,
+) -> Result<Manifest, String> {
+    let io_err = |err: io::Error| format!("{}", err);
+
+    if dir.exists() && fs::read_dir(dir).map_err(io_err)?.next().is_some() {
+        return Err(format!("backup folder {} is not empty", dir.display()));
+    }
+    let _lock = DbLock::acquire(&config.db_path)?;
+    copy_dir(Path::new(&config.db_path), &dir.join(DB_DIR))
+        .map_err(|err| format!("database copy failed: {}", err))?;
+    fs::remove_file(dir.join(DB_DIR).join(LOCK_FILE)).map_err(io_err)?;
+    if !include_keys {
+        // The persisted P2P identity is a key too.
+        let identity = dir.join(DB_DIR).join(utils::P2P_IDENTITY_FILE);
+        if identity.exists() {
+            fs::remove_file(identity).map_err(io_err)?;
+        }
+    }
+    // Opening the copy updates its files, hashed afterwards.
+    let (height, state_hash) = db_state(&dir.join(DB_DIR))?;
+    if config_file.exists() {
+        fs::copy(config_file, dir.join(CONFIG_FILE)).map_err(io_err)?;
+    }
+    if include_keys {
+        fs::create_dir_all(dir.join(KEYS_DIR)).map_err(io_err)?;
+        for path in key_files(config) {
+            let name = Path::new(path)
+                .file_name()
+                .ok_or_else(|| format!("invalid keypair path {}", path))?;
+            fs::copy(path, dir.join(KEYS_DIR).join(name))
+                .map_err(|err| format!("keypair {} copy failed: {}", path, err))?;
+        }
+    }
+
+    let mut files = BTreeMap::new();
+    hash_dir(dir, dir, &mut files).map_err(io_err)?;
+    let manifest = Manifest {
+        node_version: env!("CARGO_PKG_VERSION").to_string(),
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default(),
+        network: config.network.clone(),
+        db_path: config.db_path.clone(),
+        keypair_path: config.keypair_path.clone(),
+        p2p_keypair: config.p2p_keypair.clone(),
+        api_keypair: config.api_keypair.clone(),
+        keys_included: include_keys,
+        height,
+        state_hash,
+        files,
+    };
+    let buf = serde_json::to_vec_pretty(&manifest).map_err(|err| err.to_string())?;
+    fs::write(dir.join(MANIFEST_FILE), buf).map_err(io_err)?;
+    Ok(manifest)
+}
+
+// Loads the manifest and checks the backup content against it.
+fn verify(dir: &Path) -> Result<Manifest, String> {
+    let buf = fs::read(dir.join(MANIFEST_FILE))
+        .map_err(|err| format!("manifest not readable: {}", err))?;
+    let manifest: Manifest =
+        serde_json::from_slice(&buf).map_err(|err| format!("bad manifest: {}", err))?;
+
+    let mut files = BTreeMap::new();
+    hash_dir(dir, dir, &mut files).map_err(|err| err.to_string())?;
+    files.remove(MANIFEST_FILE);
+    for (name, hash) in &manifest.files {
+        match files.remove(name) {
+            Some(found) if &found == hash => (),
+            Some(_) => return Err(format!("{} is corrupted", name)),
+            None => return Err(format!("{} is missing", name)),
+        }
+    }
+    if let Some(name) = files.keys().next() {
+        return Err(format!("{} is not part of the backup", name));
+    }
+    Ok(manifest)
+}
+
+fn restore(config: &Config, config_file: &Path, dir: &Path, force: bool) -> Result<(), String> {
+    let manifest = verify(dir)?;
+    if manifest.network != config.network {
+        warn!(
+            "backup network {} differs from the configured {}",
+            manifest.network, config.network
+        );
+    }
+
+    // The current database, if any, is moved aside rather than deleted.
+    let db_path = Path::new(&config.db_path);
+    if db_path.exists() {
+        if !force {
+            return Err(format!(
+                "{} exists, use --force to replace it",
+                config.db_path
+            ));
+        }
+        let mut aside = PathBuf::from(format!(
+            "{}.pre-restore-{}",
+            config.db_path.trim_end_matches('/'),
+            manifest.created
+        ));
+        while aside.exists() {
+            aside.set_extension("1");
+        }
+        fs::rename(db_path, &aside).map_err(|err| err.to_string())?;
+        println!("Previous database moved to {}", aside.display());
+    }
+    copy_dir(&dir.join(DB_DIR), db_path).map_err(|err| format!("database copy failed: {}", err))?;
+    let (height, state_hash) = db_state(db_path)?;
+    if (height, &state_hash) != (manifest.height, &manifest.state_hash) {
+        return Err(format!(
+            "restored database at height {:?} state {:?}, expected height {:?} state {:?}",
+            height, state_hash, manifest.height, manifest.state_hash
+        ));
+    }
+
+    if manifest.keys_included {
+        let keys = [
+            &manifest.keypair_path,
+            &manifest.p2p_keypair,
+            &manifest.api_keypair,
+        ];
+        for path in keys.into_iter().flatten() {
+            let name = match Path::new(path).file_name() {
+                Some(name) => name,
+                None => continue,
+            };
+            let source = dir.join(KEYS_DIR).join(name);
+            if !source.exists() {
+                continue;
+            }
+            if Path::new(path).exists() && !force {
+                println!("Keypair {} exists, not restored", path);
+                continue;
+            }
+            fs::copy(&source, path).map_err(|err| format!("keypair {}: {}", path, err))?;
+        }
+    }
+
+    let backup_config = dir.join(CONFIG_FILE);
+    if backup_config.exists() {
+        if config_file.exists() {
+            println!(
+                "Configuration file not replaced, backup copy in {}",
+                backup_config.display()
+            );
+        } else {
+            fs::copy(&backup_config, config_file).map_err(|err| err.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn create_and_verify() {
+        let tmp = TempDir::new().unwrap();
+        let db_path = tmp.path().join("db");
+        drop(RocksDb::new(&db_path));
+        let config = Config {
+            db_path: db_path.to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        let dir = tmp.path().join("backup");
+
+        let manifest = create(&config, Path::new("missing.toml"), &dir, false).unwrap();
+        assert!(manifest.files.contains_key("db/CURRENT"));
+        assert!(!manifest.files.contains_key(&format!("db/{}", LOCK_FILE)));
+        assert_eq!(manifest.height, None);
+        assert!(verify(&dir).is_ok());
+
+        fs::write(dir.join("db/CURRENT"), "MANIFEST-999999\n").unwrap();
+        assert!(verify(&dir).is_err());
+    }
+}
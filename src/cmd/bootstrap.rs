@@ -0,0 +1,145 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! `bootstrap` subcommand: genesis assembly.
+//!
+//! Builds the bootstrap file loaded by the node on the first start: the
+//! service contract, the genesis transactions and a nonce making the file,
+//! thus the network name, unique.
+
+use super::{ceremony, tx};
+use crate::app::{calculate_network_name, Bootstrap};
+use crate::config::DEFAULT_BOOTSTRAP_PATH;
+use clap::ArgMatches;
+use rand::RngCore;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use trinci_core::{base::serialize::rmp_serialize, Transaction};
+
+pub fn run(node_matches: &ArgMatches, matches: &ArgMatches) -> i32 {
+    match matches.subcommand() {
+        Some(("build", matches)) => {
+            let wasm = Path::new(matches.value_of("wasm").unwrap_or_default());
+            let txs = matches.value_of("txs").map(Path::new);
+            let nonce = matches.value_of("nonce").map(str::to_owned);
+            let output = Path::new(matches.value_of("output").unwrap_or(DEFAULT_BOOTSTRAP_PATH));
+            if output.exists() && !matches.is_present("force") {
+                eprintln!(
+                    "Error: {} already exists, use --force to overwrite it",
+                    output.display()
+                );
+                return 1;
+            }
+            match build(wasm, txs, nonce).and_then(|buf| {
+                fs::write(output, &buf).map_err(|err| format!("{}: {}", output.display(), err))?;
+                Ok(buf)
+            }) {
+                Ok(buf) => {
+                    println!("Bootstrap written to {}", output.display());
+                    println!("Network name: {}", calculate_network_name(&buf));
+                    0
+                }
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    1
+                }
+            }
+        }
+        Some(("host", matches)) => ceremony::host(node_matches, matches),
+        Some(("join", matches)) => ceremony::join(node_matches, matches),
+        _ => 2,
+    }
+}
+
+// Genesis transactions files, in name order.
+fn tx_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(dir).map_err(|err| format!("{}: {}", dir.display(), err))?;
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|err| format!("{}: {}", dir.display(), err))?
+            .path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some("json") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Loads the genesis transactions found in the directory, if any.
+pub(super) fn load_txs(dir: Option<&Path>) -> Result<Vec<Transaction>, String> {
+    match dir {
+        Some(dir) => tx_files(dir)?.iter().map(|path| tx::load(path)).collect(),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Random bootstrap nonce.
+pub(super) fn random_nonce() -> String {
+    let mut nonce = [0; 16];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    hex::encode(nonce)
+}
+
+/// Serializes the bootstrap file content.
+pub(super) fn assemble(
+    bin: Vec<u8>,
+    txs: Vec<Transaction>,
+    nonce: String,
+) -> Result<Vec<u8>, String> {
+    let bootstrap = Bootstrap { bin, txs, nonce };
+    rmp_serialize(&bootstrap).map_err(|err| err.to_string())
+}
+
+/// Assembles the bootstrap file content, the nonce is random if not given.
+pub(super) fn build(
+    wasm: &Path,
+    txs: Option<&Path>,
+    nonce: Option<String>,
+) -> Result<Vec<u8>, String> {
+    let bin = fs::read(wasm).map_err(|err| format!("{}: {}", wasm.display(), err))?;
+    let txs = load_txs(txs)?;
+    assemble(bin, txs, nonce.unwrap_or_else(random_nonce))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bootstrap_reader::BootstrapReader;
+    use tempfile::TempDir;
+
+    #[test]
+    fn build_and_load() {
+        let dir = TempDir::new().unwrap();
+        let wasm = dir.path().join("service.wasm");
+        fs::write(&wasm, b"\0asm").unwrap();
+
+        let buf = build(&wasm, None, Some("nonce".to_string())).unwrap();
+        let output = dir.path().join("bootstrap.bin");
+        fs::write(&output, &buf).unwrap();
+
+        let mut bootstrap = BootstrapReader::open(output.to_str().unwrap()).unwrap();
+        assert_eq!(bootstrap.take_bin(), b"\0asm");
+        assert_eq!(bootstrap.txs_count(), 0);
+        assert_eq!(bootstrap.finish().unwrap(), calculate_network_name(&buf));
+        // Same inputs, same network.
+        assert_eq!(build(&wasm, None, Some("nonce".to_string())).unwrap(), buf);
+    }
+}
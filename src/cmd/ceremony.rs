@@ -0,0 +1,556 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! `bootstrap host` and `bootstrap join` subcommands: genesis ceremony.
+//!
+//! Lets the founding nodes of a consortium assemble the bootstrap file
+//! together, instead of trusting a single operator to do it alone.
+//! One founder hosts the ceremony on a dedicated port, the others join it
+//! with their node keypair:
+//! 1. every founder contributes its signed genesis transactions;
+//! 2. with all the contributions the host assembles the proposal, its own
+//!    transactions first and then the others in the founders order;
+//! 3. every founder checks that the proposal carries its transactions and
+//!    endorses it by signing the network name;
+//! 4. with all the endorsements the ceremony is sealed and every founder
+//!    writes the same bootstrap file, along with the endorsements.
+
+use super::bootstrap::{assemble, load_txs, random_nonce};
+use crate::api::{client, service::ApiConfig, service::ApiService, Request, Response, Router};
+use crate::app::{calculate_network_name, Bootstrap};
+use crate::config::DEFAULT_BOOTSTRAP_PATH;
+use crate::utils::{self, Signer};
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use trinci_core::{
+    base::{
+        serialize::{rmp_deserialize, rmp_serialize},
+        Mutex,
+    },
+    PublicKey, Transaction,
+};
+
+/// Endorsements file extension, replacing the bootstrap file one.
+pub const ENDORSEMENTS_EXT: &str = "endorsements.json";
+
+/// Interval between two checks of the ceremony progress.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Founder genesis transactions, signed with the founder node keypair.
+#[derive(Serialize, Deserialize)]
+struct Contribution {
+    public_key: PublicKey,
+    txs: Vec<Transaction>,
+    /// Signature of the msgpack transactions list (hex).
+    signature: String,
+}
+
+/// Founder approval of the proposal.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Endorsement {
+    pub public_key: PublicKey,
+    /// Signature of the network name (hex).
+    pub signature: String,
+}
+
+/// Ceremony outcome, saved next to the bootstrap file.
+#[derive(Serialize, Deserialize)]
+pub struct Seal {
+    pub network: String,
+    /// Endorsements in the founders order.
+    pub endorsements: Vec<Endorsement>,
+}
+
+/// Ceremony progress, via `GET /ceremony`.
+#[derive(Serialize)]
+struct Status<'a> {
+    founders: &'a [String],
+    contributed: Vec<&'a String>,
+    endorsed: Vec<&'a String>,
+    network: Option<String>,
+}
+
+fn contribution_data(txs: &[Transaction]) -> Result<Vec<u8>, String> {
+    rmp_serialize(&txs).map_err(|err| err.to_string())
+}
+
+fn sign(signer: &dyn Signer, data: &[u8]) -> Result<String, String> {
+    signer
+        .sign(data)
+        .map(hex::encode)
+        .map_err(|err| format!("signature failure: {}", err))
+}
+
+// Checks the signature and returns the founder account.
+fn verify(
+    founders: &[String],
+    public_key: &PublicKey,
+    data: &[u8],
+    signature: &str,
+) -> Result<String, String> {
+    let account = public_key.to_account_id();
+    if !founders.contains(&account) {
+        return Err(format!("`{}` is not a founder", account));
+    }
+    let signature = hex::decode(signature).map_err(|_| "invalid signature encoding")?;
+    if !public_key.verify(data, &signature) {
+        return Err(format!("invalid signature from `{}`", account));
+    }
+    Ok(account)
+}
+
+/// Ceremony state, kept by the host.
+struct Ceremony {
+    /// Founders accounts, the host first.
+    founders: Vec<String>,
+    wasm: Vec<u8>,
+    nonce: String,
+    contributions: BTreeMap<String, Vec<Transaction>>,
+    /// Bootstrap file content, once every founder contributed.
+    proposal: Option<Vec<u8>>,
+    endorsements: BTreeMap<String, Endorsement>,
+    /// Founders that downloaded the seal.
+    delivered: BTreeSet<String>,
+}
+
+impl Ceremony {
+    fn new(founders: Vec<String>, wasm: Vec<u8>, nonce: String) -> Self {
+        Ceremony {
+            founders,
+            wasm,
+            nonce,
+            contributions: BTreeMap::new(),
+            proposal: None,
+            endorsements: BTreeMap::new(),
+            delivered: BTreeSet::new(),
+        }
+    }
+
+    fn network(&self) -> Option<String> {
+        self.proposal.as_deref().map(calculate_network_name)
+    }
+
+    fn contribute(&mut self, contribution: Contribution) -> Result<String, String> {
+        if self.proposal.is_some() {
+            return Err("contributions closed".to_string());
+        }
+        let data = contribution_data(&contribution.txs)?;
+        let account = verify(
+            &self.founders,
+            &contribution.public_key,
+            &data,
+            &contribution.signature,
+        )?;
+        self.contributions.insert(account.clone(), contribution.txs);
+        if self.contributions.len() == self.founders.len() {
+            let txs = self
+                .founders
+                .iter()
+                .flat_map(|founder| self.contributions[founder].iter().cloned())
+                .collect();
+            self.proposal = Some(assemble(self.wasm.clone(), txs, self.nonce.clone())?);
+        }
+        Ok(account)
+    }
+
+    fn endorse(&mut self, endorsement: Endorsement) -> Result<String, String> {
+        let network = self.network().ok_or("proposal not ready")?;
+        let account = verify(
+            &self.founders,
+            &endorsement.public_key,
+            network.as_bytes(),
+            &endorsement.signature,
+        )?;
+        self.endorsements.insert(account.clone(), endorsement);
+        Ok(account)
+    }
+
+    fn seal(&self) -> Option<Seal> {
+        if self.endorsements.len() != self.founders.len() {
+            return None;
+        }
+        Some(Seal {
+            network: self.network()?,
+            endorsements: self
+                .founders
+                .iter()
+                .map(|founder| self.endorsements[founder].clone())
+                .collect(),
+        })
+    }
+
+    fn routes(ceremony: Arc<Mutex<Self>>, router: &mut Router) {
+        let state = ceremony.clone();
+        router.add("GET", "/ceremony", move |_: &Request| {
+            let state = state.lock();
+            Response::json(&Status {
+                founders: &state.founders,
+                contributed: state.contributions.keys().collect(),
+                endorsed: state.endorsements.keys().collect(),
+                network: state.network(),
+            })
+        });
+        let state = ceremony.clone();
+        router.add("POST", "/ceremony/contributions", move |req: &Request| {
+            let contribution = match req.json::<Contribution>() {
+                Ok(contribution) => contribution,
+                Err(res) => return res,
+            };
+            match state.lock().contribute(contribution) {
+                Ok(account) => {
+                    println!("Contribution from {}", account);
+                    Response::ok()
+                }
+                Err(err) => Response::error(409, err),
+            }
+        });
+        let state = ceremony.clone();
+        router.add(
+            "GET",
+            "/ceremony/proposal",
+            move |_: &Request| match &state.lock().proposal {
+                Some(proposal) => Response {
+                    status: 200,
+                    content_type: "application/octet-stream",
+                    body: proposal.clone(),
+                },
+                None => Response::error(503, "waiting for the contributions"),
+            },
+        );
+        let state = ceremony.clone();
+        router.add("POST", "/ceremony/endorsements", move |req: &Request| {
+            let endorsement = match req.json::<Endorsement>() {
+                Ok(endorsement) => endorsement,
+                Err(res) => return res,
+            };
+            match state.lock().endorse(endorsement) {
+                Ok(account) => {
+                    println!("Endorsement from {}", account);
+                    Response::ok()
+                }
+                Err(err) => Response::error(409, err),
+            }
+        });
+        router.add("GET", "/ceremony/seal", move |req: &Request| {
+            let mut state = ceremony.lock();
+            match state.seal() {
+                Some(seal) => {
+                    if let Some(account) = req.query::<String>("account") {
+                        state.delivered.insert(account);
+                    }
+                    Response::json(&seal)
+                }
+                None => Response::error(503, "waiting for the endorsements"),
+            }
+        });
+    }
+}
+
+// Node keypair, the founder identity.
+fn founder_signer(node_matches: &ArgMatches) -> Result<Box<dyn Signer>, String> {
+    let config = super::node_config(node_matches).ok_or("invalid node configuration")?;
+    if config.keypair_path.is_none() {
+        return Err("the ceremony needs the node keypair (`keypair-path`)".to_string());
+    }
+    utils::load_signer(config.keypair_path).map_err(|err| format!("node keypair: {}", err))
+}
+
+fn check_output(matches: &ArgMatches) -> Result<PathBuf, String> {
+    let output = PathBuf::from(matches.value_of("output").unwrap_or(DEFAULT_BOOTSTRAP_PATH));
+    if output.exists() && !matches.is_present("force") {
+        return Err(format!(
+            "{} already exists, use --force to overwrite it",
+            output.display()
+        ));
+    }
+    Ok(output)
+}
+
+// Writes the bootstrap file and the endorsements next to it.
+fn write_sealed(output: &Path, proposal: &[u8], seal: &Seal) -> Result<(), String> {
+    let endorsements = output.with_extension(ENDORSEMENTS_EXT);
+    let json = serde_json::to_vec_pretty(seal).map_err(|err| err.to_string())?;
+    fs::write(output, proposal).map_err(|err| format!("{}: {}", output.display(), err))?;
+    fs::write(&endorsements, json).map_err(|err| format!("{}: {}", endorsements.display(), err))?;
+    println!("Bootstrap written to {}", output.display());
+    println!("Endorsements written to {}", endorsements.display());
+    println!("Network name: {}", seal.network);
+    Ok(())
+}
+
+fn exit_code(result: Result<(), String>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            1
+        }
+    }
+}
+
+/// Hosts the ceremony until every founder got the seal.
+pub fn host(node_matches: &ArgMatches, matches: &ArgMatches) -> i32 {
+    exit_code(run_host(node_matches, matches))
+}
+
+fn run_host(node_matches: &ArgMatches, matches: &ArgMatches) -> Result<(), String> {
+    let output = check_output(matches)?;
+    let signer = founder_signer(node_matches)?;
+    let wasm_path = matches.value_of("wasm").unwrap_or_default();
+    let wasm = fs::read(wasm_path).map_err(|err| format!("{}: {}", wasm_path, err))?;
+    let txs = load_txs(matches.value_of("txs").map(Path::new))?;
+    let nonce = matches
+        .value_of("nonce")
+        .map(str::to_owned)
+        .unwrap_or_else(random_nonce);
+    let listen = matches.value_of("listen").unwrap_or_default();
+    let (addr, port) = listen
+        .rsplit_once(':')
+        .and_then(|(addr, port)| Some((addr.to_string(), port.parse::<u16>().ok()?)))
+        .ok_or_else(|| format!("invalid listen address `{}` (expected ADDR:PORT)", listen))?;
+
+    let account = signer.public_key().to_account_id();
+    let mut founders = vec![account.clone()];
+    for founder in matches.values_of("founders").into_iter().flatten() {
+        if !founders.iter().any(|known| known == founder) {
+            founders.push(founder.to_string());
+        }
+    }
+    let ceremony = Arc::new(Mutex::new(Ceremony::new(founders, wasm, nonce)));
+    let signature = sign(signer.as_ref(), &contribution_data(&txs)?)?;
+    ceremony.lock().contribute(Contribution {
+        public_key: signer.public_key(),
+        txs,
+        signature,
+    })?;
+
+    let mut router = Router::new();
+    Ceremony::routes(ceremony.clone(), &mut router);
+    let config = ApiConfig {
+        addr,
+        port,
+        socket: None,
+    };
+    let mut service = ApiService::new(config, router);
+    service.start();
+    if !service.is_running() {
+        return Err(format!("unable to listen on {}", listen));
+    }
+    println!("Ceremony hosted on {}, waiting for the founders", listen);
+
+    let mut sealed = false;
+    let result = loop {
+        thread::sleep(POLL_INTERVAL);
+        let mut state = ceremony.lock();
+        if let Some(network) = state.network() {
+            if !state.endorsements.contains_key(&account) {
+                println!("Proposal ready, network name: {}", network);
+                let endorsement = sign(signer.as_ref(), network.as_bytes()).and_then(|signature| {
+                    state.endorse(Endorsement {
+                        public_key: signer.public_key(),
+                        signature,
+                    })
+                });
+                if let Err(err) = endorsement {
+                    break Err(err);
+                }
+            }
+        }
+        if let (Some(seal), false) = (state.seal(), sealed) {
+            sealed = true;
+            let proposal = state.proposal.as_deref().unwrap_or_default();
+            if let Err(err) = write_sealed(&output, proposal, &seal) {
+                break Err(err);
+            }
+        }
+        // The host does not fetch its own seal.
+        if sealed && state.delivered.len() + 1 >= state.founders.len() {
+            break Ok(());
+        }
+    };
+    service.stop();
+    result
+}
+
+// Sends a request to the host, `None` if the host is not ready yet.
+fn fetch(
+    host: &str,
+    method: &str,
+    path: &str,
+    body: Option<&[u8]>,
+) -> Result<Option<Vec<u8>>, String> {
+    let (status, body) = client::request(host, method, path, body)
+        .map_err(|err| format!("ceremony host {}: {}", host, err))?;
+    match status {
+        200 => Ok(Some(body)),
+        503 => Ok(None),
+        _ => Err(format!(
+            "ceremony host: {} {}",
+            status,
+            String::from_utf8_lossy(&body)
+        )),
+    }
+}
+
+// Waits for the host to be ready.
+fn poll(host: &str, path: &str) -> Result<Vec<u8>, String> {
+    loop {
+        if let Some(body) = fetch(host, "GET", path, None)? {
+            return Ok(body);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Joins a ceremony as founder.
+pub fn join(node_matches: &ArgMatches, matches: &ArgMatches) -> i32 {
+    exit_code(run_join(node_matches, matches))
+}
+
+fn run_join(node_matches: &ArgMatches, matches: &ArgMatches) -> Result<(), String> {
+    let output = check_output(matches)?;
+    let signer = founder_signer(node_matches)?;
+    let host = matches.value_of("host").unwrap_or_default();
+    let txs = load_txs(matches.value_of("txs").map(Path::new))?;
+    let account = signer.public_key().to_account_id();
+
+    let contribution = Contribution {
+        public_key: signer.public_key(),
+        signature: sign(signer.as_ref(), &contribution_data(&txs)?)?,
+        txs: txs.clone(),
+    };
+    let body = serde_json::to_vec(&contribution).map_err(|err| err.to_string())?;
+    fetch(host, "POST", "/ceremony/contributions", Some(&body))?;
+    println!("Contributed {} transactions as {}", txs.len(), account);
+
+    println!("Waiting for the proposal");
+    let proposal = poll(host, "/ceremony/proposal")?;
+    let bootstrap: Bootstrap =
+        rmp_deserialize(&proposal).map_err(|err| format!("invalid proposal: {}", err))?;
+    if let Some(wasm) = matches.value_of("wasm") {
+        let bin = fs::read(wasm).map_err(|err| format!("{}: {}", wasm, err))?;
+        if bin != bootstrap.bin {
+            return Err(format!(
+                "the proposal service contract differs from {}",
+                wasm
+            ));
+        }
+    }
+    if txs.iter().any(|tx| !bootstrap.txs.contains(tx)) {
+        return Err("the proposal lacks some of the contributed transactions".to_string());
+    }
+    let network = calculate_network_name(&proposal);
+    println!(
+        "Proposal: {} transactions, network name: {}",
+        bootstrap.txs.len(),
+        network
+    );
+
+    let endorsement = Endorsement {
+        public_key: signer.public_key(),
+        signature: sign(signer.as_ref(), network.as_bytes())?,
+    };
+    let body = serde_json::to_vec(&endorsement).map_err(|err| err.to_string())?;
+    fetch(host, "POST", "/ceremony/endorsements", Some(&body))?;
+
+    println!("Waiting for the other founders endorsements");
+    let seal = poll(host, &format!("/ceremony/seal?account={}", account))?;
+    let seal: Seal =
+        serde_json::from_slice(&seal).map_err(|err| format!("invalid seal: {}", err))?;
+    if seal.network != network {
+        return Err(format!("the seal is for another network: {}", seal.network));
+    }
+    for endorsement in &seal.endorsements {
+        let signature = hex::decode(&endorsement.signature).unwrap_or_default();
+        if !endorsement
+            .public_key
+            .verify(network.as_bytes(), &signature)
+        {
+            return Err(format!(
+                "invalid endorsement from `{}`",
+                endorsement.public_key.to_account_id()
+            ));
+        }
+    }
+    if !seal
+        .endorsements
+        .iter()
+        .any(|endorsement| endorsement.public_key == signer.public_key())
+    {
+        return Err("the seal lacks our endorsement".to_string());
+    }
+    write_sealed(&output, &proposal, &seal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trinci_core::crypto::{ed25519::KeyPair as Ed25519KeyPair, KeyPair};
+
+    fn founder() -> KeyPair {
+        KeyPair::Ed25519(Ed25519KeyPair::from_random())
+    }
+
+    fn contribution(founder: &KeyPair) -> Contribution {
+        Contribution {
+            public_key: founder.public_key(),
+            signature: sign(founder, &contribution_data(&[]).unwrap()).unwrap(),
+            txs: Vec::new(),
+        }
+    }
+
+    fn endorsement(founder: &KeyPair, network: &str) -> Endorsement {
+        Endorsement {
+            public_key: founder.public_key(),
+            signature: sign(founder, network.as_bytes()).unwrap(),
+        }
+    }
+
+    #[test]
+    fn ceremony_flow() {
+        let (host, other, stranger) = (founder(), founder(), founder());
+        let founders = vec![
+            host.public_key().to_account_id(),
+            other.public_key().to_account_id(),
+        ];
+        let mut ceremony = Ceremony::new(founders, b"\0asm".to_vec(), "nonce".to_string());
+
+        ceremony.contribute(contribution(&host)).unwrap();
+        assert!(ceremony.contribute(contribution(&stranger)).is_err());
+        assert!(ceremony.network().is_none());
+        ceremony.contribute(contribution(&other)).unwrap();
+        assert!(ceremony.contribute(contribution(&other)).is_err());
+
+        let network = ceremony.network().unwrap();
+        ceremony.endorse(endorsement(&host, &network)).unwrap();
+        assert!(ceremony.endorse(endorsement(&other, "forged")).is_err());
+        assert!(ceremony.seal().is_none());
+        ceremony.endorse(endorsement(&other, &network)).unwrap();
+
+        let seal = ceremony.seal().unwrap();
+        assert_eq!(seal.network, network);
+        assert_eq!(seal.endorsements.len(), 2);
+    }
+}
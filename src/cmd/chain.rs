@@ -0,0 +1,136 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! `block` and `receipt` subcommands: chain contents inspection.
+//!
+//! A running node is queried, by default the local one, and the msgpack
+//! structures are printed as JSON.
+//! The core only looks up blocks by height: a block hash is searched walking
+//! back from the last block, up to `BLOCK_SEARCH_DEPTH` blocks.
+
+use super::{rest, tx::receipt_json};
+use clap::ArgMatches;
+use serde_json::json;
+use trinci_core::{crypto::Hashable, Block, Hash, Message};
+
+/// Max number of blocks walked back to find a block by hash.
+const BLOCK_SEARCH_DEPTH: u64 = 1000;
+
+pub fn run(matches: &ArgMatches, name: &str, sub_matches: &ArgMatches) -> i32 {
+    let config = match super::node_config(matches) {
+        Some(config) => config,
+        None => return 1,
+    };
+    let res = match (name, sub_matches.subcommand()) {
+        ("block", Some(("get", matches))) => {
+            let addr = rest::node_addr(&config, matches);
+            block_get(&addr, matches.value_of("block").unwrap_or_default())
+        }
+        ("receipt", Some(("get", matches))) => {
+            let addr = rest::node_addr(&config, matches);
+            receipt_get(&addr, matches.value_of("hash").unwrap_or_default())
+        }
+        _ => return 2,
+    };
+    match res {
+        Ok(json) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&json).unwrap_or_default()
+            );
+            0
+        }
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            1
+        }
+    }
+}
+
+// Parses a hash, with or without the multihash prefix.
+fn parse_hash(hash: &str) -> Result<Hash, String> {
+    let hash = hash.to_lowercase();
+    let hash = if hash.len() == 64 {
+        format!("1220{}", hash)
+    } else {
+        hash
+    };
+    Hash::from_hex(&hash).map_err(|_| format!("invalid hash `{}`", hash))
+}
+
+fn get_block(addr: &str, height: u64) -> Result<(Block, Vec<Hash>), String> {
+    let msg = Message::GetBlockRequest {
+        height,
+        txs: true,
+        destination: None,
+    };
+    match rest::request(addr, msg)? {
+        Message::GetBlockResponse { block, txs, .. } => Ok((block, txs.unwrap_or_default())),
+        msg => Err(format!("unexpected answer: {:?}", msg)),
+    }
+}
+
+fn block_json(block: &Block, txs: &[Hash]) -> serde_json::Value {
+    let data = &block.data;
+    json!({
+        "height": data.height,
+        "hash": hex::encode(data.primary_hash().as_bytes()),
+        "size": data.size,
+        "prev_hash": hex::encode(data.prev_hash.as_bytes()),
+        "txs_hash": hex::encode(data.txs_hash.as_bytes()),
+        "rxs_hash": hex::encode(data.rxs_hash.as_bytes()),
+        "state_hash": hex::encode(data.state_hash.as_bytes()),
+        "timestamp": data.timestamp,
+        "validator": data.validator.as_ref().map(|key| key.to_account_id()),
+        "signature": hex::encode(&block.signature),
+        "txs": txs.iter().map(|hash| hex::encode(hash.as_bytes())).collect::<Vec<_>>(),
+    })
+}
+
+fn block_get(addr: &str, block: &str) -> Result<serde_json::Value, String> {
+    if let Ok(height) = block.parse::<u64>() {
+        let (block, txs) = get_block(addr, height)?;
+        return Ok(block_json(&block, &txs));
+    }
+
+    let hash = parse_hash(block)?;
+    // The last block is returned for any height above it.
+    let (mut block, mut txs) = get_block(addr, u64::MAX)?;
+    let last = block.data.height;
+    loop {
+        if block.data.primary_hash() == hash {
+            return Ok(block_json(&block, &txs));
+        }
+        let height = block.data.height;
+        if height == 0 || last - height + 1 >= BLOCK_SEARCH_DEPTH {
+            return Err(format!(
+                "block {} not found within the last {} blocks",
+                hex::encode(hash.as_bytes()),
+                last - height + 1
+            ));
+        }
+        (block, txs) = get_block(addr, height - 1)?;
+    }
+}
+
+fn receipt_get(addr: &str, hash: &str) -> Result<serde_json::Value, String> {
+    let hash = parse_hash(hash)?;
+    match rest::request(addr, Message::GetReceiptRequest { hash })? {
+        Message::GetReceiptResponse { rx } => Ok(receipt_json(&hash, &rx)),
+        msg => Err(format!("unexpected answer: {:?}", msg)),
+    }
+}
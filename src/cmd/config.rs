@@ -0,0 +1,88 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! `config` subcommand: configuration file generation and validation.
+
+use crate::config::{self, Severity, DEFAULT_CONFIG_FILE};
+use clap::ArgMatches;
+use std::{fs, path::Path};
+
+pub fn run(matches: &ArgMatches) -> i32 {
+    match matches.subcommand() {
+        Some(("init", matches)) => init(
+            matches.value_of("output").unwrap_or(DEFAULT_CONFIG_FILE),
+            matches.is_present("force"),
+        ),
+        Some(("validate", matches)) => {
+            validate(matches.value_of("file").unwrap_or(DEFAULT_CONFIG_FILE))
+        }
+        _ => 2,
+    }
+}
+
+/// Writes the sample configuration file.
+fn init(path: &str, force: bool) -> i32 {
+    if Path::new(path).exists() && !force {
+        eprintln!(
+            "File '{}' already exists, use --force to overwrite it",
+            path
+        );
+        return 1;
+    }
+    match fs::write(path, config::sample_config()) {
+        Ok(()) => {
+            println!("Configuration written to '{}'", path);
+            0
+        }
+        Err(err) => {
+            eprintln!("Error writing '{}': {}", path, err);
+            1
+        }
+    }
+}
+
+/// Reports the issues found in the given configuration file.
+fn validate(path: &str) -> i32 {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) => {
+            eprintln!("Error reading '{}': {}", path, err);
+            return 1;
+        }
+    };
+    let issues = match config::validate_content(&content) {
+        Ok(issues) => issues,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            return 1;
+        }
+    };
+    for issue in &issues {
+        println!("{}: {}", path, issue);
+    }
+    let errors = issues
+        .iter()
+        .filter(|issue| issue.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        println!("{}: {} error(s) found", path, errors);
+        1
+    } else {
+        println!("{}: ok", path);
+        0
+    }
+}
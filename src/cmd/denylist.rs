@@ -0,0 +1,115 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! `denylist` subcommand: transactions denylist management.
+//!
+//! The running node picks up the changes within a second.
+
+use crate::denylist::Denylist;
+use clap::ArgMatches;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default entry validity (one day).
+const DEFAULT_TTL: u64 = 24 * 60 * 60;
+
+pub fn run(matches: &ArgMatches, sub_matches: &ArgMatches) -> i32 {
+    let config = match super::node_config(matches) {
+        Some(config) => config,
+        None => return 1,
+    };
+    let mut denylist = Denylist::open(&config.db_path);
+
+    match sub_matches.subcommand() {
+        Some(("list", _)) => {
+            list(&denylist);
+            0
+        }
+        Some(("add", matches)) => {
+            let ttl = match matches.value_of("ttl").map(str::parse::<u64>) {
+                Some(Ok(ttl)) => ttl,
+                Some(Err(_)) => {
+                    eprintln!("Error: invalid value for --ttl");
+                    return 1;
+                }
+                None => DEFAULT_TTL,
+            };
+            let id = denylist.add(
+                matches.value_of("account").map(str::to_owned),
+                matches.value_of("method").map(str::to_owned),
+                matches.value_of("hash").map(str::to_owned),
+                Duration::from_secs(ttl),
+                matches.value_of("reason").unwrap_or_default().to_owned(),
+            );
+            save(&mut denylist, &format!("Entry {} added", id))
+        }
+        Some(("remove", matches)) => {
+            let id = match matches.value_of("id").map(str::parse::<u64>) {
+                Some(Ok(id)) => id,
+                _ => {
+                    eprintln!("Error: invalid entry identifier");
+                    return 1;
+                }
+            };
+            if !denylist.remove(id) {
+                eprintln!("Entry {} not found", id);
+                return 1;
+            }
+            save(&mut denylist, &format!("Entry {} removed", id))
+        }
+        _ => 2,
+    }
+}
+
+fn save(denylist: &mut Denylist, message: &str) -> i32 {
+    match denylist.save() {
+        Ok(()) => {
+            println!("{}", message);
+            0
+        }
+        Err(err) => {
+            eprintln!("Error saving the denylist: {}", err);
+            1
+        }
+    }
+}
+
+fn list(denylist: &Denylist) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut empty = true;
+    for entry in denylist.entries() {
+        let target = match (&entry.account, &entry.method, &entry.hash) {
+            (_, _, Some(hash)) => format!("hash {}", hash),
+            (Some(account), Some(method), None) => format!("{}::{}", account, method),
+            (Some(account), None, None) => account.clone(),
+            (None, _, None) => "-".to_string(),
+        };
+        println!(
+            "{:>4}  {:<48}  expires in {:>6}s  {}",
+            entry.id,
+            target,
+            entry.expires.saturating_sub(now),
+            entry.reason
+        );
+        empty = false;
+    }
+    if empty {
+        println!("No active entries");
+    }
+}
@@ -0,0 +1,275 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! `devnet` subcommand: local multi-node network.
+//!
+//! Runs a set of in-process nodes sharing a generated bootstrap, each with its
+//! own database folder and ports, the first one being the P2P bootstrap peer
+//! of the others. The node and P2P keypairs are stored in the nodes folders,
+//! so that a following run restarts the same network.
+
+use super::bootstrap;
+use crate::{
+    app::{calculate_network_name, App},
+    config::{Config, ConfigLoader},
+    telemetry::Telemetry,
+};
+use clap::ArgMatches;
+use std::{
+    fs,
+    io::Write,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+    thread,
+};
+use trinci_core::{crypto::ed25519, KeyPair};
+
+/// Default number of nodes.
+const DEFAULT_NODES: usize = 3;
+
+/// Default nodes data folder.
+const DEFAULT_DIR: &str = "devnet";
+
+/// Bootstrap nonce, a fixed one keeps the network name across the runs.
+const NONCE: &str = "devnet";
+
+/// Ports used by a node: REST, bridge, node API, websocket and P2P.
+const NODE_PORTS: u16 = 5;
+
+/// Local node of the network.
+struct Node {
+    config: Config,
+    keypair: ed25519::KeyPair,
+}
+
+pub fn run(node_matches: &ArgMatches, matches: &ArgMatches) -> i32 {
+    let wasm = Path::new(matches.value_of("wasm").unwrap_or_default());
+    let txs = matches.value_of("txs").map(Path::new);
+    let dir = Path::new(matches.value_of("dir").unwrap_or(DEFAULT_DIR));
+    let nodes = match matches.value_of("nodes").map(str::parse::<usize>) {
+        Some(Ok(nodes)) if nodes > 0 => nodes,
+        None => DEFAULT_NODES,
+        Some(_) => {
+            eprintln!("Error: invalid number of nodes");
+            return 2;
+        }
+    };
+    let base_port = match matches.value_of("base-port").map(str::parse::<u16>) {
+        Some(Ok(port)) => Some(port),
+        None => None,
+        Some(Err(err)) => {
+            eprintln!("Error: invalid base port: {}", err);
+            return 2;
+        }
+    };
+
+    let nodes =
+        match bootstrap_file(dir, wasm, txs).and_then(|path| plan(dir, &path, nodes, base_port)) {
+            Ok(nodes) => nodes,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                return 1;
+            }
+        };
+    crate::logger_level(node_matches.value_of("log-level").unwrap_or("info"), "");
+
+    let mut handles = Vec::with_capacity(nodes.len());
+    for (index, node) in nodes.into_iter().enumerate() {
+        let (config, keypair) = (node.config, node.keypair);
+        let summary = format!(
+            "node{}: {}, rest {}, api {}, p2p {}",
+            index,
+            keypair.public_key().to_account_id(),
+            config.rest_port,
+            config.api_port,
+            config.p2p_port
+        );
+        let (started_tx, started_rx) = mpsc::channel();
+        // The node lives within its own thread.
+        let handle = thread::spawn(move || {
+            let loader: ConfigLoader = {
+                let config = config.clone();
+                Arc::new(move || Ok(config.clone()))
+            };
+            let app = App::new(config, KeyPair::Ed25519(keypair), loader).and_then(|mut app| {
+                // An empty monitor address disables the updates.
+                app.start(Some(String::new()))?;
+                Ok(app)
+            });
+            match app {
+                Ok(mut app) => {
+                    let _ = started_tx.send(Ok(()));
+                    app.park();
+                }
+                Err(err) => {
+                    let _ = started_tx.send(Err(err.to_string()));
+                }
+            }
+        });
+        // The next nodes dial the first one as soon as they start.
+        match started_rx.recv() {
+            Ok(Ok(())) => println!("{}", summary),
+            Ok(Err(err)) => {
+                eprintln!("Error starting node{}: {}", index, err);
+                return 1;
+            }
+            Err(_) => {
+                eprintln!("Error starting node{}", index);
+                return 1;
+            }
+        }
+        handles.push(handle);
+    }
+    for handle in handles {
+        let _ = handle.join();
+    }
+    0
+}
+
+// Writes the shared bootstrap file, returns its path.
+fn bootstrap_file(dir: &Path, wasm: &Path, txs: Option<&Path>) -> Result<PathBuf, String> {
+    let buf = bootstrap::build(wasm, txs, Some(NONCE.to_owned()))?;
+    fs::create_dir_all(dir).map_err(|err| format!("{}: {}", dir.display(), err))?;
+    let path = dir.join("bootstrap.bin");
+    fs::write(&path, &buf).map_err(|err| format!("{}: {}", path.display(), err))?;
+    println!("Network name: {}", calculate_network_name(&buf));
+    Ok(path)
+}
+
+// Loads the keypair file, generated on the first run.
+fn keypair_file(path: &Path) -> Result<ed25519::KeyPair, String> {
+    let error = |err: &dyn std::fmt::Display| format!("{}: {}", path.display(), err);
+    if let Ok(bytes) = fs::read(path) {
+        return ed25519::KeyPair::from_bytes(&bytes).map_err(|err| error(&err));
+    }
+    let keypair = ed25519::KeyPair::from_random();
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(&keypair.to_bytes()))
+        .map_err(|err| error(&err))?;
+    Ok(keypair)
+}
+
+// Free local port, assigned by the system.
+fn free_port() -> Result<u16, String> {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .map_err(|err| format!("port assignment: {}", err))
+}
+
+// Nodes configurations, the first node is the P2P bootstrap peer of the others.
+fn plan(
+    dir: &Path,
+    bootstrap_path: &Path,
+    nodes: usize,
+    base_port: Option<u16>,
+) -> Result<Vec<Node>, String> {
+    let mut planned: Vec<Node> = Vec::with_capacity(nodes);
+    for index in 0..nodes {
+        let node_dir = dir.join(format!("node{}", index));
+        fs::create_dir_all(&node_dir).map_err(|err| format!("{}: {}", node_dir.display(), err))?;
+        let keypair_path = node_dir.join("node.kp");
+        let keypair = keypair_file(&keypair_path)?;
+        let p2p_keypair_path = node_dir.join("p2p.kp");
+        keypair_file(&p2p_keypair_path)?;
+
+        let mut ports = [0; NODE_PORTS as usize];
+        for (offset, port) in ports.iter_mut().enumerate() {
+            *port = match base_port {
+                Some(base) => base
+                    .checked_add(index as u16 * NODE_PORTS + offset as u16)
+                    .ok_or("ports out of range")?,
+                None => free_port()?,
+            };
+        }
+        let local = "127.0.0.1".to_string();
+        let mut config = Config {
+            keypair_path: Some(keypair_path.to_string_lossy().into_owned()),
+            p2p_keypair: Some(p2p_keypair_path.to_string_lossy().into_owned()),
+            db_path: node_dir.join("db").to_string_lossy().into_owned(),
+            bootstrap_path: bootstrap_path.to_string_lossy().into_owned(),
+            rest_addr: local.clone(),
+            rest_port: ports[0],
+            bridge_addr: local.clone(),
+            bridge_port: ports[1],
+            api_addr: local.clone(),
+            api_port: ports[2],
+            ws_addr: local.clone(),
+            ws_port: ports[3],
+            p2p_addr: local.clone(),
+            p2p_port: ports[4],
+            public_ip: Some(local.clone()),
+            monitor_file: node_dir.join("monitor.json").to_string_lossy().into_owned(),
+            telemetry: Telemetry::Off,
+            p2p_mdns: false,
+            ip_discovery: false,
+            ..Default::default()
+        };
+        if let Some(first) = planned.first() {
+            let p2p_keypair = keypair_file(Path::new(
+                first.config.p2p_keypair.as_deref().unwrap_or_default(),
+            ))?;
+            config.p2p_bootstrap_addrs = vec![format!(
+                "{}@/ip4/{}/tcp/{}",
+                p2p_keypair.public_key().to_account_id(),
+                local,
+                first.config.p2p_port
+            )];
+        }
+        planned.push(Node { config, keypair });
+    }
+    Ok(planned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn plan_nodes() {
+        let dir = TempDir::new().unwrap();
+        let bootstrap_path = dir.path().join("bootstrap.bin");
+
+        let nodes = plan(dir.path(), &bootstrap_path, 3, Some(20000)).unwrap();
+
+        assert_eq!(nodes.len(), 3);
+        assert_eq!(nodes[0].config.rest_port, 20000);
+        assert_eq!(nodes[2].config.p2p_port, 20014);
+        assert!(nodes[0].config.p2p_bootstrap_addrs.is_empty());
+        assert_ne!(nodes[1].config.db_path, nodes[2].config.db_path);
+        let p2p_keypair = keypair_file(&dir.path().join("node0").join("p2p.kp")).unwrap();
+        let addr = format!(
+            "{}@/ip4/127.0.0.1/tcp/20004",
+            p2p_keypair.public_key().to_account_id()
+        );
+        assert_eq!(nodes[2].config.p2p_bootstrap_addrs, vec![addr]);
+
+        // The keypairs are kept by the next runs.
+        let again = plan(dir.path(), &bootstrap_path, 3, None).unwrap();
+        assert_eq!(
+            again[1].keypair.public_key().to_account_id(),
+            nodes[1].keypair.public_key().to_account_id()
+        );
+    }
+}
@@ -0,0 +1,334 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! `init` subcommand: interactive first-run wizard.
+//!
+//! Asks for the network, the ports, the node keypair, the bootstrap source and
+//! the public reachability, then writes the configuration file (and the
+//! keypair file, when generated).
+
+use crate::{
+    config::{self, Severity, DEFAULT_BRIDGE_PORT, DEFAULT_CONFIG_FILE, DEFAULT_HTTP_PORT},
+    ip_discovery::DEFAULT_STUN_SERVER,
+    profile::Profile,
+    utils,
+};
+use clap::ArgMatches;
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    net::IpAddr,
+    path::Path,
+    str::FromStr,
+};
+use toml::Value;
+use trinci_core::crypto::ed25519;
+
+/// Default node keypair file.
+const DEFAULT_KEYPAIR_FILE: &str = "ed25519_keypair.bin";
+
+pub fn run(matches: &ArgMatches) -> i32 {
+    let path = matches.value_of("output").unwrap_or(DEFAULT_CONFIG_FILE);
+    if Path::new(path).exists() && !matches.is_present("force") {
+        eprintln!(
+            "File '{}' already exists, use --force to overwrite it",
+            path
+        );
+        return 1;
+    }
+
+    let stdin = io::stdin();
+    let mut wizard = Wizard {
+        input: stdin.lock(),
+        output: io::stdout(),
+    };
+    let content = match wizard.run() {
+        Ok(content) => content,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            return 1;
+        }
+    };
+    match fs::write(path, content) {
+        Ok(()) => {
+            println!("Configuration written to '{}'", path);
+            println!("Start the node with: trinci-node --config {}", path);
+            0
+        }
+        Err(err) => {
+            eprintln!("Error writing '{}': {}", path, err);
+            1
+        }
+    }
+}
+
+/// Question/answer session over a pair of streams.
+struct Wizard<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Wizard<R, W> {
+    /// Asks a question, an empty answer selects the default.
+    fn ask(&mut self, question: &str, default: &str) -> io::Result<String> {
+        if default.is_empty() {
+            write!(self.output, "{}: ", question)?;
+        } else {
+            write!(self.output, "{} [{}]: ", question, default)?;
+        }
+        self.output.flush()?;
+        let mut answer = String::new();
+        if self.input.read_line(&mut answer)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "input closed before the end of the wizard",
+            ));
+        }
+        let answer = answer.trim();
+        Ok(match answer.is_empty() {
+            true => default.to_string(),
+            false => answer.to_string(),
+        })
+    }
+
+    /// Asks until the answer is one of the choices.
+    fn choose(&mut self, question: &str, choices: &[&str], default: &str) -> io::Result<String> {
+        let question = format!("{} ({})", question, choices.join(", "));
+        loop {
+            let answer = self.ask(&question, default)?.to_lowercase();
+            if choices.contains(&answer.as_str()) {
+                return Ok(answer);
+            }
+            writeln!(self.output, "  expected one of: {}", choices.join(", "))?;
+        }
+    }
+
+    /// Asks until the answer parses.
+    fn ask_parse<T>(&mut self, question: &str, default: &str) -> io::Result<T>
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        loop {
+            match self.ask(question, default)?.parse() {
+                Ok(value) => return Ok(value),
+                Err(err) => writeln!(self.output, "  {}", err)?,
+            }
+        }
+    }
+
+    /// Runs the session, returns the configuration file content.
+    fn run(&mut self) -> io::Result<String> {
+        let mut settings: Vec<(&str, Value)> = Vec::new();
+
+        // Network.
+        let network = self.choose(
+            "Network",
+            &["dev", "testnet", "mainnet", "custom"],
+            "testnet",
+        )?;
+        let profile = network.parse::<Profile>().ok();
+        let mut defaults = config::Config::default();
+        match profile {
+            Some(profile) => {
+                profile.apply(&mut defaults);
+                settings.push(("profile", Value::String(network)));
+            }
+            None => {
+                let name = self.ask("Network name", &defaults.network)?;
+                settings.push(("network", Value::String(name)));
+            }
+        }
+
+        // Ports.
+        let rest_port: u16 = self.ask_parse("REST port", &DEFAULT_HTTP_PORT.to_string())?;
+        settings.push(("rest-port", Value::Integer(rest_port as i64)));
+        let bridge_port: u16 = self.ask_parse("Bridge port", &DEFAULT_BRIDGE_PORT.to_string())?;
+        settings.push(("bridge-port", Value::Integer(bridge_port as i64)));
+        let p2p_port: u16 =
+            self.ask_parse("P2P port (0 for random)", &defaults.p2p_port.to_string())?;
+        settings.push(("p2p-port", Value::Integer(p2p_port as i64)));
+
+        // Node keypair.
+        let keypair = self.choose("Node keypair", &["generate", "import", "none"], "generate")?;
+        match keypair.as_str() {
+            "generate" => {
+                let path = self.generate_keypair()?;
+                settings.push(("keypair-path", Value::String(path)));
+            }
+            "import" => {
+                let path = self.import_keypair()?;
+                settings.push(("keypair-path", Value::String(path)));
+            }
+            _ => writeln!(
+                self.output,
+                "  the node will use a new random keypair at every start"
+            )?,
+        }
+
+        // Bootstrap source.
+        if profile == Some(Profile::Dev) {
+            let path = self.ask("Bootstrap file", &defaults.bootstrap_path)?;
+            settings.push(("bootstrap-path", Value::String(path)));
+        } else {
+            let source = self.choose("Bootstrap source", &["file", "fetch"], "file")?;
+            let path = self.ask("Bootstrap file", &defaults.bootstrap_path)?;
+            settings.push(("bootstrap-path", Value::String(path)));
+            let fetch = source == "fetch";
+            let peers = loop {
+                let peers = self.ask("P2P bootstrap peers (comma separated)", "")?;
+                let peers: Vec<_> = peers
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|peer| !peer.is_empty())
+                    .map(|peer| Value::String(peer.to_string()))
+                    .collect();
+                if !peers.is_empty() || !fetch {
+                    break peers;
+                }
+                writeln!(
+                    self.output,
+                    "  the bootstrap file is fetched from the peers, at least one is required"
+                )?;
+            };
+            if fetch {
+                settings.push(("bootstrap-fetch", Value::Boolean(true)));
+            }
+            if !peers.is_empty() {
+                settings.push(("p2p-bootstrap-addr", Value::Array(peers)));
+            }
+
+            // Public reachability.
+            let reachability =
+                self.choose("Public reachability", &["auto", "upnp", "manual"], "auto")?;
+            match reachability.as_str() {
+                "auto" => settings.push((
+                    "public-ip-service",
+                    Value::String(DEFAULT_STUN_SERVER.to_string()),
+                )),
+                "upnp" => {
+                    settings.push(("public-ip-service", Value::String("upnp".to_string())));
+                    settings.push(("nat-fallback", Value::String("upnp".to_string())));
+                }
+                _ => {
+                    let ip: IpAddr = self.ask_parse("Public IP", "")?;
+                    settings.push(("public-ip", Value::String(ip.to_string())));
+                }
+            }
+        }
+
+        let mut content = String::from(
+            "#\n# Blockchain node configuration file, generated by `trinci-node init`.\n\
+             # See `trinci-node config init` for the full list of settings.\n#\n\n",
+        );
+        for (key, value) in settings {
+            content.push_str(&format!("{} = {}\n", key, value));
+        }
+        let issues = config::validate_content(&content)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if let Some(issue) = issues
+            .iter()
+            .find(|issue| issue.severity == Severity::Error)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                issue.to_string(),
+            ));
+        }
+        Ok(content)
+    }
+
+    /// Generates an Ed25519 keypair file, returns its path.
+    fn generate_keypair(&mut self) -> io::Result<String> {
+        loop {
+            let path = self.ask("Keypair file", DEFAULT_KEYPAIR_FILE)?;
+            let keypair = ed25519::KeyPair::from_random();
+            let mut options = fs::OpenOptions::new();
+            // Never overwrite an existing identity.
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            match options
+                .open(&path)
+                .and_then(|mut file| file.write_all(&keypair.to_bytes()))
+            {
+                Ok(()) => {
+                    writeln!(
+                        self.output,
+                        "  node account: {}",
+                        keypair.public_key().to_account_id()
+                    )?;
+                    return Ok(path);
+                }
+                Err(err) => writeln!(self.output, "  error writing '{}': {}", path, err)?,
+            }
+        }
+    }
+
+    /// Asks for an existing keypair file, returns its path.
+    fn import_keypair(&mut self) -> io::Result<String> {
+        loop {
+            let path = self.ask("Keypair file (or PKCS#11 URI)", DEFAULT_KEYPAIR_FILE)?;
+            match utils::load_signer(Some(path.clone())) {
+                Ok(signer) => {
+                    writeln!(
+                        self.output,
+                        "  node account: {}",
+                        signer.public_key().to_account_id()
+                    )?;
+                    return Ok(path);
+                }
+                Err(err) => writeln!(self.output, "  error loading '{}': {}", path, err)?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    #[test]
+    fn scripted_session() {
+        let dir = TempDir::new().unwrap();
+        let keypair = dir.path().join("node.bin");
+        let keypair = keypair.to_str().unwrap();
+        let answers = format!(
+            "mainnet\n\nabc\n8101\n\ngenerate\n{}\nfetch\n\n\n12D3KooW@/ip4/10.0.0.1/tcp/9000\nmanual\n203.0.113.10\n",
+            keypair
+        );
+        let mut wizard = Wizard {
+            input: Cursor::new(answers),
+            output: Vec::new(),
+        };
+
+        let content = wizard.run().unwrap();
+
+        let map = content.parse::<Value>().unwrap();
+        assert_eq!(map["profile"].as_str(), Some("mainnet"));
+        assert_eq!(map["rest-port"].as_integer(), Some(8000));
+        assert_eq!(map["bridge-port"].as_integer(), Some(8101));
+        assert_eq!(map["p2p-port"].as_integer(), Some(9000));
+        assert_eq!(map["keypair-path"].as_str(), Some(keypair));
+        assert_eq!(map["bootstrap-fetch"].as_bool(), Some(true));
+        assert_eq!(map["p2p-bootstrap-addr"].as_array().map(Vec::len), Some(1));
+        assert_eq!(map["public-ip"].as_str(), Some("203.0.113.10"));
+        assert!(utils::load_keypair(Some(keypair.to_string())).is_ok());
+    }
+}
@@ -0,0 +1,184 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! `join` subcommand: one-command join of an existing network.
+//!
+//! Reads the visa and the bootstrap file of a node of the network through its
+//! REST service, checks the local versions against the remote ones, writes the
+//! configuration file (network name, bootstrap file, database folder and P2P
+//! bootstrap address) and starts the node with it.
+
+use crate::{
+    config::{self, Severity, DEFAULT_CONFIG_FILE, DEFAULT_DB_PATH},
+    download, peers, version,
+};
+use clap::ArgMatches;
+use std::{env, fs, path::Path, process::Command};
+use toml::Value;
+use trinci_core::rest::service::NodeInfo;
+use version_compare::Cmp;
+
+pub fn run(matches: &ArgMatches) -> i32 {
+    let path = matches.value_of("output").unwrap_or(DEFAULT_CONFIG_FILE);
+    if Path::new(path).exists() && !matches.is_present("force") {
+        eprintln!(
+            "File '{}' already exists, use --force to overwrite it",
+            path
+        );
+        return 1;
+    }
+    let addr = matches.value_of("node-address").unwrap_or_default();
+    let content = match join(addr, matches.is_present("force")) {
+        Ok(content) => content,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            return 1;
+        }
+    };
+    if let Err(err) = fs::write(path, content) {
+        eprintln!("Error writing '{}': {}", path, err);
+        return 1;
+    }
+    println!("Configuration written to '{}'", path);
+    if matches.is_present("no-start") {
+        println!("Start the node with: trinci-node --config {}", path);
+        return 0;
+    }
+
+    println!("Starting the node");
+    let status =
+        env::current_exe().and_then(|exe| Command::new(exe).args(["--config", path]).status());
+    match status {
+        Ok(status) => status.code().unwrap_or(1),
+        Err(err) => {
+            eprintln!("Error starting the node: {}", err);
+            1
+        }
+    }
+}
+
+// Fetches the remote node visa and bootstrap, returns the configuration.
+fn join(addr: &str, force: bool) -> Result<String, String> {
+    // Same form as `bootstrap-node-address`, with or without the scheme.
+    let host = addr
+        .trim_start_matches("http://")
+        .trim_end_matches('/')
+        .to_string();
+    let visa = version::get_visa(&host).map_err(|err| format!("visa of {}: {}", host, err))?;
+    println!(
+        "Joining through node {} (node {}, core {})",
+        visa.p2p_account_id, visa.node_version.0, visa.node_version.1
+    );
+    let local = version::local();
+    for warning in version::compare(&local, &visa.node_version) {
+        println!("Warning: {}", warning);
+    }
+    // The network may already require the remote core version.
+    if version_compare::compare(&local.1, &visa.node_version.1) == Ok(Cmp::Lt) && !force {
+        return Err(format!(
+            "local core {} older than the network node {}, upgrade first or use --force",
+            local.1, visa.node_version.1
+        ));
+    }
+
+    // Named after the network once known.
+    let download_path = "join-bootstrap.bin";
+    let network = download::fetch_bootstrap(std::slice::from_ref(&host), None, download_path)
+        .map_err(|err| format!("bootstrap from {}: {}", host, err))?;
+    let bootstrap_path = format!("{}.bin", network);
+    fs::rename(download_path, &bootstrap_path)
+        .map_err(|err| format!("writing {}: {}", bootstrap_path, err))?;
+    println!(
+        "Network {}, bootstrap written to {}",
+        network, bootstrap_path
+    );
+
+    settings(&visa, &host, &network, &bootstrap_path)
+}
+
+// Configuration file joining the network of the node described by the visa.
+fn settings(
+    visa: &NodeInfo,
+    host: &str,
+    network: &str,
+    bootstrap_path: &str,
+) -> Result<String, String> {
+    // The visa carries a blank IP when the node has no `public-ip`.
+    let ip = match visa.public_ip.trim() {
+        "" => host.rsplit_once(':').map(|(ip, _)| ip).unwrap_or(host),
+        ip => ip,
+    };
+    let settings = [
+        ("network", Value::String(network.to_string())),
+        ("bootstrap-path", Value::String(bootstrap_path.to_string())),
+        (
+            "db-path",
+            Value::String(format!("{}/{}", DEFAULT_DB_PATH, network)),
+        ),
+        (
+            "p2p-bootstrap-addr",
+            Value::Array(vec![Value::String(peers::peer_address(
+                &visa.p2p_account_id,
+                ip,
+                visa.p2p_port,
+            ))]),
+        ),
+    ];
+
+    let mut content = format!(
+        "#\n# Blockchain node configuration file, generated by `trinci-node join {}`.\n\
+         # See `trinci-node config init` for the full list of settings.\n#\n\n",
+        host
+    );
+    for (key, value) in settings {
+        content.push_str(&format!("{} = {}\n", key, value));
+    }
+    let issues = config::validate_content(&content)?;
+    if let Some(issue) = issues
+        .iter()
+        .find(|issue| issue.severity == Severity::Error)
+    {
+        return Err(issue.to_string());
+    }
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_settings() {
+        let visa = NodeInfo {
+            public_ip: " ".to_string(),
+            p2p_account_id: "12D3KooWAbc".to_string(),
+            p2p_port: 9006,
+            bootstrap_url_access: String::new(),
+            bootstrap_file_path: String::new(),
+            node_version: version::local(),
+        };
+        let content = settings(&visa, "10.0.0.7:8000", "QmNet", "QmNet.bin").unwrap();
+        let map = content.parse::<Value>().unwrap();
+
+        assert_eq!(map["network"].as_str(), Some("QmNet"));
+        assert_eq!(map["db-path"].as_str(), Some("db/QmNet"));
+        assert_eq!(
+            map["p2p-bootstrap-addr"][0].as_str(),
+            Some("12D3KooWAbc@/ip4/10.0.0.7/tcp/9006")
+        );
+    }
+}
@@ -0,0 +1,93 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Node subcommands.
+//!
+//! Utilities that run in place of the node and exit.
+
+mod admin;
+mod backup;
+mod bootstrap;
+mod ceremony;
+mod chain;
+mod config;
+mod denylist;
+mod devnet;
+mod init;
+mod join;
+#[cfg(feature = "monitor")]
+mod monitor;
+mod replay;
+pub(crate) mod rest;
+mod state;
+mod tx;
+mod upgrade;
+mod visa;
+mod whoami;
+
+use clap::ArgMatches;
+
+/// Runs the subcommand found in the command line arguments, returns the
+/// process exit code.
+/// Top level arguments are passed along to let the subcommands build the
+/// node configuration.
+pub fn run(matches: &ArgMatches) -> i32 {
+    match matches.subcommand() {
+        Some(("admin", sub_matches)) => admin::run(matches, sub_matches),
+        Some(("backup", sub_matches)) => backup::run(matches, sub_matches),
+        Some(("bootstrap", sub_matches)) => bootstrap::run(matches, sub_matches),
+        Some((name @ ("block" | "receipt"), sub_matches)) => chain::run(matches, name, sub_matches),
+        Some(("config", sub_matches)) => config::run(sub_matches),
+        Some(("denylist", sub_matches)) => denylist::run(matches, sub_matches),
+        Some(("devnet", sub_matches)) => devnet::run(matches, sub_matches),
+        Some(("init", sub_matches)) => init::run(sub_matches),
+        Some(("join", sub_matches)) => join::run(sub_matches),
+        #[cfg(feature = "monitor")]
+        Some(("monitor", sub_matches)) => monitor::run(matches, sub_matches),
+        Some(("replay", sub_matches)) => replay::run(matches, sub_matches),
+        Some(("state", sub_matches)) => state::run(matches, sub_matches),
+        Some(("tx", sub_matches)) => tx::run(matches, sub_matches),
+        Some(("upgrade", sub_matches)) => upgrade::run(matches, sub_matches),
+        Some(("visa", sub_matches)) => visa::run(matches, sub_matches),
+        Some(("whoami", _)) => whoami::run(matches),
+        Some((name, _)) => {
+            eprintln!("Unknown command: {}", name);
+            2
+        }
+        None => 2,
+    }
+}
+
+// Node API address for the administration requests: the admin socket, if
+// any, being the only one serving them.
+fn admin_addr(config: &crate::config::Config) -> String {
+    match &config.admin_socket {
+        Some(path) => path.clone(),
+        None => crate::utils::host_port(&config.api_addr, config.api_port),
+    }
+}
+
+// Node configuration for the subcommands working on the node data.
+fn node_config(matches: &ArgMatches) -> Option<crate::config::Config> {
+    match crate::config::create_app_config(matches) {
+        Ok(config) => Some(config),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            None
+        }
+    }
+}
@@ -0,0 +1,115 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! `monitor` subcommand: monitor history reader.
+
+use crate::monitor::history::{History, HistoryConfig, Record};
+use clap::ArgMatches;
+
+pub fn run(matches: &ArgMatches, sub_matches: &ArgMatches) -> i32 {
+    let config = match super::node_config(matches) {
+        Some(config) => config,
+        None => return 1,
+    };
+    match sub_matches.subcommand() {
+        Some(("history", matches)) => {
+            if config.monitor_history.is_empty() {
+                eprintln!("Monitor history disabled");
+                return 1;
+            }
+            let history = History::new(HistoryConfig {
+                path: config.monitor_history.into(),
+                max_size: config.monitor_history_max_size,
+                files: config.monitor_history_files,
+            });
+            history_cmd(&history, matches)
+        }
+        _ => 2,
+    }
+}
+
+fn history_cmd(history: &History, matches: &ArgMatches) -> i32 {
+    let since = match matches.value_of("since").map(str::parse::<u64>) {
+        Some(Ok(since)) => since,
+        Some(Err(_)) => {
+            eprintln!("Error: invalid value for --since");
+            return 1;
+        }
+        None => 0,
+    };
+    let last = match matches.value_of("last").map(str::parse::<usize>) {
+        Some(Ok(last)) => Some(last),
+        Some(Err(_)) => {
+            eprintln!("Error: invalid value for --last");
+            return 1;
+        }
+        None => None,
+    };
+    let mut records = match history.records() {
+        Ok(records) => records,
+        Err(err) => {
+            eprintln!("Error reading the monitor history: {}", err);
+            return 1;
+        }
+    };
+    records.retain(|record| record.time >= since);
+    if let Some(last) = last {
+        records.drain(..records.len().saturating_sub(last));
+    }
+
+    if matches.is_present("json") {
+        for record in &records {
+            println!(
+                "{}",
+                serde_json::json!({ "time": record.time, "status": record.status })
+            );
+        }
+        return 0;
+    }
+    println!(
+        "{:>10}  {:>10}  {:>6}  {:>8}  {:>10}",
+        "time", "height", "pool", "tps", "txs"
+    );
+    for record in &records {
+        print_record(record);
+    }
+    0
+}
+
+fn print_record(record: &Record) {
+    let value = |pointer: &str| {
+        record
+            .status
+            .pointer(pointer)
+            .map(|value| value.to_string())
+            .unwrap_or_else(|| "-".to_string())
+    };
+    let tps = record
+        .status
+        .pointer("/data/throughput/tps")
+        .and_then(|tps| tps.as_f64())
+        .map(|tps| format!("{:.2}", tps))
+        .unwrap_or_else(|| "-".to_string());
+    println!(
+        "{:>10}  {:>10}  {:>6}  {:>8}  {:>10}",
+        record.time,
+        value("/data/last_block/block/data/height"),
+        value("/data/unconfirmed_pool/size"),
+        tps,
+        value("/data/throughput/totals/txs"),
+    );
+}
@@ -0,0 +1,440 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! `replay` subcommand: stored chain audit.
+//!
+//! Walks the stored blocks in a height range and reports the first
+//! divergence between a block and the data it commits to (see the
+//! `integrity` module), then executes the transactions again.
+//!
+//! The core does not keep the state of past heights: the blocks are executed
+//! from the genesis on a scratch database, seeded with the service contract of
+//! the bootstrap file, each transaction on its own fork through a local wasm
+//! machine. The transactions outcome is checked against the stored receipts
+//! and the state hash against the block, from the `--from` height on.
+//! The database is opened directly, the node must be stopped.
+
+use crate::api::client;
+use crate::bootstrap_reader::BootstrapReader;
+use crate::config::SERVICE_ACCOUNT_ID;
+use crate::integrity;
+use crate::lock::DbLock;
+use crate::service_contract::BurnFuelArgs;
+use clap::ArgMatches;
+use std::sync::Arc;
+use tempfile::TempDir;
+#[cfg(feature = "indexer")]
+use trinci_core::blockchain::indexer::StoreAssetDb;
+use trinci_core::{
+    base::{
+        serialize::{rmp_deserialize, rmp_serialize},
+        BlockchainSettings,
+    },
+    crypto::{drand::SeedSource, Hash, HashAlgorithm, Hashable},
+    db::{Db, DbFork, RocksDb, RocksDbFork},
+    wm::{Wm, WmLocal},
+    Account, Block, Transaction, TransactionData,
+};
+
+/// Contracts kept compiled by the replay wasm machine.
+const REPLAY_WM_CACHE: usize = 16;
+
+/// Seed hashes before the first block, as used by the node at startup.
+const GENESIS_SEED_HASH: &str =
+    "1220d4ff2e94b9ba93c2bd4f5e383eeb5c5022fd4a223285629cfe2c86ed4886f730";
+
+pub fn run(matches: &ArgMatches, sub_matches: &ArgMatches) -> i32 {
+    let config = match super::node_config(matches) {
+        Some(config) => config,
+        None => return 1,
+    };
+    let addr = super::admin_addr(&config);
+    if client::request(&addr, "GET", "/admin/node", None).is_ok() {
+        eprintln!("Error: the node is running, stop it first");
+        return 1;
+    }
+
+    let mut heights = [("from", 0), ("to", u64::MAX)];
+    for (name, height) in heights.iter_mut() {
+        match sub_matches.value_of(*name).map(str::parse::<u64>) {
+            Some(Ok(value)) => *height = value,
+            Some(Err(_)) => {
+                eprintln!("Error: invalid value for --{}", name);
+                return 1;
+            }
+            None => (),
+        }
+    }
+    let [(_, from), (_, to)] = heights;
+
+    let _lock = match DbLock::acquire(&config.db_path) {
+        Ok(lock) => lock,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            return 1;
+        }
+    };
+    let mut db = RocksDb::new(&config.db_path);
+    let res = integrity::verify_range(&mut db, from, to, true).and_then(|(from, to)| {
+        execute_range(&db, &config.bootstrap_path, from, to).map(|_| (from, to))
+    });
+    match res {
+        Ok((from, to)) => {
+            println!("Blocks {} to {} verified and executed again", from, to);
+            0
+        }
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            1
+        }
+    }
+}
+
+/// Database holding the state rebuilt by the blocks execution, removed when
+/// dropped.
+pub struct Scratch {
+    pub db: RocksDb,
+    _dir: TempDir,
+}
+
+/// Executes the blocks from the genesis to `to` on a scratch database,
+/// returns it or the first divergence at `from` or above.
+pub fn execute_range(
+    db: &RocksDb,
+    bootstrap_path: &str,
+    from: u64,
+    to: u64,
+) -> Result<Scratch, String> {
+    let dir = tempfile::tempdir().map_err(|err| format!("scratch database: {}", err))?;
+    let mut scratch = RocksDb::new(dir.path());
+    let bin = BootstrapReader::open(bootstrap_path)?.take_bin();
+    let mut fork = scratch.fork_create();
+    let hash = Hash::from_data(HashAlgorithm::Sha256, &bin);
+    fork.store_account(Account::new(SERVICE_ACCOUNT_ID, Some(hash)));
+    let key = format!("contracts:code:{}", hex::encode(hash));
+    fork.store_account_data(SERVICE_ACCOUNT_ID, &key, bin);
+    scratch.fork_merge(fork).map_err(|err| err.to_string())?;
+
+    let mut wm = WmLocal::new(REPLAY_WM_CACHE);
+    let genesis = Hash::from_hex(GENESIS_SEED_HASH).map_err(|err| err.to_string())?;
+    let mut prev = (genesis, genesis, genesis);
+    for height in 0..=to {
+        let block = db
+            .load_block(height)
+            .ok_or_else(|| format!("block {}: missing", height))?;
+        execute_block(db, &mut scratch, &mut wm, &block, prev, height >= from)
+            .map_err(|err| format!("block {}: {}", height, err))?;
+        prev = (
+            block.data.primary_hash(),
+            block.data.txs_hash,
+            block.data.rxs_hash,
+        );
+    }
+    Ok(Scratch {
+        db: scratch,
+        _dir: dir,
+    })
+}
+
+// Executes the block transactions on the scratch database, checking them
+// against the stored receipts and block only if `check` is set.
+fn execute_block(
+    db: &RocksDb,
+    scratch: &mut RocksDb,
+    wm: &mut WmLocal,
+    block: &Block,
+    (prev_hash, txs_hash, rxs_hash): (Hash, Hash, Hash),
+    check: bool,
+) -> Result<(), String> {
+    let data = &block.data;
+    let hashes = db.load_transactions_hashes(data.height).unwrap_or_default();
+    for hash in hashes {
+        let hex = hex::encode(hash.as_bytes());
+        let tx = db
+            .load_transaction(&hash)
+            .ok_or_else(|| format!("transaction {} missing", hex))?;
+        let rx = db
+            .load_receipt(&hash)
+            .ok_or_else(|| format!("receipt {} missing", hex))?;
+        let calls = calls(&tx);
+        let root = calls[0].1;
+        let network = root.get_network().unwrap_or_default().to_owned();
+        let seed = Arc::new(SeedSource::new(
+            network.clone(),
+            vec![0; 8],
+            prev_hash,
+            txs_hash,
+            rxs_hash,
+        ));
+
+        // A failed transaction leaves no trace apart from the burned fuel.
+        let mut fork = scratch.fork_create();
+        let mut burned_fuel = 0;
+        let mut res = Ok(Vec::new());
+        for (caller, call_data) in &calls {
+            let (fuel, call_res) = execute(
+                wm,
+                &mut fork,
+                caller,
+                call_data,
+                seed.clone(),
+                data.timestamp,
+            );
+            burned_fuel += fuel;
+            res = call_res;
+            if res.is_err() {
+                break;
+            }
+        }
+        if res.is_ok() {
+            scratch.fork_merge(fork).map_err(|err| err.to_string())?;
+        }
+        burn(
+            wm,
+            scratch,
+            &network,
+            &calls[0].0,
+            burned_fuel,
+            root.get_fuel_limit(),
+            seed,
+            data.timestamp,
+        )?;
+
+        if !check {
+            continue;
+        }
+        let divergence = match &res {
+            Ok(_) if !rx.success => Some("stored as failed, executed successfully".to_string()),
+            Err(err) if rx.success => Some(format!("stored as successful, executed with {}", err)),
+            // Bulk receipts collect the results of every node.
+            Ok(returns) if calls.len() == 1 && *returns != rx.returns => {
+                Some("stored and executed results differ".to_string())
+            }
+            _ => None,
+        };
+        if let Some(divergence) = divergence {
+            return Err(format!("transaction {}: {}", hex, divergence));
+        }
+    }
+
+    let state_hash = scratch.fork_create().state_hash("");
+    if check && state_hash != data.state_hash {
+        return Err(format!(
+            "state hash {} after execution, {} stored",
+            hex::encode(state_hash.as_bytes()),
+            hex::encode(data.state_hash.as_bytes())
+        ));
+    }
+    Ok(())
+}
+
+// Calls of a transaction with their caller: the only one of a unit
+// transaction, the root and the nodes of a bulk one.
+fn calls(tx: &Transaction) -> Vec<(String, &TransactionData)> {
+    let caller = tx.get_caller().to_account_id();
+    match tx {
+        Transaction::UnitTransaction(tx) => vec![(caller, &tx.data)],
+        Transaction::BulkTransaction(tx) => match &tx.data {
+            TransactionData::BulkV1(bulk) => {
+                let mut calls = vec![(caller, &bulk.txs.root.data)];
+                for node in bulk.txs.nodes.iter().flatten() {
+                    calls.push((node.data.get_caller().to_account_id(), &node.data));
+                }
+                calls
+            }
+            data => vec![(caller, data)],
+        },
+    }
+}
+
+// Executes a call on the fork: the contract of the transaction is bound to
+// the target account if it has none, or replaces it if the service contract
+// allows the update.
+fn execute(
+    wm: &mut WmLocal,
+    fork: &mut RocksDbFork,
+    caller: &str,
+    data: &TransactionData,
+    seed: Arc<SeedSource>,
+    timestamp: u64,
+) -> (u64, Result<Vec<u8>, String>) {
+    let target = match data.get_account() {
+        Ok(account) => account,
+        Err(err) => return (0, Err(err.to_string_full())),
+    };
+    let mut account = fork
+        .load_account(target)
+        .unwrap_or_else(|| Account::new(target, None));
+    let contract = match (
+        data.get_contract().ok().copied().flatten(),
+        account.contract,
+    ) {
+        (Some(hash), Some(current)) if hash == current => hash,
+        (Some(hash), Some(current)) => {
+            if !wm.contract_updatable(fork, hash, current, seed.clone(), timestamp) {
+                return (0, Err("contract update refused".to_string()));
+            }
+            account.contract = Some(hash);
+            fork.store_account(account);
+            hash
+        }
+        (Some(hash), None) => {
+            account.contract = Some(hash);
+            fork.store_account(account);
+            hash
+        }
+        (None, Some(current)) => current,
+        (None, None) => return (0, Err("smart contract not found".to_string())),
+    };
+    let (fuel, res) = wm.call(
+        fork,
+        0,
+        data.get_network().unwrap_or_default(),
+        caller,
+        target,
+        caller,
+        contract,
+        data.get_method().unwrap_or_default(),
+        data.get_args().unwrap_or_default(),
+        seed,
+        &mut Vec::new(),
+        #[cfg(feature = "indexer")]
+        &mut Vec::<StoreAssetDb>::new(),
+        data.get_fuel_limit(),
+        timestamp,
+    );
+    (fuel, res.map_err(|err| err.to_string_full()))
+}
+
+// Burns the fuel spent by a transaction calling the service contract method
+// of the blockchain settings, if any.
+#[allow(clippy::too_many_arguments)]
+fn burn(
+    wm: &mut WmLocal,
+    scratch: &mut RocksDb,
+    network: &str,
+    caller: &str,
+    fuel_to_burn: u64,
+    fuel_limit: u64,
+    seed: Arc<SeedSource>,
+    timestamp: u64,
+) -> Result<(), String> {
+    let method = scratch
+        .load_configuration("blockchain:settings")
+        .and_then(|buf| rmp_deserialize::<BlockchainSettings>(&buf).ok())
+        .map(|settings| settings.burning_fuel_method)
+        .unwrap_or_default();
+    let contract = scratch
+        .load_account(SERVICE_ACCOUNT_ID)
+        .and_then(|account| account.contract);
+    let contract = match contract {
+        Some(contract) if !method.is_empty() => contract,
+        _ => return Ok(()),
+    };
+    let args = rmp_serialize(&BurnFuelArgs {
+        account: caller,
+        fuel_to_burn,
+        fuel_limit,
+    })
+    .map_err(|err| err.to_string())?;
+    let mut fork = scratch.fork_create();
+    let (_, res) = wm.call(
+        &mut fork,
+        0,
+        network,
+        SERVICE_ACCOUNT_ID,
+        SERVICE_ACCOUNT_ID,
+        SERVICE_ACCOUNT_ID,
+        contract,
+        &method,
+        &args,
+        seed,
+        &mut Vec::new(),
+        #[cfg(feature = "indexer")]
+        &mut Vec::<StoreAssetDb>::new(),
+        fuel_limit,
+        timestamp,
+    );
+    if res.is_ok() {
+        scratch.fork_merge(fork).map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use trinci_core::{
+        crypto::{ed25519::KeyPair as Ed25519KeyPair, KeyPair},
+        SignedTransaction, TransactionDataV1,
+    };
+
+    fn transfer(contract: Option<Hash>) -> Transaction {
+        let keypair = KeyPair::Ed25519(Ed25519KeyPair::from_random());
+        Transaction::UnitTransaction(SignedTransaction {
+            data: TransactionData::V1(TransactionDataV1 {
+                account: "alice".to_string(),
+                fuel_limit: 1000,
+                nonce: vec![1],
+                network: "skynet".to_string(),
+                contract,
+                method: "transfer".to_string(),
+                caller: keypair.public_key(),
+                args: Vec::new(),
+            }),
+            signature: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn execute_without_contract() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = RocksDb::new(dir.path());
+        let mut fork = db.fork_create();
+        let mut wm = WmLocal::new(1);
+        let genesis = Hash::from_hex(GENESIS_SEED_HASH).unwrap();
+        let seed = Arc::new(SeedSource::new(
+            "skynet".to_string(),
+            vec![0; 8],
+            genesis,
+            genesis,
+            genesis,
+        ));
+
+        let tx = transfer(None);
+        let calls = calls(&tx);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].0, tx.get_caller().to_account_id());
+        let (fuel, res) = execute(&mut wm, &mut fork, &calls[0].0, calls[0].1, seed, 0);
+
+        assert_eq!(fuel, 0);
+        assert_eq!(res, Err("smart contract not found".to_string()));
+        assert!(fork.load_account("alice").is_none());
+    }
+
+    #[test]
+    fn execute_range_empty_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = RocksDb::new(dir.path());
+        let bootstrap = Path::new(env!("CARGO_MANIFEST_DIR")).join("data/offline-bootstrap.bin");
+
+        let res = execute_range(&db, &bootstrap.to_string_lossy(), 0, 0);
+
+        assert_eq!(res.err().unwrap(), "block 0: missing");
+    }
+}
@@ -0,0 +1,67 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Core REST client, used by the subcommands querying the chain.
+//!
+//! Messages are exchanged msgpack encoded through the `/api/v1/message`
+//! route of a running node.
+
+use crate::api::client;
+use crate::config::Config;
+use crate::utils;
+use clap::ArgMatches;
+use trinci_core::{
+    base::serialize::{rmp_deserialize, rmp_serialize},
+    Message,
+};
+
+/// Node REST address (host:port): the `--node` argument or the address the
+/// local node listens on.
+pub fn node_addr(config: &Config, sub_matches: &ArgMatches) -> String {
+    match sub_matches.value_of("node") {
+        Some(addr) => addr.to_owned(),
+        None => utils::host_port(utils::local_host(&config.rest_addr), config.rest_port),
+    }
+}
+
+/// Sends a message to the node, returns its answer.
+/// Core exceptions are returned as errors.
+pub fn request(addr: &str, msg: Message) -> Result<Message, String> {
+    let buf = rmp_serialize(&msg).map_err(|err| err.to_string())?;
+    let (status, body) = client::request(addr, "POST", "/api/v1/message", Some(&buf))
+        .map_err(|err| format!("node {} not reachable: {}", addr, err))?;
+    if status != 200 {
+        return Err(format!(
+            "node answered {}: {}",
+            status,
+            String::from_utf8_lossy(&body)
+        ));
+    }
+    match rmp_deserialize::<Message>(&body) {
+        Ok(Message::Exception(err)) => Err(err.to_string()),
+        Ok(msg) => Ok(msg),
+        Err(err) => Err(format!("unexpected answer: {}", err)),
+    }
+}
+
+/// Msgpack encoded data as JSON, hex string if not decodable.
+pub fn msgpack_to_json(buf: &[u8]) -> serde_json::Value {
+    match rmp_deserialize::<serde_json::Value>(buf) {
+        Ok(value) if !buf.is_empty() => value,
+        _ => serde_json::Value::String(hex::encode(buf)),
+    }
+}
@@ -0,0 +1,468 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! `state export` subcommand: accounts state dump for analytics and audits.
+//!
+//! The `jsonl` format writes one JSON object per line: a `header` with the
+//! block the state belongs to, then for each account an `account` line
+//! followed by its `data` entries, values hex encoded.
+//! The `parquet` format (`parquet` feature) writes one row per account and
+//! per data entry, in row groups of `ROW_GROUP_SIZE` rows; the block is in the
+//! file metadata.
+//!
+//! The core keeps the current state only: the state at a past height is
+//! rebuilt executing the blocks again from the genesis, as `replay` does. The
+//! core has no accounts index either: the accounts are collected walking the
+//! whole chain (transactions targets and callers, events emitters, assets),
+//! which requires the full history of an archive node. Accounts only touched
+//! by nested contract calls without events are missed.
+//! The database is opened directly, the node must be stopped.
+
+use super::replay;
+use crate::api::client;
+use crate::config::SERVICE_ACCOUNT_ID;
+use crate::lock::DbLock;
+use crate::state_diff;
+use clap::ArgMatches;
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+};
+use trinci_core::{
+    crypto::Hashable,
+    db::{Db, RocksDb},
+    Account, Block,
+};
+
+/// Rows written at once in a parquet row group.
+#[cfg(feature = "parquet")]
+const ROW_GROUP_SIZE: usize = 65536;
+
+/// Export file format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Jsonl,
+    Parquet,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "jsonl" => Ok(Format::Jsonl),
+            "parquet" if cfg!(feature = "parquet") => Ok(Format::Parquet),
+            "parquet" => Err("the parquet format requires the `parquet` feature".to_string()),
+            _ => Err(format!(
+                "invalid format `{}` (expected jsonl or parquet)",
+                value
+            )),
+        }
+    }
+}
+
+pub fn run(matches: &ArgMatches, sub_matches: &ArgMatches) -> i32 {
+    let args = match sub_matches.subcommand() {
+        Some(("export", export)) => export,
+        _ => return 2,
+    };
+    let config = match super::node_config(matches) {
+        Some(config) => config,
+        None => return 1,
+    };
+    let addr = super::admin_addr(&config);
+    if client::request(&addr, "GET", "/admin/node", None).is_ok() {
+        eprintln!("Error: the node is running, stop it first");
+        return 1;
+    }
+    let format = match args.value_of("format").unwrap_or("jsonl").parse() {
+        Ok(format) => format,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            return 1;
+        }
+    };
+    let at = match args.value_of("at").map(str::parse::<u64>) {
+        Some(Ok(height)) => Some(height),
+        Some(Err(_)) => {
+            eprintln!("Error: invalid value for --at");
+            return 1;
+        }
+        None => None,
+    };
+    let output = match (args.value_of("output"), format) {
+        (Some(output), _) => output,
+        (None, Format::Jsonl) => "state.jsonl",
+        (None, Format::Parquet) => "state.parquet",
+    };
+
+    let _lock = match DbLock::acquire(&config.db_path) {
+        Ok(lock) => lock,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            return 1;
+        }
+    };
+    let db = RocksDb::new(&config.db_path);
+    match export_at(&db, &config.bootstrap_path, at, output, format) {
+        Ok((height, accounts, entries)) => {
+            println!(
+                "State at block {} exported to {}: {} accounts, {} data entries",
+                height, output, accounts, entries
+            );
+            0
+        }
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            1
+        }
+    }
+}
+
+// Exports the state at the `at` height, the last one if not given.
+fn export_at(
+    db: &RocksDb,
+    bootstrap_path: &str,
+    at: Option<u64>,
+    output: &str,
+    format: Format,
+) -> Result<(u64, usize, usize), String> {
+    let last = db.load_block(u64::MAX).ok_or("empty database")?;
+    let block = match at {
+        Some(height) if height > last.data.height => {
+            return Err(format!("last block is {}", last.data.height))
+        }
+        Some(height) => db
+            .load_block(height)
+            .ok_or_else(|| format!("block {} missing", height))?,
+        None => last,
+    };
+    if block.data.height == last.data.height {
+        return export(db, db, &block, output, format);
+    }
+    let height = block.data.height;
+    println!("Executing the blocks 0 to {} again", height);
+    let scratch = replay::execute_range(db, bootstrap_path, 0, height)?;
+    export(db, &scratch.db, &block, output, format)
+}
+
+// Accounts found in the chain up to `height`, the assets are read from the
+// state.
+fn collect_accounts(
+    db: &RocksDb,
+    state: &RocksDb,
+    height: u64,
+) -> Result<BTreeSet<String>, String> {
+    let mut accounts = BTreeSet::from([SERVICE_ACCOUNT_ID.to_string()]);
+    for height in 0..=height {
+        let hashes = db.load_transactions_hashes(height).ok_or_else(|| {
+            format!(
+                "block {} transactions not stored, an archive node is required",
+                height
+            )
+        })?;
+        accounts.extend(state_diff::block_accounts(db, &hashes));
+    }
+    // Asset accounts, their assets may be held by accounts not found above.
+    let assets: Vec<String> = accounts
+        .iter()
+        .filter_map(|id| state.load_account(id))
+        .flat_map(|account| account.assets.into_keys())
+        .collect();
+    accounts.extend(assets);
+    Ok(accounts)
+}
+
+fn account_line(account: &Account) -> Value {
+    json!({
+        "type": "account",
+        "id": account.id,
+        "assets": assets_json(account),
+        "contract": account.contract.map(|hash| hex::encode(hash.as_bytes())),
+        "data_hash": account.data_hash.map(|hash| hex::encode(hash.as_bytes())),
+    })
+}
+
+fn assets_json(account: &Account) -> Value {
+    account
+        .assets
+        .iter()
+        .map(|(asset, value)| (asset.clone(), Value::String(hex::encode(value))))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// Export file writer.
+trait StateWriter {
+    fn account(&mut self, account: &Account) -> io::Result<()>;
+    fn data(&mut self, account: &str, key: &str, value: Vec<u8>) -> io::Result<()>;
+    /// Writes the buffered content and syncs the file.
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+struct JsonlWriter(BufWriter<File>);
+
+impl JsonlWriter {
+    fn create(file: File, block: &Block) -> io::Result<Self> {
+        let mut writer = JsonlWriter(BufWriter::new(file));
+        writer.line(&json!({
+            "type": "header",
+            "height": block.data.height,
+            "block_hash": hex::encode(block.data.primary_hash().as_bytes()),
+            "state_hash": hex::encode(block.data.state_hash.as_bytes()),
+        }))?;
+        Ok(writer)
+    }
+
+    fn line(&mut self, line: &Value) -> io::Result<()> {
+        serde_json::to_writer(&mut self.0, line)?;
+        self.0.write_all(b"\n")
+    }
+}
+
+impl StateWriter for JsonlWriter {
+    fn account(&mut self, account: &Account) -> io::Result<()> {
+        self.line(&account_line(account))
+    }
+
+    fn data(&mut self, account: &str, key: &str, value: Vec<u8>) -> io::Result<()> {
+        self.line(&json!({
+            "type": "data",
+            "account": account,
+            "key": key,
+            "value": hex::encode(value),
+        }))
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.0.into_inner()?.sync_all()
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_writer {
+    use super::{assets_json, StateWriter, ROW_GROUP_SIZE};
+    use parquet::{
+        data_type::{ByteArray, ByteArrayType},
+        errors::ParquetError,
+        file::{metadata::KeyValue, properties::WriterProperties, writer::SerializedFileWriter},
+        schema::parser::parse_message_type,
+    };
+    use std::{fs::File, io, sync::Arc};
+    use trinci_core::{crypto::Hashable, Account, Block};
+
+    const SCHEMA: &str = "message state {
+        REQUIRED BINARY type (UTF8);
+        REQUIRED BINARY account (UTF8);
+        OPTIONAL BINARY key (UTF8);
+        OPTIONAL BINARY value;
+        OPTIONAL BINARY contract (UTF8);
+        OPTIONAL BINARY assets (UTF8);
+        OPTIONAL BINARY data_hash (UTF8);
+    }";
+
+    /// Number of columns of the schema, the first two are required.
+    const COLUMNS: usize = 7;
+
+    fn io_error(err: ParquetError) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, err.to_string())
+    }
+
+    /// Account or data entry row, a value per column.
+    type Row = [Option<ByteArray>; COLUMNS];
+
+    pub struct ParquetWriter {
+        writer: SerializedFileWriter<File>,
+        file: File,
+        rows: Vec<Row>,
+    }
+
+    impl ParquetWriter {
+        pub fn create(file: File, block: &Block) -> io::Result<Self> {
+            let schema = Arc::new(parse_message_type(SCHEMA).map_err(io_error)?);
+            let metadata = vec![
+                KeyValue::new("height".to_string(), block.data.height.to_string()),
+                KeyValue::new(
+                    "block_hash".to_string(),
+                    hex::encode(block.data.primary_hash().as_bytes()),
+                ),
+                KeyValue::new(
+                    "state_hash".to_string(),
+                    hex::encode(block.data.state_hash.as_bytes()),
+                ),
+            ];
+            let props = WriterProperties::builder()
+                .set_key_value_metadata(Some(metadata))
+                .build();
+            let writer = SerializedFileWriter::new(file.try_clone()?, schema, Arc::new(props))
+                .map_err(io_error)?;
+            Ok(ParquetWriter {
+                writer,
+                file,
+                rows: Vec::new(),
+            })
+        }
+
+        fn push(&mut self, row: Row) -> io::Result<()> {
+            self.rows.push(row);
+            if self.rows.len() >= ROW_GROUP_SIZE {
+                self.flush().map_err(io_error)?;
+            }
+            Ok(())
+        }
+
+        // Writes the buffered rows as a row group.
+        fn flush(&mut self) -> Result<(), ParquetError> {
+            if self.rows.is_empty() {
+                return Ok(());
+            }
+            let rows = std::mem::take(&mut self.rows);
+            let mut group = self.writer.next_row_group()?;
+            let mut index = 0;
+            while let Some(mut column) = group.next_column()? {
+                let values: Vec<ByteArray> =
+                    rows.iter().filter_map(|row| row[index].clone()).collect();
+                let levels: Vec<i16> = rows.iter().map(|row| row[index].is_some() as i16).collect();
+                let levels = (index >= 2).then_some(levels.as_slice());
+                column
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, levels, None)?;
+                column.close()?;
+                index += 1;
+            }
+            group.close()?;
+            Ok(())
+        }
+    }
+
+    impl StateWriter for ParquetWriter {
+        fn account(&mut self, account: &Account) -> io::Result<()> {
+            let hash = |hash: Option<trinci_core::Hash>| {
+                hash.map(|hash| ByteArray::from(hex::encode(hash.as_bytes()).as_str()))
+            };
+            self.push([
+                Some(ByteArray::from("account")),
+                Some(ByteArray::from(account.id.as_str())),
+                None,
+                None,
+                hash(account.contract),
+                Some(ByteArray::from(assets_json(account).to_string().as_str())),
+                hash(account.data_hash),
+            ])
+        }
+
+        fn data(&mut self, account: &str, key: &str, value: Vec<u8>) -> io::Result<()> {
+            self.push([
+                Some(ByteArray::from("data")),
+                Some(ByteArray::from(account)),
+                Some(ByteArray::from(key)),
+                Some(ByteArray::from(value)),
+                None,
+                None,
+                None,
+            ])
+        }
+
+        fn finish(mut self: Box<Self>) -> io::Result<()> {
+            self.flush().map_err(io_error)?;
+            let ParquetWriter { writer, file, .. } = *self;
+            writer.close().map_err(io_error)?;
+            file.sync_all()
+        }
+    }
+}
+
+/// Exports the state read from `state` at the given block to `output`,
+/// returns the block height and the number of accounts and data entries
+/// written.
+fn export(
+    db: &RocksDb,
+    state: &RocksDb,
+    block: &Block,
+    output: &str,
+    format: Format,
+) -> Result<(u64, usize, usize), String> {
+    let height = block.data.height;
+    let accounts = collect_accounts(db, state, height)?;
+
+    // Written aside and renamed once complete.
+    let part = format!("{}.part", output);
+    let io_err = |err: io::Error| format!("{}: {}", part, err);
+    let file = File::create(&part).map_err(io_err)?;
+    let mut writer: Box<dyn StateWriter> = match format {
+        Format::Jsonl => Box::new(JsonlWriter::create(file, block).map_err(io_err)?),
+        #[cfg(feature = "parquet")]
+        Format::Parquet => {
+            Box::new(parquet_writer::ParquetWriter::create(file, block).map_err(io_err)?)
+        }
+        #[cfg(not(feature = "parquet"))]
+        Format::Parquet => unreachable!("parsed only with the parquet feature"),
+    };
+
+    let (mut count, mut entries) = (0, 0);
+    for id in &accounts {
+        let account = match state.load_account(id) {
+            Some(account) => account,
+            None => continue,
+        };
+        writer.account(&account).map_err(io_err)?;
+        count += 1;
+        let mut keys = state.load_account_keys(id);
+        keys.sort();
+        for key in keys {
+            if let Some(value) = state.load_account_data(id, &key) {
+                writer.data(id, &key, value).map_err(io_err)?;
+                entries += 1;
+            }
+        }
+    }
+    writer.finish().map_err(io_err)?;
+    fs::rename(&part, output).map_err(|err| format!("{}: {}", output, err))?;
+    Ok((height, count, entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trinci_core::{Hash, HashAlgorithm};
+
+    #[test]
+    fn account_export_line() {
+        let mut account = Account::new("alice", None);
+        account.assets.insert("TRINCI".to_string(), vec![0x0a]);
+        account.data_hash = Some(Hash::from_data(HashAlgorithm::Sha256, b"data"));
+
+        let line = account_line(&account);
+
+        assert_eq!(line["id"], "alice");
+        assert_eq!(line["assets"]["TRINCI"], "0a");
+        assert!(line["contract"].is_null());
+        assert_eq!(line["data_hash"].as_str().unwrap().len(), 68);
+    }
+
+    #[test]
+    fn export_format() {
+        assert_eq!("jsonl".parse(), Ok(Format::Jsonl));
+        assert_eq!(
+            "parquet".parse::<Format>().is_ok(),
+            cfg!(feature = "parquet")
+        );
+        assert!("csv".parse::<Format>().is_err());
+    }
+}
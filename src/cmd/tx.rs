@@ -0,0 +1,144 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! `tx` subcommand: transactions submission.
+//!
+//! The signed transaction is sent to a running node, by default the local
+//! one, and its receipt is awaited. Files with the `.json` extension are
+//! parsed as JSON, any other as msgpack.
+
+use super::rest;
+use clap::ArgMatches;
+use serde_json::json;
+use std::{
+    fs,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+use trinci_core::{base::serialize::rmp_deserialize, Hash, Message, Receipt, Transaction};
+
+/// Receipt polling period.
+const POLL_PERIOD: Duration = Duration::from_secs(1);
+
+/// Default receipt wait, in seconds.
+const DEFAULT_TIMEOUT: u64 = 60;
+
+pub fn run(matches: &ArgMatches, sub_matches: &ArgMatches) -> i32 {
+    let config = match super::node_config(matches) {
+        Some(config) => config,
+        None => return 1,
+    };
+    match sub_matches.subcommand() {
+        Some(("submit", matches)) => {
+            let addr = rest::node_addr(&config, matches);
+            let file = matches.value_of("file").unwrap_or_default();
+            let timeout = match matches.value_of("timeout").map(str::parse::<u64>) {
+                Some(Ok(timeout)) => timeout,
+                Some(Err(_)) => {
+                    eprintln!("Error: invalid value for --timeout");
+                    return 1;
+                }
+                None => DEFAULT_TIMEOUT,
+            };
+            let wait = (!matches.is_present("no-wait")).then(|| Duration::from_secs(timeout));
+            match submit(&addr, Path::new(file), wait) {
+                Ok(true) => 0,
+                Ok(false) => 1,
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    1
+                }
+            }
+        }
+        _ => 2,
+    }
+}
+
+/// Loads a signed transaction, JSON or msgpack by file extension.
+pub fn load(path: &Path) -> Result<Transaction, String> {
+    let buf = fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let tx = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::from_slice(&buf).map_err(|err| err.to_string()),
+        _ => rmp_deserialize(&buf).map_err(|err| err.to_string()),
+    };
+    tx.map_err(|err| format!("{}: invalid transaction: {}", path.display(), err))
+}
+
+/// Receipt as JSON, with the decoded contract result.
+pub fn receipt_json(hash: &Hash, rx: &Receipt) -> serde_json::Value {
+    json!({
+        "hash": hex::encode(hash.as_bytes()),
+        "height": rx.height,
+        "index": rx.index,
+        "burned_fuel": rx.burned_fuel,
+        "success": rx.success,
+        "returns": rest::msgpack_to_json(&rx.returns),
+        "events": rx.events.as_ref().map(|events| {
+            events
+                .iter()
+                .map(|event| {
+                    json!({
+                        "emitter_account": event.emitter_account,
+                        "event_name": event.event_name,
+                        "event_data": rest::msgpack_to_json(&event.event_data),
+                    })
+                })
+                .collect::<Vec<_>>()
+        }),
+    })
+}
+
+// Submits the transaction, waits for the receipt if `wait` is set.
+// Returns the transaction outcome, `true` if not awaited.
+fn submit(addr: &str, path: &Path, wait: Option<Duration>) -> Result<bool, String> {
+    let tx = load(path)?;
+    let hash = match rest::request(addr, Message::PutTransactionRequest { confirm: true, tx })? {
+        Message::PutTransactionResponse { hash } => hash,
+        msg => return Err(format!("unexpected answer: {:?}", msg)),
+    };
+    println!("Transaction {} submitted", hex::encode(hash.as_bytes()));
+    let timeout = match wait {
+        Some(timeout) => timeout,
+        None => return Ok(true),
+    };
+
+    let start = Instant::now();
+    loop {
+        let res = rest::request(addr, Message::GetReceiptRequest { hash });
+        match res {
+            Ok(Message::GetReceiptResponse { rx }) => {
+                let json = receipt_json(&hash, &rx);
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&json).unwrap_or_default()
+                );
+                return Ok(rx.success);
+            }
+            Ok(msg) => return Err(format!("unexpected answer: {:?}", msg)),
+            // Not yet in a block.
+            Err(_) if start.elapsed() < timeout => thread::sleep(POLL_PERIOD),
+            Err(err) => {
+                return Err(format!(
+                    "receipt not available after {}s: {}",
+                    timeout.as_secs(),
+                    err
+                ))
+            }
+        }
+    }
+}
@@ -0,0 +1,330 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! `upgrade` subcommand: coordinated binary swap of a running node.
+//!
+//! The node is driven through its API: once the target height is reached
+//! the node is drained and shut down, the database is snapshotted and the
+//! new binary is started with the arguments and the working directory of the
+//! running node, as reported by its status. The new node runs in its own
+//! session, detached from the upgrade command, with the standard streams
+//! appended to a log file next to the database snapshot. The upgrade is
+//! confirmed only if the new node reports the same state hash at the stop
+//! height, otherwise the new node is stopped.
+//!
+//! The new binary version must not be lower than the running one, the
+//! minimum required by the blockchain settings and the optional `--require`.
+
+use crate::api::client;
+use crate::control::NodeStatus;
+use crate::lock;
+use crate::utils::{self, copy_dir, Signer};
+use clap::ArgMatches;
+use std::{
+    fs::{self, OpenOptions},
+    io,
+    path::Path,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+use version_compare::Cmp;
+
+/// Node status polling period.
+const POLL_PERIOD: Duration = Duration::from_secs(2);
+
+/// Time given to the in-flight requests to complete after draining.
+const DRAIN_GRACE: Duration = Duration::from_secs(5);
+
+/// Max time to wait for the old node to exit and the new one to come up.
+const SWAP_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Running node API, with the keypair signing the administration requests.
+struct NodeApi {
+    addr: String,
+    keypair: Option<Box<dyn Signer>>,
+}
+
+impl NodeApi {
+    fn request(&self, method: &str, path: &str) -> io::Result<(u16, Vec<u8>)> {
+        client::request_signed(&self.addr, method, path, None, self.keypair.as_deref())
+    }
+}
+
+pub fn run(matches: &ArgMatches, sub_matches: &ArgMatches) -> i32 {
+    let config = match super::node_config(matches) {
+        Some(config) => config,
+        None => return 1,
+    };
+    let keypair = match config
+        .api_keypair
+        .clone()
+        .map(|path| utils::load_signer(Some(path)))
+    {
+        Some(Ok(keypair)) => Some(keypair),
+        Some(Err(err)) => {
+            eprintln!("Error: API keypair: {}", err);
+            return 1;
+        }
+        None => None,
+    };
+    let api = NodeApi {
+        addr: super::admin_addr(&config),
+        keypair,
+    };
+    let binary = sub_matches.value_of("binary").unwrap_or_default();
+    let height = match sub_matches.value_of("at-height").map(str::parse::<u64>) {
+        Some(Ok(height)) => height,
+        _ => {
+            eprintln!("Error: invalid value for --at-height");
+            return 1;
+        }
+    };
+
+    match upgrade(
+        &api,
+        &config.db_path,
+        binary,
+        height,
+        sub_matches.value_of("require"),
+    ) {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            1
+        }
+    }
+}
+
+fn upgrade(
+    api: &NodeApi,
+    db_path: &str,
+    binary: &str,
+    height: u64,
+    require: Option<&str>,
+) -> Result<(), String> {
+    // Version checks.
+    let node = status(api, None)?;
+    let version = binary_version(binary)?;
+    println!("Running node {}, new binary {}", node.version, version);
+    if compare(&version, &node.version)? == Cmp::Lt {
+        return Err(format!("{} is a downgrade of {}", version, node.version));
+    }
+    for require in node.min_node_version.as_deref().into_iter().chain(require) {
+        if compare(&version, require)? == Cmp::Lt {
+            return Err(format!("{} does not satisfy required {}", version, require));
+        }
+    }
+
+    // Wait for the target height.
+    let mut node = node;
+    while node.height.unwrap_or_default() < height {
+        thread::sleep(POLL_PERIOD);
+        node = status(api, None)?;
+    }
+
+    // Drain and stop the node, the reported state is the reference one.
+    println!("Height {} reached, draining", height);
+    post(api, "/admin/node/drain")?;
+    thread::sleep(DRAIN_GRACE);
+    let node = status(api, None)?;
+    let (stop_height, state_hash) = match (node.height, &node.state_hash) {
+        (Some(height), Some(hash)) => (height, hash.clone()),
+        _ => return Err("node reported no block".to_string()),
+    };
+    println!(
+        "Stopping node at height {} (state {})",
+        stop_height, state_hash
+    );
+    post(api, "/admin/node/shutdown")?;
+    wait_exit(node.pid)?;
+
+    // Snapshot the database to roll back on failure.
+    let snapshot = format!(
+        "{}.pre-upgrade-{}",
+        db_path.trim_end_matches('/'),
+        stop_height
+    );
+    copy_dir(Path::new(db_path), Path::new(&snapshot))
+        .map_err(|err| format!("database snapshot failed: {}", err))?;
+    println!("Database snapshot in {}", snapshot);
+
+    let log = format!(
+        "{}.upgrade-{}.log",
+        db_path.trim_end_matches('/'),
+        stop_height
+    );
+    let mut child = start(binary, &node, Path::new(&log))?;
+    println!("New node started, output in {}", log);
+
+    match verify(api, stop_height, &state_hash, &mut child) {
+        Ok(new_node) => {
+            println!(
+                "Upgrade completed, node {} running with pid {}",
+                version, new_node.pid
+            );
+            Ok(())
+        }
+        Err(err) => {
+            // The node may have detached itself (daemon mode).
+            let _ = child.kill();
+            let _ = child.wait();
+            if let Ok(new_node) = status(api, None) {
+                terminate(new_node.pid);
+            }
+            Err(format!(
+                "{}; new node stopped, restore {} into {} and restart the previous binary",
+                err, snapshot, db_path
+            ))
+        }
+    }
+}
+
+// Starts the new binary with the arguments and the working directory of the
+// stopped node, in a new session to outlive the upgrade command.
+fn start(binary: &str, node: &NodeStatus, log: &Path) -> Result<Child, String> {
+    // The working directory may change.
+    let binary = fs::canonicalize(binary).map_err(|err| format!("{}: {}", binary, err))?;
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log)
+        .map_err(|err| format!("{}: {}", log.display(), err))?;
+    let stderr = log.try_clone().map_err(|err| err.to_string())?;
+
+    let mut command = Command::new(&binary);
+    command
+        .args(&node.args)
+        .stdin(Stdio::null())
+        .stdout(log)
+        .stderr(stderr);
+    if let Some(cwd) = &node.cwd {
+        command.current_dir(cwd);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // SAFETY: `setsid` is async-signal-safe.
+        unsafe {
+            command.pre_exec(|| match libc::setsid() {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            });
+        }
+    }
+    command
+        .spawn()
+        .map_err(|err| format!("unable to start {}: {}", binary.display(), err))
+}
+
+// Asks a node process to stop.
+#[cfg(unix)]
+fn terminate(pid: u32) {
+    if let Ok(pid) = libc::pid_t::try_from(pid) {
+        // SAFETY: no preconditions.
+        unsafe { libc::kill(pid, libc::SIGTERM) };
+    }
+}
+
+#[cfg(not(unix))]
+fn terminate(_pid: u32) {}
+
+// Waits for the new node API and checks the state at the stop height.
+fn verify(
+    api: &NodeApi,
+    height: u64,
+    state_hash: &str,
+    child: &mut Child,
+) -> Result<NodeStatus, String> {
+    let start = Instant::now();
+    let node = loop {
+        // A successful exit is a node detaching itself (daemon mode).
+        match child.try_wait() {
+            Ok(Some(code)) if !code.success() => {
+                return Err(format!("new node exited with {}", code))
+            }
+            _ => (),
+        }
+        if let Ok(node) = status(api, Some(height)) {
+            break node;
+        }
+        if start.elapsed() > SWAP_TIMEOUT {
+            return Err("new node API not reachable".to_string());
+        }
+        thread::sleep(POLL_PERIOD);
+    };
+    match node.state_hash.as_deref() {
+        Some(hash) if hash == state_hash => Ok(node),
+        hash => Err(format!(
+            "state mismatch at height {}: expected {}, found {}",
+            height,
+            state_hash,
+            hash.unwrap_or("none")
+        )),
+    }
+}
+
+fn status(api: &NodeApi, height: Option<u64>) -> Result<NodeStatus, String> {
+    let path = match height {
+        Some(height) => format!("/admin/node?height={}", height),
+        None => "/admin/node".to_string(),
+    };
+    let (code, body) = api
+        .request("GET", &path)
+        .map_err(|err| format!("node API {} not reachable: {}", api.addr, err))?;
+    if code != 200 {
+        return Err(format!("node status request failed ({})", code));
+    }
+    serde_json::from_slice(&body).map_err(|err| format!("bad node status: {}", err))
+}
+
+fn post(api: &NodeApi, path: &str) -> Result<(), String> {
+    match api.request("POST", path) {
+        Ok((200, _)) => Ok(()),
+        Ok((code, _)) => Err(format!("{} request failed ({})", path, code)),
+        Err(err) => Err(format!("{} request failed: {}", path, err)),
+    }
+}
+
+// Version reported by `<binary> --version`, e.g. "T2 Node 0.2.11".
+fn binary_version(binary: &str) -> Result<String, String> {
+    let output = Command::new(binary)
+        .arg("--version")
+        .output()
+        .map_err(|err| format!("unable to run {}: {}", binary, err))?;
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .last()
+        .map(str::to_string)
+        .ok_or_else(|| format!("{} reported no version", binary))
+}
+
+fn compare(a: &str, b: &str) -> Result<Cmp, String> {
+    version_compare::compare(a, b).map_err(|_| format!("cannot compare {} with {}", a, b))
+}
+
+// Waits for the node process to release the database.
+fn wait_exit(pid: u32) -> Result<(), String> {
+    let start = Instant::now();
+    while lock::is_alive(pid) {
+        if start.elapsed() > SWAP_TIMEOUT {
+            return Err(format!("node process {} did not exit", pid));
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+    Ok(())
+}
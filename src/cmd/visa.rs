@@ -0,0 +1,46 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! `visa` subcommand: fetches a node visa from its node API and checks that
+//! it is signed by the node keypair.
+
+use crate::visa;
+use clap::ArgMatches;
+
+pub fn run(matches: &ArgMatches, sub_matches: &ArgMatches) -> i32 {
+    let addr = match sub_matches.value_of("addr") {
+        Some(addr) => addr.to_string(),
+        None => match super::node_config(matches) {
+            Some(config) => super::admin_addr(&config),
+            None => return 1,
+        },
+    };
+    match visa::fetch(&addr) {
+        Ok(visa) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&visa.visa).unwrap_or_default()
+            );
+            println!("signature verified, issued by {}", visa.visa.account_id);
+            0
+        }
+        Err(err) => {
+            eprintln!("Error: visa of {}: {}", addr, err);
+            1
+        }
+    }
+}
@@ -0,0 +1,81 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! `whoami` subcommand: node identities.
+//!
+//! The node uses a distinct keypair per role: the node keypair signs the
+//! blocks, the P2P one identifies the node within the P2P network and the
+//! API one authenticates the node API administration requests. Each file
+//! can be replaced independently. Without a file the node keypair is random
+//! at every start, the P2P one is persisted within the database folder and
+//! the API authentication is disabled.
+
+use crate::utils;
+use clap::ArgMatches;
+use std::path::Path;
+use trinci_core::crypto::KeyPair;
+
+pub fn run(matches: &ArgMatches) -> i32 {
+    let config = match super::node_config(matches) {
+        Some(config) => config,
+        None => return 1,
+    };
+    let identity = Path::new(&config.db_path).join(utils::P2P_IDENTITY_FILE);
+    let identity = if identity.exists() {
+        format!("persisted in {}", identity.display())
+    } else {
+        "generated at the first start".to_string()
+    };
+    let roles = [
+        (
+            "block signing",
+            &config.keypair_path,
+            "random at every start",
+        ),
+        ("p2p identity", &config.p2p_keypair, identity.as_str()),
+        ("api authentication", &config.api_keypair, "disabled"),
+    ];
+    let mut code = 0;
+    for (role, path, missing) in roles {
+        let path = match path {
+            Some(path) => path,
+            None => {
+                println!("{:<20}{}", role, missing);
+                continue;
+            }
+        };
+        let account_id = if role == "p2p identity" {
+            match utils::load_keypair(Some(path.clone())) {
+                Ok(KeyPair::Ecdsa(_)) => Err("not an Ed25519 keypair".to_string()),
+                Ok(KeyPair::Ed25519(keypair)) => Ok(keypair.public_key().to_account_id()),
+                Err(err) => Err(err.to_string()),
+            }
+        } else {
+            utils::load_signer(Some(path.clone()))
+                .map(|signer| signer.public_key().to_account_id())
+                .map_err(|err| err.to_string())
+        };
+        match account_id {
+            Ok(account_id) => println!("{:<20}{} ({})", role, account_id, path),
+            Err(err) => {
+                eprintln!("Error: {} keypair {}: {}", role, path, err);
+                code = 1;
+            }
+        }
+    }
+    code
+}
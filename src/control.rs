@@ -0,0 +1,250 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Node lifecycle control, used to coordinate upgrades and maintenance.
+//!
+//! Draining refuses the new REST and bridge requests, P2P traffic is left
+//! untouched to keep following the chain. Shutdown stops the services and
+//! terminates the node process. P2P participation can be suspended for
+//! maintenance windows, as the REST, bridge and WebSocket services can be
+//! stopped; the services are switched by the application loop.
+//! Handover retires a validator: the new requests are refused, the pending
+//! transactions are left to be executed, then the node stops proposing blocks,
+//! leaves the network and terminates.
+
+use crate::api::{Request, Response, Router};
+use crate::catchup::SyncStatus;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use trinci_core::{
+    base::{serialize::rmp_deserialize, BlockchainSettings, RwLock},
+    db::{Db, RocksDb},
+};
+
+/// Services that can be stopped and started via the node API.
+pub const SERVICES: [&str; 3] = ["rest", "bridge", "ws"];
+
+/// Node state, as reported by the node API.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NodeStatus {
+    /// Node version.
+    pub version: String,
+    /// Core version.
+    pub core_version: String,
+    /// Process identifier.
+    pub pid: u32,
+    /// Command line arguments, program name excluded.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Working directory.
+    #[serde(default)]
+    pub cwd: Option<String>,
+    /// Reported block height.
+    pub height: Option<u64>,
+    /// State hash (hex) after the reported block.
+    pub state_hash: Option<String>,
+    /// Minimum version required by the blockchain settings.
+    pub min_node_version: Option<String>,
+    /// New REST and bridge requests are refused.
+    pub draining: bool,
+    /// P2P participation suspended (offline mode).
+    #[serde(default)]
+    pub offline: bool,
+    /// Services stopped via the node API.
+    #[serde(default)]
+    pub stopped_services: Vec<String>,
+    /// Validator handover in progress.
+    #[serde(default)]
+    pub handover: bool,
+    /// Chain catch up progress.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sync: Option<SyncStatus>,
+}
+
+pub struct NodeControl {
+    db: Arc<RwLock<RocksDb>>,
+    draining: AtomicBool,
+    shutdown: AtomicBool,
+    handover: AtomicBool,
+    /// Node started in offline mode, the p2p service can't join the network.
+    started_offline: bool,
+    p2p_active: AtomicBool,
+    /// Services requested to be stopped.
+    stopped: RwLock<BTreeSet<&'static str>>,
+    /// Last catch up progress sample.
+    sync: RwLock<Option<SyncStatus>>,
+}
+
+impl NodeControl {
+    pub fn new(db: Arc<RwLock<RocksDb>>, offline: bool) -> Self {
+        NodeControl {
+            db,
+            draining: AtomicBool::new(false),
+            shutdown: AtomicBool::new(false),
+            handover: AtomicBool::new(false),
+            started_offline: offline,
+            p2p_active: AtomicBool::new(!offline),
+            stopped: RwLock::new(BTreeSet::new()),
+            sync: RwLock::new(None),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn is_shutdown_requested(&self) -> bool {
+        self.shutdown.load(Ordering::Relaxed)
+    }
+
+    /// Asks the node to stop, as the shutdown route does.
+    pub fn request_shutdown(&self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_handover_requested(&self) -> bool {
+        self.handover.load(Ordering::Relaxed)
+    }
+
+    /// Requested P2P participation.
+    pub fn is_p2p_active(&self) -> bool {
+        self.p2p_active.load(Ordering::Relaxed)
+    }
+
+    /// Requested state of a service (see `SERVICES`).
+    pub fn is_service_active(&self, name: &str) -> bool {
+        !self.stopped.read().contains(name)
+    }
+
+    /// Updates the catch up progress reported by the node status.
+    pub fn set_sync(&self, sync: SyncStatus) {
+        *self.sync.write() = Some(sync);
+    }
+
+    /// Node status at the given block height, the last one if `None`.
+    pub fn status(&self, height: Option<u64>) -> NodeStatus {
+        let db = self.db.read();
+        let block = db.load_block(height.unwrap_or(u64::MAX));
+        let settings = db
+            .load_configuration("blockchain:settings")
+            .and_then(|buf| rmp_deserialize::<BlockchainSettings>(&buf).ok());
+        NodeStatus {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            core_version: trinci_core::VERSION.to_string(),
+            pid: std::process::id(),
+            args: std::env::args().skip(1).collect(),
+            cwd: std::env::current_dir()
+                .ok()
+                .map(|cwd| cwd.to_string_lossy().into_owned()),
+            height: block.as_ref().map(|block| block.data.height),
+            state_hash: block.map(|block| hex::encode(block.data.state_hash.as_bytes())),
+            min_node_version: settings.map(|settings| settings.min_node_version),
+            draining: self.is_draining(),
+            offline: !self.is_p2p_active(),
+            stopped_services: self
+                .stopped
+                .read()
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            handover: self.is_handover_requested(),
+            sync: self.sync.read().clone(),
+        }
+    }
+
+    /// Registers the control routes within the node API.
+    pub fn routes(control: Arc<Self>, router: &mut Router) {
+        let ctl = control.clone();
+        router.add("GET", "/admin/node", move |req: &Request| {
+            Response::json(&ctl.status(req.query("height")))
+        });
+        let ctl = control.clone();
+        router.add("POST", "/admin/node/drain", move |_: &Request| {
+            warn!("[control] draining, new REST and bridge requests are refused");
+            ctl.draining.store(true, Ordering::Relaxed);
+            Response::ok()
+        });
+        let ctl = control.clone();
+        router.add("POST", "/admin/node/p2p", move |req: &Request| {
+            let active = match req.query::<bool>("active") {
+                Some(active) => active,
+                None => return Response::error(400, "missing or invalid `active`"),
+            };
+            // The p2p service configuration is fixed at startup.
+            if active && ctl.started_offline {
+                return Response::error(409, "node started offline, restart it online");
+            }
+            if ctl.p2p_active.swap(active, Ordering::Relaxed) != active {
+                warn!(
+                    "[control] p2p participation {}",
+                    if active { "resumed" } else { "suspended" }
+                );
+            }
+            Response::ok()
+        });
+        let ctl = control.clone();
+        router.add(
+            "POST",
+            "/admin/node/services/:name",
+            move |req: &Request| {
+                let name = req.params.get("name").map(String::as_str);
+                let name = match SERVICES.iter().find(|service| Some(**service) == name) {
+                    Some(service) => *service,
+                    None => return Response::error(404, "unknown service"),
+                };
+                let active = match req.query::<bool>("active") {
+                    Some(active) => active,
+                    None => return Response::error(400, "missing or invalid `active`"),
+                };
+                let mut stopped = ctl.stopped.write();
+                let changed = if active {
+                    stopped.remove(name)
+                } else {
+                    stopped.insert(name)
+                };
+                if changed {
+                    warn!(
+                        "[control] {} service {}",
+                        name,
+                        if active { "start" } else { "stop" }
+                    );
+                }
+                Response::ok()
+            },
+        );
+        let ctl = control.clone();
+        router.add("POST", "/admin/node/handover", move |_: &Request| {
+            warn!(
+                "[control] validator handover requested, new REST and bridge requests are refused"
+            );
+            ctl.draining.store(true, Ordering::Relaxed);
+            ctl.handover.store(true, Ordering::Relaxed);
+            Response::ok()
+        });
+        router.add("POST", "/admin/node/shutdown", move |_: &Request| {
+            warn!("[control] shutdown requested");
+            control.request_shutdown();
+            Response::ok()
+        });
+    }
+}
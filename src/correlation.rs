@@ -0,0 +1,413 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Request tracing.
+//!
+//! Every request received via REST, bridge and P2P gets a correlation
+//! identifier at the gateway, logged as `[trace <id>]` from the reception to
+//! the response. The blockchain messages can't carry it, thus the submitted
+//! transactions are followed by hash: their execution in a block is logged
+//! under the same identifier, with the receipt outcome.
+//!
+//! The identifiers are OpenTelemetry trace identifiers: when a collector is
+//! configured the spans (`gateway <request>` and `block execution`) are
+//! exported in batches as OTLP/HTTP JSON.
+
+use crate::api::client;
+use crate::tasks;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use trinci_core::{
+    base::Mutex,
+    blockchain::{BlockRequestSender, Event, Message},
+    Hash,
+};
+
+/// Max time a submitted transaction is followed.
+const FOLLOW_TTL: Duration = Duration::from_secs(600);
+
+/// Max transactions followed.
+const MAX_FOLLOWED: usize = 10_000;
+
+/// Max spans waiting for the export, the exceeding ones are dropped.
+const MAX_SPANS: usize = 8192;
+
+/// Interval between two exports.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Blockchain subscription identifier.
+const SUBSCRIPTION_ID: &str = "correlation";
+
+/// Finished span.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    /// Trace (correlation) identifier, 32 hex digits.
+    pub trace_id: String,
+    /// Span identifier, 16 hex digits.
+    pub span_id: String,
+    pub parent_id: Option<String>,
+    pub name: String,
+    /// Nanoseconds since the epoch.
+    pub start: u64,
+    pub end: u64,
+    pub attributes: Vec<(&'static str, String)>,
+    pub error: bool,
+}
+
+/// Span in progress, see `Correlation::start`.
+pub struct ActiveSpan {
+    trace_id: String,
+    span_id: String,
+    kind: &'static str,
+    source: &'static str,
+    /// Transaction submitted.
+    hash: Option<Hash>,
+    start: u64,
+    at: Instant,
+}
+
+impl ActiveSpan {
+    /// Correlation identifier.
+    pub fn id(&self) -> &str {
+        &self.trace_id
+    }
+}
+
+/// Submitted transaction waiting for its block.
+struct Followed {
+    trace_id: String,
+    parent_id: String,
+    start: u64,
+    at: Instant,
+}
+
+/// Nanoseconds since the epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Random identifier of `len` bytes, hex encoded.
+pub fn random_id(len: usize) -> String {
+    let mut id = vec![0; len];
+    rand::thread_rng().fill(&mut id[..]);
+    hex::encode(id)
+}
+
+/// OTLP resource describing the node.
+pub fn otlp_resource() -> Value {
+    json!({
+        "attributes": [
+            {"key": "service.name", "value": {"stringValue": "trinci-node"}},
+            {"key": "service.version", "value": {"stringValue": env!("CARGO_PKG_VERSION")}},
+        ]
+    })
+}
+
+pub struct Correlation {
+    /// Collector base URL.
+    endpoint: Option<String>,
+    followed: Mutex<HashMap<Hash, Followed>>,
+    spans: Mutex<Vec<Span>>,
+}
+
+impl Correlation {
+    pub fn new(endpoint: Option<String>) -> Self {
+        Correlation {
+            endpoint,
+            followed: Mutex::new(HashMap::new()),
+            spans: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn record(&self, span: Span) {
+        if self.endpoint.is_none() {
+            return;
+        }
+        let mut spans = self.spans.lock();
+        if spans.len() < MAX_SPANS {
+            spans.push(span);
+        }
+    }
+
+    /// Opens the span of a request received from `source`.
+    pub fn start(&self, source: &'static str, kind: &'static str, req: &Message) -> ActiveSpan {
+        let hash = match req {
+            Message::PutTransactionRequest { tx, .. } => Some(tx.get_primary_hash()),
+            _ => None,
+        };
+        let span = ActiveSpan {
+            trace_id: random_id(16),
+            span_id: random_id(8),
+            kind,
+            source,
+            hash,
+            start: now(),
+            at: Instant::now(),
+        };
+        match &span.hash {
+            Some(hash) => debug!(
+                "[trace {}] {} {} from {}",
+                span.trace_id,
+                kind,
+                hex::encode(hash.as_bytes()),
+                source
+            ),
+            None => debug!("[trace {}] {} from {}", span.trace_id, kind, source),
+        }
+        span
+    }
+
+    /// Closes the span once the response is sent, the transactions accepted
+    /// are followed up to their block.
+    pub fn end(&self, span: ActiveSpan, error: bool) {
+        debug!(
+            "[trace {}] {} served in {:?}{}",
+            span.trace_id,
+            span.kind,
+            span.at.elapsed(),
+            if error { ", failed" } else { "" }
+        );
+        let mut attributes = vec![("trinci.source", span.source.to_string())];
+        if let Some(hash) = &span.hash {
+            attributes.push(("trinci.tx", hex::encode(hash.as_bytes())));
+            if !error {
+                let mut followed = self.followed.lock();
+                followed.retain(|_, tx| tx.at.elapsed() < FOLLOW_TTL);
+                if followed.len() < MAX_FOLLOWED {
+                    followed.insert(
+                        *hash,
+                        Followed {
+                            trace_id: span.trace_id.clone(),
+                            parent_id: span.span_id.clone(),
+                            start: span.start,
+                            at: span.at,
+                        },
+                    );
+                }
+            }
+        }
+        self.record(Span {
+            trace_id: span.trace_id,
+            span_id: span.span_id,
+            parent_id: None,
+            name: format!("gateway {}", span.kind),
+            start: span.start,
+            end: now(),
+            attributes,
+            error,
+        });
+    }
+
+    /// Closes the followed transactions executed in a block, with their
+    /// receipt outcome read through the blockchain channel.
+    pub fn executed(&self, chan: &BlockRequestSender, height: u64, txs: &[Hash]) {
+        let executed: Vec<(Hash, Followed)> = {
+            let mut followed = self.followed.lock();
+            if followed.is_empty() {
+                return;
+            }
+            txs.iter()
+                .filter_map(|hash| Some((*hash, followed.remove(hash)?)))
+                .collect()
+        };
+        for (hash, tx) in executed {
+            let rx = chan
+                .send_sync(Message::GetReceiptRequest { hash })
+                .ok()
+                .and_then(|res| res.recv_sync().ok());
+            let mut attributes = vec![
+                ("trinci.tx", hex::encode(hash.as_bytes())),
+                ("trinci.height", height.to_string()),
+            ];
+            let success = match rx {
+                Some(Message::GetReceiptResponse { rx }) => {
+                    attributes.push(("trinci.burned_fuel", rx.burned_fuel.to_string()));
+                    Some(rx.success)
+                }
+                _ => None,
+            };
+            debug!(
+                "[trace {}] transaction executed in block {} after {:?}{}",
+                tx.trace_id,
+                height,
+                tx.at.elapsed(),
+                match success {
+                    Some(true) => "",
+                    Some(false) => ", failed",
+                    None => ", receipt not found",
+                }
+            );
+            self.record(Span {
+                trace_id: tx.trace_id,
+                span_id: random_id(8),
+                parent_id: Some(tx.parent_id),
+                name: "block execution".to_string(),
+                start: tx.start,
+                end: now(),
+                attributes,
+                error: success == Some(false),
+            });
+        }
+    }
+
+    /// OTLP/HTTP JSON export request of the spans.
+    pub fn otlp_body(spans: &[Span]) -> Value {
+        let spans: Vec<Value> = spans
+            .iter()
+            .map(|span| {
+                let attributes: Vec<Value> = span
+                    .attributes
+                    .iter()
+                    .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+                    .collect();
+                json!({
+                    "traceId": span.trace_id,
+                    "spanId": span.span_id,
+                    "parentSpanId": span.parent_id.clone().unwrap_or_default(),
+                    "name": span.name,
+                    "kind": 2,
+                    "startTimeUnixNano": span.start.to_string(),
+                    "endTimeUnixNano": span.end.to_string(),
+                    "attributes": attributes,
+                    "status": {"code": if span.error { 2 } else { 1 }},
+                })
+            })
+            .collect();
+        json!({
+            "resourceSpans": [{
+                "resource": otlp_resource(),
+                "scopeSpans": [{
+                    "scope": {"name": "trinci-node"},
+                    "spans": spans,
+                }]
+            }]
+        })
+    }
+
+    /// Exports the spans every `EXPORT_INTERVAL`, returns at once when no
+    /// collector is configured.
+    pub fn export(correlation: Arc<Self>) {
+        let target = match correlation.endpoint.as_deref().map(client::split_url) {
+            Some(Some((addr, path))) => (addr, format!("{}/v1/traces", path.trim_end_matches('/'))),
+            Some(None) => {
+                warn!("[trace] invalid collector URL, only plain `http://` is supported");
+                return;
+            }
+            None => return,
+        };
+        while tasks::sleep(EXPORT_INTERVAL) {
+            let spans = std::mem::take(&mut *correlation.spans.lock());
+            if spans.is_empty() {
+                continue;
+            }
+            let body = Self::otlp_body(&spans).to_string();
+            match client::request(&target.0, "POST", &target.1, Some(body.as_bytes())) {
+                Ok((status, _)) if status < 300 => (),
+                Ok((status, body)) => warn!(
+                    "[trace] collector answered {}: {}",
+                    status,
+                    String::from_utf8_lossy(&body)
+                ),
+                Err(err) => warn!("[trace] collector unreachable: {}", err),
+            }
+        }
+    }
+
+    /// Follows the executed blocks until the blockchain channel is closed.
+    pub fn run(correlation: Arc<Self>, chan: BlockRequestSender) {
+        let req = Message::Subscribe {
+            id: SUBSCRIPTION_ID.to_owned(),
+            events: Event::BLOCK,
+        };
+        let rx_chan = match chan.send_sync(req) {
+            Ok(rx_chan) => rx_chan,
+            Err(_) => {
+                warn!("[trace] blockchain channel closed");
+                return;
+            }
+        };
+        while let Ok(msg) = rx_chan.recv_sync() {
+            if let Message::GetBlockResponse {
+                block,
+                txs: Some(txs),
+                ..
+            } = msg
+            {
+                correlation.executed(&chan, block.data.height, &txs);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway::service::sink;
+    use crate::tasks::Tasks;
+    use trinci_core::{
+        crypto::{ed25519::KeyPair as Ed25519KeyPair, KeyPair},
+        SignedTransaction, Transaction, TransactionData, TransactionDataV1,
+    };
+
+    #[test]
+    fn follow_transaction() {
+        let keypair = KeyPair::Ed25519(Ed25519KeyPair::from_random());
+        let tx = Transaction::UnitTransaction(SignedTransaction {
+            data: TransactionData::V1(TransactionDataV1 {
+                account: "TRINCI".to_string(),
+                fuel_limit: 1000,
+                nonce: vec![0],
+                network: "skynet".to_string(),
+                contract: None,
+                method: "transfer".to_string(),
+                caller: keypair.public_key(),
+                args: Vec::new(),
+            }),
+            signature: Vec::new(),
+        });
+        let hash = tx.get_primary_hash();
+        let correlation = Correlation::new(Some("http://127.0.0.1:4318".to_string()));
+        let req = Message::PutTransactionRequest {
+            confirm: true,
+            tx: tx.clone(),
+        };
+
+        let span = correlation.start("rest", "PutTransactionRequest", &req);
+        let id = span.id().to_string();
+        correlation.end(span, false);
+        correlation.executed(&sink(&Tasks::default(), "no receipts"), 7, &[hash]);
+
+        let spans = correlation.spans.lock().clone();
+        assert_eq!(spans.len(), 2);
+        assert!(spans.iter().all(|span| span.trace_id == id));
+        assert_eq!(spans[1].parent_id.as_ref(), Some(&spans[0].span_id));
+        assert!(correlation.followed.lock().is_empty());
+
+        let body = Correlation::otlp_body(&spans);
+        let otlp_spans = &body["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(otlp_spans[1]["name"], "block execution");
+        assert_eq!(otlp_spans[1]["traceId"].as_str().unwrap().len(), 32);
+    }
+}
@@ -0,0 +1,233 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Crash reports.
+//!
+//! A process wide panic hook writes a report of any thread panic (node,
+//! versions, configuration hash, last block, backtrace) to its own
+//! `crash-{time}-{sequence}.txt` file within the database folder, concurrent
+//! panics included. The first crash is then handled by the application loop:
+//! the monitor endpoint is notified (`crash` alert) and the node goes through
+//! the supervised shutdown, exiting with an error status so that the service
+//! manager restarts it. The hook itself does no network or process I/O.
+
+use crate::tasks::panic_message;
+use std::{
+    backtrace::Backtrace,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    panic::{self, Location},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use trinci_core::{
+    base::RwLock,
+    crypto::HashAlgorithm,
+    db::{Db, RocksDb},
+    Hashable,
+};
+
+pub struct CrashReporter {
+    dir: PathBuf,
+    node_id: String,
+    config_hash: String,
+    db: Arc<RwLock<RocksDb>>,
+    /// Reports written, to name them.
+    sequence: AtomicU64,
+    /// Summary of the first crash.
+    crashed: OnceLock<String>,
+    /// First crash handled.
+    taken: AtomicBool,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl CrashReporter {
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        node_id: String,
+        config_hash: String,
+        db: Arc<RwLock<RocksDb>>,
+    ) -> Self {
+        CrashReporter {
+            dir: dir.into(),
+            node_id,
+            config_hash,
+            db,
+            sequence: AtomicU64::new(0),
+            crashed: OnceLock::new(),
+            taken: AtomicBool::new(false),
+        }
+    }
+
+    // Creates a new report file, never overwriting a previous one.
+    fn create(&self, time: u64) -> io::Result<(PathBuf, File)> {
+        loop {
+            let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+            let path = self.dir.join(format!("crash-{}-{}.txt", time, sequence));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((path, file)),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // Last block (height, hash), skipped if the database is locked: the
+    // panicking thread may be the lock owner.
+    fn last_block(&self) -> Option<(u64, String)> {
+        let block = self.db.try_read()?.load_block(u64::MAX)?;
+        let hash = hex::encode(block.hash(HashAlgorithm::Sha256).as_bytes());
+        Some((block.data.height, hash))
+    }
+
+    fn render(
+        &self,
+        time: u64,
+        thread: &str,
+        message: &str,
+        last_block: Option<(u64, String)>,
+        backtrace: &str,
+    ) -> String {
+        let last_block = last_block
+            .map(|(height, hash)| format!("{} {}", height, hash))
+            .unwrap_or_else(|| "unknown".to_string());
+        format!(
+            "TRINCI node crash report\n\
+             time: {}\n\
+             node: {}\n\
+             node version: {}\n\
+             core version: {}\n\
+             config hash: {}\n\
+             last block: {}\n\
+             thread: {}\n\
+             panic: {}\n\
+             \n\
+             backtrace:\n{}\n",
+            time,
+            self.node_id,
+            env!("CARGO_PKG_VERSION"),
+            trinci_core::VERSION,
+            self.config_hash,
+            last_block,
+            thread,
+            message,
+            backtrace
+        )
+    }
+
+    fn report(&self, message: String, location: Option<&Location>) {
+        let thread = thread::current();
+        let thread = thread.name().unwrap_or("unnamed");
+        let message = match location {
+            Some(location) => format!("{} at {}", message, location),
+            None => message,
+        };
+        let time = now();
+        let backtrace = Backtrace::force_capture().to_string();
+        let report = self.render(time, thread, &message, self.last_block(), &backtrace);
+        let written = self
+            .create(time)
+            .and_then(|(path, mut file)| file.write_all(report.as_bytes()).map(|()| path));
+        let summary = match written {
+            Ok(path) => format!("{} panicked: {} (report {:?})", thread, message, path),
+            Err(err) => format!(
+                "{} panicked: {} (report not written: {})",
+                thread, message, err
+            ),
+        };
+        let _ = self.crashed.set(summary);
+    }
+
+    /// Installs the process wide panic hook, the previous one is still run.
+    pub fn install(reporter: Arc<Self>) {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            reporter.report(panic_message(info.payload()), info.location());
+            previous(info);
+        }));
+    }
+
+    /// First crash, as a summary, returned once.
+    pub fn take(&self) -> Option<String> {
+        self.crashed
+            .get()
+            .filter(|_| !self.taken.swap(true, Ordering::SeqCst))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn report_layout() {
+        let dir = TempDir::new().unwrap();
+        let db = Arc::new(RwLock::new(RocksDb::new(dir.path().join("db"))));
+        let reporter =
+            CrashReporter::new(dir.path(), "QmNode".to_string(), "c0ffee".to_string(), db);
+
+        let report = reporter.render(
+            1_700_000_000,
+            "trinci-worker",
+            "boom at src/replica.rs:1:1",
+            Some((42, "1220ab".to_string())),
+            "0: main",
+        );
+
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "TRINCI node crash report");
+        assert!(lines.contains(&"node: QmNode"));
+        assert!(lines.contains(&"config hash: c0ffee"));
+        assert!(lines.contains(&"last block: 42 1220ab"));
+        assert!(lines.contains(&"thread: trinci-worker"));
+        assert!(lines.contains(&"panic: boom at src/replica.rs:1:1"));
+        assert_eq!(lines[lines.len() - 1], "0: main");
+        assert!(reporter.take().is_none());
+    }
+
+    #[test]
+    fn reports_not_overwritten() {
+        let dir = TempDir::new().unwrap();
+        let db = Arc::new(RwLock::new(RocksDb::new(dir.path().join("db"))));
+        let reporter =
+            CrashReporter::new(dir.path(), "QmNode".to_string(), "c0ffee".to_string(), db);
+        std::fs::write(dir.path().join("crash-7-0.txt"), "previous").unwrap();
+
+        let (first, _) = reporter.create(7).unwrap();
+        let (second, _) = reporter.create(7).unwrap();
+
+        assert_eq!(first, dir.path().join("crash-7-1.txt"));
+        assert_eq!(second, dir.path().join("crash-7-2.txt"));
+        reporter.report("boom".to_string(), None);
+        reporter.report("bang".to_string(), None);
+        assert!(reporter.take().unwrap().contains("boom"));
+        assert!(reporter.take().is_none());
+    }
+}
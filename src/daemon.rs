@@ -0,0 +1,192 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Daemon mode.
+//!
+//! Detaches the node from the terminal, to run it as an OS service without
+//! wrapper scripts: double fork, new session and standard streams redirected
+//! to `/dev/null`. The launching process exits once the daemon is set up,
+//! with a failure status if the setup failed, as expected by the service
+//! managers of the "forking" kind.
+//! On Windows the daemon mode runs the node as a service instead (see the
+//! `winservice` module), it is refused on the other platforms.
+//!
+//! The PID file is removed when dropped, thus before `process::exit`, which
+//! skips the destructors.
+
+use crate::lock;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    process,
+};
+
+/// PID file, removed on drop.
+/// A file left by a crashed node is detected by its process identifier and
+/// replaced.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &str) -> Result<Self, String> {
+        let path = PathBuf::from(path);
+        let io_error = |err: io::Error| format!("{}: {}", path.display(), err);
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    writeln!(file, "{}", process::id()).map_err(io_error)?;
+                    return Ok(PidFile { path });
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => (),
+                Err(err) => return Err(io_error(err)),
+            }
+            let pid = fs::read_to_string(&path)
+                .ok()
+                .and_then(|pid| pid.trim().parse::<u32>().ok());
+            match pid {
+                Some(pid) if lock::is_alive(pid) => {
+                    return Err(format!(
+                        "node already running with process {} ({})",
+                        pid,
+                        path.display()
+                    ))
+                }
+                _ => fs::remove_file(&path).map_err(io_error)?,
+            }
+        }
+        Err(format!("{}: unable to create", path.display()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+fn last_error(call: &str) -> String {
+    format!("{}: {}", call, io::Error::last_os_error())
+}
+
+// Runs in the first child: new session, second fork, PID file and standard
+// streams.
+#[cfg(unix)]
+fn detach(pid_file: Option<&str>) -> Result<Option<PidFile>, String> {
+    // SAFETY: no preconditions.
+    if unsafe { libc::setsid() } == -1 {
+        return Err(last_error("setsid"));
+    }
+    // Not a session leader anymore, the daemon can't acquire a terminal.
+    // SAFETY: the process is single threaded.
+    match unsafe { libc::fork() } {
+        -1 => return Err(last_error("fork")),
+        0 => (),
+        // SAFETY: terminates the intermediate process only.
+        _ => unsafe { libc::_exit(0) },
+    }
+    let pid_file = pid_file.map(PidFile::create).transpose()?;
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .map_err(|err| format!("/dev/null: {}", err))?;
+    for fd in 0..3 {
+        // SAFETY: both descriptors are valid.
+        if unsafe { libc::dup2(std::os::unix::io::AsRawFd::as_raw_fd(&null), fd) } == -1 {
+            return Err(last_error("dup2"));
+        }
+    }
+    Ok(pid_file)
+}
+
+/// Turns the process into a daemon, to be called before starting any
+/// thread. Returns in the daemon only: the launching process exits when
+/// the daemon reports the setup outcome, reporting the errors.
+#[cfg(unix)]
+pub fn daemonize(pid_file: Option<&str>) -> Result<Option<PidFile>, String> {
+    use std::{fs::File, io::Read, os::unix::io::FromRawFd};
+
+    let mut fds = [0; 2];
+    // SAFETY: `fds` holds the two descriptors.
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(last_error("pipe"));
+    }
+    // SAFETY: the descriptors were just created and are owned here.
+    let (mut rx, mut tx) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    // SAFETY: the process is single threaded.
+    match unsafe { libc::fork() } {
+        -1 => return Err(last_error("fork")),
+        0 => drop(rx),
+        _ => {
+            // Waits for the setup outcome, the pipe is closed by the daemon.
+            drop(tx);
+            let mut outcome = String::new();
+            let _ = rx.read_to_string(&mut outcome);
+            match outcome.as_str() {
+                "ok" => process::exit(0),
+                "" => eprintln!("Error: daemon setup failed"),
+                err => eprintln!("Error: {}", err),
+            }
+            process::exit(1);
+        }
+    }
+    match detach(pid_file) {
+        Ok(pid_file) => {
+            let _ = tx.write_all(b"ok");
+            Ok(pid_file)
+        }
+        // Reported by the launching process.
+        Err(err) => {
+            let _ = tx.write_all(err.as_bytes());
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_pid_file: Option<&str>) -> Result<Option<PidFile>, String> {
+    Err("daemon mode is only available on Unix and Windows".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn pid_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("node.pid");
+        let path = path.to_str().unwrap();
+
+        let pid_file = PidFile::create(path).unwrap();
+        assert!(PidFile::create(path).is_err());
+        drop(pid_file);
+        assert!(!dir.path().join("node.pid").exists());
+
+        // Left by a crashed process.
+        fs::write(path, u32::MAX.to_string()).unwrap();
+        let _pid_file = PidFile::create(path).unwrap();
+        assert_eq!(
+            fs::read_to_string(path).unwrap().trim(),
+            process::id().to_string()
+        );
+    }
+}
@@ -0,0 +1,353 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Transactions denylist.
+//!
+//! Emergency brake to stop a node from pooling and relaying transactions that
+//! target a vulnerable contract, while the fix lands on-chain.
+//! Entries are persisted in the database folder and expire after a while.
+
+use crate::api::{Request, Response, Router};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use trinci_core::{base::Mutex, Transaction, TransactionData};
+
+/// Denylist file name within the database folder.
+pub const DENYLIST_FILE: &str = "denylist.toml";
+
+/// Min interval between two checks of the file modification time.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Denylist entry.
+/// An entry matches a transaction by target `account` (optionally restricted
+/// to a `method`) or by transaction `hash`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Entry identifier.
+    pub id: u64,
+    /// Target account.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Target method, only meaningful together with `account`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    /// Transaction hash (hex).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// Expiration time, seconds since the epoch.
+    pub expires: u64,
+    /// Free text motivation.
+    #[serde(default)]
+    pub reason: String,
+}
+
+impl Entry {
+    fn matches(&self, hash: &str, targets: &[(&str, &str)]) -> bool {
+        if let Some(entry_hash) = &self.hash {
+            let entry_hash = entry_hash.to_lowercase();
+            // Accept the hash with or without the multihash prefix.
+            if hash == entry_hash || hash.strip_prefix("1220") == Some(entry_hash.as_str()) {
+                return true;
+            }
+        }
+        match &self.account {
+            Some(account) => targets.iter().any(|(target, method)| {
+                target == account && self.method.as_deref().map(|m| m == *method).unwrap_or(true)
+            }),
+            None => false,
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires <= now
+    }
+}
+
+/// Entry addition request, via node API.
+#[derive(Deserialize)]
+struct AddRequest {
+    account: Option<String>,
+    method: Option<String>,
+    hash: Option<String>,
+    /// Validity in seconds.
+    ttl: u64,
+    #[serde(default)]
+    reason: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct DenylistFile {
+    #[serde(default)]
+    entry: Vec<Entry>,
+}
+
+/// Persisted denylist, the file is reloaded when modified by another process.
+pub struct Denylist {
+    path: PathBuf,
+    entries: Vec<Entry>,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Target (account, method) pairs of a transaction, bulk nodes included.
+pub(crate) fn targets(tx: &Transaction) -> Vec<(&str, &str)> {
+    fn target(data: &TransactionData) -> Option<(&str, &str)> {
+        Some((data.get_account().ok()?, data.get_method().ok()?))
+    }
+    match tx {
+        Transaction::UnitTransaction(tx) => target(&tx.data).into_iter().collect(),
+        Transaction::BulkTransaction(tx) => match &tx.data {
+            TransactionData::BulkV1(bulk) => {
+                let mut targets: Vec<_> = target(&bulk.txs.root.data).into_iter().collect();
+                for node in bulk.txs.nodes.iter().flatten() {
+                    targets.extend(target(&node.data));
+                }
+                targets
+            }
+            data => target(data).into_iter().collect(),
+        },
+    }
+}
+
+impl Denylist {
+    /// Opens the denylist stored within the given database folder.
+    pub fn open<P: AsRef<Path>>(db_path: P) -> Self {
+        let mut denylist = Denylist {
+            path: db_path.as_ref().join(DENYLIST_FILE),
+            entries: Vec::new(),
+            modified: None,
+            last_check: Instant::now(),
+        };
+        if let Err(err) = denylist.load() {
+            error!("[denylist] error loading {:?}: {}", denylist.path, err);
+        }
+        denylist
+    }
+
+    fn load(&mut self) -> io::Result<()> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                self.entries.clear();
+                self.modified = None;
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+        let file: DenylistFile = toml::from_str(&content)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        self.entries = file.entry;
+        self.modified = fs::metadata(&self.path)?.modified().ok();
+        Ok(())
+    }
+
+    /// Writes the entries to the file, dropping the expired ones.
+    pub fn save(&mut self) -> io::Result<()> {
+        let now = now();
+        self.entries.retain(|entry| !entry.is_expired(now));
+        let file = DenylistFile {
+            entry: self.entries.clone(),
+        };
+        let content = toml::to_string(&file)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, content)?;
+        self.modified = fs::metadata(&self.path)?.modified().ok();
+        Ok(())
+    }
+
+    /// Reloads the file if it has been modified since the last load.
+    pub fn refresh(&mut self) {
+        if self.last_check.elapsed() < RELOAD_INTERVAL {
+            return;
+        }
+        self.last_check = Instant::now();
+        let modified = fs::metadata(&self.path)
+            .and_then(|meta| meta.modified())
+            .ok();
+        if modified != self.modified {
+            match self.load() {
+                Ok(()) => info!("[denylist] reloaded, {} entries", self.entries.len()),
+                Err(err) => error!("[denylist] error reloading {:?}: {}", self.path, err),
+            }
+        }
+    }
+
+    /// Active entries.
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        let now = now();
+        self.entries
+            .iter()
+            .filter(move |entry| !entry.is_expired(now))
+    }
+
+    /// Adds an entry valid for `ttl`, returns its identifier.
+    pub fn add(
+        &mut self,
+        account: Option<String>,
+        method: Option<String>,
+        hash: Option<String>,
+        ttl: Duration,
+        reason: String,
+    ) -> u64 {
+        let id = self.entries.iter().map(|entry| entry.id).max().unwrap_or(0) + 1;
+        self.entries.push(Entry {
+            id,
+            account,
+            method,
+            hash: hash.map(|hash| hash.to_lowercase()),
+            expires: now() + ttl.as_secs(),
+            reason,
+        });
+        id
+    }
+
+    /// Removes an entry, returns `false` if not found.
+    pub fn remove(&mut self, id: u64) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|entry| entry.id != id);
+        self.entries.len() != len
+    }
+
+    /// Returns the first active entry matching the transaction.
+    pub fn check(&self, tx: &Transaction) -> Option<&Entry> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        let hash = hex::encode(tx.get_primary_hash().as_bytes());
+        let targets = targets(tx);
+        self.entries().find(|entry| entry.matches(&hash, &targets))
+    }
+
+    /// Registers the denylist administration routes within the node API.
+    pub fn routes(denylist: Arc<Mutex<Self>>, router: &mut Router) {
+        let list = denylist.clone();
+        router.add("GET", "/admin/denylist", move |_: &Request| {
+            let mut list = list.lock();
+            list.refresh();
+            Response::json(&list.entries().collect::<Vec<_>>())
+        });
+        let list = denylist.clone();
+        router.add("POST", "/admin/denylist", move |req: &Request| {
+            let add = match req.json::<AddRequest>() {
+                Ok(add) => add,
+                Err(res) => return res,
+            };
+            if add.account.is_none() && add.hash.is_none() {
+                return Response::error(400, "`account` or `hash` required");
+            }
+            let mut list = list.lock();
+            list.refresh();
+            let id = list.add(
+                add.account,
+                add.method,
+                add.hash,
+                Duration::from_secs(add.ttl),
+                add.reason,
+            );
+            match list.save() {
+                Ok(()) => Response::json(&id),
+                Err(err) => Response::error(500, err.to_string()),
+            }
+        });
+        router.add("DELETE", "/admin/denylist/:id", move |req: &Request| {
+            let id = match req.param::<u64>("id") {
+                Some(id) => id,
+                None => return Response::error(400, "invalid entry identifier"),
+            };
+            let mut list = denylist.lock();
+            list.refresh();
+            if !list.remove(id) {
+                return Response::error(404, "Not Found");
+            }
+            match list.save() {
+                Ok(()) => Response::ok(),
+                Err(err) => Response::error(500, err.to_string()),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn entry(account: Option<&str>, method: Option<&str>, hash: Option<&str>) -> Entry {
+        Entry {
+            id: 1,
+            account: account.map(str::to_owned),
+            method: method.map(str::to_owned),
+            hash: hash.map(str::to_owned),
+            expires: u64::MAX,
+            reason: String::new(),
+        }
+    }
+
+    #[test]
+    fn entry_matches() {
+        let targets = [("TRINCI", "transfer")];
+
+        assert!(entry(Some("TRINCI"), None, None).matches("1220ab", &targets));
+        assert!(entry(Some("TRINCI"), Some("transfer"), None).matches("1220ab", &targets));
+        assert!(!entry(Some("TRINCI"), Some("mint"), None).matches("1220ab", &targets));
+        assert!(!entry(Some("other"), None, None).matches("1220ab", &targets));
+        assert!(entry(None, None, Some("1220ab")).matches("1220ab", &targets));
+        assert!(entry(None, None, Some("AB")).matches("1220ab", &targets));
+    }
+
+    #[test]
+    fn save_and_reopen() {
+        let dir = TempDir::new().unwrap();
+        let mut denylist = Denylist::open(dir.path());
+        let id = denylist.add(
+            Some("TRINCI".to_string()),
+            None,
+            None,
+            Duration::from_secs(3600),
+            "test".to_string(),
+        );
+        denylist.add(
+            None,
+            None,
+            Some("1220ab".to_string()),
+            Duration::ZERO,
+            String::new(),
+        );
+        denylist.save().unwrap();
+
+        let mut denylist = Denylist::open(dir.path());
+
+        assert_eq!(denylist.entries().count(), 1);
+        assert!(denylist.remove(id));
+        assert_eq!(denylist.entries().count(), 0);
+    }
+}
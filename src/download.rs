@@ -0,0 +1,280 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Bootstrap file download.
+//!
+//! The bootstrap file is requested to all the candidate nodes at once, each
+//! download streamed to its own partial file next to the destination. The
+//! first complete file whose network name matches the expected one is
+//! accepted, the other downloads are abandoned. A download interrupted by an
+//! I/O error leaves its partial file behind: the next attempt resumes it from
+//! the same node with a `Range` request, or restarts it if the node ignores
+//! the range.
+
+use crate::{app::calculate_network_name, proxy};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Bootstrap file path within the REST service.
+const BOOTSTRAP_PATH: &str = "/api/v1/bootstrap";
+
+/// Socket read/write timeout.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Body read buffer size, the partial file is written at every read.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Partial file of the download from `host`.
+fn part_path(path: &str, host: &str) -> String {
+    let host: String = host
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}.{}.part", path, host)
+}
+
+/// Downloads the file into `part`, resuming its content if any.
+fn download(host: &str, part: &str, stop: &AtomicBool) -> Result<(), String> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(part)
+        .map_err(|err| format!("{}: {}", part, err))?;
+    let offset = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+
+    let stream = proxy::connect(host).map_err(|err| err.to_string())?;
+    stream
+        .set_read_timeout(Some(IO_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(IO_TIMEOUT)))
+        .map_err(|err| err.to_string())?;
+    let range = match offset {
+        0 => String::new(),
+        offset => format!("Range: bytes={}-\r\n", offset),
+    };
+    write!(
+        &stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\n\r\n",
+        BOOTSTRAP_PATH, host, range
+    )
+    .map_err(|err| err.to_string())?;
+
+    let mut reader = BufReader::new(stream);
+    let (status, len) = read_head(&mut reader).map_err(|err| err.to_string())?;
+    match status {
+        206 => debug!("[bootstrap] {}: resuming from byte {}", host, offset),
+        200 => file.set_len(0).map_err(|err| err.to_string())?,
+        // The partial file is already complete.
+        416 if offset > 0 => return Ok(()),
+        status => return Err(format!("status {}", status)),
+    }
+
+    let mut received = 0;
+    let mut buf = vec![0; CHUNK_SIZE];
+    while !stop.load(Ordering::Relaxed) {
+        let read = reader.read(&mut buf).map_err(|err| err.to_string())?;
+        if read == 0 {
+            break;
+        }
+        file.write_all(&buf[..read])
+            .map_err(|err| format!("{}: {}", part, err))?;
+        received += read as u64;
+    }
+    match len {
+        Some(len) if received < len && !stop.load(Ordering::Relaxed) => {
+            Err(format!("truncated after {} of {} bytes", received, len))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Reads the response head, returns the status and the body length, if known.
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<(u16, Option<u64>)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed response");
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(invalid)?;
+    let mut len = None;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            return Ok((status, len));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            match name.trim().to_lowercase().as_str() {
+                "content-length" => len = value.trim().parse().ok(),
+                "transfer-encoding" if value.trim() != "identity" => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "chunked transfer encoding not supported",
+                    ))
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Downloads and verifies the file from `host`, returns its network name.
+fn fetch_from(
+    host: &str,
+    part: &str,
+    network: Option<&str>,
+    stop: &AtomicBool,
+) -> Result<String, String> {
+    download(host, part, stop)?;
+    let bootstrap = fs::read(part).map_err(|err| format!("{}: {}", part, err))?;
+    let name = calculate_network_name(&bootstrap);
+    match network {
+        Some(network) if name != network => {
+            // Not resumable, the content is wrong.
+            let _ = fs::remove_file(part);
+            Err(format!("file for network {}, expected {}", name, network))
+        }
+        _ => Ok(name),
+    }
+}
+
+/// Fetches the bootstrap file from the REST service of the given nodes
+/// (`host:port`) concurrently: the first complete file whose network name
+/// matches `network`, if given, is written to `path`. Returns the network
+/// name of the file.
+pub fn fetch_bootstrap(
+    hosts: &[String],
+    network: Option<&str>,
+    path: &str,
+) -> Result<String, String> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel();
+    for host in hosts {
+        let (host, part, network) = (
+            host.clone(),
+            part_path(path, host),
+            network.map(str::to_owned),
+        );
+        let (stop, tx) = (stop.clone(), tx.clone());
+        thread::spawn(move || {
+            let result = fetch_from(&host, &part, network.as_deref(), &stop);
+            // Abandoned download.
+            if stop.load(Ordering::Relaxed) {
+                let _ = fs::remove_file(&part);
+            }
+            let _ = tx.send((host, part, result));
+        });
+    }
+    drop(tx);
+
+    for (host, part, result) in rx {
+        match result {
+            Ok(name) => {
+                stop.store(true, Ordering::Relaxed);
+                fs::rename(&part, path).map_err(|err| format!("writing {}: {}", path, err))?;
+                // The abandoned downloads are not resumed.
+                for host in hosts {
+                    let _ = fs::remove_file(part_path(path, host));
+                }
+                info!("[bootstrap] {} fetched from {}", path, host);
+                return Ok(name);
+            }
+            Err(err) => warn!("[bootstrap] {}: {}", host, err),
+        }
+    }
+    Err(match network {
+        Some(network) => format!(
+            "bootstrap file of network {} not available from the bootstrap peers",
+            network
+        ),
+        None => "bootstrap file not available".to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use tempfile::TempDir;
+
+    // Serves `content` to every request, honoring the `Range` header.
+    fn serve(content: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut offset = 0;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    if let Some(range) = line.strip_prefix("Range: bytes=") {
+                        offset = range.trim_end().trim_end_matches('-').parse().unwrap();
+                    }
+                    line.clear();
+                }
+                let status = if offset > 0 {
+                    "206 Partial Content"
+                } else {
+                    "200 OK"
+                };
+                let body = &content[offset..];
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n",
+                    status,
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn resume_and_verify() {
+        let dir = TempDir::new().unwrap();
+        let path = dir
+            .path()
+            .join("bootstrap.bin")
+            .to_string_lossy()
+            .to_string();
+        let good = serve(b"bootstrap content");
+        let bad = serve(b"another network");
+        fs::write(part_path(&path, &good), b"bootstrap").unwrap();
+
+        let network = calculate_network_name(b"bootstrap content");
+        let hosts = vec![bad.clone(), good.clone()];
+        assert_eq!(fetch_bootstrap(&hosts, Some(&network), &path), Ok(network));
+        assert_eq!(fs::read(&path).unwrap(), b"bootstrap content");
+        assert!(!Path::new(&part_path(&path, &good)).exists());
+    }
+}
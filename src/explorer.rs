@@ -0,0 +1,387 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Chain explorer routes.
+//!
+//! Paginated listings of blocks, block transactions, account receipts and
+//! account data keys, read straight from the database, enough for a
+//! lightweight explorer to run against a node. Responses are JSON, or msgpack
+//! when asked via the `Accept` header.
+//!
+//! The core does not index transactions by account: the account receipts are
+//! searched walking back the blocks, up to `MAX_SCAN_BLOCKS` per request.
+//!
+//! The account data values can be requested with their anchor: the last
+//! block height, hash and state hash and the account data hash, read together
+//! with the values. The core does not expose the Merkle paths of its state
+//! tree, so no inclusion proof is attached.
+
+use crate::api::{Request, Response, Router};
+use crate::denylist;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use trinci_core::{
+    base::RwLock,
+    crypto::Hashable,
+    db::{Db, RocksDb},
+    Block, Hash,
+};
+
+/// Items per page when not requested.
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+
+/// Max items per page.
+pub const MAX_PAGE_SIZE: usize = 100;
+
+/// Max blocks walked back by an account receipts search.
+pub const MAX_SCAN_BLOCKS: u64 = 1000;
+
+/// Page of a listing, `next` is the cursor of the following page.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<u64>,
+}
+
+impl<T> Default for Page<T> {
+    fn default() -> Self {
+        Page {
+            items: Vec::new(),
+            next: None,
+        }
+    }
+}
+
+/// Block summary.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct BlockItem {
+    pub height: u64,
+    pub hash: String,
+    /// Number of transactions.
+    pub size: u32,
+    pub timestamp: u64,
+    pub prev_hash: String,
+    pub txs_hash: String,
+    pub rxs_hash: String,
+    pub state_hash: String,
+    /// Validator account.
+    pub validator: Option<String>,
+}
+
+impl From<&Block> for BlockItem {
+    fn from(block: &Block) -> Self {
+        let data = &block.data;
+        BlockItem {
+            height: data.height,
+            hash: hex::encode(data.primary_hash().as_bytes()),
+            size: data.size,
+            timestamp: data.timestamp,
+            prev_hash: hex::encode(data.prev_hash.as_bytes()),
+            txs_hash: hex::encode(data.txs_hash.as_bytes()),
+            rxs_hash: hex::encode(data.rxs_hash.as_bytes()),
+            state_hash: hex::encode(data.state_hash.as_bytes()),
+            validator: data.validator.as_ref().map(|key| key.to_account_id()),
+        }
+    }
+}
+
+/// Transaction summary, with its receipt outcome.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TxItem {
+    pub hash: String,
+    /// Target account, of the root transaction for bulk transactions.
+    pub account: Option<String>,
+    pub method: Option<String>,
+    /// Caller account.
+    pub caller: String,
+    /// Receipt fields, missing if the receipt is not stored.
+    pub height: Option<u64>,
+    pub index: Option<u32>,
+    pub success: Option<bool>,
+    pub burned_fuel: Option<u64>,
+}
+
+/// Account data value, hex encoded, `None` if the key is not set.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DataItem {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+/// State the account data have been read at.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Anchor {
+    pub height: u64,
+    pub block_hash: String,
+    pub state_hash: String,
+    /// Account data hash, covered by the state hash.
+    pub data_hash: Option<String>,
+}
+
+/// Account data values, with their anchor if requested.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct AccountData {
+    pub items: Vec<DataItem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub anchor: Option<Anchor>,
+}
+
+/// Account data request, via node API.
+#[derive(Deserialize)]
+struct DataRequest {
+    keys: Vec<String>,
+    #[serde(default)]
+    anchor: bool,
+}
+
+pub struct Explorer {
+    db: Arc<RwLock<RocksDb>>,
+}
+
+// Page of the sorted keys starting from `offset`.
+fn keys_page(mut keys: Vec<String>, offset: usize, limit: usize) -> Page<String> {
+    keys.sort();
+    let end = keys.len().min(offset.saturating_add(limit.max(1)));
+    let next = (end < keys.len()).then_some(end as u64);
+    Page {
+        items: keys.drain(offset.min(end)..end).collect(),
+        next,
+    }
+}
+
+// Requested page size, within the limits.
+pub(crate) fn page_size(req: &Request) -> usize {
+    req.query::<usize>("limit")
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE)
+}
+
+impl Explorer {
+    pub fn new(db: Arc<RwLock<RocksDb>>) -> Self {
+        Explorer { db }
+    }
+
+    // Transaction summary, `None` if the transaction is not stored.
+    fn tx_item(db: &RocksDb, hash: &Hash) -> Option<TxItem> {
+        let tx = db.load_transaction(hash)?;
+        let rx = db.load_receipt(hash);
+        let target = denylist::targets(&tx).first().copied();
+        Some(TxItem {
+            hash: hex::encode(hash.as_bytes()),
+            account: target.map(|(account, _)| account.to_owned()),
+            method: target.map(|(_, method)| method.to_owned()),
+            caller: tx.get_caller().to_account_id(),
+            height: rx.as_ref().map(|rx| rx.height),
+            index: rx.as_ref().map(|rx| rx.index),
+            success: rx.as_ref().map(|rx| rx.success),
+            burned_fuel: rx.as_ref().map(|rx| rx.burned_fuel),
+        })
+    }
+
+    /// Blocks within `from..=to`, ascending, at most `limit` of them.
+    /// By default the last `limit` blocks are returned.
+    pub fn blocks(&self, from: Option<u64>, to: Option<u64>, limit: usize) -> Page<BlockItem> {
+        let db = self.db.read();
+        let last = match db.load_block(u64::MAX) {
+            Some(block) => block.data.height,
+            None => return Page::default(),
+        };
+        let limit = limit.max(1) as u64;
+        let to = to.unwrap_or(last).min(last);
+        let from = from.unwrap_or_else(|| (to + 1).saturating_sub(limit));
+        if from > to {
+            return Page::default();
+        }
+        let end = to.min(from + limit - 1);
+        Page {
+            items: (from..=end)
+                .filter_map(|height| db.load_block(height))
+                .map(|block| BlockItem::from(&block))
+                .collect(),
+            next: (end < to).then_some(end + 1),
+        }
+    }
+
+    /// Transactions of a block, starting from `offset`.
+    pub fn block_txs(&self, height: u64, offset: usize, limit: usize) -> Option<Page<TxItem>> {
+        let db = self.db.read();
+        let hashes = db.load_transactions_hashes(height)?;
+        let end = hashes.len().min(offset.saturating_add(limit.max(1)));
+        let items = hashes
+            .get(offset..end)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|hash| Self::tx_item(&db, hash))
+            .collect();
+        Some(Page {
+            items,
+            next: (end < hashes.len()).then_some(end as u64),
+        })
+    }
+
+    /// Transactions targeting or called by `account`, most recent first,
+    /// walking back from the block at height `to`. Pages end at a block
+    /// boundary, `next` is the `to` of the following page.
+    pub fn account_receipts(&self, account: &str, to: Option<u64>, limit: usize) -> Page<TxItem> {
+        let db = self.db.read();
+        let last = match db.load_block(u64::MAX) {
+            Some(block) => block.data.height,
+            None => return Page::default(),
+        };
+        let to = to.unwrap_or(last).min(last);
+        let mut items = Vec::new();
+        let mut height = to;
+        loop {
+            let hashes = db.load_transactions_hashes(height).unwrap_or_default();
+            for hash in hashes.iter().rev() {
+                let matches = db
+                    .load_transaction(hash)
+                    .map(|tx| {
+                        tx.get_caller().to_account_id() == account
+                            || denylist::targets(&tx)
+                                .iter()
+                                .any(|(target, _)| *target == account)
+                    })
+                    .unwrap_or(false);
+                if matches {
+                    items.extend(Self::tx_item(&db, hash));
+                }
+            }
+            if height == 0 {
+                return Page { items, next: None };
+            }
+            height -= 1;
+            if items.len() >= limit || to - height >= MAX_SCAN_BLOCKS {
+                return Page {
+                    items,
+                    next: Some(height),
+                };
+            }
+        }
+    }
+
+    /// Data keys of an account, sorted, starting from `offset`.
+    /// `None` if the account does not exist.
+    pub fn account_keys(&self, account: &str, offset: usize, limit: usize) -> Option<Page<String>> {
+        let db = self.db.read();
+        db.load_account(account)?;
+        Some(keys_page(db.load_account_keys(account), offset, limit))
+    }
+
+    /// Data values of an account, with the state they have been read at.
+    /// `None` if the account does not exist.
+    pub fn account_data(
+        &self,
+        account: &str,
+        keys: &[String],
+        anchor: bool,
+    ) -> Option<AccountData> {
+        let db = self.db.read();
+        let data_hash = db.load_account(account)?.data_hash;
+        let items = keys
+            .iter()
+            .map(|key| DataItem {
+                key: key.clone(),
+                value: db.load_account_data(account, key).map(hex::encode),
+            })
+            .collect();
+        let anchor = anchor
+            .then(|| db.load_block(u64::MAX))
+            .flatten()
+            .map(|block| Anchor {
+                height: block.data.height,
+                block_hash: hex::encode(block.data.primary_hash().as_bytes()),
+                state_hash: hex::encode(block.data.state_hash.as_bytes()),
+                data_hash: data_hash.map(|hash| hex::encode(hash.as_bytes())),
+            });
+        Some(AccountData { items, anchor })
+    }
+
+    /// Registers the explorer routes within the node API.
+    pub fn routes(explorer: Arc<Self>, router: &mut Router) {
+        let chain = explorer.clone();
+        router.add("GET", "/api/v1/blocks", move |req: &Request| {
+            let page = chain.blocks(req.query("from"), req.query("to"), page_size(req));
+            Response::negotiate(req, &page)
+        });
+        let chain = explorer.clone();
+        router.add("GET", "/api/v1/blocks/:height/txs", move |req: &Request| {
+            let height = match req.param::<u64>("height") {
+                Some(height) => height,
+                None => return Response::error(400, "invalid block height"),
+            };
+            let offset = req.query::<usize>("offset").unwrap_or(0);
+            match chain.block_txs(height, offset, page_size(req)) {
+                Some(page) => Response::negotiate(req, &page),
+                None => Response::error(404, "Not Found"),
+            }
+        });
+        let chain = explorer.clone();
+        router.add(
+            "GET",
+            "/api/v1/account/:id/receipts",
+            move |req: &Request| {
+                let account = req.params.get("id").cloned().unwrap_or_default();
+                let page = chain.account_receipts(&account, req.query("to"), page_size(req));
+                Response::negotiate(req, &page)
+            },
+        );
+        let chain = explorer.clone();
+        router.add("GET", "/api/v1/account/:id/keys", move |req: &Request| {
+            let account = req.params.get("id").cloned().unwrap_or_default();
+            let offset = req.query::<usize>("offset").unwrap_or(0);
+            match chain.account_keys(&account, offset, page_size(req)) {
+                Some(page) => Response::negotiate(req, &page),
+                None => Response::error(404, "Not Found"),
+            }
+        });
+        router.add("POST", "/api/v1/account/:id/data", move |req: &Request| {
+            let account = req.params.get("id").cloned().unwrap_or_default();
+            let data = match req.json::<DataRequest>() {
+                Ok(data) => data,
+                Err(res) => return res,
+            };
+            if data.keys.len() > MAX_PAGE_SIZE {
+                return Response::error(400, format!("at most {} keys", MAX_PAGE_SIZE));
+            }
+            match explorer.account_data(&account, &data.keys, data.anchor) {
+                Some(data) => Response::negotiate(req, &data),
+                None => Response::error(404, "Not Found"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn account_keys_pages() {
+        let keys = vec!["c".to_string(), "a".to_string(), "b".to_string()];
+
+        let page = keys_page(keys.clone(), 0, 2);
+        assert_eq!(page.items, ["a", "b"]);
+        assert_eq!(page.next, Some(2));
+
+        let page = keys_page(keys.clone(), 2, 2);
+        assert_eq!(page.items, ["c"]);
+        assert_eq!(page.next, None);
+
+        assert!(keys_page(keys, 5, 2).items.is_empty());
+    }
+}
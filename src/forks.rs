@@ -0,0 +1,285 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Fork detection.
+//!
+//! The blocks received from the peers are compared with the other blocks
+//! seen at the same height, the locally stored one included. Competing blocks
+//! (same height, different hash) are logged as a fork report, with the
+//! validators that signed them and the peers they come from, counted in the
+//! metrics and notified by the monitor as a `fork` alert.
+//!
+//! The core does not report the reorgs of the local chain: a branch the node
+//! switched away from shows up as a competing block at its heights.
+
+use crate::api::{Request, Response, Router};
+use crate::metrics::MetricsSource;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use trinci_core::{
+    base::{serialize::rmp_deserialize, Mutex, RwLock},
+    crypto::Hashable,
+    db::{Db, RocksDb},
+    Block, Message,
+};
+
+/// Heights below the highest seen one whose blocks are remembered.
+const WINDOW_HEIGHTS: u64 = 100;
+
+/// Number of fork reports kept.
+const MAX_REPORTS: usize = 50;
+
+/// Origin of the locally stored blocks.
+const LOCAL_ORIGIN: &str = "local";
+
+/// Block competing at a height.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ForkBlock {
+    pub hash: String,
+    pub prev_hash: String,
+    /// Validator account.
+    pub validator: Option<String>,
+    pub timestamp: u64,
+    /// Peer the block was first received from, `local` for the stored one.
+    pub origin: Option<String>,
+}
+
+impl ForkBlock {
+    fn new(block: &Block, origin: Option<&str>) -> Self {
+        ForkBlock {
+            hash: hex::encode(block.data.primary_hash().as_bytes()),
+            prev_hash: hex::encode(block.data.prev_hash.as_bytes()),
+            validator: block.data.validator.as_ref().map(|key| key.to_account_id()),
+            timestamp: block.data.timestamp,
+            origin: origin.map(str::to_owned),
+        }
+    }
+}
+
+/// Competing blocks detected at a height.
+#[derive(Serialize, Clone, Debug)]
+pub struct ForkReport {
+    pub height: u64,
+    /// Blocks seen at the height, in arrival order.
+    pub blocks: Vec<ForkBlock>,
+    /// Detection time, seconds since the epoch.
+    pub time: u64,
+}
+
+impl std::fmt::Display for ForkReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} competing blocks at height {}:",
+            self.blocks.len(),
+            self.height
+        )?;
+        for block in &self.blocks {
+            write!(
+                f,
+                " [{} prev {} by {} from {}]",
+                block.hash,
+                block.prev_hash,
+                block.validator.as_deref().unwrap_or("-"),
+                block.origin.as_deref().unwrap_or("-")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Fork reports, as served by the node API.
+#[derive(Serialize)]
+struct ForksResponse {
+    total: u64,
+    reports: Vec<ForkReport>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Blocks seen by height.
+    seen: BTreeMap<u64, Vec<ForkBlock>>,
+    /// Most recent reports.
+    reports: VecDeque<ForkReport>,
+    /// Competing blocks detected.
+    total: u64,
+}
+
+#[derive(Default)]
+pub struct ForkDetector {
+    db: Option<Arc<RwLock<RocksDb>>>,
+    inner: Mutex<Inner>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl ForkDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compares the received blocks with the stored ones too.
+    pub fn with_db(mut self, db: Arc<RwLock<RocksDb>>) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Checks the blocks carried by a message received from `peer`, packed
+    /// messages included.
+    pub fn observe(&self, msg: &Message, peer: Option<&str>) {
+        match msg {
+            Message::GetBlockResponse { block, origin, .. } => {
+                self.record(block, origin.as_deref().or(peer));
+            }
+            Message::Packed { buf } => {
+                if let Ok(msg) = rmp_deserialize::<Message>(buf) {
+                    self.observe(&msg, peer);
+                } else if let Ok(msgs) = rmp_deserialize::<Vec<Message>>(buf) {
+                    msgs.iter().for_each(|msg| self.observe(msg, peer));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Stored block at `height`, the core answers the last block for the
+    // heights above it.
+    fn local_block(&self, height: u64) -> Option<ForkBlock> {
+        let block = self.db.as_ref()?.read().load_block(height)?;
+        (block.data.height == height).then(|| ForkBlock::new(&block, Some(LOCAL_ORIGIN)))
+    }
+
+    /// Records a block, returns the fork report if it competes with the
+    /// blocks already seen at its height.
+    fn record(&self, block: &Block, origin: Option<&str>) -> Option<ForkReport> {
+        let height = block.data.height;
+        let received = ForkBlock::new(block, origin);
+        let local = self.local_block(height);
+
+        let mut guard = self.inner.lock();
+        let inner = &mut *guard;
+        let last = inner.seen.keys().next_back().copied().unwrap_or_default();
+        if height + WINDOW_HEIGHTS <= last {
+            return None;
+        }
+        let blocks = inner.seen.entry(height).or_default();
+        let known = blocks.len();
+        for block in local.into_iter().chain(Some(received)) {
+            if !blocks.iter().any(|seen| seen.hash == block.hash) {
+                blocks.push(block);
+            }
+        }
+        let report = (blocks.len() > known && blocks.len() > 1).then(|| ForkReport {
+            height,
+            blocks: blocks.clone(),
+            time: now(),
+        });
+        let first = height.max(last).saturating_sub(WINDOW_HEIGHTS - 1);
+        inner.seen = inner.seen.split_off(&first);
+        let report = report?;
+        warn!("[forks] {}", report);
+        inner.total += 1;
+        if inner.reports.len() == MAX_REPORTS {
+            inner.reports.pop_front();
+        }
+        inner.reports.push_back(report.clone());
+        Some(report)
+    }
+
+    /// Competing blocks detected since the start.
+    pub fn total(&self) -> u64 {
+        self.inner.lock().total
+    }
+
+    /// Most recent report.
+    pub fn last_report(&self) -> Option<ForkReport> {
+        self.inner.lock().reports.back().cloned()
+    }
+
+    /// Registers the forks routes within the node API.
+    pub fn routes(forks: Arc<Self>, router: &mut Router) {
+        router.add("GET", "/admin/forks", move |_: &Request| {
+            let inner = forks.inner.lock();
+            Response::json(&ForksResponse {
+                total: inner.total,
+                reports: inner.reports.iter().cloned().collect(),
+            })
+        });
+    }
+}
+
+impl MetricsSource for ForkDetector {
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# TYPE trinci_forks_total counter");
+        let _ = writeln!(out, "trinci_forks_total {}", self.total());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trinci_core::{BlockData, Hash, HashAlgorithm};
+
+    fn block(height: u64, prev: &[u8]) -> Block {
+        Block {
+            data: BlockData {
+                validator: None,
+                height,
+                size: 0,
+                prev_hash: Hash::from_data(HashAlgorithm::Sha256, prev),
+                txs_hash: Hash::default(),
+                rxs_hash: Hash::default(),
+                state_hash: Hash::default(),
+                timestamp: 0,
+            },
+            signature: vec![],
+        }
+    }
+
+    #[test]
+    fn competing_blocks() {
+        let forks = ForkDetector::new();
+
+        assert!(forks.record(&block(5, b"a"), Some("peer1")).is_none());
+        assert!(forks.record(&block(5, b"a"), Some("peer2")).is_none());
+        let report = forks.record(&block(5, b"b"), Some("peer3")).unwrap();
+        assert_eq!(report.height, 5);
+        assert_eq!(report.blocks.len(), 2);
+        assert_eq!(report.blocks[0].origin.as_deref(), Some("peer1"));
+        assert_eq!(report.blocks[1].origin.as_deref(), Some("peer3"));
+        assert!(forks.record(&block(5, b"b"), Some("peer1")).is_none());
+        assert!(forks.record(&block(5, b"c"), None).is_some());
+        assert_eq!(forks.total(), 2);
+
+        // Heights out of the window are forgotten.
+        assert!(forks
+            .record(&block(5 + WINDOW_HEIGHTS, b"a"), None)
+            .is_none());
+        assert!(forks.record(&block(5, b"d"), None).is_none());
+        assert_eq!(forks.total(), 2);
+    }
+}
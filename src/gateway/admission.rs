@@ -0,0 +1,339 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Transactions admission.
+//!
+//! Node local policy applied to the transactions before they reach the
+//! unconfirmed pool: size, fuel limit, target accounts and number of pending
+//! transactions per signer account.
+//! When the pool is saturated the transactions submitted by the clients are
+//! refused with a retry hint, the P2P ones are still accepted.
+//! The pool content is not exposed by the core: the pending transactions are
+//! tracked from their admission until they are seen in a block, or for
+//! `PENDING_TTL` at most (e.g. when refused by the blockchain service).
+
+use crate::denylist;
+use crate::resources::ResourceGuard;
+use crate::tasks;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use trinci_core::{
+    base::{serialize::rmp_serialize, Mutex},
+    blockchain::{BlockRequestSender, Event, Message},
+    Hash, Transaction, TransactionData,
+};
+
+/// Max time a transaction is accounted as pending.
+const PENDING_TTL: Duration = Duration::from_secs(600);
+
+/// Blockchain subscription identifier.
+const SUBSCRIPTION_ID: &str = "admission";
+
+/// Max age of the pool size read from the blockchain service, estimated in
+/// between from the transactions admitted.
+const POOL_REFRESH: Duration = Duration::from_secs(1);
+
+/// Admission rules, the zero limits are disabled.
+#[derive(Debug, Clone, Default)]
+pub struct AdmissionConfig {
+    /// Max serialized transaction size in bytes.
+    pub max_tx_size: usize,
+    /// Max pending transactions per signer account.
+    pub max_pending: usize,
+    /// Min transaction fuel limit.
+    pub min_fuel: u64,
+    /// Target accounts allowed, empty to allow every account not blocked.
+    pub allowed_accounts: HashSet<String>,
+    /// Target accounts blocked.
+    pub blocked_accounts: HashSet<String>,
+    /// Pool size above which the client transactions are refused.
+    pub max_pool: usize,
+    /// Seconds suggested to the refused clients before retrying.
+    pub retry_after: u64,
+}
+
+/// Unconfirmed pool size estimate.
+#[derive(Default)]
+struct PoolDepth {
+    size: usize,
+    updated: Option<Instant>,
+}
+
+// Unconfirmed pool size, as reported by the blockchain service.
+pub(crate) fn pool_size(chan: &BlockRequestSender) -> Option<usize> {
+    let rx_chan = chan.send_sync(Message::GetCoreStatsRequest).ok()?;
+    match rx_chan.recv_sync() {
+        Ok(Message::GetCoreStatsResponse((_, size, _))) => Some(size),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct Pending {
+    /// Signer account and admission time of each transaction.
+    txs: HashMap<Hash, (String, Instant)>,
+    /// Pending transactions per signer account.
+    accounts: HashMap<String, usize>,
+}
+
+impl Pending {
+    fn remove(&mut self, hash: &Hash) {
+        if let Some((account, _)) = self.txs.remove(hash) {
+            if let Some(count) = self.accounts.get_mut(&account) {
+                *count -= 1;
+                if *count == 0 {
+                    self.accounts.remove(&account);
+                }
+            }
+        }
+    }
+
+    fn expire(&mut self) {
+        let expired: Vec<Hash> = self
+            .txs
+            .iter()
+            .filter(|(_, (_, time))| time.elapsed() >= PENDING_TTL)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            self.remove(&hash);
+        }
+    }
+}
+
+pub struct Admission {
+    config: AdmissionConfig,
+    pending: Mutex<Pending>,
+    pool: Mutex<PoolDepth>,
+    /// Blockchain service channel, to read the pool size.
+    pool_chan: Option<BlockRequestSender>,
+    /// Throttles the transactions when the node resources run low.
+    resources: Option<Arc<ResourceGuard>>,
+}
+
+impl Admission {
+    pub fn new(config: AdmissionConfig) -> Self {
+        Admission {
+            config,
+            pending: Mutex::new(Pending::default()),
+            pool: Mutex::new(PoolDepth::default()),
+            pool_chan: None,
+            resources: None,
+        }
+    }
+
+    /// Throttles the transactions as instructed by the resources guard.
+    pub fn with_resources(mut self, resources: Arc<ResourceGuard>) -> Self {
+        self.resources = Some(resources);
+        self
+    }
+
+    /// Reads the unconfirmed pool size through the blockchain service channel,
+    /// needed by `check_pool`.
+    pub fn with_pool(mut self, chan: BlockRequestSender) -> Self {
+        self.pool_chan = Some(chan);
+        self
+    }
+
+    /// Checks the transaction against the rules, a transaction admitted is
+    /// accounted as pending.
+    pub fn admit(&self, tx: &Transaction) -> Result<(), String> {
+        let config = &self.config;
+        if let Some(resources) = &self.resources {
+            if !resources.admit_tx() {
+                return Err("node resources low, transactions throttled".to_string());
+            }
+        }
+        if config.max_tx_size > 0 {
+            let size = rmp_serialize(tx).map(|buf| buf.len()).unwrap_or_default();
+            if size > config.max_tx_size {
+                return Err(format!(
+                    "transaction size {} above the node limit {}",
+                    size, config.max_tx_size
+                ));
+            }
+        }
+        // Bulk transactions are judged by their root fuel limit.
+        let data = match tx {
+            Transaction::UnitTransaction(tx) => &tx.data,
+            Transaction::BulkTransaction(tx) => match &tx.data {
+                TransactionData::BulkV1(bulk) => &bulk.txs.root.data,
+                data => data,
+            },
+        };
+        if data.get_fuel_limit() < config.min_fuel {
+            return Err(format!(
+                "transaction fuel limit below the node minimum {}",
+                config.min_fuel
+            ));
+        }
+        for (account, _) in denylist::targets(tx) {
+            let allowed =
+                config.allowed_accounts.is_empty() || config.allowed_accounts.contains(account);
+            if !allowed || config.blocked_accounts.contains(account) {
+                return Err(format!(
+                    "target account {} not accepted by the node",
+                    account
+                ));
+            }
+        }
+        if config.max_pool > 0 {
+            self.pool.lock().size += 1;
+        }
+        if config.max_pending == 0 {
+            return Ok(());
+        }
+
+        let hash = tx.get_primary_hash();
+        let signer = tx.get_caller().to_account_id();
+        let mut pending = self.pending.lock();
+        pending.expire();
+        if pending.txs.contains_key(&hash) {
+            return Ok(());
+        }
+        let count = pending.accounts.entry(signer.clone()).or_default();
+        if *count >= config.max_pending {
+            return Err(format!(
+                "account {} has {} transactions pending, node limit reached",
+                signer, count
+            ));
+        }
+        *count += 1;
+        pending.txs.insert(hash, (signer, Instant::now()));
+        Ok(())
+    }
+
+    /// Checks the unconfirmed pool size against `max_pool`, the error carries
+    /// the pool depth and the time to wait before retrying.
+    /// The size is read at most every `POOL_REFRESH`.
+    pub fn check_pool(&self) -> Result<(), String> {
+        let (max_pool, chan) = match &self.pool_chan {
+            Some(chan) if self.config.max_pool > 0 => (self.config.max_pool, chan),
+            _ => return Ok(()),
+        };
+        let mut pool = self.pool.lock();
+        if pool
+            .updated
+            .is_none_or(|time| time.elapsed() >= POOL_REFRESH)
+        {
+            if let Some(size) = pool_size(chan) {
+                pool.size = size;
+            }
+            pool.updated = Some(Instant::now());
+        }
+        if pool.size < max_pool {
+            return Ok(());
+        }
+        Err(format!(
+            "unconfirmed pool saturated, retry after {} seconds (pool depth {}, node limit {})",
+            self.config.retry_after, pool.size, max_pool
+        ))
+    }
+
+    /// Releases the transactions included in a block.
+    pub fn confirmed(&self, txs: &[Hash]) {
+        let mut pending = self.pending.lock();
+        for hash in txs {
+            pending.remove(hash);
+        }
+    }
+
+    /// Releases the pending transactions as the blocks are built, until the
+    /// blockchain channel is closed or the task cancelled.
+    pub fn track(admission: Arc<Self>, bc_chan: BlockRequestSender) {
+        if admission.config.max_pending == 0 {
+            return;
+        }
+        let req = Message::Subscribe {
+            id: SUBSCRIPTION_ID.to_owned(),
+            events: Event::BLOCK,
+        };
+        let rx_chan = match bc_chan.send_sync(req) {
+            Ok(rx_chan) => rx_chan,
+            Err(_) => return,
+        };
+        while let Ok(msg) = rx_chan.recv_sync() {
+            if tasks::cancelled() {
+                break;
+            }
+            if let Message::GetBlockResponse { txs: Some(txs), .. } = msg {
+                admission.confirmed(&txs);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trinci_core::{
+        crypto::{ed25519::KeyPair as Ed25519KeyPair, KeyPair},
+        SignedTransaction, TransactionDataV1,
+    };
+
+    fn transaction(keypair: &KeyPair, account: &str, nonce: u8, fuel_limit: u64) -> Transaction {
+        Transaction::UnitTransaction(SignedTransaction {
+            data: TransactionData::V1(TransactionDataV1 {
+                account: account.to_string(),
+                fuel_limit,
+                nonce: vec![nonce],
+                network: "skynet".to_string(),
+                contract: None,
+                method: "transfer".to_string(),
+                caller: keypair.public_key(),
+                args: Vec::new(),
+            }),
+            signature: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn admission_rules() {
+        let admission = Admission::new(AdmissionConfig {
+            max_pending: 2,
+            min_fuel: 100,
+            blocked_accounts: ["spam".to_string()].into_iter().collect(),
+            ..Default::default()
+        });
+        // Pending transactions are accounted per signer.
+        let keypair = KeyPair::Ed25519(Ed25519KeyPair::from_random());
+
+        assert!(admission
+            .admit(&transaction(&keypair, "TRINCI", 0, 10))
+            .is_err());
+        assert!(admission
+            .admit(&transaction(&keypair, "spam", 0, 1000))
+            .is_err());
+
+        let first = transaction(&keypair, "TRINCI", 1, 1000);
+        assert!(admission.admit(&first).is_ok());
+        assert!(admission
+            .admit(&transaction(&keypair, "TRINCI", 2, 1000))
+            .is_ok());
+        assert!(admission
+            .admit(&transaction(&keypair, "TRINCI", 3, 1000))
+            .is_err());
+
+        admission.confirmed(&[first.get_primary_hash()]);
+        assert!(admission
+            .admit(&transaction(&keypair, "TRINCI", 3, 1000))
+            .is_ok());
+    }
+}
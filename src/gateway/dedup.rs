@@ -0,0 +1,216 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! P2P gossip deduplication.
+//!
+//! Under load the same transactions reach the node several times through the
+//! gossip mesh. The duplicates refused by the blockchain service are counted
+//! and, with a cache configured (`p2p-gossip-cache`), the hashes of the last
+//! gossiped transactions are remembered: their repetitions are refused by
+//! the gateway without reaching the blockchain service.
+//!
+//! The gossip parameters (mesh fanout, heartbeat) are fixed by the core P2P
+//! service, the node can't tune them.
+
+use crate::metrics::MetricsSource;
+use crate::traffic;
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::Write,
+};
+use trinci_core::{
+    base::{serialize::rmp_deserialize, Mutex},
+    blockchain::Message,
+    ErrorKind, Hash,
+};
+
+/// Default number of gossiped transactions remembered, 0 disables the cache.
+pub const DEFAULT_GOSSIP_CACHE: usize = 0;
+
+/// Duplicated gossip counters.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct DedupStats {
+    /// Duplicates refused by the gateway cache.
+    pub cached: u64,
+    /// Duplicates refused by the blockchain service.
+    pub refused: u64,
+    /// Serialized size of the duplicates.
+    pub bytes: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    seen: HashSet<Hash>,
+    /// Cached hashes, oldest first.
+    order: VecDeque<Hash>,
+    stats: DedupStats,
+}
+
+pub struct GossipDedup {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+// Hash of the transaction carried by a gossip message.
+fn gossip_tx(msg: &Message) -> Option<Hash> {
+    let buf = match msg {
+        Message::Packed { buf } => buf,
+        _ => return None,
+    };
+    match rmp_deserialize::<Message>(buf).ok()? {
+        Message::PutTransactionRequest { tx, .. } => Some(tx.get_primary_hash()),
+        _ => None,
+    }
+}
+
+// Whether a response is the refusal of an already known transaction.
+fn is_duplicate(res: &Message) -> bool {
+    match res {
+        Message::Exception(err) => matches!(
+            err.kind,
+            ErrorKind::DuplicatedUnconfirmedTx | ErrorKind::DuplicatedConfirmedTx
+        ),
+        Message::Packed { buf } => match rmp_deserialize::<Message>(buf) {
+            Ok(Message::Exception(err)) => matches!(
+                err.kind,
+                ErrorKind::DuplicatedUnconfirmedTx | ErrorKind::DuplicatedConfirmedTx
+            ),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+impl GossipDedup {
+    pub fn new(capacity: usize) -> Self {
+        GossipDedup {
+            capacity,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Checks a gossip message against the cache, returns `true` if it
+    /// carries a transaction already received.
+    pub fn check(&self, msg: &Message) -> bool {
+        if self.capacity == 0 {
+            return false;
+        }
+        let hash = match gossip_tx(msg) {
+            Some(hash) => hash,
+            None => return false,
+        };
+        let mut inner = self.inner.lock();
+        if inner.seen.contains(&hash) {
+            inner.stats.cached += 1;
+            inner.stats.bytes += traffic::message_size(msg) as u64;
+            return true;
+        }
+        if inner.order.len() >= self.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.seen.remove(&oldest);
+            }
+        }
+        inner.seen.insert(hash);
+        inner.order.push_back(hash);
+        false
+    }
+
+    /// Accounts the blockchain service response to a message of `size` bytes.
+    pub fn observe(&self, size: usize, res: &Message) {
+        if is_duplicate(res) {
+            let mut inner = self.inner.lock();
+            inner.stats.refused += 1;
+            inner.stats.bytes += size as u64;
+        }
+    }
+
+    pub fn stats(&self) -> DedupStats {
+        self.inner.lock().stats.clone()
+    }
+}
+
+impl MetricsSource for GossipDedup {
+    fn render(&self, out: &mut String) {
+        let stats = self.stats();
+        let _ = writeln!(out, "# TYPE trinci_p2p_duplicates_total counter");
+        let _ = writeln!(
+            out,
+            "trinci_p2p_duplicates_total{{stage=\"gateway\"}} {}",
+            stats.cached
+        );
+        let _ = writeln!(
+            out,
+            "trinci_p2p_duplicates_total{{stage=\"blockchain\"}} {}",
+            stats.refused
+        );
+        let _ = writeln!(out, "# TYPE trinci_p2p_duplicate_bytes_total counter");
+        let _ = writeln!(out, "trinci_p2p_duplicate_bytes_total {}", stats.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trinci_core::{
+        base::serialize::rmp_serialize,
+        crypto::{ed25519::KeyPair as Ed25519KeyPair, KeyPair},
+        Error, SignedTransaction, Transaction, TransactionData, TransactionDataV1,
+    };
+
+    fn gossip(keypair: &KeyPair, nonce: u8) -> Message {
+        let tx = Transaction::UnitTransaction(SignedTransaction {
+            data: TransactionData::V1(TransactionDataV1 {
+                account: "TRINCI".to_string(),
+                fuel_limit: 1000,
+                nonce: vec![nonce],
+                network: "skynet".to_string(),
+                contract: None,
+                method: "transfer".to_string(),
+                caller: keypair.public_key(),
+                args: Vec::new(),
+            }),
+            signature: Vec::new(),
+        });
+        let msg = Message::PutTransactionRequest { confirm: false, tx };
+        Message::Packed {
+            buf: rmp_serialize(&msg).unwrap(),
+        }
+    }
+
+    #[test]
+    fn cache_duplicates() {
+        let keypair = KeyPair::Ed25519(Ed25519KeyPair::from_random());
+        let dedup = GossipDedup::new(1);
+
+        assert!(!dedup.check(&gossip(&keypair, 1)));
+        assert!(dedup.check(&gossip(&keypair, 1)));
+        // The oldest hash is evicted.
+        assert!(!dedup.check(&gossip(&keypair, 2)));
+        assert!(!dedup.check(&gossip(&keypair, 1)));
+
+        let err = Error::new(ErrorKind::DuplicatedUnconfirmedTx);
+        dedup.observe(100, &Message::Exception(err));
+        dedup.observe(
+            100,
+            &Message::Exception(Error::new(ErrorKind::MalformedData)),
+        );
+
+        let stats = dedup.stats();
+        assert_eq!((stats.cached, stats.refused), (1, 1));
+        assert!(stats.bytes > 100);
+    }
+}
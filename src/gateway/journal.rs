@@ -0,0 +1,377 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Unconfirmed transactions journal.
+//!
+//! The unconfirmed pool lives in the core memory only, a restart loses it.
+//! The transactions admitted by the gateway are appended to a journal in the
+//! database folder and forgotten once seen in a block, refused by the
+//! blockchain service or older than `JOURNAL_TTL`. At startup the journaled
+//! transactions are submitted again.
+//!
+//! The file is a sequence of records: kind byte, payload length (4 bytes,
+//! little endian) and payload. An incomplete record at the end of the file,
+//! left by a crash while writing, is dropped.
+
+use crate::tasks;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use trinci_core::{
+    base::{
+        serialize::{rmp_deserialize, rmp_serialize},
+        Mutex,
+    },
+    blockchain::{BlockRequestSender, Event, Message},
+    Hash, Transaction,
+};
+
+/// Journal file name within the database folder.
+pub const JOURNAL_FILE: &str = "txjournal.bin";
+
+/// Max age of a journaled transaction.
+const JOURNAL_TTL: Duration = Duration::from_secs(3600);
+
+/// Dead records tolerated before rewriting the file with the live ones.
+const COMPACT_THRESHOLD: usize = 1000;
+
+/// Blockchain subscription identifier.
+const SUBSCRIPTION_ID: &str = "journal";
+
+/// Transaction record: admission time (8 bytes, seconds since the epoch)
+/// followed by the msgpack transaction.
+const RECORD_ADD: u8 = 0;
+/// Transaction removal record: the transaction hash.
+const RECORD_REMOVE: u8 = 1;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn write_record<W: Write>(writer: &mut W, kind: u8, payload: &[u8]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(5 + payload.len());
+    buf.push(kind);
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(payload);
+    writer.write_all(&buf)
+}
+
+// Next record, `None` at the end of the file or on an incomplete record.
+fn read_record<R: Read>(reader: &mut R) -> Option<(u8, Vec<u8>)> {
+    let mut head = [0; 5];
+    reader.read_exact(&mut head).ok()?;
+    let len = u32::from_le_bytes([head[1], head[2], head[3], head[4]]) as usize;
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).ok()?;
+    Some((head[0], payload))
+}
+
+struct Journal {
+    file: File,
+    /// Live transactions by journal sequence: admission time and msgpack
+    /// transaction.
+    txs: BTreeMap<u64, (Hash, u64, Vec<u8>)>,
+    /// Sequence of each live transaction.
+    index: HashMap<Hash, u64>,
+    next: u64,
+    /// Records not describing a live transaction.
+    dead: usize,
+}
+
+/// Persistent journal of the unconfirmed transactions.
+pub struct TxJournal {
+    path: PathBuf,
+    journal: Mutex<Journal>,
+}
+
+impl Journal {
+    fn insert(&mut self, hash: Hash, time: u64, buf: Vec<u8>) {
+        if self.index.contains_key(&hash) {
+            return;
+        }
+        self.index.insert(hash, self.next);
+        self.txs.insert(self.next, (hash, time, buf));
+        self.next += 1;
+    }
+
+    fn remove(&mut self, hash: &Hash) -> bool {
+        match self.index.remove(hash) {
+            Some(seq) => {
+                self.txs.remove(&seq);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expire(&mut self) -> usize {
+        let limit = now().saturating_sub(JOURNAL_TTL.as_secs());
+        let expired: Vec<Hash> = self
+            .txs
+            .values()
+            .filter(|(_, time, _)| *time < limit)
+            .map(|(hash, _, _)| *hash)
+            .collect();
+        for hash in &expired {
+            self.remove(hash);
+        }
+        expired.len()
+    }
+}
+
+impl TxJournal {
+    /// Opens the journal stored within the given database folder.
+    pub fn open<P: AsRef<Path>>(db_path: P) -> io::Result<Self> {
+        let path = db_path.as_ref().join(JOURNAL_FILE);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut journal = Journal {
+            file: OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(&path)?,
+            txs: BTreeMap::new(),
+            index: HashMap::new(),
+            next: 0,
+            dead: 0,
+        };
+        let mut reader = io::BufReader::new(File::open(&path)?);
+        while let Some((kind, payload)) = read_record(&mut reader) {
+            match kind {
+                RECORD_ADD if payload.len() > 8 => {
+                    let (time, buf) = payload.split_at(8);
+                    let time = u64::from_le_bytes(time.try_into().unwrap_or_default());
+                    match rmp_deserialize::<Transaction>(buf) {
+                        Ok(tx) => journal.insert(tx.get_primary_hash(), time, buf.to_vec()),
+                        Err(_) => warn!("[journal] invalid transaction record dropped"),
+                    }
+                }
+                RECORD_REMOVE => {
+                    if let Ok(hash) = Hash::from_bytes(&payload) {
+                        journal.remove(&hash);
+                    }
+                }
+                _ => warn!("[journal] unknown record dropped"),
+            }
+        }
+        journal.expire();
+        let journal = TxJournal {
+            path,
+            journal: Mutex::new(journal),
+        };
+        // Drops the dead and incomplete records.
+        journal.compact(&mut journal.journal.lock())?;
+        Ok(journal)
+    }
+
+    // Rewrites the file with the live transactions only.
+    fn compact(&self, journal: &mut Journal) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut file = io::BufWriter::new(File::create(&tmp)?);
+        for (_, time, buf) in journal.txs.values() {
+            write_record(
+                &mut file,
+                RECORD_ADD,
+                &[&time.to_le_bytes(), buf.as_slice()].concat(),
+            )?;
+        }
+        file.into_inner()?.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        journal.file = OpenOptions::new().append(true).open(&self.path)?;
+        journal.dead = 0;
+        Ok(())
+    }
+
+    /// Number of journaled transactions.
+    pub fn len(&self) -> usize {
+        self.journal.lock().txs.len()
+    }
+
+    /// Records an admitted transaction, on disk before returning.
+    pub fn add(&self, tx: &Transaction) {
+        let hash = tx.get_primary_hash();
+        let mut journal = self.journal.lock();
+        if journal.index.contains_key(&hash) {
+            return;
+        }
+        let buf = match rmp_serialize(tx) {
+            Ok(buf) => buf,
+            Err(_) => return,
+        };
+        let time = now();
+        let payload = [&time.to_le_bytes(), buf.as_slice()].concat();
+        let res = write_record(&mut journal.file, RECORD_ADD, &payload)
+            .and_then(|_| journal.file.sync_data());
+        match res {
+            Ok(()) => journal.insert(hash, time, buf),
+            Err(err) => error!("[journal] write error: {}", err),
+        }
+    }
+
+    /// Forgets the transactions, either confirmed or refused.
+    pub fn remove(&self, hashes: &[Hash]) {
+        let mut journal = self.journal.lock();
+        let removed: Vec<&Hash> = hashes.iter().filter(|hash| journal.remove(hash)).collect();
+        // The expired ones are dropped by the next load.
+        let expired = journal.expire();
+        if removed.is_empty() && expired == 0 {
+            return;
+        }
+        let dead = journal.dead + 2 * removed.len() + expired;
+        // Truncated as soon as nothing is left pending.
+        let res = if journal.txs.is_empty() || dead > COMPACT_THRESHOLD {
+            self.compact(&mut journal)
+        } else {
+            journal.dead = dead;
+            // Removal records are not synced: at worst a confirmed
+            // transaction is submitted again and refused as duplicate.
+            removed.iter().try_for_each(|hash| {
+                write_record(&mut journal.file, RECORD_REMOVE, hash.as_bytes())
+            })
+        };
+        if let Err(err) = res {
+            error!("[journal] write error: {}", err);
+        }
+    }
+
+    /// Submits the journaled transactions to the blockchain service, in
+    /// admission order. The refused ones are forgotten.
+    pub fn replay(&self, bc_chan: &BlockRequestSender) {
+        let txs: Vec<Vec<u8>> = {
+            let journal = self.journal.lock();
+            journal
+                .txs
+                .values()
+                .map(|(_, _, buf)| buf.clone())
+                .collect()
+        };
+        if txs.is_empty() {
+            return;
+        }
+        info!(
+            "[journal] submitting {} unconfirmed transactions",
+            txs.len()
+        );
+        let mut refused = Vec::new();
+        for buf in txs {
+            let tx: Transaction = match rmp_deserialize(&buf) {
+                Ok(tx) => tx,
+                Err(_) => continue,
+            };
+            let hash = tx.get_primary_hash();
+            let req = Message::PutTransactionRequest { confirm: true, tx };
+            let res = match bc_chan.send_sync(req) {
+                Ok(res_chan) => res_chan.recv_sync(),
+                Err(_) => break,
+            };
+            if !matches!(res, Ok(Message::PutTransactionResponse { .. })) {
+                refused.push(hash);
+            }
+        }
+        if !refused.is_empty() {
+            debug!("[journal] {} transactions refused", refused.len());
+            self.remove(&refused);
+        }
+    }
+
+    /// Forgets the transactions as the blocks are built, until the
+    /// blockchain channel is closed or the task cancelled.
+    pub fn track(journal: Arc<Self>, bc_chan: BlockRequestSender) {
+        let req = Message::Subscribe {
+            id: SUBSCRIPTION_ID.to_owned(),
+            events: Event::BLOCK,
+        };
+        let rx_chan = match bc_chan.send_sync(req) {
+            Ok(rx_chan) => rx_chan,
+            Err(_) => return,
+        };
+        while let Ok(msg) = rx_chan.recv_sync() {
+            if tasks::cancelled() {
+                break;
+            }
+            if let Message::GetBlockResponse { txs: Some(txs), .. } = msg {
+                journal.remove(&txs);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use trinci_core::{
+        crypto::{ed25519::KeyPair as Ed25519KeyPair, KeyPair},
+        SignedTransaction, TransactionData, TransactionDataV1,
+    };
+
+    fn transaction(nonce: u8) -> Transaction {
+        let keypair = KeyPair::Ed25519(Ed25519KeyPair::from_random());
+        Transaction::UnitTransaction(SignedTransaction {
+            data: TransactionData::V1(TransactionDataV1 {
+                account: "TRINCI".to_string(),
+                fuel_limit: 1000,
+                nonce: vec![nonce],
+                network: "skynet".to_string(),
+                contract: None,
+                method: "transfer".to_string(),
+                caller: keypair.public_key(),
+                args: Vec::new(),
+            }),
+            signature: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn reopen_and_truncate() {
+        let dir = TempDir::new().unwrap();
+        let journal = TxJournal::open(dir.path()).unwrap();
+        let (first, second) = (transaction(1), transaction(2));
+        journal.add(&first);
+        journal.add(&second);
+        journal.add(&first);
+        journal.remove(&[first.get_primary_hash()]);
+        drop(journal);
+
+        // Crash while writing a record.
+        let path = dir.path().join(JOURNAL_FILE);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[RECORD_ADD, 100, 0]).unwrap();
+
+        let journal = TxJournal::open(dir.path()).unwrap();
+        assert_eq!(journal.len(), 1);
+        assert!(journal
+            .journal
+            .lock()
+            .index
+            .contains_key(&second.get_primary_hash()));
+
+        journal.remove(&[second.get_primary_hash()]);
+        assert_eq!(journal.len(), 0);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+    }
+}
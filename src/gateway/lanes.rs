@@ -0,0 +1,446 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Transactions priority lanes.
+//!
+//! The block service selects the block transactions from the unconfirmed
+//! pool in arrival order. When a priority policy is set and the pool already
+//! holds a block worth of transactions (the `block_threshold` setting), the
+//! transactions are held by the gateway and released into the pool as it
+//! drains, so the blocks are filled in policy order: the ones targeting the
+//! service account (governance) first, then the others by policy:
+//!
+//! - `fee`: higher fuel limit first;
+//! - `fair`: round robin across the signer accounts.
+//!
+//! Both the client and the P2P transactions are held, the sender gets the
+//! response once its transaction is released. The packed bridge requests
+//! carrying several messages are not.
+//!
+//! The policy comes from the `pool-priority` setting, overridden by the
+//! `pool_priority` field of the service account `blockchain:settings` data
+//! when the service contract sets it. The core settings don't carry the
+//! field, it is read by the node alongside them.
+
+use crate::config::SERVICE_ACCOUNT_ID;
+use crate::denylist;
+use crate::gateway::admission;
+use crate::metrics::MetricsSource;
+use crate::tasks;
+use serde::{de::IgnoredAny, Deserialize};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::Arc,
+    time::Duration,
+};
+use trinci_core::{
+    base::{serialize::rmp_deserialize, Mutex},
+    blockchain::{BlockRequestSender, BlockResponseSender, Message},
+    db::{Db, RocksDb},
+    Transaction, TransactionData,
+};
+
+/// Interval between two pool size reads while transactions are held.
+const RELEASE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Max transactions held, the exceeding ones go straight to the pool.
+const MAX_HELD: usize = 10_000;
+
+/// Service account data key of the blockchain settings.
+const SETTINGS_KEY: &str = "blockchain:settings";
+
+/// Transactions release order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LanePolicy {
+    /// Arrival order, nothing is held.
+    Fifo,
+    /// Higher fuel limit first.
+    Fee,
+    /// Round robin across the signer accounts.
+    Fair,
+}
+
+impl std::str::FromStr for LanePolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "fifo" => Ok(LanePolicy::Fifo),
+            "fee" => Ok(LanePolicy::Fee),
+            "fair" => Ok(LanePolicy::Fair),
+            _ => Err(format!(
+                "invalid pool priority `{}` (expected fifo, fee or fair)",
+                value
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for LanePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let policy = match self {
+            LanePolicy::Fifo => "fifo",
+            LanePolicy::Fee => "fee",
+            LanePolicy::Fair => "fair",
+        };
+        write!(f, "{}", policy)
+    }
+}
+
+/// Governance flag, fuel limit and signer account of a transaction.
+type Rank = (bool, u64, String);
+
+fn rank(tx: &Transaction) -> Rank {
+    // Bulk transactions are judged by their root.
+    let data = match tx {
+        Transaction::UnitTransaction(tx) => &tx.data,
+        Transaction::BulkTransaction(tx) => match &tx.data {
+            TransactionData::BulkV1(bulk) => &bulk.txs.root.data,
+            data => data,
+        },
+    };
+    let governance = denylist::targets(tx)
+        .iter()
+        .any(|(account, _)| *account == SERVICE_ACCOUNT_ID);
+    (
+        governance,
+        data.get_fuel_limit(),
+        tx.get_caller().to_account_id(),
+    )
+}
+
+// Transaction carried by a request, packed as the P2P gossip or not.
+fn request_tx(req: &Message) -> Option<Transaction> {
+    match req {
+        Message::PutTransactionRequest { tx, .. } => Some(tx.clone()),
+        Message::Packed { buf } => match rmp_deserialize::<Message>(buf).ok()? {
+            Message::PutTransactionRequest { tx, .. } => Some(tx),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Blockchain settings as stored by the service contract, in the core field
+/// order (array encoding) or by name (map encoding), followed by the
+/// optional release policy.
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct LaneSettings {
+    #[serde(default)]
+    accept_broadcast: IgnoredAny,
+    #[serde(default)]
+    block_threshold: IgnoredAny,
+    #[serde(default)]
+    block_timeout: IgnoredAny,
+    #[serde(default)]
+    burning_fuel_method: IgnoredAny,
+    #[serde(default)]
+    network_name: IgnoredAny,
+    #[serde(default)]
+    is_production: IgnoredAny,
+    #[serde(default)]
+    min_node_version: IgnoredAny,
+    #[serde(default)]
+    pool_priority: Option<String>,
+}
+
+/// Release policy set by the service account settings, if any.
+pub fn settings_policy(db: &RocksDb) -> Option<LanePolicy> {
+    let buf = db.load_account_data(SERVICE_ACCOUNT_ID, SETTINGS_KEY)?;
+    decode_policy(&buf)
+}
+
+fn decode_policy(buf: &[u8]) -> Option<LanePolicy> {
+    let settings = rmp_deserialize::<LaneSettings>(buf).ok()?;
+    match settings.pool_priority?.parse() {
+        Ok(policy) => Some(policy),
+        Err(err) => {
+            warn!("[lanes] service account settings: {}", err);
+            None
+        }
+    }
+}
+
+struct Held<T> {
+    governance: bool,
+    fuel: u64,
+    signer: String,
+    seq: u64,
+    item: T,
+}
+
+/// Held transactions, in release order.
+struct LaneQueue<T> {
+    policy: LanePolicy,
+    held: Vec<Held<T>>,
+    seq: u64,
+    /// Transactions released per signer since the queue was last empty.
+    served: HashMap<String, u64>,
+}
+
+impl<T> LaneQueue<T> {
+    fn new(policy: LanePolicy) -> Self {
+        LaneQueue {
+            policy,
+            held: Vec::new(),
+            seq: 0,
+            served: HashMap::new(),
+        }
+    }
+
+    fn push(&mut self, (governance, fuel, signer): Rank, item: T) {
+        self.seq += 1;
+        self.held.push(Held {
+            governance,
+            fuel,
+            signer,
+            seq: self.seq,
+            item,
+        });
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let served = &self.served;
+        let policy = self.policy;
+        let (index, _) = self.held.iter().enumerate().min_by_key(|(_, held)| {
+            let rank = match policy {
+                LanePolicy::Fifo => 0,
+                LanePolicy::Fee => u64::MAX - held.fuel,
+                LanePolicy::Fair => served.get(&held.signer).copied().unwrap_or_default(),
+            };
+            (!held.governance, rank, held.seq)
+        })?;
+        let held = self.held.remove(index);
+        if self.held.is_empty() {
+            self.served.clear();
+        } else if !held.governance {
+            *self.served.entry(held.signer).or_default() += 1;
+        }
+        Some(held.item)
+    }
+
+    fn len(&self) -> usize {
+        self.held.len()
+    }
+}
+
+/// Priority lanes in front of the unconfirmed pool.
+pub struct Lanes {
+    /// Pool size a block is built at, from the blockchain settings.
+    threshold: AtomicUsize,
+    /// Last pool size read, increased by the transactions not held.
+    pool: AtomicUsize,
+    queue: Mutex<LaneQueue<(Message, BlockResponseSender)>>,
+}
+
+impl Lanes {
+    pub fn new(policy: LanePolicy, block_threshold: usize) -> Self {
+        Lanes {
+            threshold: AtomicUsize::new(block_threshold),
+            pool: AtomicUsize::new(0),
+            queue: Mutex::new(LaneQueue::new(policy)),
+        }
+    }
+
+    /// Updates the block threshold, on blockchain settings change.
+    pub fn set_block_threshold(&self, block_threshold: usize) {
+        self.threshold.store(block_threshold, Ordering::Relaxed);
+    }
+
+    /// Release policy in use.
+    pub fn policy(&self) -> LanePolicy {
+        self.queue.lock().policy
+    }
+
+    /// Updates the release policy, on blockchain settings change. The
+    /// transactions already held are released in the new order.
+    pub fn set_policy(&self, policy: LanePolicy) {
+        let mut queue = self.queue.lock();
+        if queue.policy != policy {
+            info!("[lanes] pool priority: {}", policy);
+            queue.policy = policy;
+        }
+    }
+
+    /// Number of transactions held.
+    pub fn held(&self) -> usize {
+        self.queue.lock().len()
+    }
+
+    /// Holds a transaction request while the pool is backlogged, the
+    /// requests not held are given back to be forwarded.
+    pub fn hold(
+        &self,
+        req: Message,
+        res_chan: BlockResponseSender,
+    ) -> Option<(Message, BlockResponseSender)> {
+        if self.policy() == LanePolicy::Fifo {
+            return Some((req, res_chan));
+        }
+        let rank = match request_tx(&req) {
+            Some(tx) => rank(&tx),
+            None => return Some((req, res_chan)),
+        };
+        let mut queue = self.queue.lock();
+        let backlogged = queue.len() > 0
+            || self.pool.load(Ordering::Relaxed) >= self.threshold.load(Ordering::Relaxed);
+        if !backlogged || queue.len() >= MAX_HELD {
+            self.pool.fetch_add(1, Ordering::Relaxed);
+            return Some((req, res_chan));
+        }
+        queue.push(rank, (req, res_chan));
+        None
+    }
+
+    /// Releases the held transactions to the gateway as the pool drains,
+    /// until the gateway channel is closed or the task cancelled.
+    pub fn run(lanes: Arc<Self>, gw_chan: BlockRequestSender) {
+        while tasks::sleep(RELEASE_INTERVAL) {
+            if lanes.held() == 0 && lanes.policy() == LanePolicy::Fifo {
+                continue;
+            }
+            let size = match admission::pool_size(&gw_chan) {
+                Some(size) => size,
+                None => continue,
+            };
+            lanes.pool.store(size, Ordering::Relaxed);
+            let room = lanes.threshold.load(Ordering::Relaxed).saturating_sub(size);
+            for _ in 0..room {
+                let (req, res_chan) = match lanes.queue.lock().pop() {
+                    Some(held) => held,
+                    None => break,
+                };
+                let gw_res = match gw_chan.send_sync(req) {
+                    Ok(gw_res) => gw_res,
+                    Err(_) => return,
+                };
+                lanes.pool.fetch_add(1, Ordering::Relaxed);
+                if let Ok(res) = gw_res.recv_sync() {
+                    let _ = res_chan.send_sync(res);
+                }
+            }
+        }
+    }
+}
+
+impl MetricsSource for Lanes {
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# TYPE trinci_lanes_held_transactions gauge");
+        let _ = writeln!(out, "trinci_lanes_held_transactions {}", self.held());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Serialize;
+    use trinci_core::{
+        base::BlockchainSettings,
+        crypto::{ed25519::KeyPair as Ed25519KeyPair, KeyPair},
+        SignedTransaction, TransactionDataV1,
+    };
+
+    fn transaction(keypair: &KeyPair, account: &str, fuel_limit: u64) -> Transaction {
+        Transaction::UnitTransaction(SignedTransaction {
+            data: TransactionData::V1(TransactionDataV1 {
+                account: account.to_string(),
+                fuel_limit,
+                nonce: vec![0],
+                network: "skynet".to_string(),
+                contract: None,
+                method: "transfer".to_string(),
+                caller: keypair.public_key(),
+                args: Vec::new(),
+            }),
+            signature: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn release_order() {
+        let alice = KeyPair::Ed25519(Ed25519KeyPair::from_random());
+        let bob = KeyPair::Ed25519(Ed25519KeyPair::from_random());
+        let txs = [
+            (&alice, "asset", 100),
+            (&alice, "asset", 300),
+            (&alice, "asset", 200),
+            (&bob, "asset", 50),
+            (&bob, SERVICE_ACCOUNT_ID, 10),
+        ];
+        let order = |policy| {
+            let mut queue = LaneQueue::new(policy);
+            for (index, (keypair, account, fuel)) in txs.iter().enumerate() {
+                queue.push(rank(&transaction(keypair, account, *fuel)), index);
+            }
+            std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(order(LanePolicy::Fifo), vec![4, 0, 1, 2, 3]);
+        assert_eq!(order(LanePolicy::Fee), vec![4, 1, 2, 0, 3]);
+        assert_eq!(order(LanePolicy::Fair), vec![4, 0, 3, 1, 2]);
+    }
+
+    #[test]
+    fn policy_from_settings() {
+        #[derive(Serialize)]
+        struct Settings {
+            accept_broadcast: bool,
+            block_threshold: usize,
+            block_timeout: u16,
+            burning_fuel_method: String,
+            network_name: Option<String>,
+            is_production: bool,
+            min_node_version: String,
+            pool_priority: Option<String>,
+        }
+        let settings = |pool_priority: Option<&str>| Settings {
+            accept_broadcast: true,
+            block_threshold: 42,
+            block_timeout: 3,
+            burning_fuel_method: String::new(),
+            network_name: Some("skynet".to_string()),
+            is_production: false,
+            min_node_version: "0.2.7".to_string(),
+            pool_priority: pool_priority.map(str::to_string),
+        };
+
+        let named = rmp_serde::to_vec_named(&settings(Some("fee"))).unwrap();
+        assert_eq!(decode_policy(&named), Some(LanePolicy::Fee));
+        let array = rmp_serde::to_vec(&settings(Some("fair"))).unwrap();
+        assert_eq!(decode_policy(&array), Some(LanePolicy::Fair));
+        let invalid = rmp_serde::to_vec(&settings(Some("lifo"))).unwrap();
+        assert_eq!(decode_policy(&invalid), None);
+        let unset = rmp_serde::to_vec(&settings(None)).unwrap();
+        assert_eq!(decode_policy(&unset), None);
+
+        // Settings stored by a service contract unaware of the field.
+        let core = trinci_core::base::serialize::rmp_serialize(&BlockchainSettings {
+            accept_broadcast: true,
+            block_threshold: 42,
+            block_timeout: 3,
+            burning_fuel_method: String::new(),
+            network_name: Some("skynet".to_string()),
+            is_production: false,
+            min_node_version: "0.2.7".to_string(),
+        })
+        .unwrap();
+        assert_eq!(decode_policy(&core), None);
+    }
+}
@@ -0,0 +1,215 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+#[cfg(feature = "chaos")]
+use crate::chaos::Chaos;
+use crate::checkpoints::Checkpoints;
+use crate::control::NodeControl;
+use crate::correlation::Correlation;
+use crate::denylist::Denylist;
+use crate::forks::ForkDetector;
+use crate::gateway::admission::Admission;
+use crate::gateway::dedup::GossipDedup;
+use crate::gateway::journal::TxJournal;
+use crate::gateway::lanes::Lanes;
+use crate::gateway::worker::{self, GatewayWorker, Tap};
+use crate::metrics::Metrics;
+use crate::peers::PeerFilter;
+use crate::reputation::Reputation;
+use crate::tasks::{self, Tasks};
+use crate::traffic::Traffic;
+use std::{
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+use trinci_core::{
+    base::{Mutex, RwLock},
+    blockchain::{BlockRequestSender, Message},
+    channel::confirmed_channel,
+    Error, ErrorKind,
+};
+
+/// Node components the gateway checks and accounts the requests with.
+#[derive(Clone)]
+pub struct GatewayContext {
+    /// Transactions denylist
+    pub denylist: Arc<Mutex<Denylist>>,
+    /// Requests metrics
+    pub metrics: Arc<Metrics>,
+    /// Node lifecycle control
+    pub control: Arc<NodeControl>,
+    /// P2P peers filter
+    pub peers: Arc<RwLock<PeerFilter>>,
+    /// P2P peers reputation
+    pub reputation: Arc<Reputation>,
+    /// Transactions admission rules
+    pub admission: Arc<Admission>,
+    /// Transactions priority lanes
+    pub lanes: Arc<Lanes>,
+    /// Unconfirmed transactions journal
+    pub journal: Option<Arc<TxJournal>>,
+    /// P2P traffic statistics
+    pub traffic: Arc<Traffic>,
+    /// P2P gossip duplicates
+    pub dedup: Arc<GossipDedup>,
+    /// Competing blocks detection
+    pub forks: Arc<ForkDetector>,
+    /// Trusted checkpoints
+    pub checkpoints: Arc<Checkpoints>,
+    /// Requests tracing
+    pub correlation: Option<Arc<Correlation>>,
+    /// Fault injection
+    #[cfg(feature = "chaos")]
+    pub chaos: Arc<Chaos>,
+}
+
+pub struct GatewayService {
+    /// Worker object
+    worker: Option<GatewayWorker>,
+    /// Worker thread handler
+    handler: Option<JoinHandle<GatewayWorker>>,
+    /// To check if the worker still alive
+    canary: Arc<()>,
+    /// Worker input channel
+    chan: BlockRequestSender,
+    /// Gateway workers supervision
+    tasks: Tasks,
+    /// Node services channels, closed when the service stops
+    taps: Mutex<Vec<BlockRequestSender>>,
+    /// Node components shared with the workers
+    context: GatewayContext,
+}
+
+impl GatewayService {
+    pub fn new(bc_chan: BlockRequestSender, context: GatewayContext, tasks: Tasks) -> Self {
+        let (chan, rx_chan) = confirmed_channel::<Message, Message>();
+        let track_chan = bc_chan.clone();
+        let track_admission = context.admission.clone();
+        tasks.spawn("admission", move || {
+            Admission::track(track_admission, track_chan)
+        });
+        if let Some(journal) = context.journal.clone() {
+            let track_chan = bc_chan.clone();
+            tasks.spawn("journal", move || TxJournal::track(journal, track_chan));
+        }
+        let release_lanes = context.lanes.clone();
+        let release_chan = chan.clone();
+        tasks.spawn("lanes", move || Lanes::run(release_lanes, release_chan));
+        let worker = GatewayWorker::new(
+            rx_chan,
+            bc_chan,
+            context.denylist.clone(),
+            context.admission.clone(),
+            context.journal.clone(),
+        );
+
+        GatewayService {
+            worker: Some(worker),
+            handler: None,
+            canary: Arc::new(()),
+            chan,
+            tasks,
+            taps: Mutex::new(Vec::new()),
+            context,
+        }
+    }
+
+    /// Get a channel to be handed to a node service in place of the
+    /// blockchain one. Requests sent through it are accounted in the
+    /// metrics under the `source` label. While the node is draining the
+    /// requests not coming from P2P are refused, the P2P ones are checked
+    /// against the peers filter and the bans, accounted in the P2P traffic
+    /// and their refusals scored in the peers reputation, the duplicated
+    /// gossip is counted and dropped when cached, the blocks are checked for
+    /// forks and the ones conflicting with the checkpoints refused. The
+    /// transactions not coming from P2P are refused while the unconfirmed
+    /// pool is saturated, all of them may be held by the priority lanes while
+    /// the pool is backlogged. Requests are traced when enabled.
+    pub fn request_channel(&self, source: &'static str) -> BlockRequestSender {
+        let (chan, rx_chan) = confirmed_channel::<Message, Message>();
+        let gw_chan = self.chan.clone();
+        let tap = Tap::new(source, &self.context);
+        self.taps.lock().push(chan.clone());
+        self.tasks
+            .spawn("gateway_tap", move || worker::tap(tap, rx_chan, gw_chan));
+        chan
+    }
+
+    /// Start gateway service if not already running
+    pub fn start(&mut self) {
+        debug!("Starting GATEWAY service");
+
+        let mut worker = match self.worker.take() {
+            Some(worker) => worker,
+            None => {
+                warn!("Service was already running");
+                return;
+            }
+        };
+
+        let mut canary = Arc::clone(&self.canary);
+        let handle = thread::spawn(move || {
+            let _ = Arc::get_mut(&mut canary);
+            worker.run();
+            worker
+        });
+        self.handler = Some(handle)
+    }
+
+    /// Stop gateway service
+    pub fn stop(&mut self) {
+        debug!("Stopping GATEWAY service");
+        let handle = match self.handler.take() {
+            Some(handle) => handle,
+            None => {
+                debug!("Service not running");
+                return;
+            }
+        };
+        if self.chan.send_sync(Message::Stop).is_err() {
+            warn!("[gateway] worker channel closed");
+        }
+        for tap in self.taps.lock().drain(..) {
+            let _ = tap.send_sync(Message::Stop);
+        }
+        match handle.join() {
+            Ok(worker) => self.worker = Some(worker),
+            Err(_) => error!("[gateway] worker thread panicked"),
+        }
+    }
+
+    /// Check if gateway is running
+    pub fn is_running(&self) -> bool {
+        Arc::strong_count(&self.canary) == 2
+    }
+}
+
+/// Channel answering every request with an error, in place of the blockchain
+/// one when the node doesn't run the blockchain service (relay role).
+pub fn sink(tasks: &Tasks, reason: &'static str) -> BlockRequestSender {
+    let (chan, rx_chan) = confirmed_channel::<Message, Message>();
+    tasks.spawn("gateway_sink", move || {
+        while let Ok((_req, res_chan)) = rx_chan.recv_sync() {
+            if tasks::cancelled() {
+                break;
+            }
+            let err = Error::new_ext(ErrorKind::Other, reason);
+            let _ = res_chan.send_sync(Message::Exception(err));
+        }
+    });
+    chan
+}
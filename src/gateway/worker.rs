@@ -0,0 +1,425 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+#[cfg(feature = "chaos")]
+use crate::chaos::Chaos;
+use crate::checkpoints::Checkpoints;
+use crate::control::NodeControl;
+use crate::correlation::Correlation;
+use crate::denylist::Denylist;
+use crate::forks::ForkDetector;
+use crate::gateway::admission::Admission;
+use crate::gateway::dedup::GossipDedup;
+use crate::gateway::journal::TxJournal;
+use crate::gateway::lanes::Lanes;
+use crate::gateway::service::GatewayContext;
+use crate::metrics::{self, Metrics};
+use crate::peers::{self, PeerFilter};
+use crate::reputation::{self, Offense, Reputation};
+use crate::tasks;
+use crate::traffic::{self, Direction, Traffic};
+use std::{
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use trinci_core::{
+    base::{
+        serialize::{rmp_deserialize, rmp_serialize},
+        Mutex, RwLock,
+    },
+    blockchain::{
+        BlockRequestReceiver, BlockRequestSender, BlockResponseReceiver, BlockResponseSender,
+        Message,
+    },
+    Error, ErrorKind, Result,
+};
+
+pub struct GatewayWorker {
+    /// Requests coming from the node services.
+    rx_chan: BlockRequestReceiver,
+    /// Blockchain service channel.
+    bc_chan: BlockRequestSender,
+    /// Transactions denylist.
+    denylist: Arc<Mutex<Denylist>>,
+    /// Transactions admission rules.
+    admission: Arc<Admission>,
+    /// Unconfirmed transactions journal.
+    journal: Option<Arc<TxJournal>>,
+}
+
+// Relays the responses of a subscription until one of the two sides closes.
+fn relay(
+    bc_res: BlockResponseReceiver,
+    res_chan: BlockResponseSender,
+    traffic: Option<Arc<Traffic>>,
+) {
+    while let Ok(res) = bc_res.recv_sync() {
+        if let Some(traffic) = &traffic {
+            let kind = metrics::message_kind(&res);
+            traffic.record(Direction::Sent, None, kind, traffic::message_size(&res));
+        }
+        if res_chan.send_sync(res).is_err() {
+            break;
+        }
+    }
+}
+
+// Refuses the transactions while the unconfirmed pool is saturated, the
+// other requests are served.
+fn check_pool(req: &Message, admission: &Admission) -> Option<String> {
+    let packed = match req {
+        Message::PutTransactionRequest { .. } => None,
+        Message::Packed { buf } => Some(buf),
+        _ => return None,
+    };
+    let reason = admission.check_pool().err()?;
+    if let Some(buf) = packed {
+        let is_tx = |msg: &Message| matches!(msg, Message::PutTransactionRequest { .. });
+        let txs = match rmp_deserialize::<Message>(buf) {
+            Ok(msg) => is_tx(&msg),
+            Err(_) => rmp_deserialize::<Vec<Message>>(buf).is_ok_and(|msgs| msgs.iter().any(is_tx)),
+        };
+        if !txs {
+            return None;
+        }
+    }
+    Some(reason)
+}
+
+/// Components a tap checks and accounts the requests of a node service
+/// with, the peers related ones are given to the P2P tap only.
+pub(crate) struct Tap {
+    /// Node service name, the metrics label.
+    source: &'static str,
+    metrics: Arc<Metrics>,
+    control: Arc<NodeControl>,
+    peers: Arc<RwLock<PeerFilter>>,
+    admission: Arc<Admission>,
+    lanes: Arc<Lanes>,
+    correlation: Option<Arc<Correlation>>,
+    reputation: Option<Arc<Reputation>>,
+    traffic: Option<Arc<Traffic>>,
+    dedup: Option<Arc<GossipDedup>>,
+    forks: Option<Arc<ForkDetector>>,
+    checkpoints: Option<Arc<Checkpoints>>,
+    #[cfg(feature = "chaos")]
+    chaos: Arc<Chaos>,
+}
+
+impl Tap {
+    pub(crate) fn new(source: &'static str, context: &GatewayContext) -> Self {
+        let p2p = source == "p2p";
+        Tap {
+            source,
+            metrics: context.metrics.clone(),
+            control: context.control.clone(),
+            peers: context.peers.clone(),
+            admission: context.admission.clone(),
+            lanes: context.lanes.clone(),
+            correlation: context.correlation.clone(),
+            reputation: p2p.then(|| context.reputation.clone()),
+            traffic: p2p.then(|| context.traffic.clone()),
+            dedup: p2p.then(|| context.dedup.clone()),
+            forks: p2p.then(|| context.forks.clone()),
+            checkpoints: p2p.then(|| context.checkpoints.clone()),
+            #[cfg(feature = "chaos")]
+            chaos: context.chaos.clone(),
+        }
+    }
+}
+
+/// Forwards the requests of a single node service to the gateway, measuring
+/// the time taken to get the response. Terminates when the service drops
+/// its channel, the gateway is stopped (`Stop` message) or the task is
+/// cancelled.
+/// When `traffic` is given the exchanged messages are accounted there too,
+/// with `reputation` the peers messages refused as invalid are scored and
+/// with `dedup` the duplicated gossip is counted, or dropped when cached,
+/// with `forks` the blocks received are checked against the competing ones
+/// and with `checkpoints` the blocks conflicting with them are refused.
+/// The transactions may be held by the priority `lanes`, that
+/// forward them later on. With `correlation` the requests forwarded are
+/// traced.
+pub(crate) fn tap(tap: Tap, rx_chan: BlockRequestReceiver, gw_chan: BlockRequestSender) {
+    let Tap {
+        source,
+        metrics,
+        control,
+        peers,
+        admission,
+        lanes,
+        correlation,
+        reputation,
+        traffic,
+        dedup,
+        forks,
+        checkpoints,
+        #[cfg(feature = "chaos")]
+        chaos,
+    } = tap;
+    while let Ok((req, res_chan)) = rx_chan.recv_sync() {
+        if matches!(req, Message::Stop) || tasks::cancelled() {
+            break;
+        }
+        let kind = metrics::message_kind(&req);
+        // Requests carrying a destination come from that peer, the response
+        // goes back to it.
+        let peer = (traffic.is_some() || reputation.is_some() || forks.is_some())
+            .then(|| peers::message_peer(&req).map(str::to_owned))
+            .flatten();
+        if let Some(traffic) = &traffic {
+            let size = traffic::message_size(&req);
+            traffic.record(Direction::Received, peer.as_deref(), kind, size);
+            traffic.observe_blocks(&req);
+        }
+        if let Some(forks) = &forks {
+            forks.observe(&req, peer.as_deref());
+        }
+        #[cfg(feature = "chaos")]
+        if source == "p2p" {
+            if chaos.drop_p2p() {
+                debug!("[chaos] {} dropped", kind);
+                continue;
+            }
+            if let Some(delay) = chaos.p2p_delay(&req) {
+                thread::sleep(delay);
+            }
+        }
+        let refused = if source == "p2p" {
+            let banned = || reputation.as_ref().and_then(|rep| rep.check(&req));
+            let conflict = || {
+                let reason = checkpoints.as_ref()?.check(&req)?;
+                warn!(
+                    "[gateway] {} from peer {} refused: {}",
+                    kind,
+                    peer.as_deref().unwrap_or("unknown"),
+                    reason
+                );
+                if let (Some(reputation), Some(peer)) = (&reputation, &peer) {
+                    reputation.report(peer, Offense::CheckpointConflict);
+                }
+                Some(reason)
+            };
+            peers
+                .read()
+                .check(&req)
+                .or_else(banned)
+                .map(|peer| {
+                    debug!("[gateway] {} from peer {} refused", kind, peer);
+                    format!("peer {} refused", peer)
+                })
+                .or_else(conflict)
+        } else if control.is_draining() {
+            Some("node draining, retry on another node".to_string())
+        } else {
+            check_pool(&req, &admission)
+        };
+        if let Some(reason) = refused {
+            metrics.observe(source, kind, Duration::ZERO, true);
+            let err = Error::new_ext(ErrorKind::Other, reason);
+            let _ = res_chan.send_sync(Message::Exception(err));
+            continue;
+        }
+        if dedup.as_ref().map(|dedup| dedup.check(&req)) == Some(true) {
+            metrics.observe(source, kind, Duration::ZERO, true);
+            let err = Error::new(ErrorKind::DuplicatedUnconfirmedTx);
+            let _ = res_chan.send_sync(Message::Exception(err));
+            continue;
+        }
+        let size = dedup.as_ref().map(|_| traffic::message_size(&req));
+        let (req, res_chan) = match lanes.hold(req, res_chan) {
+            Some(req) => req,
+            None => continue,
+        };
+        let subscribe = matches!(req, Message::Subscribe { .. });
+        // Peer offense if the request is refused as invalid.
+        let offense = reputation
+            .as_ref()
+            .and(peer.as_ref())
+            .and_then(|_| reputation::offense(&req));
+        let span = correlation
+            .as_ref()
+            .map(|correlation| correlation.start(source, kind, &req));
+        let start = Instant::now();
+        let gw_res = match gw_chan.send_sync(req) {
+            Ok(gw_res) => gw_res,
+            Err(_) => break,
+        };
+        if subscribe {
+            metrics.observe(source, kind, start.elapsed(), false);
+            if let (Some(correlation), Some(span)) = (&correlation, span) {
+                correlation.end(span, false);
+            }
+            let traffic = traffic.clone();
+            thread::spawn(move || relay(gw_res, res_chan, traffic));
+            continue;
+        }
+        let res = gw_res.recv_sync();
+        if let (Some(reputation), Some(peer), Some(offense), Ok(Message::Exception(err))) =
+            (&reputation, &peer, offense, &res)
+        {
+            if reputation::is_peer_fault(err) {
+                reputation.report(peer, offense);
+            }
+        }
+        if let (Some(dedup), Some(size), Ok(res)) = (&dedup, size, &res) {
+            dedup.observe(size, res);
+        }
+        let error = matches!(res, Ok(Message::Exception(_)) | Err(_));
+        metrics.observe(source, kind, start.elapsed(), error);
+        if let (Some(correlation), Some(span)) = (&correlation, span) {
+            correlation.end(span, error);
+        }
+        if let Ok(res) = res {
+            if let Some(traffic) = &traffic {
+                let size = traffic::message_size(&res);
+                traffic.record(Direction::Sent, peer.as_deref(), kind, size);
+            }
+            let _ = res_chan.send_sync(res);
+        }
+    }
+}
+
+impl GatewayWorker {
+    pub fn new(
+        rx_chan: BlockRequestReceiver,
+        bc_chan: BlockRequestSender,
+        denylist: Arc<Mutex<Denylist>>,
+        admission: Arc<Admission>,
+        journal: Option<Arc<TxJournal>>,
+    ) -> Self {
+        GatewayWorker {
+            rx_chan,
+            bc_chan,
+            denylist,
+            admission,
+            journal,
+        }
+    }
+
+    /// Applies the node policies to a single (unpacked) message.
+    fn check(&self, msg: &Message) -> Result<()> {
+        if let Message::PutTransactionRequest { tx, .. } = msg {
+            let mut denylist = self.denylist.lock();
+            denylist.refresh();
+            if let Some(entry) = denylist.check(tx) {
+                let hash = hex::encode(tx.get_primary_hash().as_bytes());
+                warn!(
+                    "[gateway] transaction {} refused, denylist entry {} ({})",
+                    hash, entry.id, entry.reason
+                );
+                return Err(Error::new_ext(
+                    ErrorKind::Other,
+                    format!("transaction refused by node denylist (entry {})", entry.id),
+                ));
+            }
+            drop(denylist);
+            if let Err(reason) = self.admission.admit(tx) {
+                debug!(
+                    "[gateway] transaction {} refused, {}",
+                    hex::encode(tx.get_primary_hash().as_bytes()),
+                    reason
+                );
+                return Err(Error::new_ext(ErrorKind::Other, reason));
+            }
+            if let Some(journal) = &self.journal {
+                journal.add(tx);
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the node policies to a request.
+    /// Packed requests are opened and the refused messages dropped.
+    fn filter(&self, req: Message) -> Result<Message> {
+        let buf = match req {
+            Message::Packed { buf } => buf,
+            req => return self.check(&req).map(|_| req),
+        };
+        if let Ok(msg) = rmp_deserialize::<Message>(&buf) {
+            self.check(&msg)?;
+            return Ok(Message::Packed { buf });
+        }
+        match rmp_deserialize::<Vec<Message>>(&buf) {
+            Ok(msgs) => {
+                let count = msgs.len();
+                let msgs: Vec<Message> = msgs
+                    .into_iter()
+                    .filter(|msg| self.check(msg).is_ok())
+                    .collect();
+                if msgs.len() == count {
+                    Ok(Message::Packed { buf })
+                } else {
+                    rmp_serialize(&msgs).map(|buf| Message::Packed { buf })
+                }
+            }
+            // Let the blockchain service judge the malformed ones.
+            Err(_) => Ok(Message::Packed { buf }),
+        }
+    }
+
+    /// Forwards the requests to the blockchain service until a `Stop`
+    /// message is received or one of the channels is closed.
+    pub fn run(&mut self) {
+        debug!("[gateway] running");
+        loop {
+            let (req, res_chan) = match self.rx_chan.recv_sync() {
+                Ok(req) => req,
+                Err(_) => {
+                    warn!("[gateway] node services channel closed");
+                    break;
+                }
+            };
+            if let Message::Stop = req {
+                break;
+            }
+
+            let req = match self.filter(req) {
+                Ok(req) => req,
+                Err(err) => {
+                    let _ = res_chan.send_sync(Message::Exception(err));
+                    continue;
+                }
+            };
+
+            let subscribe = matches!(req, Message::Subscribe { .. });
+            // Journaled transactions refused by the blockchain service.
+            let journaled = match (&req, &self.journal) {
+                (Message::PutTransactionRequest { tx, .. }, Some(_)) => Some(tx.get_primary_hash()),
+                _ => None,
+            };
+            let bc_res = match self.bc_chan.send_sync(req) {
+                Ok(bc_res) => bc_res,
+                Err(_) => {
+                    warn!("[gateway] blockchain channel closed");
+                    break;
+                }
+            };
+            if subscribe {
+                thread::spawn(move || relay(bc_res, res_chan, None));
+            } else if let Ok(res) = bc_res.recv_sync() {
+                if let (Some(hash), Message::Exception(_)) = (journaled, &res) {
+                    if let Some(journal) = &self.journal {
+                        journal.remove(&[hash]);
+                    }
+                }
+                let _ = res_chan.send_sync(res);
+            }
+        }
+    }
+}
@@ -0,0 +1,589 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Public listeners guard.
+//!
+//! The core REST and bridge services have no protection against abusive
+//! clients. When limits are configured the node binds the public address
+//! itself and relays the connections to the core service, moved to a
+//! loopback port, applying:
+//! - a cap on the concurrent connections, overall and per client IP;
+//! - a per client IP rate limit, on the HTTP requests for REST and on the
+//!   new connections for the bridge;
+//! - a max HTTP request size (REST only, the bridge messages are not framed
+//!   in a way the node can inspect).
+//!
+//! The open connections are capped at `MAX_CONNECTIONS` per guard even with
+//! no limit configured, each one taking two relay threads. The HTTP requests
+//! carry the client address to the core service in the `X-Forwarded-For` and
+//! `X-Real-IP` headers, the ones sent by the client being dropped; the bridge
+//! streams are opaque, the core service sees the loopback address.
+//!
+//! The core services bind their address themselves, the loopback port they
+//! are moved to can't be handed over as a bound socket. The guard binds it
+//! when created and keeps it until the service starts, and again while the
+//! service is stopped, so that no other local process can take it meanwhile.
+//!
+//! The guard also serves the additional listening addresses of a service,
+//! the core services binding a single address.
+
+use crate::metrics::MetricsSource;
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use trinci_core::base::Mutex;
+
+/// Max size of the HTTP request line plus headers.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Max wait for the next HTTP request data, against slow clients holding the
+/// connection slots.
+const HTTP_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Max open connections of a guard, whatever the configured limits.
+pub const MAX_CONNECTIONS: usize = 1024;
+
+/// Request headers carrying the client address, replaced by the guard.
+const FORWARDED_HEADERS: [&str; 2] = ["x-forwarded-for", "x-real-ip"];
+
+/// Idle rate limiter buckets are dropped after this time.
+const BUCKET_TTL: Duration = Duration::from_secs(60);
+
+/// Relayed protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// HTTP/1.1, requests are inspected.
+    Http,
+    /// Opaque stream.
+    Stream,
+}
+
+/// Guard limits, the zero ones are disabled.
+#[derive(Debug, Clone, Default)]
+pub struct GuardConfig {
+    /// Max concurrent connections.
+    pub max_connections: usize,
+    /// Max concurrent connections of a client IP.
+    pub max_connections_per_ip: usize,
+    /// Requests (HTTP) or connections (stream) per second of a client IP.
+    pub rate: u32,
+    /// Max HTTP request size in bytes, head included.
+    pub max_request_size: usize,
+}
+
+impl GuardConfig {
+    /// Whether any limit is configured.
+    pub fn is_enabled(&self) -> bool {
+        self.max_connections > 0
+            || self.max_connections_per_ip > 0
+            || self.rate > 0
+            || self.max_request_size > 0
+    }
+}
+
+/// Token bucket rate limiter per client IP, `rate` tokens per second with a
+/// burst of one second.
+struct RateLimiter {
+    rate: u32,
+    buckets: HashMap<IpAddr, (f64, Instant)>,
+    last_cleanup: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: u32) -> Self {
+        RateLimiter {
+            rate,
+            buckets: HashMap::new(),
+            last_cleanup: Instant::now(),
+        }
+    }
+
+    fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+        let rate = self.rate as f64;
+        if now.duration_since(self.last_cleanup) >= BUCKET_TTL {
+            self.buckets
+                .retain(|_, (_, last)| now.duration_since(*last) < BUCKET_TTL);
+            self.last_cleanup = now;
+        }
+        let (tokens, last) = self.buckets.entry(ip).or_insert((rate, now));
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * rate).min(rate);
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    connections: AtomicU64,
+    refused_connections: AtomicU64,
+    limited_requests: AtomicU64,
+    oversized_requests: AtomicU64,
+}
+
+pub struct Guard {
+    name: &'static str,
+    protocol: Protocol,
+    config: GuardConfig,
+    /// Public addresses.
+    addrs: Vec<String>,
+    /// Core service address.
+    upstream: SocketAddr,
+    /// Whether the core service was moved to a loopback port by the guard.
+    loopback: bool,
+    /// Listener keeping the loopback port while the core service is down.
+    reservation: Mutex<Option<TcpListener>>,
+    /// Open connections per client IP.
+    connections: Mutex<HashMap<IpAddr, usize>>,
+    limiter: Mutex<RateLimiter>,
+    counters: Counters,
+    running: AtomicBool,
+}
+
+// Plain HTTP error response, the connection is closed afterwards.
+fn http_error(stream: &mut TcpStream, status: u16, reason: &str) {
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        reason.len(),
+        reason
+    );
+    let _ = stream.flush();
+}
+
+impl Guard {
+    pub fn new(
+        name: &'static str,
+        protocol: Protocol,
+        config: GuardConfig,
+        addrs: Vec<String>,
+        upstream: SocketAddr,
+    ) -> Self {
+        let rate = config.rate;
+        Guard {
+            name,
+            protocol,
+            config,
+            addrs,
+            upstream,
+            loopback: false,
+            reservation: Mutex::new(None),
+            connections: Mutex::new(HashMap::new()),
+            limiter: Mutex::new(RateLimiter::new(rate)),
+            counters: Counters::default(),
+            running: AtomicBool::new(false),
+        }
+    }
+
+    /// Guard of a core service moved to a free loopback port, kept bound
+    /// until the service starts.
+    pub fn loopback(
+        name: &'static str,
+        protocol: Protocol,
+        config: GuardConfig,
+        addrs: Vec<String>,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut guard = Guard::new(name, protocol, config, addrs, listener.local_addr()?);
+        guard.loopback = true;
+        guard.reservation = Mutex::new(Some(listener));
+        Ok(guard)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Core service address.
+    pub fn upstream(&self) -> SocketAddr {
+        self.upstream
+    }
+
+    /// Frees the loopback port right before the core service binds it.
+    pub fn release_upstream(&self) {
+        self.reservation.lock().take();
+    }
+
+    /// Binds the loopback port again once the core service is stopped.
+    pub fn hold_upstream(&self) {
+        if !self.loopback {
+            return;
+        }
+        let mut reservation = self.reservation.lock();
+        if reservation.is_none() {
+            match TcpListener::bind(self.upstream) {
+                Ok(listener) => *reservation = Some(listener),
+                Err(err) => warn!(
+                    "[guard] {} can't hold {}: {}",
+                    self.name, self.upstream, err
+                ),
+            }
+        }
+    }
+
+    // Max open connections, the configured one bounded by the hard cap.
+    fn max_connections(&self) -> usize {
+        match self.config.max_connections {
+            0 => MAX_CONNECTIONS,
+            max => max.min(MAX_CONNECTIONS),
+        }
+    }
+
+    /// Binds the public addresses and relays the connections in background.
+    /// The listeners outlive the core service restarts, further calls are
+    /// no-ops.
+    pub fn start(guard: Arc<Self>) -> io::Result<()> {
+        if guard.running.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let listeners = match guard
+            .addrs
+            .iter()
+            .map(TcpListener::bind)
+            .collect::<io::Result<Vec<_>>>()
+        {
+            Ok(listeners) => listeners,
+            Err(err) => {
+                guard.running.store(false, Ordering::Relaxed);
+                return Err(err);
+            }
+        };
+        info!(
+            "[guard] {} listening on {}, relaying to {}",
+            guard.name,
+            guard.addrs.join(", "),
+            guard.upstream
+        );
+        for listener in listeners {
+            let guard = guard.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => guard.accept(stream),
+                        Err(err) => warn!("[guard] {} accept error: {}", guard.name, err),
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    // Applies the connection limits and spawns the relay.
+    fn accept(self: &Arc<Self>, mut stream: TcpStream) {
+        let ip = match stream.peer_addr() {
+            Ok(peer) => peer.ip(),
+            Err(_) => return,
+        };
+        let refused = {
+            let mut connections = self.connections.lock();
+            let total: usize = connections.values().sum();
+            let count = connections.entry(ip).or_default();
+            let refused = total >= self.max_connections()
+                || (self.config.max_connections_per_ip > 0
+                    && *count >= self.config.max_connections_per_ip)
+                || (self.protocol == Protocol::Stream
+                    && !self.limiter.lock().allow(ip, Instant::now()));
+            if !refused {
+                *count += 1;
+            } else if *count == 0 {
+                connections.remove(&ip);
+            }
+            refused
+        };
+        if refused {
+            self.counters
+                .refused_connections
+                .fetch_add(1, Ordering::Relaxed);
+            debug!("[guard] {} connection from {} refused", self.name, ip);
+            if self.protocol == Protocol::Http {
+                http_error(&mut stream, 503, "Too many connections");
+            }
+            return;
+        }
+        self.counters.connections.fetch_add(1, Ordering::Relaxed);
+        let guard = self.clone();
+        thread::spawn(move || {
+            if let Err(err) = guard.relay(stream, ip) {
+                debug!("[guard] {} connection from {}: {}", guard.name, ip, err);
+            }
+            let mut connections = guard.connections.lock();
+            if let Some(count) = connections.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    connections.remove(&ip);
+                }
+            }
+        });
+    }
+
+    fn relay(&self, client: TcpStream, ip: IpAddr) -> io::Result<()> {
+        if self.protocol == Protocol::Http {
+            client.set_read_timeout(Some(HTTP_READ_TIMEOUT))?;
+        }
+        let upstream = TcpStream::connect(self.upstream)?;
+        let mut client_out = client.try_clone()?;
+        let mut upstream_in = upstream.try_clone()?;
+        // Responses flow back untouched.
+        let responses = thread::spawn(move || {
+            let _ = io::copy(&mut upstream_in, &mut client_out);
+            let _ = client_out.shutdown(Shutdown::Both);
+        });
+        let result = match self.protocol {
+            Protocol::Stream => io::copy(&mut &client, &mut &upstream).map(|_| ()),
+            Protocol::Http => self.relay_requests(&client, &upstream, ip),
+        };
+        let _ = upstream.shutdown(Shutdown::Both);
+        let _ = client.shutdown(Shutdown::Both);
+        let _ = responses.join();
+        result
+    }
+
+    // Forwards the HTTP requests one by one, checking rate and size, with
+    // the client address in the forwarding headers.
+    fn relay_requests(
+        &self,
+        client: &TcpStream,
+        upstream: &TcpStream,
+        ip: IpAddr,
+    ) -> io::Result<()> {
+        let mut reader = BufReader::new(client);
+        let mut upstream = upstream;
+        loop {
+            let mut head = Vec::new();
+            let mut forwarded = Vec::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = Vec::new();
+                let read = (&mut reader)
+                    .take((MAX_HEAD_SIZE + 1 - head.len()) as u64)
+                    .read_until(b'\n', &mut line)?;
+                if read == 0 {
+                    // Connection closed by the client.
+                    return Ok(());
+                }
+                head.extend_from_slice(&line);
+                if head.len() > MAX_HEAD_SIZE || !line.ends_with(b"\n") {
+                    return self.refuse_request(client, 431, "Request Header Fields Too Large");
+                }
+                let text = String::from_utf8_lossy(&line);
+                let text = text.trim_end();
+                if text.is_empty() {
+                    let _ = write!(
+                        forwarded,
+                        "X-Forwarded-For: {}\r\nX-Real-IP: {}\r\n",
+                        ip, ip
+                    );
+                    forwarded.extend_from_slice(&line);
+                    break;
+                }
+                let name = text
+                    .split_once(':')
+                    .map(|(name, _)| name.trim().to_lowercase());
+                if !name
+                    .as_deref()
+                    .is_some_and(|name| FORWARDED_HEADERS.contains(&name))
+                {
+                    forwarded.extend_from_slice(&line);
+                }
+                if let Some((name, value)) = text.split_once(':') {
+                    let name = name.trim().to_lowercase();
+                    if name == "content-length" {
+                        content_length = value.trim().parse::<usize>().map_err(|_| {
+                            io::Error::new(io::ErrorKind::InvalidData, "invalid content length")
+                        })?;
+                    } else if name == "transfer-encoding" {
+                        return self.refuse_request(client, 411, "Length Required");
+                    }
+                }
+            }
+            if !self.limiter.lock().allow(ip, Instant::now()) {
+                self.counters
+                    .limited_requests
+                    .fetch_add(1, Ordering::Relaxed);
+                return self.refuse_request(client, 429, "Too Many Requests");
+            }
+            if self.config.max_request_size > 0
+                && head.len() + content_length > self.config.max_request_size
+            {
+                self.counters
+                    .oversized_requests
+                    .fetch_add(1, Ordering::Relaxed);
+                return self.refuse_request(client, 413, "Payload Too Large");
+            }
+            upstream.write_all(&forwarded)?;
+            let copied = io::copy(
+                &mut (&mut reader).take(content_length as u64),
+                &mut upstream,
+            )?;
+            if copied < content_length as u64 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn refuse_request(&self, client: &TcpStream, status: u16, reason: &str) -> io::Result<()> {
+        let mut client = client.try_clone()?;
+        http_error(&mut client, status, reason);
+        Ok(())
+    }
+}
+
+impl MetricsSource for Guard {
+    fn render(&self, out: &mut String) {
+        let name = self.name;
+        let counters = [
+            ("connections", &self.counters.connections),
+            ("refused_connections", &self.counters.refused_connections),
+            ("limited_requests", &self.counters.limited_requests),
+            ("oversized_requests", &self.counters.oversized_requests),
+        ];
+        for (counter, value) in counters {
+            let _ = writeln!(out, "# TYPE trinci_guard_{}_total counter", counter);
+            let _ = writeln!(
+                out,
+                "trinci_guard_{}_total{{listener=\"{}\"}} {}",
+                counter,
+                name,
+                value.load(Ordering::Relaxed)
+            );
+        }
+        let open: usize = self.connections.lock().values().sum();
+        let _ = writeln!(out, "# TYPE trinci_guard_open_connections gauge");
+        let _ = writeln!(
+            out,
+            "trinci_guard_open_connections{{listener=\"{}\"}} {}",
+            name, open
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(2);
+
+        assert!(limiter.allow(ip, start));
+        assert!(limiter.allow(ip, start));
+        assert!(!limiter.allow(ip, start));
+        assert!(limiter.allow(other, start));
+        assert!(limiter.allow(ip, start + Duration::from_millis(500)));
+        assert!(!limiter.allow(ip, start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn relay_extra_addrs() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            for mut stream in upstream.incoming().flatten() {
+                let _ = stream.write_all(b"pong");
+            }
+        });
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+        let addrs = vec![format!("127.0.0.1:{}", port), format!("127.0.0.2:{}", port)];
+        let guard = Arc::new(Guard::new(
+            "test",
+            Protocol::Stream,
+            GuardConfig::default(),
+            addrs.clone(),
+            upstream_addr,
+        ));
+        Guard::start(guard).unwrap();
+
+        for addr in addrs {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"pong");
+        }
+    }
+
+    #[test]
+    fn forward_client_address() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let received = thread::spawn(move || {
+            let (stream, _) = upstream.accept().unwrap();
+            let mut head = String::new();
+            let mut reader = BufReader::new(stream);
+            while reader.read_line(&mut head).unwrap() > 2 {}
+            head
+        });
+        let guard = Arc::new(Guard::new(
+            "test",
+            Protocol::Http,
+            GuardConfig::default(),
+            Vec::new(),
+            upstream_addr,
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, peer) = listener.accept().unwrap();
+            let _ = guard.relay(stream, peer.ip());
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: node\r\nX-Forwarded-For: 6.6.6.6\r\n\r\n")
+            .unwrap();
+
+        let head = received.join().unwrap();
+        assert!(head.starts_with("GET / HTTP/1.1\r\nHost: node\r\n"));
+        assert!(head.contains("X-Forwarded-For: 127.0.0.1\r\n"));
+        assert!(head.contains("X-Real-IP: 127.0.0.1\r\n"));
+        assert!(!head.contains("6.6.6.6"));
+    }
+
+    #[test]
+    fn loopback_reservation() {
+        let guard =
+            Guard::loopback("test", Protocol::Stream, GuardConfig::default(), Vec::new()).unwrap();
+        assert!(TcpListener::bind(guard.upstream()).is_err());
+        guard.release_upstream();
+        assert!(TcpListener::bind(guard.upstream()).is_ok());
+        guard.hold_upstream();
+        assert!(TcpListener::bind(guard.upstream()).is_err());
+    }
+}
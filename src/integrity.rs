@@ -0,0 +1,178 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Database integrity checks.
+//!
+//! A block is checked against the data it commits to: the previous block
+//! hash, the transactions, the receipts and, for the last block, the current
+//! state hash.
+//! The core does not keep the state of past heights, so the state hash of the
+//! blocks below the last one can't be verified.
+
+use trinci_core::{
+    crypto::Hashable,
+    db::{Db, DbFork, RocksDb},
+    Block,
+};
+
+/// Number of most recent blocks linkage checked by the `quick` verification.
+pub const QUICK_VERIFY_DEPTH: u64 = 100;
+
+/// Database verification at startup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbVerify {
+    /// No verification.
+    None,
+    /// Last block state hash and recent blocks linkage.
+    Quick,
+    /// Last block state hash, whole chain linkage, transactions and receipts.
+    Full,
+}
+
+impl std::str::FromStr for DbVerify {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(DbVerify::None),
+            "quick" => Ok(DbVerify::Quick),
+            "full" => Ok(DbVerify::Full),
+            _ => Err(format!(
+                "invalid db verification `{}` (expected none, quick or full)",
+                value
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for DbVerify {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mode = match self {
+            DbVerify::None => "none",
+            DbVerify::Quick => "quick",
+            DbVerify::Full => "full",
+        };
+        write!(f, "{}", mode)
+    }
+}
+
+// Checks the block against the previous one.
+fn verify_linkage(block: &Block, prev: Option<&Block>) -> Result<(), String> {
+    match prev {
+        Some(prev) if block.data.prev_hash != prev.data.primary_hash() => {
+            Err("previous block hash mismatch".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Checks the block against the stored transactions and receipts.
+fn verify_contents(db: &RocksDb, block: &Block) -> Result<(), String> {
+    let data = &block.data;
+    let hashes = db.load_transactions_hashes(data.height).unwrap_or_default();
+    if hashes.len() != data.size as usize {
+        return Err(format!(
+            "{} transactions stored, {} expected",
+            hashes.len(),
+            data.size
+        ));
+    }
+    for (index, hash) in hashes.iter().enumerate() {
+        let hex = hex::encode(hash.as_bytes());
+        match db.load_transaction(hash) {
+            Some(tx) if tx.get_primary_hash() == *hash => (),
+            Some(_) => return Err(format!("transaction {} hash mismatch", hex)),
+            None => return Err(format!("transaction {} missing", hex)),
+        }
+        match db.load_receipt(hash) {
+            Some(rx) if rx.height == data.height && rx.index as usize == index => (),
+            Some(rx) => {
+                return Err(format!(
+                    "receipt {} at height {} index {}, expected index {}",
+                    hex, rx.height, rx.index, index
+                ))
+            }
+            None => return Err(format!("receipt {} missing", hex)),
+        }
+    }
+    Ok(())
+}
+
+/// Verifies the blocks from `from` to `to` (capped at the last block), the
+/// transactions and receipts only if `contents` is set.
+/// Returns the verified range or the first divergence.
+pub fn verify_range(
+    db: &mut RocksDb,
+    from: u64,
+    to: u64,
+    contents: bool,
+) -> Result<(u64, u64), String> {
+    let last = db.load_block(u64::MAX).ok_or("empty database")?.data.height;
+    let to = to.min(last);
+    if from > to {
+        return Err(format!("empty range, last block is {}", last));
+    }
+
+    let mut prev = match from {
+        0 => None,
+        height => db.load_block(height - 1),
+    };
+    for height in from..=to {
+        let block = db
+            .load_block(height)
+            .ok_or_else(|| format!("block {}: missing", height))?;
+        verify_linkage(&block, prev.as_ref())
+            .and_then(|_| {
+                if contents {
+                    verify_contents(db, &block)
+                } else {
+                    Ok(())
+                }
+            })
+            .map_err(|err| format!("block {}: {}", height, err))?;
+        prev = Some(block);
+    }
+
+    if to == last {
+        let state_hash = db.fork_create().state_hash("");
+        if prev.map(|block| block.data.state_hash) != Some(state_hash) {
+            return Err(format!(
+                "block {}: state hash mismatch, current state {}",
+                last,
+                hex::encode(state_hash.as_bytes())
+            ));
+        }
+    }
+    Ok((from, to))
+}
+
+/// Startup verification, an empty database is always valid.
+pub fn verify(db: &mut RocksDb, mode: DbVerify) -> Result<(), String> {
+    let last = match db.load_block(u64::MAX) {
+        Some(block) => block.data.height,
+        None => return Ok(()),
+    };
+    let (from, to) = match mode {
+        DbVerify::None => return Ok(()),
+        DbVerify::Quick => {
+            verify_range(db, last.saturating_sub(QUICK_VERIFY_DEPTH - 1), last, false)?
+        }
+        DbVerify::Full => verify_range(db, 0, last, true)?,
+    };
+    info!("[db] blocks {} to {} verified", from, to);
+    Ok(())
+}
@@ -0,0 +1,441 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! IP addresses discovery.
+//!
+//! Fills `local-ip` and `public-ip` when they are not configured: the local
+//! address is the one of the interface routing the outbound traffic, the
+//! public address is asked to the `public-ip-service`, either a STUN server
+//! (`stun:host:port`), an HTTP echo service answering with the bare address
+//! (`http://...`, `https://...`) or the UPnP gateway (`upnp`, external address
+//! of the p2p port mapping requested via the UPnP negotiator tool).
+//!
+//! The detected addresses are checked again periodically to follow dynamic
+//! IPs. The visa is built by the core at startup, a public address change is
+//! reported by the monitor right away but reaches the visa after a restart.
+
+use crate::tasks;
+use crate::{config::Config, nat::NatFallback};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    process::Command,
+    str::FromStr,
+    time::Duration,
+};
+
+/// Default seconds between two checks of the detected addresses.
+pub const DEFAULT_IP_DISCOVERY_INTERVAL: u64 = 300;
+
+/// Public IP service used by the automatic network setup, when none is
+/// configured.
+pub const DEFAULT_STUN_SERVER: &str = "stun:stun.l.google.com:19302";
+
+/// P2P port used by the automatic network setup, when none is configured.
+const AUTO_P2P_PORT: u16 = 9000;
+
+/// Network requests timeout.
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// STUN magic cookie (RFC 5389).
+const STUN_COOKIE: u32 = 0x2112_a442;
+
+/// Public IP service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IpService {
+    /// STUN server address (host:port).
+    Stun(String),
+    /// HTTP echo service URL.
+    Http(String),
+    /// UPnP gateway.
+    Upnp,
+}
+
+impl FromStr for IpService {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value == "upnp" {
+            Ok(IpService::Upnp)
+        } else if let Some(server) = value.strip_prefix("stun:") {
+            Ok(IpService::Stun(server.to_string()))
+        } else if value.starts_with("http://") || value.starts_with("https://") {
+            Ok(IpService::Http(value.to_string()))
+        } else {
+            Err(format!(
+                "invalid public ip service `{}` (expected stun:host:port, an http(s) URL or upnp)",
+                value
+            ))
+        }
+    }
+}
+
+impl std::fmt::Display for IpService {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IpService::Stun(server) => write!(f, "stun:{}", server),
+            IpService::Http(url) => write!(f, "{}", url),
+            IpService::Upnp => write!(f, "upnp"),
+        }
+    }
+}
+
+/// Addresses discovery configuration.
+pub struct IpDiscoveryConfig {
+    /// Public IP service, the public address is not detected if missing.
+    pub service: Option<IpService>,
+    /// UPnP negotiator tool path.
+    pub upnp_tool: String,
+    /// P2P port mapped via UPnP.
+    pub p2p_port: u16,
+    /// Seconds between two checks, zero disables them.
+    pub interval: u64,
+}
+
+/// Addresses discovery, only the addresses not configured are detected.
+pub struct IpDiscovery {
+    config: IpDiscoveryConfig,
+    detect_local: bool,
+    detect_public: bool,
+    local_ip: Option<String>,
+    public_ip: Option<String>,
+}
+
+/// Address of the interface routing the outbound traffic.
+/// No packet is sent, connecting a UDP socket only selects the route.
+pub fn local_ip() -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((Ipv4Addr::new(192, 0, 2, 1), 9)).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    (!ip.is_unspecified()).then_some(ip)
+}
+
+// Parses a STUN binding success response, returns the mapped address.
+fn parse_stun_response(buf: &[u8], transaction: &[u8; 12]) -> Option<SocketAddr> {
+    if buf.len() < 20
+        || buf[0..2] != [0x01, 0x01]
+        || buf[4..8] != STUN_COOKIE.to_be_bytes()
+        || &buf[8..20] != transaction
+    {
+        return None;
+    }
+    let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    let attrs = buf.get(20..20 + len)?;
+    let mut mapped = None;
+    let mut offset = 0;
+    while offset + 4 <= attrs.len() {
+        let kind = u16::from_be_bytes([attrs[offset], attrs[offset + 1]]);
+        let len = u16::from_be_bytes([attrs[offset + 2], attrs[offset + 3]]) as usize;
+        let value = attrs.get(offset + 4..offset + 4 + len)?;
+        // Only IPv4 family addresses.
+        if len >= 8 && value[1] == 0x01 {
+            let port = u16::from_be_bytes([value[2], value[3]]);
+            let ip = [value[4], value[5], value[6], value[7]];
+            match kind {
+                // XOR-MAPPED-ADDRESS
+                0x0020 => {
+                    let cookie = STUN_COOKIE.to_be_bytes();
+                    let port = port ^ (STUN_COOKIE >> 16) as u16;
+                    let ip = Ipv4Addr::new(
+                        ip[0] ^ cookie[0],
+                        ip[1] ^ cookie[1],
+                        ip[2] ^ cookie[2],
+                        ip[3] ^ cookie[3],
+                    );
+                    return Some(SocketAddr::new(ip.into(), port));
+                }
+                // MAPPED-ADDRESS
+                0x0001 => mapped = Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)),
+                _ => (),
+            }
+        }
+        // Attributes are padded to four bytes.
+        offset += 4 + len.div_ceil(4) * 4;
+    }
+    mapped
+}
+
+fn stun(server: &str) -> Result<IpAddr, String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(|err| err.to_string())?;
+    socket
+        .set_read_timeout(Some(TIMEOUT))
+        .map_err(|err| err.to_string())?;
+    socket
+        .connect(server)
+        .map_err(|err| format!("{}: {}", server, err))?;
+
+    let transaction: [u8; 12] = rand::random();
+    let mut request = vec![0x00, 0x01, 0x00, 0x00];
+    request.extend_from_slice(&STUN_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction);
+    socket
+        .send(&request)
+        .map_err(|err| format!("{}: {}", server, err))?;
+
+    let mut buf = [0; 512];
+    let len = socket
+        .recv(&mut buf)
+        .map_err(|err| format!("{}: {}", server, err))?;
+    parse_stun_response(&buf[..len], &transaction)
+        .map(|addr| addr.ip())
+        .ok_or_else(|| format!("{}: invalid STUN response", server))
+}
+
+fn http_echo(url: &str) -> Result<IpAddr, String> {
+    use isahc::{config::Configurable, ReadResponseExt};
+
+    let request = crate::proxy::apply(isahc::Request::get(url))
+        .timeout(TIMEOUT)
+        .body(())
+        .map_err(|err| err.to_string())?;
+    let mut response = isahc::send(request).map_err(|err| format!("{}: {}", url, err))?;
+    if !response.status().is_success() {
+        return Err(format!("{}: status {}", url, response.status()));
+    }
+    let body = response.text().map_err(|err| err.to_string())?;
+    body.trim()
+        .parse()
+        .map_err(|_| format!("{}: unexpected answer `{}`", url, body.trim()))
+}
+
+fn upnp(tool: &str, local_ip: Option<&str>, p2p_port: u16) -> Result<IpAddr, String> {
+    let local_ip = local_ip.ok_or("UPnP discovery requires the local ip")?;
+    if p2p_port == 0 {
+        return Err("UPnP discovery requires `p2p-port`".to_string());
+    }
+    let output = Command::new(tool)
+        .arg(local_ip)
+        .arg(p2p_port.to_string())
+        .output()
+        .map_err(|err| format!("unable to run {}: {}", tool, err))?;
+    // The tool prints the mapped address followed by the protocol used.
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mapped = stdout.split_whitespace().next().unwrap_or_default();
+    if !output.status.success() {
+        return Err(format!(
+            "UPnP mapping failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    mapped
+        .parse::<SocketAddr>()
+        .map(|addr| addr.ip())
+        .or_else(|_| mapped.parse())
+        .map_err(|_| format!("unexpected UPnP answer `{}`", stdout.trim()))
+}
+
+impl IpDiscovery {
+    /// Discovery of the addresses missing from the node configuration,
+    /// `None` if there is nothing to detect.
+    pub fn new(config: &Config) -> Option<Self> {
+        if !config.ip_discovery {
+            return None;
+        }
+        let service = config.public_ip_service.clone();
+        let detect_local = config.local_ip.is_none();
+        let detect_public = config.public_ip.is_none() && service.is_some();
+        if !detect_local && !detect_public {
+            return None;
+        }
+        Some(IpDiscovery {
+            config: IpDiscoveryConfig {
+                service,
+                upnp_tool: config.nat_upnp_tool.clone(),
+                p2p_port: config.p2p_port,
+                interval: config.ip_discovery_interval,
+            },
+            detect_local,
+            detect_public,
+            local_ip: config.local_ip.clone(),
+            public_ip: config.public_ip.clone(),
+        })
+    }
+
+    fn public_ip(&self) -> Result<IpAddr, String> {
+        match &self.config.service {
+            Some(IpService::Stun(server)) => stun(server),
+            Some(IpService::Http(url)) => http_echo(url),
+            Some(IpService::Upnp) => upnp(
+                &self.config.upnp_tool,
+                self.local_ip.as_deref(),
+                self.config.p2p_port,
+            ),
+            None => Err("public ip service not configured".to_string()),
+        }
+    }
+
+    /// Detects the addresses, returns `true` if any of them changed.
+    pub fn detect(&mut self) -> bool {
+        let mut changed = false;
+        if self.detect_local {
+            match local_ip().map(|ip| ip.to_string()) {
+                Some(ip) if self.local_ip.as_ref() != Some(&ip) => {
+                    info!("[ip] local ip: {}", ip);
+                    self.local_ip = Some(ip);
+                    changed = true;
+                }
+                Some(_) => (),
+                None => warn!("[ip] local ip not detected"),
+            }
+        }
+        if self.detect_public {
+            match self.public_ip().map(|ip| ip.to_string()) {
+                Ok(ip) if self.public_ip.as_ref() != Some(&ip) => {
+                    info!("[ip] public ip: {}", ip);
+                    self.public_ip = Some(ip);
+                    changed = true;
+                }
+                Ok(_) => (),
+                Err(err) => warn!("[ip] public ip not detected: {}", err),
+            }
+        }
+        changed
+    }
+
+    /// Copies the detected addresses into the node configuration.
+    pub fn populate(&self, config: &mut Config) {
+        if self.detect_local {
+            config.local_ip = self.local_ip.clone();
+        }
+        if self.detect_public {
+            config.public_ip = self.public_ip.clone();
+        }
+    }
+
+    /// Detected addresses, as (local, public).
+    pub fn addresses(&self) -> (Option<String>, Option<String>) {
+        (self.local_ip.clone(), self.public_ip.clone())
+    }
+}
+
+/// Automatic network setup (`auto-network-setup`), formerly done by the
+/// `start.sh` script: completes the configuration to listen for P2P on all
+/// the interfaces, to detect the missing addresses and to map the P2P port on
+/// the gateway when the node is unreachable, then runs the detection.
+/// Fails if an address required to be reachable is not detected.
+pub fn auto_setup(config: &mut Config) -> Result<Option<IpDiscovery>, String> {
+    config.ip_discovery = true;
+    if config.public_ip_service.is_none() {
+        config.public_ip_service = Some(DEFAULT_STUN_SERVER.parse()?);
+    }
+    if config.p2p_port == 0 {
+        config.p2p_port = AUTO_P2P_PORT;
+    }
+    if config
+        .p2p_addr
+        .parse::<IpAddr>()
+        .is_ok_and(|ip| ip.is_loopback())
+    {
+        config.p2p_addr = Ipv4Addr::UNSPECIFIED.to_string();
+    }
+    if config.nat_fallback == NatFallback::None {
+        config.nat_fallback = NatFallback::Upnp;
+    }
+
+    let mut discovery = IpDiscovery::new(config);
+    if let Some(discovery) = discovery.as_mut() {
+        discovery.detect();
+        discovery.populate(config);
+    }
+    if config.local_ip.is_none() {
+        return Err("auto network setup: local ip not detected, set `local-ip`".to_string());
+    }
+    if config.public_ip.is_none() {
+        return Err(
+            "auto network setup: public ip not detected, check `public-ip-service` or set `public-ip`"
+                .to_string(),
+        );
+    }
+    info!(
+        "[ip] auto network setup: local {}, public {}, p2p {}:{}",
+        config.local_ip.as_deref().unwrap_or_default(),
+        config.public_ip.as_deref().unwrap_or_default(),
+        config.p2p_addr,
+        config.p2p_port
+    );
+    Ok(discovery)
+}
+
+/// Checks the addresses every `interval` seconds, `on_change` is called
+/// with the new (local, public) addresses.
+pub fn run<F>(mut discovery: IpDiscovery, on_change: F)
+where
+    F: Fn(Option<String>, Option<String>),
+{
+    let interval = discovery.config.interval;
+    if interval == 0 {
+        return;
+    }
+    while tasks::sleep(Duration::from_secs(interval)) {
+        let public_ip = discovery.public_ip.clone();
+        if discovery.detect() {
+            if discovery.public_ip != public_ip {
+                warn!("[ip] public ip changed, the visa is updated at the next restart");
+            }
+            let (local_ip, public_ip) = discovery.addresses();
+            on_change(local_ip, public_ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stun_xor_mapped_address() {
+        let transaction = [7; 12];
+        let cookie = STUN_COOKIE.to_be_bytes();
+        let mut response = vec![0x01, 0x01, 0x00, 0x0c];
+        response.extend_from_slice(&cookie);
+        response.extend_from_slice(&transaction);
+        // XOR-MAPPED-ADDRESS 203.0.113.10:40000
+        response.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01]);
+        response.extend_from_slice(&(40000 ^ (STUN_COOKIE >> 16) as u16).to_be_bytes());
+        for (byte, key) in [203, 0, 113, 10].iter().zip(cookie) {
+            response.push(byte ^ key);
+        }
+
+        assert_eq!(
+            parse_stun_response(&response, &transaction),
+            Some("203.0.113.10:40000".parse().unwrap())
+        );
+        assert_eq!(parse_stun_response(&response, &[0; 12]), None);
+        assert_eq!(
+            "stun:stun.example.org:3478".parse(),
+            Ok(IpService::Stun("stun.example.org:3478".to_string()))
+        );
+    }
+
+    #[test]
+    fn auto_setup_completes_config() {
+        let mut config = Config {
+            local_ip: Some("192.168.1.10".to_string()),
+            public_ip: Some("203.0.113.10".to_string()),
+            ..Default::default()
+        };
+
+        assert!(auto_setup(&mut config).unwrap().is_none());
+        assert_eq!(config.p2p_addr, "0.0.0.0");
+        assert_eq!(config.p2p_port, AUTO_P2P_PORT);
+        assert_eq!(config.nat_fallback, NatFallback::Upnp);
+        assert!(config.public_ip_service.is_some());
+
+        config.public_ip = None;
+        config.public_ip_service = Some(IpService::Http("http://127.0.0.1:1".to_string()));
+        assert!(auto_setup(&mut config).is_err());
+    }
+}
@@ -0,0 +1,126 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Database folder lock.
+//!
+//! RocksDB refuses to be opened by two processes, failing with an opaque
+//! panic. The node holds a lock file, containing its process identifier,
+//! within the database folder for its whole life.
+//! A lock left by a crashed node is detected by its process identifier and
+//! replaced, the process liveness is checked with a null signal: on the
+//! platforms without signals the lock is never considered stale.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Lock file name within the database folder.
+pub const LOCK_FILE: &str = "node.lock";
+
+/// Database folder lock, released on drop.
+pub struct DbLock {
+    path: PathBuf,
+}
+
+// Returns `false` only if the process is surely not running.
+#[cfg(unix)]
+pub(crate) fn is_alive(pid: u32) -> bool {
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) => pid,
+        Err(_) => return false,
+    };
+    // SAFETY: the null signal only checks the process existence.
+    unsafe { libc::kill(pid, 0) == 0 }
+    || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_alive(_pid: u32) -> bool {
+    true
+}
+
+impl DbLock {
+    /// Locks the given database folder, the error is meant for the operator.
+    pub fn acquire<P: AsRef<Path>>(db_path: P) -> Result<Self, String> {
+        let path = db_path.as_ref().join(LOCK_FILE);
+        let io_error = |err: io::Error| format!("{}: {}", path.display(), err);
+        fs::create_dir_all(db_path.as_ref()).map_err(io_error)?;
+
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    write!(file, "{}", process::id()).map_err(io_error)?;
+                    return Ok(DbLock { path });
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => (),
+                Err(err) => return Err(io_error(err)),
+            }
+            // An unreadable identifier is left by a crash while locking.
+            let pid = fs::read_to_string(&path)
+                .ok()
+                .and_then(|pid| pid.trim().parse::<u32>().ok());
+            match pid {
+                Some(pid) if is_alive(pid) => {
+                    return Err(format!(
+                        "database {} in use by process {}, stop it or set another `db-path` (if no node is running remove {})",
+                        db_path.as_ref().display(),
+                        pid,
+                        path.display()
+                    ))
+                }
+                _ => {
+                    warn!("[db] removing the stale lock {}", path.display());
+                    fs::remove_file(&path).map_err(io_error)?;
+                }
+            }
+        }
+        Err(format!("{}: unable to lock", path.display()))
+    }
+}
+
+impl Drop for DbLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn acquire_and_release() {
+        let dir = TempDir::new().unwrap();
+        let lock = DbLock::acquire(dir.path()).unwrap();
+        assert!(DbLock::acquire(dir.path()).is_err());
+
+        drop(lock);
+        assert!(!dir.path().join(LOCK_FILE).exists());
+
+        // Left by a crashed process.
+        fs::write(dir.path().join(LOCK_FILE), u32::MAX.to_string()).unwrap();
+        let _lock = DbLock::acquire(dir.path()).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join(LOCK_FILE)).unwrap(),
+            process::id().to_string()
+        );
+    }
+}
@@ -0,0 +1,284 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Node log file.
+//!
+//! Log records are written to a file rotated when it exceeds a size cap or an
+//! age: `<file>` is compressed into `<file>.1.gz`, `<file>.1.gz` becomes
+//! `<file>.2.gz` and so on, the oldest one is dropped.
+//! When the log files approach the disk usage cap only warnings and errors
+//! are written, until a rotation frees enough space. The rotation can also
+//! be requested via the node API, e.g. before shipping the logs.
+
+use crate::api::{Request, Response, Router};
+use flate2::{write::GzEncoder, Compression};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use simplelog::{SharedLogger, WriteLogger};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+/// Default log file size that triggers the rotation (10 MiB).
+pub const DEFAULT_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Default log file age, in seconds, that triggers the rotation (one day).
+pub const DEFAULT_LOG_MAX_AGE: u64 = 24 * 3600;
+
+/// Default number of rotated log files.
+pub const DEFAULT_LOG_FILES: usize = 5;
+
+/// Log file in use.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Rotation requested, done at the next record.
+static ROTATE: AtomicBool = AtomicBool::new(false);
+
+/// Log file configuration.
+#[derive(Clone)]
+pub struct LogFileConfig {
+    /// Current log file.
+    pub path: PathBuf,
+    /// File size in bytes that triggers the rotation.
+    pub max_size: u64,
+    /// File age in seconds that triggers the rotation, zero disables it.
+    pub max_age: u64,
+    /// Number of rotated files kept.
+    pub files: usize,
+    /// Disk usage cap of the current and rotated files, zero disables it.
+    pub max_disk: u64,
+}
+
+/// Rotating log file.
+pub struct LogFile {
+    config: LogFileConfig,
+    file: File,
+    size: u64,
+    opened: SystemTime,
+    /// Size of the rotated files.
+    rotated_size: u64,
+    /// Set when the disk usage is near the cap.
+    warn_only: Arc<AtomicBool>,
+}
+
+impl LogFile {
+    /// Opens the log file, appending to the existing one.
+    pub fn open(config: LogFileConfig) -> io::Result<Self> {
+        if let Some(dir) = config.path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let meta = file.metadata()?;
+        let mut log_file = LogFile {
+            size: meta.len(),
+            opened: meta.created().unwrap_or_else(|_| SystemTime::now()),
+            config,
+            file,
+            rotated_size: 0,
+            warn_only: Arc::new(AtomicBool::new(false)),
+        };
+        log_file.rotated_size = log_file.rotated_size();
+        log_file.update_quota();
+        Ok(log_file)
+    }
+
+    // Path of the rotated file with the given index, zero is the current one.
+    fn file(&self, index: usize) -> PathBuf {
+        match index {
+            0 => self.config.path.clone(),
+            _ => {
+                let mut path = self.config.path.clone().into_os_string();
+                path.push(format!(".{}.gz", index));
+                path.into()
+            }
+        }
+    }
+
+    fn rotated_size(&self) -> u64 {
+        (1..=self.config.files)
+            .filter_map(|index| fs::metadata(self.file(index)).ok())
+            .map(|meta| meta.len())
+            .sum()
+    }
+
+    fn update_quota(&self) {
+        let max_disk = self.config.max_disk;
+        let near = max_disk > 0 && self.size + self.rotated_size >= max_disk / 10 * 9;
+        if self.warn_only.swap(near, Ordering::Relaxed) != near && near {
+            // Bypasses the logger, this is called while writing a record.
+            eprintln!(
+                "log files near the {} bytes cap, only warnings and errors are logged",
+                max_disk
+            );
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        let max_age = Duration::from_secs(self.config.max_age);
+        !max_age.is_zero() && self.opened.elapsed().unwrap_or_default() >= max_age
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.config.files > 0 {
+            let _ = fs::remove_file(self.file(self.config.files));
+            for index in (1..self.config.files).rev() {
+                match fs::rename(self.file(index), self.file(index + 1)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => (),
+                }
+            }
+            let mut encoder = GzEncoder::new(File::create(self.file(1))?, Compression::default());
+            io::copy(&mut File::open(self.file(0))?, &mut encoder)?;
+            encoder.finish()?;
+        }
+        self.file = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(self.file(0))?;
+        self.size = 0;
+        self.opened = SystemTime::now();
+        self.rotated_size = self.rotated_size();
+        self.update_quota();
+        Ok(())
+    }
+
+    /// File logger, records above `level` are dropped.
+    pub fn logger(self, level: LevelFilter, config: simplelog::Config) -> Box<dyn SharedLogger> {
+        ACTIVE.store(true, Ordering::Relaxed);
+        let warn_only = self.warn_only.clone();
+        Box::new(FileLogger {
+            inner: WriteLogger::new(level, config, self),
+            warn_only,
+        })
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let requested = ROTATE.swap(false, Ordering::Relaxed);
+        if self.size > 0
+            && (requested
+                || self.size + buf.len() as u64 > self.config.max_size
+                || self.is_expired())
+        {
+            self.rotate()?;
+        }
+        let len = self.file.write(buf)?;
+        self.size += len as u64;
+        self.update_quota();
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// File logger, dropping the records below warning when the disk usage is
+/// near the cap.
+struct FileLogger {
+    inner: Box<WriteLogger<LogFile>>,
+    warn_only: Arc<AtomicBool>,
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        (metadata.level() <= Level::Warn || !self.warn_only.load(Ordering::Relaxed))
+            && self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+impl SharedLogger for FileLogger {
+    fn level(&self) -> LevelFilter {
+        self.inner.level()
+    }
+
+    fn config(&self) -> Option<&simplelog::Config> {
+        self.inner.config()
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
+
+/// Registers the log rotation route within the node API.
+pub fn routes(router: &mut Router) {
+    router.add("POST", "/admin/log/rotate", |_: &Request| {
+        if !ACTIVE.load(Ordering::Relaxed) {
+            return Response::error(409, "no log file");
+        }
+        ROTATE.store(true, Ordering::Relaxed);
+        info!("[log] rotation requested");
+        Response::ok()
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn rotate_and_quota() {
+        let dir = TempDir::new().unwrap();
+        let mut file = LogFile::open(LogFileConfig {
+            path: dir.path().join("node.log"),
+            max_size: 1024,
+            max_age: 0,
+            files: 2,
+            max_disk: 2048,
+        })
+        .unwrap();
+        let line = [b'x'; 100];
+        for _ in 0..50 {
+            file.write_all(&line).unwrap();
+        }
+
+        assert!(dir.path().join("node.log.1.gz").exists());
+        assert!(dir.path().join("node.log.2.gz").exists());
+        assert!(!dir.path().join("node.log.3.gz").exists());
+        // Compressed, far below the cap.
+        assert!(!file.warn_only.load(Ordering::Relaxed));
+
+        file.config.max_disk = 100;
+        file.write_all(&line).unwrap();
+        assert!(file.warn_only.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,249 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Per module log filters.
+//!
+//! The log records pass through a global filter set: `env_logger` style
+//! directives (`module=level`, comma separated) select the level of the
+//! targets below a module, the most specific one wins, the other node and
+//! core targets use the `log-level`. Third party crates are silent unless
+//! selected by a directive.
+//! The filters are changed at runtime through the node API.
+
+use crate::api::{Request, Response, Router};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use std::{str::FromStr, sync::RwLock};
+
+/// Log filters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFilters {
+    /// Level of the targets not matching any directive.
+    default: LevelFilter,
+    /// Module and level directives.
+    directives: Vec<(String, LevelFilter)>,
+}
+
+static FILTERS: RwLock<LogFilters> = RwLock::new(LogFilters::new(LevelFilter::Trace));
+
+/// Parses a `log-level` value, unknown values select `debug`.
+pub fn parse_level(level: &str) -> LevelFilter {
+    match level {
+        "off" => LevelFilter::Off,
+        "error" => LevelFilter::Error,
+        "warn" => LevelFilter::Warn,
+        "info" => LevelFilter::Info,
+        "trace" => LevelFilter::Trace,
+        _ => LevelFilter::Debug,
+    }
+}
+
+impl LogFilters {
+    /// Same level for every target.
+    pub const fn new(default: LevelFilter) -> Self {
+        LogFilters {
+            default,
+            directives: Vec::new(),
+        }
+    }
+
+    /// Parses the comma separated directives, a directive without module
+    /// overrides the default level.
+    pub fn parse(default: LevelFilter, directives: &str) -> Result<Self, String> {
+        let mut filters = LogFilters::new(default);
+        for directive in directives.split(',').map(str::trim) {
+            if directive.is_empty() {
+                continue;
+            }
+            let invalid = || format!("invalid log filter `{}`", directive);
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let level = LevelFilter::from_str(level.trim()).map_err(|_| invalid())?;
+                    let module = module.trim();
+                    if module.is_empty() {
+                        return Err(invalid());
+                    }
+                    filters.directives.retain(|(other, _)| other != module);
+                    filters.directives.push((module.to_string(), level));
+                }
+                None => {
+                    filters.default = LevelFilter::from_str(directive).map_err(|_| invalid())?
+                }
+            }
+        }
+        // Most specific first.
+        filters
+            .directives
+            .sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Ok(filters)
+    }
+
+    /// Level of a record target.
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .find(|(module, _)| {
+                target
+                    .strip_prefix(module.as_str())
+                    .map(|rest| rest.is_empty() || rest.starts_with("::"))
+                    .unwrap_or(false)
+            })
+            .map(|(_, level)| *level)
+            .unwrap_or(if target.starts_with("trinci") {
+                self.default
+            } else {
+                LevelFilter::Off
+            })
+    }
+
+    /// Most verbose level.
+    pub fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+
+    /// Directives, in configuration syntax.
+    pub fn directives(&self) -> String {
+        self.directives
+            .iter()
+            .map(|(module, level)| format!("{}={}", module, level.as_str().to_lowercase()))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// Replaces the global filters.
+pub fn set(filters: LogFilters) {
+    log::set_max_level(filters.max_level());
+    if let Ok(mut current) = FILTERS.write() {
+        *current = filters;
+    }
+}
+
+/// Current global filters.
+pub fn get() -> LogFilters {
+    FILTERS
+        .read()
+        .map(|filters| filters.clone())
+        .unwrap_or_else(|_| LogFilters::new(LevelFilter::Trace))
+}
+
+struct FilteredLogger {
+    inner: Box<dyn Log>,
+}
+
+impl Log for FilteredLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let level = match FILTERS.read() {
+            Ok(filters) => filters.level(metadata.target()),
+            Err(_) => LevelFilter::Trace,
+        };
+        metadata.level() <= level && self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Installs the global logger, the records pass through the filters.
+pub fn init(inner: Box<dyn Log>) -> Result<(), log::SetLoggerError> {
+    log::set_boxed_logger(Box::new(FilteredLogger { inner }))?;
+    log::set_max_level(get().max_level());
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+struct FiltersDto {
+    level: String,
+    filters: String,
+}
+
+/// Registers the log filters routes within the node API.
+pub fn routes(router: &mut Router) {
+    router.add("GET", "/admin/log/filters", |_: &Request| {
+        let filters = get();
+        Response::json(&FiltersDto {
+            level: filters.default.as_str().to_lowercase(),
+            filters: filters.directives(),
+        })
+    });
+    router.add("POST", "/admin/log/filters", |req: &Request| {
+        let dto = match req.json::<FiltersDto>() {
+            Ok(dto) => dto,
+            Err(res) => return res,
+        };
+        let level = match LevelFilter::from_str(&dto.level) {
+            Ok(level) => level,
+            Err(_) => return Response::error(400, format!("invalid level `{}`", dto.level)),
+        };
+        match LogFilters::parse(level, &dto.filters) {
+            Ok(filters) => {
+                info!(
+                    "[log] level {}, filters `{}`",
+                    dto.level,
+                    filters.directives()
+                );
+                set(filters);
+                Response::ok()
+            }
+            Err(err) => Response::error(400, err),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_match() {
+        let filters = LogFilters::parse(
+            LevelFilter::Info,
+            "trinci_core::p2p=debug, trinci_core=warn",
+        )
+        .unwrap();
+
+        assert_eq!(
+            filters.level("trinci_core::p2p::worker"),
+            LevelFilter::Debug
+        );
+        assert_eq!(filters.level("trinci_core::p2pool"), LevelFilter::Warn);
+        assert_eq!(filters.level("trinci_core"), LevelFilter::Warn);
+        assert_eq!(filters.level("trinci"), LevelFilter::Info);
+        assert_eq!(filters.level("libp2p_kad"), LevelFilter::Off);
+        assert_eq!(filters.max_level(), LevelFilter::Debug);
+        assert_eq!(
+            filters.directives(),
+            "trinci_core::p2p=debug,trinci_core=warn"
+        );
+
+        let filters = LogFilters::parse(LevelFilter::Info, "error").unwrap();
+        assert_eq!(filters.level("trinci"), LevelFilter::Error);
+
+        assert!(LogFilters::parse(LevelFilter::Info, "trinci=loud").is_err());
+        assert!(LogFilters::parse(LevelFilter::Info, "=debug").is_err());
+    }
+}
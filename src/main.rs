@@ -18,75 +18,242 @@
 #[macro_use]
 extern crate log;
 
+mod api;
 mod app;
+mod bootstrap_reader;
+mod catchup;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod checkpoints;
+mod clock;
+mod cmd;
 mod config;
+mod control;
+mod correlation;
+mod crash;
+mod daemon;
+mod denylist;
+mod download;
+mod explorer;
+mod forks;
+mod gateway;
+mod guard;
+mod integrity;
+mod ip_discovery;
+mod lock;
+mod logfile;
+mod logfilter;
+mod mdns;
+mod metrics;
+mod nat;
+mod notify;
+#[cfg(feature = "otel")]
+mod otel;
+mod p2p;
+mod pacer;
+mod peers;
+mod pkcs11;
+mod profile;
+mod propagation;
+mod proxy;
+mod replica;
+mod reputation;
+mod resources;
+mod service_contract;
+mod snapshots;
+mod state_diff;
+mod stats;
+mod tasks;
+mod telemetry;
 mod tracer;
+mod traffic;
 mod utils;
+mod version;
+mod visa;
+mod watchdog;
+#[cfg(windows)]
+mod winservice;
+mod wm_cache;
+mod ws;
 
 #[cfg(feature = "monitor")]
 mod monitor;
 
-use crate::app::App;
-use config::Config;
+use crate::app::{App, NodeRole};
+use crate::correlation::Correlation;
+use crate::crash::CrashReporter;
+use config::{Config, ConfigSource};
 use log::LevelFilter;
-use simplelog::{ColorChoice, TermLogger, TerminalMode};
-use std::env;
+use logfile::{LogFile, LogFileConfig};
+use simplelog::{ColorChoice, CombinedLogger, SharedLogger, TermLogger, TerminalMode};
+use std::{env, sync::Arc};
 
 /// Logger initialization.
-/// Output is set to standard output.
-fn logger_init() {
-    let config = simplelog::ConfigBuilder::new()
-        .add_filter_allow_str("trinci")
-        .build();
-
-    TermLogger::init(
-        LevelFilter::Trace,
-        config,
+/// Output is set to standard output and, if given, to the log file. With a
+/// log file only warnings and errors reach the standard output.
+fn logger_init(file: Option<LogFile>) {
+    // Targets are filtered by the `logfilter` module.
+    let config = simplelog::ConfigBuilder::new().build();
+
+    let term_level = match file {
+        Some(_) => LevelFilter::Warn,
+        None => LevelFilter::Trace,
+    };
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![TermLogger::new(
+        term_level,
+        config.clone(),
         TerminalMode::Stdout,
         ColorChoice::Auto,
-    )
-    .expect("logger init");
+    )];
+    if let Some(file) = file {
+        loggers.push(file.logger(LevelFilter::Trace, config));
+    }
+    logfilter::init(CombinedLogger::new(loggers)).expect("logger init");
 }
 
-/// Sets logger verbosity level.
-fn logger_level(level: &str) {
-    let level = match level {
-        "off" => LevelFilter::Off,
-        "error" => LevelFilter::Error,
-        "warn" => LevelFilter::Warn,
-        "info" => LevelFilter::Info,
-        "trace" => LevelFilter::Trace,
-        _ => LevelFilter::Debug,
-    };
-    log::set_max_level(level);
+/// Opens the configured log file.
+fn log_file(config: &Config) -> Option<LogFile> {
+    let path = config.log_file.as_ref()?;
+    let file = LogFile::open(LogFileConfig {
+        path: path.into(),
+        max_size: config.log_max_size,
+        max_age: config.log_max_age,
+        files: config.log_files,
+        max_disk: config.log_max_disk,
+    });
+    match file {
+        Ok(file) => Some(file),
+        Err(err) => {
+            eprintln!("Error opening the log file {}: {}", path, err);
+            None
+        }
+    }
+}
+
+/// Sets logger verbosity level and per module filters.
+fn logger_level(level: &str, filters: &str) {
+    let level = logfilter::parse_level(level);
+    // Already validated with the configuration.
+    let filters = logfilter::LogFilters::parse(level, filters)
+        .unwrap_or_else(|_| logfilter::LogFilters::new(level));
+    logfilter::set(filters);
 }
 
 /// Prints the node configuration.
-fn show_config(config: &Config) {
+fn show_config(config: &Config, sources: &[(&str, ConfigSource)]) {
     let keypair_path = config.keypair_path.as_deref().unwrap_or("null");
     info!("Configuration:");
+    if let Some(profile) = config.profile {
+        info!("  Profile:                {}", profile);
+    }
+    info!("  Role:                   {}", config.role);
     info!("  Keypair path:           {}", keypair_path);
+    if let Some(path) = &config.p2p_keypair {
+        info!("  P2P keypair path:       {}", path);
+    }
+    if let Some(path) = &config.api_keypair {
+        info!("  API keypair path:       {}", path);
+    }
     info!("  Network Id:             {}", config.network);
     info!("  Block threshold:        {}", config.block_threshold);
     info!("  Block timeout:          {}", config.block_timeout);
     info!("  Database path:          {}", config.db_path);
     info!("  Boot files path:        {}", config.bootstrap_path);
+    if let Some(log_file) = &config.log_file {
+        info!("  Log file:               {}", log_file);
+    }
+    if let Some(pid_file) = &config.pid_file {
+        info!("  PID file:               {}", pid_file);
+    }
     info!("  WM cache max size:      {}", config.wm_cache_max);
     info!(
-        "  REST service address:   {}:{}",
-        config.rest_addr, config.rest_port
+        "  REST service address:   {}",
+        utils::host_port(&config.rest_addr, config.rest_port)
     );
+    if !config.rest_extra_addrs.is_empty() {
+        info!(
+            "  REST extra addresses:   {}",
+            config.rest_extra_addrs.join(", ")
+        );
+    }
     info!(
-        "  Bridge service address: {}:{}",
-        config.bridge_addr, config.bridge_port
+        "  Bridge service address: {}",
+        utils::host_port(&config.bridge_addr, config.bridge_port)
+    );
+    if !config.bridge_extra_addrs.is_empty() {
+        info!(
+            "  Bridge extra addresses: {}",
+            config.bridge_extra_addrs.join(", ")
+        );
+    }
+    info!(
+        "  API service address:    {}",
+        utils::host_port(&config.api_addr, config.api_port)
+    );
+    if let Some(path) = &config.admin_socket {
+        info!("  Admin socket:           {}", path);
+    }
+    info!(
+        "  WS service address:     {}",
+        utils::host_port(&config.ws_addr, config.ws_port)
     );
     info!("  P2P service address:    {}", config.p2p_addr);
+    if !config.p2p_extra_addrs.is_empty() {
+        info!(
+            "  P2P extra addresses:    {}",
+            config.p2p_extra_addrs.join(", ")
+        );
+    }
     info!(
         "  P2P bootstrap address:  {}",
-        config.p2p_bootstrap_addr.clone().unwrap_or_default()
+        config.p2p_bootstrap_addrs.join(", ")
+    );
+    match (&config.public_ip, config.p2p_port) {
+        (Some(ip), port) if port != 0 => info!("  P2P public address:     {}:{}", ip, port),
+        _ => info!("  P2P public address:     unknown"),
+    }
+    if !config.p2p_allowed_peers.is_empty() || !config.p2p_blocked_peers.is_empty() {
+        info!(
+            "  P2P peers filter:       {} allowed, {} blocked",
+            config.p2p_allowed_peers.len(),
+            config.p2p_blocked_peers.len()
+        );
+    }
+    info!(
+        "  P2P reachability check: {}",
+        config.nat_probe.as_deref().unwrap_or("self-dial")
     );
-    if config.offline {
-        info!("  Offline mode:  Active");
+    info!("  P2P NAT fallback:       {}", config.nat_fallback);
+    if config.p2p_mdns {
+        info!("  P2P mDNS discovery:     Active");
+    }
+    if let Some(proxy) = &config.http_proxy {
+        info!(
+            "  HTTP proxy:             {}",
+            utils::hide_credentials(proxy)
+        );
+    }
+    info!(
+        "  Offline mode:           {}",
+        if config.offline { "Active" } else { "Inactive" }
+    );
+    if config.simulation {
+        info!("  Simulation mode:        Active");
+    }
+
+    // Settings not at their default value.
+    info!("Settings source:");
+    for (name, source) in sources {
+        if *source != ConfigSource::Default {
+            info!("  {:<24}{}", name, source);
+        }
+    }
+    info!("  Others:                 default");
+    for (name, hint) in config::deprecated_settings(sources) {
+        warn!("`{}` is deprecated, {}", name, hint);
+    }
+    for warning in config::config_warnings(config) {
+        warn!("Configuration: {}", warning);
     }
 
     // Feature enabled
@@ -97,6 +264,10 @@ fn show_config(config: &Config) {
         info!("  tmp2:  Active");
         active_feature = true;
     }
+    if cfg!(feature = "pkcs11") {
+        info!("  pkcs11:  Active");
+        active_feature = true;
+    }
     if cfg!(feature = "monitor") {
         info!("  monitor:  Active");
         active_feature = true;
@@ -115,17 +286,88 @@ fn show_config(config: &Config) {
 }
 
 fn main() {
-    logger_init();
-    let config = config::create_app_config();
-    logger_level(&config.log_level);
+    let matches = config::parse_args();
+    if matches.subcommand().is_some() {
+        logger_init(None);
+        std::process::exit(cmd::run(&matches));
+    }
+    let config = match config::create_app_config(&matches) {
+        Ok(config) => config,
+        Err(err) => {
+            logger_init(None);
+            error!("Error: {}", err);
+            std::process::exit(1);
+        }
+    };
+    // Before any thread is started.
+    if config.daemon && config.log_file.is_none() {
+        eprintln!("Warning: daemon mode without `log-file`, the node output is discarded");
+    }
+    // On Windows the daemon mode runs the node as a service instead.
+    let pid_file = match (config.daemon, &config.pid_file) {
+        #[cfg(not(windows))]
+        (true, path) => daemon::daemonize(path.as_deref()),
+        (_, Some(path)) => daemon::PidFile::create(path).map(Some),
+        (_, None) => Ok(None),
+    };
+    let pid_file = match pid_file {
+        Ok(pid_file) => pid_file,
+        Err(err) => {
+            logger_init(None);
+            error!("Error: {}", err);
+            std::process::exit(1);
+        }
+    };
+    #[cfg(windows)]
+    let code = match config.daemon {
+        true => winservice::run(config, &matches).unwrap_or_else(|err| {
+            logger_init(None);
+            error!("Error: {}", err);
+            1
+        }),
+        false => run(config, &matches),
+    };
+    #[cfg(not(windows))]
+    let code = run(config, &matches);
+    // `process::exit` skips the destructors.
+    drop(pid_file);
+    std::process::exit(code);
+}
+
+// Runs the node until its shutdown, returns the exit status.
+fn run(mut config: Config, matches: &clap::ArgMatches) -> i32 {
+    logger_init(log_file(&config));
+    logger_level(&config.log_level, &config.log_filters);
+    proxy::set(config.http_proxy.clone());
+
+    let ip_discovery = if config.auto_network_setup {
+        match ip_discovery::auto_setup(&mut config) {
+            Ok(discovery) => discovery,
+            Err(err) => {
+                error!("Error: {}", err);
+                return 1;
+            }
+        }
+    } else {
+        let mut discovery = ip_discovery::IpDiscovery::new(&config);
+        if let Some(discovery) = discovery.as_mut() {
+            discovery.detect();
+            discovery.populate(&mut config);
+        }
+        discovery
+    };
 
     info!("Starting TRINCI Node");
     info!("  Node version:         {}", env!("CARGO_PKG_VERSION"));
     info!("  Core version:         {}", trinci_core::VERSION);
 
-    show_config(&config);
+    show_config(&config, &config::config_sources(matches));
 
-    let filename = config.keypair_path.clone();
+    // The node keypair only signs blocks.
+    let filename = match config.role {
+        NodeRole::Full => config.keypair_path.clone(),
+        NodeRole::Api | NodeRole::Relay | NodeRole::Replica => None,
+    };
     let keypair = utils::load_keypair(filename).expect("keypair generation fail");
     info!("Node ID: {}", keypair.public_key().to_account_id());
 
@@ -141,24 +383,91 @@ fn main() {
     };
 
     #[cfg(not(feature = "monitor"))]
-    let (addr, file) = { (None::<String>, None::<String>) };
+    let addr = None::<String>;
     #[cfg(feature = "monitor")]
-    let (addr, file) = {
-        (
-            Some(config.monitor_addr.clone()),
-            Some(config.monitor_file.clone()),
-        )
+    let addr = Some(match config.telemetry {
+        telemetry::Telemetry::Remote if !config.offline => config.monitor_addr.clone(),
+        // An empty address disables the updates.
+        _ => String::new(),
+    });
+    let loader: config::ConfigLoader = {
+        let matches = matches.clone();
+        Arc::new(move || config::create_app_config(&matches))
     };
-    let mut app = App::new(config, keypair);
-    app.start(file, addr);
+    let mut app = match App::new(config, keypair, loader) {
+        Ok(app) => app,
+        Err(err) => {
+            error!("Error: {}", err);
+            return 1;
+        }
+    };
+    CrashReporter::install(app.crash.clone());
+    if let Err(err) = app.start(addr) {
+        error!("Error: {}", err);
+        return 1;
+    }
+    #[cfg(windows)]
+    winservice::attach(app.control.clone());
+
+    // Blocks throughput metrics.
+    if app.role != NodeRole::Relay {
+        let chan = app.block_svc.lock().request_channel();
+        let tracer = app.tracer.clone();
+        app.tasks.spawn("tracer", move || tracer::run(tracer, chan));
 
-    // Temporary blockchain "stuff" tracer.
-    let chan = app.block_svc.lock().request_channel();
-    std::thread::spawn(move || tracer::run(chan));
+        // Receipt push notifications.
+        let chan = app.block_svc.lock().request_channel();
+        let notifier = app.notifier.clone();
+        app.tasks
+            .spawn("notify", move || notify::run(notifier, chan));
+
+        // Transactions followed up to their block.
+        if let Some(correlation) = app.correlation.clone() {
+            let chan = app.block_svc.lock().request_channel();
+            app.tasks
+                .spawn("correlation", move || Correlation::run(correlation, chan));
+        }
+
+        // OpenTelemetry export.
+        #[cfg(feature = "otel")]
+        if let Some(otel) = app.otel.clone() {
+            let chan = app.block_svc.lock().request_channel();
+            let export = otel.clone();
+            let export_chan = chan.clone();
+            app.tasks.spawn("otel_export", move || {
+                otel::Otel::export(export, export_chan)
+            });
+            app.tasks.spawn("otel", move || otel::Otel::run(otel, chan));
+        }
+    }
+    if let Some(correlation) = app.correlation.clone() {
+        app.tasks.spawn("correlation_export", move || {
+            Correlation::export(correlation)
+        });
+    }
+
+    // Follow dynamic IPs.
+    if let Some(discovery) = ip_discovery {
+        #[cfg(feature = "monitor")]
+        let status = app.monitor_svc.as_ref().map(|monitor| monitor.status());
+        app.tasks.spawn("ip_discovery", move || {
+            ip_discovery::run(discovery, |_local_ip, _public_ip| {
+                #[cfg(feature = "monitor")]
+                if let Some(status) = &status {
+                    let mut status = status.write();
+                    status.data.ip_endpoint = _local_ip;
+                    status.data.pub_ip = _public_ip;
+                }
+            })
+        });
+    }
 
     // Start litening into brigde soket
     // TODO: make a module.
 
     info!("System up and running...");
-    app.park();
+    match app.park() {
+        true => 0,
+        false => 1,
+    }
 }
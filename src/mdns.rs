@@ -0,0 +1,130 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Local network peer discovery (`p2p-mdns`).
+//!
+//! Meant for test networks of several nodes on one LAN. The node announces
+//! its p2p address as a multicast DNS service and, before starting the p2p
+//! service, browses the service announcements of the other nodes. The
+//! discovered addresses are appended to the bootstrap ones.
+
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// DNS-SD service type of the TRINCI p2p service.
+const SERVICE_TYPE: &str = "_trinci._tcp.local.";
+
+/// TXT property holding the p2p peer identifier.
+const PEER_PROPERTY: &str = "peer";
+
+/// Time spent browsing the announcements at startup.
+pub const DISCOVERY_WINDOW: Duration = Duration::from_secs(3);
+
+pub struct Mdns {
+    daemon: ServiceDaemon,
+    peer_id: String,
+}
+
+// P2P address of an announced node.
+fn peer_address(info: &ServiceInfo) -> Option<String> {
+    let peer = info.get_property_val_str(PEER_PROPERTY)?;
+    let addr = info
+        .get_addresses()
+        .iter()
+        .min_by_key(|addr| addr.is_ipv6())?;
+    let proto = match addr {
+        IpAddr::V4(_) => "ip4",
+        IpAddr::V6(_) => "ip6",
+    };
+    Some(format!(
+        "{}@/{}/{}/tcp/{}",
+        peer,
+        proto,
+        addr,
+        info.get_port()
+    ))
+}
+
+impl Mdns {
+    /// Starts the daemon and announces the p2p service, if the port is known.
+    pub fn start(peer_id: &str, port: u16) -> Option<Self> {
+        let daemon = match ServiceDaemon::new() {
+            Ok(daemon) => daemon,
+            Err(err) => {
+                error!("[mdns] unable to start: {}", err);
+                return None;
+            }
+        };
+        if port == 0 {
+            warn!("[mdns] random p2p port, the node is not announced");
+        } else {
+            let host = format!("{}.local.", peer_id);
+            let result = ServiceInfo::new(
+                SERVICE_TYPE,
+                peer_id,
+                &host,
+                "",
+                port,
+                &[(PEER_PROPERTY, peer_id)][..],
+            )
+            .and_then(|info| daemon.register(info.enable_addr_auto()));
+            if let Err(err) = result {
+                error!("[mdns] unable to announce the node: {}", err);
+            }
+        }
+        Some(Mdns {
+            daemon,
+            peer_id: peer_id.to_owned(),
+        })
+    }
+
+    /// Browses the p2p services announced within `window`.
+    pub fn discover(&self, window: Duration) -> Vec<String> {
+        let receiver = match self.daemon.browse(SERVICE_TYPE) {
+            Ok(receiver) => receiver,
+            Err(err) => {
+                error!("[mdns] unable to browse: {}", err);
+                return vec![];
+            }
+        };
+        let deadline = Instant::now() + window;
+        let mut peers = vec![];
+        while let Ok(event) = receiver.recv_deadline(deadline) {
+            if let ServiceEvent::ServiceResolved(info) = event {
+                if info.get_property_val_str(PEER_PROPERTY) == Some(self.peer_id.as_str()) {
+                    continue;
+                }
+                if let Some(addr) = peer_address(&info) {
+                    if !peers.contains(&addr) {
+                        info!("[mdns] discovered peer {}", addr);
+                        peers.push(addr);
+                    }
+                }
+            }
+        }
+        let _ = self.daemon.stop_browse(SERVICE_TYPE);
+        peers
+    }
+
+    /// Withdraws the announcement and stops the daemon.
+    pub fn stop(&self) {
+        let _ = self.daemon.shutdown();
+    }
+}
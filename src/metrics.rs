@@ -0,0 +1,203 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Node services requests metrics.
+//!
+//! Every request forwarded by the gateway is accounted by source service
+//! (REST, bridge, P2P) and message type. REST routes are translated by the
+//! core one-to-one into request messages, thus the message type identifies
+//! the route as well.
+
+use crate::api::{Request, Response, Router};
+use std::{collections::BTreeMap, fmt::Write, sync::Arc, time::Duration};
+use trinci_core::{base::Mutex, blockchain::Message};
+
+/// Latency histogram upper bounds, in seconds.
+pub const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Metrics of a single (source, message type) pair.
+#[derive(Default)]
+struct Stat {
+    count: u64,
+    errors: u64,
+    /// Non cumulative buckets counters, the last one is `+Inf`.
+    buckets: [u64; BUCKETS.len() + 1],
+    sum: f64,
+}
+
+/// Metrics exposed by other node components.
+pub trait MetricsSource: Send + Sync {
+    /// Appends the metrics in Prometheus text exposition format.
+    fn render(&self, out: &mut String);
+}
+
+/// Requests metrics registry.
+#[derive(Default)]
+pub struct Metrics {
+    stats: Mutex<BTreeMap<(&'static str, &'static str), Stat>>,
+    sources: Mutex<Vec<Arc<dyn MetricsSource>>>,
+}
+
+/// Label used for a request message.
+pub fn message_kind(msg: &Message) -> &'static str {
+    match msg {
+        Message::Subscribe { .. } => "subscribe",
+        Message::Unsubscribe { .. } => "unsubscribe",
+        Message::PutTransactionRequest { .. } => "put_transaction",
+        Message::GetTransactionRequest { .. } => "get_transaction",
+        Message::GetReceiptRequest { .. } => "get_receipt",
+        Message::GetBlockRequest { .. } => "get_block",
+        Message::GetAccountRequest { .. } => "get_account",
+        Message::GetCoreStatsRequest => "get_core_stats",
+        Message::GetNetworkIdRequest => "get_network_id",
+        Message::GetSeedRequest => "get_seed",
+        Message::GetP2pIdRequest => "get_p2p_id",
+        Message::Packed { .. } => "packed",
+        _ => "other",
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a source to the exposed metrics.
+    pub fn register(&self, source: Arc<dyn MetricsSource>) {
+        self.sources.lock().push(source);
+    }
+
+    /// Accounts a served request.
+    pub fn observe(
+        &self,
+        source: &'static str,
+        kind: &'static str,
+        elapsed: Duration,
+        error: bool,
+    ) {
+        let secs = elapsed.as_secs_f64();
+        let mut stats = self.stats.lock();
+        let stat = stats.entry((source, kind)).or_default();
+        stat.count += 1;
+        if error {
+            stat.errors += 1;
+        }
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(BUCKETS.len());
+        stat.buckets[bucket] += 1;
+        stat.sum += secs;
+    }
+
+    /// Metrics in Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let stats = self.stats.lock();
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# TYPE trinci_requests_total counter");
+        for ((source, kind), stat) in stats.iter() {
+            let _ = writeln!(
+                out,
+                "trinci_requests_total{{source=\"{}\",message=\"{}\"}} {}",
+                source, kind, stat.count
+            );
+        }
+        let _ = writeln!(out, "# TYPE trinci_request_errors_total counter");
+        for ((source, kind), stat) in stats.iter() {
+            let _ = writeln!(
+                out,
+                "trinci_request_errors_total{{source=\"{}\",message=\"{}\"}} {}",
+                source, kind, stat.errors
+            );
+        }
+        let _ = writeln!(out, "# TYPE trinci_request_duration_seconds histogram");
+        for ((source, kind), stat) in stats.iter() {
+            let mut cumulative = 0;
+            for (i, count) in stat.buckets.iter().enumerate() {
+                cumulative += count;
+                let bound = match BUCKETS.get(i) {
+                    Some(bound) => bound.to_string(),
+                    None => "+Inf".to_string(),
+                };
+                let _ = writeln!(
+                    out,
+                    "trinci_request_duration_seconds_bucket{{source=\"{}\",message=\"{}\",le=\"{}\"}} {}",
+                    source, kind, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "trinci_request_duration_seconds_sum{{source=\"{}\",message=\"{}\"}} {}",
+                source, kind, stat.sum
+            );
+            let _ = writeln!(
+                out,
+                "trinci_request_duration_seconds_count{{source=\"{}\",message=\"{}\"}} {}",
+                source, kind, stat.count
+            );
+        }
+        drop(stats);
+        for source in self.sources.lock().iter() {
+            source.render(&mut out);
+        }
+        out
+    }
+
+    /// Registers the metrics endpoint within the node API.
+    pub fn routes(metrics: Arc<Self>, router: &mut Router) {
+        router.add("GET", "/metrics", move |_: &Request| {
+            let mut res = Response::text(200, metrics.render());
+            res.content_type = "text/plain; version=0.0.4";
+            res
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_histogram() {
+        let metrics = Metrics::new();
+        metrics.observe("rest", "get_account", Duration::from_millis(3), false);
+        metrics.observe("rest", "get_account", Duration::from_millis(300), true);
+        metrics.observe("bridge", "put_transaction", Duration::from_secs(20), false);
+
+        let out = metrics.render();
+
+        assert!(out.contains("trinci_requests_total{source=\"rest\",message=\"get_account\"} 2"));
+        assert!(
+            out.contains("trinci_request_errors_total{source=\"rest\",message=\"get_account\"} 1")
+        );
+        assert!(out.contains(
+            "trinci_request_duration_seconds_bucket{source=\"rest\",message=\"get_account\",le=\"0.005\"} 1"
+        ));
+        assert!(out.contains(
+            "trinci_request_duration_seconds_bucket{source=\"rest\",message=\"get_account\",le=\"0.5\"} 2"
+        ));
+        assert!(out.contains(
+            "trinci_request_duration_seconds_bucket{source=\"bridge\",message=\"put_transaction\",le=\"10\"} 0"
+        ));
+        assert!(out.contains(
+            "trinci_request_duration_seconds_bucket{source=\"bridge\",message=\"put_transaction\",le=\"+Inf\"} 1"
+        ));
+    }
+}
@@ -0,0 +1,222 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Monitor alerts.
+//!
+//! Conditions checked by the monitor at every status refresh (no new block
+//! for a while, unconfirmed pool too large), the resources guard conditions
+//! and node events (start, service failure, fork, crash) are notified to a webhook, as a JSON POST, and/or to a local
+//! script, through the `TRINCI_ALERT*` environment variables.
+//! Conditions are notified when raised and when resolved.
+//!
+//! The core p2p service does not report the connected peers, thus no peers
+//! condition is available.
+
+use crate::proxy;
+use isahc::{Request, RequestExt};
+use serde::Serialize;
+use std::{
+    collections::BTreeSet,
+    process::Command,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use trinci_core::base::Mutex;
+
+/// Alerts configuration.
+#[derive(Default)]
+pub struct AlertConfig {
+    /// Seconds without a new block that raise `no_block`, zero disables.
+    pub no_block_secs: u64,
+    /// Unconfirmed pool size that raises `pool_size`, zero disables.
+    pub pool_size: usize,
+    /// Webhook URL.
+    pub webhook: Option<String>,
+    /// Local script.
+    pub exec: Option<String>,
+}
+
+/// Alert notification.
+#[derive(Serialize, Clone, Debug)]
+pub struct Alert {
+    /// Alert kind: `no_block`, `pool_size`, `node_started`, `service_down`,
+    /// `memory`, `open_files`, `disk_space`, `stalled`, `burn_method`, `fork`.
+    pub kind: &'static str,
+    pub message: String,
+    /// Condition resolved.
+    pub resolved: bool,
+    /// Node identifier.
+    pub node: String,
+    /// Seconds since the epoch.
+    pub time: u64,
+}
+
+pub struct Alerter {
+    config: AlertConfig,
+    node: String,
+    /// Raised conditions.
+    raised: Mutex<BTreeSet<&'static str>>,
+}
+
+fn send(config: (Option<String>, Option<String>), alert: Alert) {
+    let (webhook, exec) = config;
+    if let Some(url) = webhook {
+        let result = serde_json::to_string(&alert)
+            .map_err(|err| err.to_string())
+            .and_then(|body| {
+                proxy::apply(Request::post(url))
+                    .header("content-type", "application/json")
+                    .body(body)
+                    .map_err(|err| err.to_string())
+            })
+            .and_then(|req| req.send().map_err(|err| err.to_string()));
+        if let Err(err) = result {
+            warn!("[monitor] alert webhook: {}", err);
+        }
+    }
+    if let Some(script) = exec {
+        let result = Command::new(&script)
+            .env("TRINCI_ALERT", alert.kind)
+            .env("TRINCI_ALERT_MESSAGE", &alert.message)
+            .env("TRINCI_ALERT_RESOLVED", alert.resolved.to_string())
+            .env("TRINCI_ALERT_NODE", &alert.node)
+            .status();
+        match result {
+            Ok(status) if !status.success() => warn!("[monitor] alert script: {}", status),
+            Err(err) => warn!("[monitor] alert script {}: {}", script, err),
+            _ => (),
+        }
+    }
+}
+
+impl Alerter {
+    pub fn new(config: AlertConfig, node: String) -> Self {
+        Alerter {
+            config,
+            node,
+            raised: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    // Sends the alert in background, the sinks may be slow.
+    fn dispatch(
+        &self,
+        kind: &'static str,
+        message: String,
+        resolved: bool,
+    ) -> Option<JoinHandle<()>> {
+        let alert = Alert {
+            kind,
+            message,
+            resolved,
+            node: self.node.clone(),
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        if resolved {
+            info!("[monitor] alert {} resolved", kind);
+        } else {
+            warn!("[monitor] alert {}: {}", kind, alert.message);
+        }
+        if self.config.webhook.is_none() && self.config.exec.is_none() {
+            return None;
+        }
+        let sinks = (self.config.webhook.clone(), self.config.exec.clone());
+        Some(thread::spawn(move || send(sinks, alert)))
+    }
+
+    // Updates the state of a condition, returns `true` if it changed.
+    fn transition(&self, kind: &'static str, active: bool) -> bool {
+        let mut raised = self.raised.lock();
+        if active {
+            raised.insert(kind)
+        } else {
+            raised.remove(kind)
+        }
+    }
+
+    /// Updates a condition, notified only when raised or resolved.
+    pub fn condition(&self, kind: &'static str, active: bool, message: impl FnOnce() -> String) {
+        if self.transition(kind, active) {
+            self.dispatch(kind, message(), !active);
+        }
+    }
+
+    /// Notifies a node event, the handle allows to wait for the delivery.
+    pub fn event(&self, kind: &'static str, message: String) -> Option<JoinHandle<()>> {
+        self.dispatch(kind, message, false)
+    }
+
+    /// Notifies a node event and waits up to `timeout` for the delivery, a
+    /// slow sink is left behind.
+    pub fn deliver(&self, kind: &'static str, message: String, timeout: Duration) {
+        let handle = match self.event(kind, message) {
+            Some(handle) => handle,
+            None => return,
+        };
+        let deadline = Instant::now() + timeout;
+        while !handle.is_finished() {
+            if Instant::now() >= deadline {
+                warn!("[monitor] alert {} not delivered in {:?}", kind, timeout);
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let _ = handle.join();
+    }
+
+    /// Checks the conditions against the current node status.
+    pub fn check(&self, last_block_age: u64, pool_size: usize) {
+        let no_block = self.config.no_block_secs;
+        self.condition(
+            "no_block",
+            no_block != 0 && last_block_age >= no_block,
+            || format!("no new block for {} seconds", last_block_age),
+        );
+        let max_pool = self.config.pool_size;
+        self.condition("pool_size", max_pool != 0 && pool_size > max_pool, || {
+            format!("{} unconfirmed transactions in the pool", pool_size)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conditions_edge_triggered() {
+        let alerter = Alerter::new(
+            AlertConfig {
+                no_block_secs: 60,
+                pool_size: 100,
+                ..Default::default()
+            },
+            "node".to_string(),
+        );
+
+        alerter.check(120, 10);
+        assert!(alerter.raised.lock().contains("no_block"));
+        assert!(!alerter.transition("no_block", true));
+
+        alerter.check(0, 500);
+        let raised = alerter.raised.lock().clone();
+        assert_eq!(raised.into_iter().collect::<Vec<_>>(), ["pool_size"]);
+    }
+}
@@ -0,0 +1,170 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Monitor history.
+//!
+//! Append-only JSON lines file with the node status recorded by the monitor,
+//! one record per update. When the file exceeds the size cap it is rotated:
+//! `<file>` becomes `<file>.1`, `<file>.1` becomes `<file>.2` and so on, the
+//! oldest one is dropped.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// History file configuration.
+#[derive(Clone)]
+pub struct HistoryConfig {
+    /// Current history file.
+    pub path: PathBuf,
+    /// File size in bytes that triggers the rotation.
+    pub max_size: u64,
+    /// Number of rotated files kept.
+    pub files: usize,
+}
+
+#[derive(Serialize)]
+struct Entry<'a, T> {
+    time: u64,
+    status: &'a T,
+}
+
+/// History record, as read back.
+#[derive(Deserialize)]
+pub struct Record {
+    /// Record time, seconds since the epoch.
+    pub time: u64,
+    /// Recorded status.
+    pub status: serde_json::Value,
+}
+
+pub struct History {
+    config: HistoryConfig,
+}
+
+impl History {
+    pub fn new(config: HistoryConfig) -> Self {
+        History { config }
+    }
+
+    // Path of the rotated file with the given index, zero is the current one.
+    fn file(&self, index: usize) -> PathBuf {
+        match index {
+            0 => self.config.path.clone(),
+            _ => {
+                let mut path = self.config.path.clone().into_os_string();
+                path.push(format!(".{}", index));
+                path.into()
+            }
+        }
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        if self.config.files == 0 {
+            return fs::remove_file(self.file(0));
+        }
+        let _ = fs::remove_file(self.file(self.config.files));
+        for index in (0..self.config.files).rev() {
+            match fs::rename(self.file(index), self.file(index + 1)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends a status record, rotating the file if needed.
+    pub fn append<T: Serialize>(&self, status: &T) -> io::Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut line = serde_json::to_vec(&Entry { time, status })?;
+        line.push(b'\n');
+
+        let size = fs::metadata(&self.config.path)
+            .map(|meta| meta.len())
+            .unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > self.config.max_size {
+            self.rotate()?;
+        }
+        if let Some(dir) = self.config.path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)?;
+        file.write_all(&line)
+    }
+
+    /// Reads the records, oldest first. Malformed lines are skipped.
+    pub fn records(&self) -> io::Result<Vec<Record>> {
+        let mut records = vec![];
+        for index in (0..=self.config.files).rev() {
+            let file = match fs::File::open(self.file(index)) {
+                Ok(file) => file,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            for line in BufReader::new(file).lines() {
+                if let Ok(record) = serde_json::from_str::<Record>(&line?) {
+                    records.push(record);
+                }
+            }
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn append_and_rotate() {
+        let dir = TempDir::new().unwrap();
+        let history = History::new(HistoryConfig {
+            path: dir.path().join("history.jsonl"),
+            max_size: 64,
+            files: 2,
+        });
+        for height in 0..10u64 {
+            history
+                .append(&serde_json::json!({ "height": height }))
+                .unwrap();
+        }
+
+        let records = history.records().unwrap();
+        let heights: Vec<_> = records
+            .iter()
+            .map(|record| record.status["height"].as_u64().unwrap())
+            .collect();
+
+        assert!(!dir.path().join("history.jsonl.3").exists());
+        assert!(heights.len() < 10);
+        assert_eq!(heights.last(), Some(&9));
+        assert!(heights.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
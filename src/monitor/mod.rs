@@ -15,5 +15,9 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
 
+pub mod alert;
+pub mod history;
+pub mod page;
 pub mod service;
+pub mod status;
 pub(crate) mod worker;
@@ -0,0 +1,94 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Node status page, served by the node API.
+//!
+//! The page polls `/status/data` and renders the monitor status sections.
+
+use crate::api::{Request, Response, Router};
+use crate::monitor::status::MonitorConfig;
+use std::sync::Arc;
+use trinci_core::base::RwLock;
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>TRINCI node status</title>
+<style>
+body { font-family: monospace; margin: 2em; color: #222; }
+h2 { font-size: 1em; margin: 1.5em 0 0.3em; text-transform: uppercase; }
+table { border-collapse: collapse; }
+td { border: 1px solid #ccc; padding: 0.2em 0.6em; word-break: break-all; }
+td:first-child { color: #666; white-space: nowrap; }
+#updated { color: #666; }
+</style>
+</head>
+<body>
+<h1>TRINCI node status</h1>
+<div id="updated">loading...</div>
+<div id="status"></div>
+<script>
+function render(sections) {
+  const root = document.getElementById("status");
+  root.replaceChildren();
+  for (const section of sections) {
+    const title = document.createElement("h2");
+    title.textContent = section.title;
+    root.appendChild(title);
+    const table = document.createElement("table");
+    if (section.rows.length === 0) {
+      table.insertRow().insertCell().textContent = "None";
+    }
+    for (const [field, value] of section.rows) {
+      const row = table.insertRow();
+      row.insertCell().textContent = field;
+      row.insertCell().textContent = value;
+    }
+    root.appendChild(table);
+  }
+}
+function refresh() {
+  fetch("/status/data")
+    .then((res) => res.json())
+    .then((sections) => {
+      render(sections);
+      document.getElementById("updated").textContent =
+        "updated " + new Date().toLocaleTimeString();
+    })
+    .catch((err) => {
+      document.getElementById("updated").textContent = "node unreachable: " + err;
+    });
+}
+refresh();
+setInterval(refresh, 5000);
+</script>
+</body>
+</html>
+"#;
+
+/// Registers the status page routes within the node API.
+pub fn routes(status: Arc<RwLock<MonitorConfig>>, router: &mut Router) {
+    router.add("GET", "/status", |_: &Request| Response {
+        status: 200,
+        content_type: "text/html; charset=utf-8",
+        body: PAGE.as_bytes().to_vec(),
+    });
+    router.add("GET", "/status/data", move |_: &Request| {
+        Response::json(&status.read().sections())
+    });
+}
@@ -15,35 +15,58 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::monitor::worker::{MonitorConfig, MonitorWorker};
-use std::{
-    sync::Arc,
-    thread::{self, JoinHandle},
-};
-use trinci_core::blockchain::BlockRequestSender;
+use crate::clock::Clock;
+use crate::monitor::alert::Alerter;
+use crate::monitor::history::History;
+use crate::monitor::status::MonitorConfig;
+use crate::monitor::worker::MonitorWorker;
+use crate::tasks::Tasks;
+use crate::telemetry::Redact;
+use crate::tracer::Tracer;
+use crate::traffic::Traffic;
+use std::sync::Arc;
+use trinci_core::{base::RwLock, blockchain::BlockRequestSender};
 
 pub struct MonitorService {
     /// Worker object
     worker: Option<MonitorWorker>,
-    /// Worker thread handler
-    handler: Option<JoinHandle<MonitorWorker>>,
     /// To check if the worker still alive
     canary: Arc<()>,
+    /// Node status, shared with the worker
+    status: Arc<RwLock<MonitorConfig>>,
 }
 
 impl MonitorService {
-    pub fn new(config: MonitorConfig, bc_chan: BlockRequestSender, offline: bool) -> Self {
-        let worker = MonitorWorker::new(config, bc_chan, offline);
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: MonitorConfig,
+        bc_chan: BlockRequestSender,
+        redact: Vec<Redact>,
+        tracer: Arc<Tracer>,
+        traffic: Arc<Traffic>,
+        history: Option<History>,
+        alerter: Arc<Alerter>,
+        clock: Clock,
+    ) -> Self {
+        let worker = MonitorWorker::new(
+            config, bc_chan, redact, tracer, traffic, history, alerter, clock,
+        );
+        let status = worker.status();
 
         MonitorService {
             worker: Some(worker),
-            handler: None,
             canary: Arc::new(()),
+            status,
         }
     }
 
-    /// Start monitor service if not already running
-    pub fn start(&mut self, addr: String, file: String) {
+    /// Node status, as refreshed by the worker.
+    pub fn status(&self) -> Arc<RwLock<MonitorConfig>> {
+        self.status.clone()
+    }
+
+    /// Start monitor service if not already running, as a supervised task.
+    pub fn start(&mut self, addr: String, tasks: &Tasks) {
         debug!("Starting MONITOR service");
 
         let mut worker = match self.worker.take() {
@@ -55,12 +78,10 @@ impl MonitorService {
         };
 
         let mut canary = Arc::clone(&self.canary);
-        let handle = thread::spawn(move || {
+        tasks.spawn("monitor", move || {
             let _ = Arc::get_mut(&mut canary);
-            worker.run(addr, file); // it was run_sync() in bridge
-            worker
+            worker.run(addr); // it was run_sync() in bridge
         });
-        self.handler = Some(handle)
     }
 
     /// Stop monitor service
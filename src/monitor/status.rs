@@ -0,0 +1,353 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Node status, as tracked by the monitor worker, sent to the monitor
+//! server, recorded in the history and rendered by the status page.
+
+use crate::catchup::SyncStatus;
+use crate::tracer::TracerStats;
+use crate::traffic::TrafficStats;
+use serde::Serialize;
+use trinci_core::{crypto::Hash, Block};
+
+/// structure to track node information
+#[derive(Serialize)]
+/// structure that holds the hash of the unconfirmed transaction queue and it's dimension
+pub struct UnconfirmedPool {
+    pub(crate) hash: Hash,
+    pub(crate) size: usize,
+}
+
+#[derive(Serialize)]
+/// structure that holds the last block received by the node and its hash
+pub struct LastBlock {
+    pub(crate) block: Block,
+    pub(crate) hash: Hash,
+}
+
+#[derive(Serialize)]
+pub struct P2pInfo {
+    pub p2p_addr: String,
+    /// P2p service tcp port.
+    pub p2p_port: u16,
+    /// P2P service bootstrap address, the first answering one.
+    pub p2p_bootstrap_addr: Option<String>,
+    /// P2P service configured bootstrap addresses.
+    pub p2p_bootstrap_peers: Vec<String>,
+    /// Port mapping protocol accepted by the gateway (upnp, pcp, nat-pmp).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port_mapping: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct NetworkConfig {
+    pub name: String,
+    pub block_threshold: usize,
+    pub block_timeout: u16,
+}
+
+#[derive(Serialize)]
+pub enum NodeRole {
+    Ordinary,
+    #[allow(dead_code)] // FIXME
+    Validator,
+}
+
+#[derive(Serialize)]
+pub struct Status {
+    /// public key associated with the node
+    pub public_key: String,
+    /// public key associated with the node for p2p network
+    pub nw_public_key: String,
+    /// ip entry point to contact the node (local)
+    pub ip_endpoint: Option<String>,
+    /// ip seen from the extern
+    pub pub_ip: Option<String>,
+    /// node's role
+    pub role: NodeRole,
+    /// partial network config that reside in the bootstrap
+    pub nw_config: NetworkConfig,
+    /// core version held by the node
+    pub core_version: String,
+    /// digest of the node settings, to detect configuration drifts
+    pub config_hash: String,
+    /// last node's block
+    pub last_block: Option<LastBlock>,
+    /// structure that holds some information about the unconfirmed tx queue
+    pub unconfirmed_pool: Option<UnconfirmedPool>,
+    /// infos regarding the p2p config
+    pub p2p_info: P2pInfo,
+    /// seed
+    pub seed: u64,
+    /// blocks and transactions throughput
+    pub throughput: Option<TracerStats>,
+    /// P2P traffic statistics
+    pub p2p_traffic: Option<TrafficStats>,
+    /// chain catch up progress
+    pub sync: Option<SyncStatus>,
+}
+
+/// Due to server interaction the Monitor server
+/// Structure needs this names as field
+/// It holds the node information
+#[derive(Serialize)]
+#[allow(non_snake_case)]
+pub struct MonitorConfig {
+    pub(crate) nodeID: String,
+    pub(crate) data: Status,
+}
+
+/// Status page section, a list of (field, value) rows.
+#[derive(Serialize)]
+pub(crate) struct Section {
+    title: &'static str,
+    rows: Vec<(&'static str, String)>,
+}
+
+impl MonitorConfig {
+    /// Node status in a human readable format, as shown by the status page.
+    pub(crate) fn sections(&self) -> Vec<Section> {
+        fn or_none(value: &Option<String>) -> String {
+            value.clone().unwrap_or_else(|| String::from("None"))
+        }
+        let data = &self.data;
+        let role = match &data.role {
+            NodeRole::Ordinary => "ordinary",
+            NodeRole::Validator => "validator",
+        };
+
+        let mut sections = vec![
+            Section {
+                title: "node info",
+                rows: vec![
+                    ("node id", self.nodeID.clone()),
+                    ("public key", data.public_key.clone()),
+                    ("network public key", data.nw_public_key.clone()),
+                    ("public IP", or_none(&data.pub_ip)),
+                    ("IP end point", or_none(&data.ip_endpoint)),
+                    ("role", role.to_string()),
+                    ("core version", data.core_version.clone()),
+                    ("config hash", data.config_hash.clone()),
+                ],
+            },
+            Section {
+                title: "network info",
+                rows: vec![
+                    ("network name", data.nw_config.name.clone()),
+                    (
+                        "block threshold",
+                        data.nw_config.block_threshold.to_string(),
+                    ),
+                    ("block timeout", data.nw_config.block_timeout.to_string()),
+                ],
+            },
+            Section {
+                title: "p2p info",
+                rows: vec![
+                    ("p2p address", data.p2p_info.p2p_addr.clone()),
+                    ("p2p port", data.p2p_info.p2p_port.to_string()),
+                    (
+                        "p2p bootstrap address",
+                        or_none(&data.p2p_info.p2p_bootstrap_addr),
+                    ),
+                    (
+                        "p2p bootstrap peers",
+                        data.p2p_info.p2p_bootstrap_peers.len().to_string(),
+                    ),
+                    ("p2p port mapping", or_none(&data.p2p_info.port_mapping)),
+                ],
+            },
+        ];
+
+        let rows = match &data.last_block {
+            Some(last_block) => {
+                let block = &last_block.block.data;
+                vec![
+                    ("hash", hex::encode(last_block.hash.as_bytes())),
+                    ("height", block.height.to_string()),
+                    ("size", block.size.to_string()),
+                    ("previous hash", hex::encode(block.prev_hash.as_bytes())),
+                    ("txs hash", hex::encode(block.txs_hash.as_bytes())),
+                    ("rxs hash", hex::encode(block.rxs_hash.as_bytes())),
+                    ("state hash", hex::encode(block.state_hash.as_bytes())),
+                ]
+            }
+            None => vec![],
+        };
+        sections.push(Section {
+            title: "last block",
+            rows,
+        });
+
+        let mut rows = match &data.unconfirmed_pool {
+            Some(pool) => vec![
+                ("hash", hex::encode(pool.hash.hash_value())),
+                ("length", pool.size.to_string()),
+            ],
+            None => vec![("length", "0".to_string())],
+        };
+        rows.push(("seed", data.seed.to_string()));
+        sections.push(Section {
+            title: "unconfirmed pool",
+            rows,
+        });
+
+        let rows = match &data.throughput {
+            Some(stats) => vec![
+                ("tps", format!("{:.2}", stats.tps)),
+                (
+                    "block interval",
+                    stats
+                        .block_interval
+                        .as_ref()
+                        .map(|interval| format!("{:.2} s", interval.avg))
+                        .unwrap_or_default(),
+                ),
+                ("blocks", stats.totals.blocks.to_string()),
+                ("transactions", stats.totals.txs.to_string()),
+                ("fuel burned", stats.totals.fuel.to_string()),
+            ],
+            None => vec![],
+        };
+        sections.push(Section {
+            title: "throughput",
+            rows,
+        });
+
+        let rows = match &data.p2p_traffic {
+            Some(traffic) => {
+                let mut rows = vec![
+                    ("bytes received", traffic.bytes_received.to_string()),
+                    ("bytes sent", traffic.bytes_sent.to_string()),
+                ];
+                if let Some(peer) = traffic.peers.first() {
+                    rows.push((
+                        "busiest peer",
+                        format!(
+                            "{} ({:.0} B/s in, {:.0} B/s out)",
+                            peer.peer, peer.rate_received, peer.rate_sent
+                        ),
+                    ));
+                }
+                if let Some(propagation) = &traffic.block_propagation {
+                    rows.push((
+                        "block propagation",
+                        format!(
+                            "p50 {} ms, p95 {} ms ({} blocks)",
+                            propagation.p50_ms, propagation.p95_ms, propagation.blocks
+                        ),
+                    ));
+                }
+                rows
+            }
+            None => vec![],
+        };
+        sections.push(Section {
+            title: "p2p traffic",
+            rows,
+        });
+
+        let rows = match &data.sync {
+            Some(sync) => vec![
+                ("syncing", sync.syncing.to_string()),
+                (
+                    "height",
+                    format!(
+                        "{}/{}",
+                        sync.height.map(|h| h.to_string()).unwrap_or_default(),
+                        sync.target.map(|h| h.to_string()).unwrap_or_default()
+                    ),
+                ),
+                ("rate", format!("{:.1} blocks/s", sync.blocks_per_sec)),
+                (
+                    "eta",
+                    sync.eta_secs
+                        .map(|eta| format!("{} s", eta))
+                        .unwrap_or_default(),
+                ),
+            ],
+            None => vec![],
+        };
+        sections.push(Section {
+            title: "sync",
+            rows,
+        });
+        sections
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status() -> MonitorConfig {
+        MonitorConfig {
+            nodeID: "node".to_string(),
+            data: Status {
+                public_key: "pk".to_string(),
+                nw_public_key: "nwpk".to_string(),
+                ip_endpoint: None,
+                pub_ip: Some("203.0.113.10".to_string()),
+                role: NodeRole::Ordinary,
+                nw_config: NetworkConfig {
+                    name: "net".to_string(),
+                    block_threshold: 42,
+                    block_timeout: 3,
+                },
+                core_version: "0.2.10".to_string(),
+                config_hash: "c0ffee".to_string(),
+                last_block: None,
+                unconfirmed_pool: None,
+                p2p_info: P2pInfo {
+                    p2p_addr: "0.0.0.0".to_string(),
+                    p2p_port: 9006,
+                    p2p_bootstrap_addr: None,
+                    p2p_bootstrap_peers: vec![],
+                    port_mapping: None,
+                },
+                seed: 7,
+                throughput: None,
+                p2p_traffic: None,
+                sync: None,
+            },
+        }
+    }
+
+    #[test]
+    fn monitor_server_layout() {
+        let json = serde_json::to_value(status()).unwrap();
+
+        assert_eq!(json["nodeID"], "node");
+        assert_eq!(json["data"]["role"], "Ordinary");
+        assert_eq!(json["data"]["nw_config"]["block_threshold"], 42);
+        assert_eq!(json["data"]["p2p_info"]["p2p_port"], 9006);
+        assert!(json["data"]["last_block"].is_null());
+        assert_eq!(json["data"]["seed"], 7);
+    }
+
+    #[test]
+    fn page_sections() {
+        let sections = serde_json::to_value(status().sections()).unwrap();
+
+        assert_eq!(sections[0]["title"], "node info");
+        assert_eq!(
+            sections[0]["rows"][0],
+            serde_json::json!(["node id", "node"])
+        );
+        assert_eq!(sections[3]["rows"], serde_json::json!([]));
+    }
+}
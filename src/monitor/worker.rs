@@ -23,116 +23,87 @@
 //              request dynamic infos to core via GetCoreStatsRequest to blockchain
 //              receive infos via GetCoreStatsresponse
 //              send infos to all the stations
-use ascii_table::{Align, AsciiTable};
+use crate::proxy;
 use isahc::{Request, RequestExt};
-use serde::Serialize;
-use std::{fmt::Display, fs::File, io::Write, thread::sleep, time::Duration};
+use std::time::Duration;
 #[cfg(feature = "monitor")]
 use trinci_core::{
+    base::RwLock,
     blockchain::BlockRequestSender,
-    crypto::{Hash, HashAlgorithm, Hashable},
+    crypto::{HashAlgorithm, Hashable},
     Block, Message,
 };
 
 use crate::app::load_config_from_service;
+use crate::clock::Clock;
+use crate::monitor::alert::Alerter;
+use crate::monitor::history::History;
+use crate::monitor::status::{LastBlock, MonitorConfig, UnconfirmedPool};
+use crate::telemetry::{self, Redact};
+use crate::tracer::Tracer;
+use crate::traffic::Traffic;
+use std::sync::Arc;
 
-/// structure to track node information
-#[derive(Serialize)]
-/// structure that holds the hash of the unconfirmed transaction queue and it's dimension
-pub struct UnconfirmedPool {
-    hash: Hash,
-    size: usize,
-}
-
-#[derive(Serialize)]
-/// structure that holds the last block received by the node and its hash
-pub struct LastBlock {
-    block: Block,
-    hash: Hash,
-}
-
-#[derive(Serialize)]
-pub struct P2pInfo {
-    pub p2p_addr: String,
-    /// P2p service tcp port.
-    pub p2p_port: u16,
-    /// P2P service bootstrap address.
-    pub p2p_bootstrap_addr: Option<String>,
-}
-
-#[derive(Serialize)]
-pub struct NetworkConfig {
-    pub name: String,
-    pub block_threshold: usize,
-    pub block_timeout: u16,
-}
-
-#[derive(Serialize)]
-pub enum NodeRole {
-    Ordinary,
-    #[allow(dead_code)] // FIXME
-    Validator,
-}
+/// Seconds between two status refreshes.
+const STATUS_REFRESH: u64 = 10;
 
-#[derive(Serialize)]
-pub struct Status {
-    /// public key associated with the node
-    pub public_key: String,
-    /// public key associated with the node for p2p network
-    pub nw_public_key: String,
-    /// ip entry point to contact the node (local)
-    pub ip_endpoint: Option<String>,
-    /// ip seen from the extern
-    pub pub_ip: Option<String>,
-    /// node's role
-    pub role: NodeRole,
-    /// partial network config that reside in the bootstrap
-    pub nw_config: NetworkConfig,
-    /// core version held by the node
-    pub core_version: String,
-    /// last node's block
-    pub last_block: Option<LastBlock>,
-    /// structure that holds some information about the unconfirmed tx queue
-    pub unconfirmed_pool: Option<UnconfirmedPool>,
-    /// infos regarding the p2p config
-    pub p2p_info: P2pInfo,
-    /// seed
-    pub seed: u64,
-}
-
-/// Due to server interaction the Monitor server
-/// Structure needs this names as field
-/// It holds the node information
-#[derive(Serialize)]
-#[allow(non_snake_case)]
-pub struct MonitorConfig {
-    pub(crate) nodeID: String,
-    pub(crate) data: Status,
-}
+/// Seconds between two updates sent to the monitor server.
+const UPDATE_PERIOD: u64 = 60 * 5;
 
 pub struct MonitorWorker {
-    config: MonitorConfig,
+    config: Arc<RwLock<MonitorConfig>>,
     bc_chan: BlockRequestSender,
-    offline: bool,
+    /// Fields blanked in the remote updates.
+    redact: Vec<Redact>,
+    tracer: Arc<Tracer>,
+    traffic: Arc<Traffic>,
+    history: Option<History>,
+    alerter: Arc<Alerter>,
+    clock: Clock,
 }
 
 impl MonitorWorker {
-    pub fn new(config: MonitorConfig, bc_chan: BlockRequestSender, offline: bool) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: MonitorConfig,
+        bc_chan: BlockRequestSender,
+        redact: Vec<Redact>,
+        tracer: Arc<Tracer>,
+        traffic: Arc<Traffic>,
+        history: Option<History>,
+        alerter: Arc<Alerter>,
+        clock: Clock,
+    ) -> Self {
         MonitorWorker {
-            config,
+            config: Arc::new(RwLock::new(config)),
             bc_chan,
-            offline,
+            redact,
+            tracer,
+            traffic,
+            history,
+            alerter,
+            clock,
         }
     }
 
+    /// Node status shared with the status page.
+    pub fn status(&self) -> Arc<RwLock<MonitorConfig>> {
+        self.config.clone()
+    }
+
     /// Updates node status
     fn update(&mut self, block: Option<Block>, unconfirmed_pool: Option<UnconfirmedPool>) {
-        self.config.data.unconfirmed_pool = unconfirmed_pool;
+        {
+            let mut config = self.config.write();
+            config.data.unconfirmed_pool = unconfirmed_pool;
 
-        if let Some(block) = block {
-            let hash = block.hash(HashAlgorithm::Sha256);
-            let last_block = LastBlock { block, hash };
-            self.config.data.last_block = Some(last_block);
+            if let Some(block) = block {
+                let hash = block.hash(HashAlgorithm::Sha256);
+                let last_block = LastBlock { block, hash };
+                config.data.last_block = Some(last_block);
+            }
+            config.data.throughput = Some(self.tracer.stats());
+            config.data.p2p_traffic = Some(self.traffic.stats());
         }
 
         // Retrieve the seed
@@ -146,7 +117,7 @@ impl MonitorWorker {
         };
 
         match rx_chan.recv_sync() {
-            Ok(Message::GetSeedRespone(seed)) => self.config.data.seed = seed,
+            Ok(Message::GetSeedRespone(seed)) => self.config.write().data.seed = seed,
             Ok(res) => {
                 warn!("[monitor] unexpected message {:?}", res);
             }
@@ -158,8 +129,11 @@ impl MonitorWorker {
 
     /// Send json structure containing node status to the `addr`
     fn send_update(&mut self, addr: String) {
-        let request = match serde_json::to_string(&self.config) {
-            Ok(request) => request,
+        let request = match serde_json::to_value(&*self.config.read()) {
+            Ok(mut status) => {
+                telemetry::redact(&mut status, &self.redact);
+                status.to_string()
+            }
             Err(_error) => {
                 warn!("[monitor] error in serializing monitor structure");
                 return;
@@ -168,7 +142,7 @@ impl MonitorWorker {
 
         debug!("{}", request);
 
-        let response = match Request::post(addr)
+        let response = match proxy::apply(Request::post(addr))
             .header("content-type", "application/json")
             .body(request)
         {
@@ -185,169 +159,20 @@ impl MonitorWorker {
         }
     }
 
-    /// Saves node status in a human readable format in the `file` specified
-    fn save_update(&mut self, file: String) {
-        let mut ascii_table = AsciiTable::default();
-        ascii_table.set_max_width(100);
-        ascii_table
-            .column(0)
-            .set_header("field")
-            .set_align(Align::Left);
-        ascii_table
-            .column(1)
-            .set_header("value")
-            .set_align(Align::Center);
-
-        // data preparation
-        let role = match &self.config.data.role {
-            NodeRole::Ordinary => "ordinary",
-            NodeRole::Validator => "validator",
-        };
-
-        let ip_endpoint = match &self.config.data.ip_endpoint {
-            Some(ip) => ip.clone(),
-            None => String::from("None"),
-        };
-
-        let pub_ip = match &self.config.data.pub_ip {
-            Some(ip) => ip.clone(),
-            None => String::from("None"),
-        };
-
-        let data: Vec<Vec<&dyn Display>> = vec![
-            vec![&"public key", &self.config.data.public_key],
-            vec![&"network public key", &self.config.data.nw_public_key],
-            vec![&"public IP", &pub_ip],
-            vec![&"IP end point", &ip_endpoint],
-            vec![&"role", &role],
-            vec![&"core version", &self.config.data.core_version],
-        ];
-        let mut file = File::create(file).unwrap();
-        file.write_all(b"\nnode id:\n")
-            .is_err()
-            .then(|| warn!("[monitor] error in file write"));
-        file.write_all(self.config.nodeID.as_bytes())
-            .is_err()
-            .then(|| warn!("[monitor] error in file write"));
-        file.write_all(b"\n\nnode info\n")
-            .is_err()
-            .then(|| warn!("[monitor] error in file write"));
-        file.write_all(ascii_table.format(data).as_bytes())
-            .is_err()
-            .then(|| warn!("[monitor] error in file write"));
-
-        // ----------------------
-        // network data handling
-
-        // data preparation todo!()
-        //let network_id = match from_utf8(monitor.node_status.nw_config.network_id.as_bytes()){
-        //    Ok(str) => str,
-        //    Err(_) => "None",
-        //};
-
-        let network_data: Vec<Vec<&dyn Display>> = vec![
-            vec![&"network name", &self.config.data.nw_config.name],
-            vec![
-                &"block threshold",
-                &self.config.data.nw_config.block_threshold,
-            ],
-            vec![&"block timeout", &self.config.data.nw_config.block_timeout],
-        ];
-        file.write_all(b"\nnetwork info\n")
-            .is_err()
-            .then(|| warn!("[monitor] error in file write"));
-        file.write_all(ascii_table.format(network_data).as_bytes())
-            .is_err()
-            .then(|| warn!("[monitor] error in file write"));
-
-        // ----------------------
-        // p2p data handling
-
-        // data preparation
-        let bootstrap_addr = match &self.config.data.p2p_info.p2p_bootstrap_addr {
-            Some(addr) => addr.clone(),
-            None => String::from("None"),
-        };
-
-        let p2p_data: Vec<Vec<&dyn Display>> = vec![
-            vec![&"p2p address", &self.config.data.p2p_info.p2p_addr],
-            vec![&"p2p port", &self.config.data.p2p_info.p2p_port],
-            vec![&"p2p bootsrap address", &bootstrap_addr],
-        ];
-        file.write_all(b"\np2p info\n")
-            .is_err()
-            .then(|| warn!("[monitor] error in file write"));
-        file.write_all(ascii_table.format(p2p_data).as_bytes())
-            .is_err()
-            .then(|| warn!("[monitor] error in file write"));
-
-        // ----------------------
-        // last block handling
-        file.write_all(b"\nlast block\n")
-            .is_err()
-            .then(|| warn!("[monitor] error in file write"));
-        match &self.config.data.last_block {
-            Some(last_block) => {
-                // data preparation
-
-                let last_block_hash = hex::encode(last_block.hash.as_bytes());
-                let prev_hash = hex::encode(last_block.block.data.prev_hash.as_bytes());
-                let txs_hash = hex::encode(last_block.block.data.txs_hash.as_bytes());
-                let rxs_hash = hex::encode(last_block.block.data.rxs_hash.as_bytes());
-                let state_hash = hex::encode(last_block.block.data.state_hash.as_bytes());
-
-                let block_data: Vec<Vec<&dyn Display>> = vec![
-                    vec![&"hash", &last_block_hash],
-                    vec![&"height", &last_block.block.data.height],
-                    vec![&"size", &last_block.block.data.size],
-                    vec![&"previous hash", &prev_hash],
-                    vec![&"txs hash", &txs_hash],
-                    vec![&"rxs hash", &rxs_hash],
-                    vec![&"state hash", &state_hash],
-                ];
-                file.write_all(ascii_table.format(block_data).as_bytes())
-                    .is_err()
-                    .then(|| warn!("[monitor] error in file write"));
-            }
-            None => {
-                file.write_all(b"None\n")
-                    .is_err()
-                    .then(|| warn!("[monitor] error in file write"));
+    /// Appends the node status to the history file
+    fn save_history(&self) {
+        if let Some(history) = &self.history {
+            if let Err(err) = history.append(&*self.config.read()) {
+                warn!("[monitor] error writing the history: {}", err);
             }
         }
-        // ----------------------
-        // unconfirmed pool handling
-        file.write_all(b"\nunconfirmed pool\n")
-            .is_err()
-            .then(|| warn!("[monitor] error in file write"));
-        match &self.config.data.unconfirmed_pool {
-            Some(pool) => {
-                let hash = hex::encode(pool.hash.hash_value());
-                let pool_data: Vec<Vec<&dyn Display>> =
-                    vec![vec![&"hash", &hash], vec![&"lenght", &pool.size]];
-                file.write_all(ascii_table.format(pool_data).as_bytes())
-                    .is_err()
-                    .then(|| warn!("[monitor] error in file write"));
-            }
-            None => {
-                file.write_all(b"None\n")
-                    .is_err()
-                    .then(|| warn!("[monitor] error in file write"));
-            }
-        }
-
-        let seed: Vec<Vec<&dyn Display>> = vec![vec![&"seed", &self.config.data.seed]];
-        file.write_all(ascii_table.format(seed).as_bytes())
-            .is_err()
-            .then(|| warn!("[monitor] error in file write"));
-
-        debug!("[monitor] update saved");
     }
 
-    /// Run monitor, it saves every 5 minutes the node status in `file`
-    /// and sends a his json representation to `addr`
-    pub fn run(&mut self, addr: String, file: String) {
-        debug!("[monitor] running, monitor data updated every 5 min");
+    /// Run monitor, it refreshes the node status every `STATUS_REFRESH`
+    /// seconds, every 5 minutes records it in the history and sends its json
+    /// representation to `addr`
+    pub fn run(&mut self, addr: String) {
+        debug!("[monitor] running, monitor data sent every 5 min");
 
         // retrieve network id
         let request = Message::GetNetworkIdRequest;
@@ -359,7 +184,9 @@ impl MonitorWorker {
             }
         };
         match rx_chan.recv_sync() {
-            Ok(Message::GetNetworkIdResponse(info)) => self.config.data.nw_config.name = info,
+            Ok(Message::GetNetworkIdResponse(info)) => {
+                self.config.write().data.nw_config.name = info
+            }
             Ok(res) => {
                 warn!("[monitor] unexpected message {:?}", res);
             }
@@ -370,11 +197,18 @@ impl MonitorWorker {
 
         // load block config
         let block_config = load_config_from_service(&self.bc_chan);
-        self.config.data.nw_config.block_threshold = block_config.block_threshold;
-        self.config.data.nw_config.block_timeout = block_config.block_timeout;
+        {
+            let mut config = self.config.write();
+            config.data.nw_config.block_threshold = block_config.block_threshold;
+            config.data.nw_config.block_timeout = block_config.block_timeout;
+        }
 
+        let mut elapsed = 0;
+        let mut last_height = None;
+        let mut last_block_time = self.clock.now();
         loop {
-            sleep(Duration::new(60 * 5, 0));
+            self.clock.sleep(Duration::from_secs(STATUS_REFRESH));
+            elapsed += STATUS_REFRESH;
 
             let request = Message::GetCoreStatsRequest;
             let rx_chan = match self.bc_chan.send_sync(request) {
@@ -387,19 +221,29 @@ impl MonitorWorker {
 
             match rx_chan.recv_sync() {
                 Ok(Message::GetCoreStatsResponse(info)) => {
-                    if !self.offline {
-                        if info.1 > 0 {
-                            let unconfirmed_pool = Some(UnconfirmedPool {
-                                hash: info.0,
-                                size: info.1,
-                            });
-                            self.update(info.2, unconfirmed_pool);
-                        } else {
-                            self.update(info.2, None)
+                    let height = info.2.as_ref().map(|block| block.data.height);
+                    if height != last_height {
+                        last_height = height;
+                        last_block_time = self.clock.now();
+                    }
+                    self.alerter
+                        .check((self.clock.now() - last_block_time).as_secs(), info.1);
+                    if info.1 > 0 {
+                        let unconfirmed_pool = Some(UnconfirmedPool {
+                            hash: info.0,
+                            size: info.1,
+                        });
+                        self.update(info.2, unconfirmed_pool);
+                    } else {
+                        self.update(info.2, None)
+                    }
+                    if elapsed >= UPDATE_PERIOD {
+                        elapsed = 0;
+                        self.save_history();
+                        // An empty address disables the updates.
+                        if !addr.is_empty() {
+                            self.send_update(addr.clone());
                         }
-
-                        self.send_update(addr.clone());
-                        self.save_update(file.clone());
                     }
                 }
                 Ok(res) => {
@@ -0,0 +1,332 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! P2P reachability detection.
+//!
+//! Checks whether the advertised p2p address (`public-ip`, `p2p-port`)
+//! accepts connections from outside: a peer node is asked to dial back the
+//! node (`nat-probe`), otherwise the node dials itself, which only succeeds
+//! if the router supports hairpinning.
+//!
+//! The core p2p service has no relay or hole punching support, when the node
+//! is unreachable the only fallback is a port mapping on the gateway (UPnP,
+//! PCP or NAT-PMP), requested via the `upnp_negotiator` tool and refreshed
+//! before the lease expires.
+
+use crate::api::{client, Request, Response, Router};
+use crate::tasks;
+use crate::utils;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    process::Command,
+    sync::Arc,
+    time::Duration,
+};
+use trinci_core::base::Mutex;
+
+/// Dial attempts timeout.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// UPnP mapping refresh period, the tool requests a 120 seconds lease.
+const UPNP_REFRESH: Duration = Duration::from_secs(60);
+
+/// Action taken when the node is unreachable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NatFallback {
+    None,
+    Upnp,
+}
+
+impl std::str::FromStr for NatFallback {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(NatFallback::None),
+            "upnp" => Ok(NatFallback::Upnp),
+            _ => Err(format!(
+                "invalid nat fallback `{}` (expected none or upnp)",
+                value
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for NatFallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let fallback = match self {
+            NatFallback::None => "none",
+            NatFallback::Upnp => "upnp",
+        };
+        write!(f, "{}", fallback)
+    }
+}
+
+/// Reachability detection configuration.
+pub struct NatConfig {
+    /// IP seen from the extern.
+    pub public_ip: Option<String>,
+    /// Local IP, used for the UPnP mapping.
+    pub local_ip: Option<String>,
+    /// P2P service port.
+    pub p2p_port: u16,
+    /// Node API address of a peer that dials back the node.
+    pub probe: Option<String>,
+    pub fallback: NatFallback,
+    /// UPnP negotiator tool path.
+    pub upnp_tool: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Reachability {
+    Reachable,
+    Unreachable,
+    Unknown,
+}
+
+/// Reachability status, as reported by the node API.
+#[derive(Debug, Clone, Serialize)]
+pub struct NatStatus {
+    /// Advertised p2p address.
+    pub advertised: Option<String>,
+    pub reachability: Reachability,
+    /// Detection method.
+    pub method: &'static str,
+    /// UPnP mapped address, if the fallback is active.
+    pub mapped: Option<String>,
+    /// Port mapping protocol accepted by the gateway.
+    pub mapping_method: Option<String>,
+    pub detail: Option<String>,
+}
+
+/// Dial back response.
+#[derive(Serialize, Deserialize)]
+struct DialBack {
+    addr: String,
+    reachable: bool,
+}
+
+fn dial(addr: &str) -> Result<(), String> {
+    let addr: SocketAddr = addr
+        .to_socket_addrs()
+        .map_err(|err| err.to_string())?
+        .next()
+        .ok_or_else(|| format!("{} not resolved", addr))?;
+    TcpStream::connect_timeout(&addr, DIAL_TIMEOUT)
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+pub struct Nat {
+    config: NatConfig,
+    status: Mutex<NatStatus>,
+}
+
+impl Nat {
+    pub fn new(config: NatConfig) -> Self {
+        let advertised = match (&config.public_ip, config.p2p_port) {
+            (Some(ip), port) if port != 0 => Some(utils::host_port(ip, port)),
+            _ => None,
+        };
+        Nat {
+            config,
+            status: Mutex::new(NatStatus {
+                advertised,
+                reachability: Reachability::Unknown,
+                method: "none",
+                mapped: None,
+                mapping_method: None,
+                detail: Some("not checked yet".to_string()),
+            }),
+        }
+    }
+
+    pub fn status(&self) -> NatStatus {
+        self.status.lock().clone()
+    }
+
+    // Checks the reachability of the given address.
+    fn probe(&self, addr: &str) -> (Reachability, &'static str, Option<String>) {
+        match &self.config.probe {
+            Some(probe) => {
+                let port = addr.rsplit(':').next().unwrap_or_default();
+                let path = format!("/p2p/dialback?port={}", port);
+                match client::request(probe, "GET", &path, None) {
+                    Ok((200, body)) => match serde_json::from_slice::<DialBack>(&body) {
+                        Ok(res) if res.reachable => (Reachability::Reachable, "dial-back", None),
+                        Ok(res) => (
+                            Reachability::Unreachable,
+                            "dial-back",
+                            Some(format!("{} not reachable from {}", res.addr, probe)),
+                        ),
+                        Err(err) => (Reachability::Unknown, "dial-back", Some(err.to_string())),
+                    },
+                    Ok((code, _)) => (
+                        Reachability::Unknown,
+                        "dial-back",
+                        Some(format!("probe node answered {}", code)),
+                    ),
+                    Err(err) => (Reachability::Unknown, "dial-back", Some(err.to_string())),
+                }
+            }
+            None => match dial(addr) {
+                Ok(()) => (Reachability::Reachable, "self-dial", None),
+                // Routers without hairpinning refuse the self dial anyway.
+                Err(err) => (Reachability::Unknown, "self-dial", Some(err)),
+            },
+        }
+    }
+
+    // Requests the port mapping, returns the external address and the
+    // protocol used.
+    fn upnp_map(&self) -> Result<(String, String), String> {
+        let local_ip = self
+            .config
+            .local_ip
+            .as_ref()
+            .ok_or("UPnP mapping requires `local-ip`")?;
+        // No NAT for IPv6, the firewall has to let the connections in.
+        if utils::is_ipv6(local_ip) {
+            return Err("no port mapping for IPv6 addresses".to_string());
+        }
+        let output = Command::new(&self.config.upnp_tool)
+            .arg(local_ip)
+            .arg(self.config.p2p_port.to_string())
+            .output()
+            .map_err(|err| format!("unable to run {}: {}", self.config.upnp_tool, err))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut mapped = stdout.split_whitespace();
+        match (output.status.success(), mapped.next()) {
+            (true, Some(addr)) => Ok((
+                addr.to_string(),
+                // Older tools only print the UPnP mapped address.
+                mapped.next().unwrap_or("upnp").to_string(),
+            )),
+            _ => Err(format!(
+                "port mapping failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+        }
+    }
+
+    /// Runs the detection, applying the fallback if the node is unreachable.
+    pub fn check(&self) -> NatStatus {
+        let advertised = match self.status.lock().advertised.clone() {
+            Some(advertised) => advertised,
+            None => {
+                let mut status = self.status.lock();
+                status.detail = Some("`public-ip` or `p2p-port` not configured".to_string());
+                return status.clone();
+            }
+        };
+        let (mut reachability, mut method, mut detail) = self.probe(&advertised);
+        let mut mapped = None;
+        let mut mapping_method = None;
+
+        if reachability != Reachability::Reachable && self.config.fallback == NatFallback::Upnp {
+            match self.upnp_map() {
+                Ok((addr, protocol)) => {
+                    info!("[nat] port mapped to {} via {}", addr, protocol);
+                    if addr != advertised {
+                        warn!(
+                            "[nat] mapped {}, differs from the advertised {}",
+                            addr, advertised
+                        );
+                    }
+                    let (upnp_reachability, upnp_method, upnp_detail) = self.probe(&addr);
+                    if upnp_reachability != Reachability::Unknown || method == "self-dial" {
+                        reachability = upnp_reachability;
+                        method = upnp_method;
+                        detail = upnp_detail;
+                    }
+                    mapped = Some(addr);
+                    mapping_method = Some(protocol);
+                }
+                Err(err) => detail = Some(err),
+            }
+        }
+
+        let mut status = self.status.lock();
+        status.reachability = reachability;
+        status.method = method;
+        status.mapped = mapped;
+        status.mapping_method = mapping_method;
+        status.detail = detail;
+        status.clone()
+    }
+
+    /// Registers the reachability routes within the node API.
+    pub fn routes(nat: Arc<Self>, router: &mut Router) {
+        router.add("GET", "/admin/p2p/nat", move |_: &Request| {
+            Response::json(&nat.status())
+        });
+        // Dials back the requester, used by the peers to check themselves.
+        router.add("GET", "/p2p/dialback", |req: &Request| {
+            let port = match req.query::<u16>("port") {
+                Some(port) if port != 0 => port,
+                _ => return Response::error(400, "missing or invalid `port`"),
+            };
+            let addr = SocketAddr::new(req.peer.ip(), port);
+            let reachable = !req.peer.ip().is_unspecified()
+                && TcpStream::connect_timeout(&addr, DIAL_TIMEOUT).is_ok();
+            Response::json(&DialBack {
+                addr: addr.to_string(),
+                reachable,
+            })
+        });
+    }
+}
+
+/// Runs the detection once the p2p service is listening, then keeps the
+/// port mapping alive if the fallback was needed.
+/// The detection outcome is passed to `report`.
+pub fn run<F>(nat: Arc<Nat>, report: F)
+where
+    F: Fn(&NatStatus),
+{
+    let status = nat.check();
+    report(&status);
+    match status.reachability {
+        Reachability::Reachable => info!(
+            "[nat] p2p address {} reachable ({})",
+            status.advertised.unwrap_or_default(),
+            status.method
+        ),
+        Reachability::Unreachable => error!(
+            "[nat] p2p address {} NOT reachable ({}): {}",
+            status.advertised.unwrap_or_default(),
+            status.method,
+            status.detail.unwrap_or_default()
+        ),
+        Reachability::Unknown => warn!(
+            "[nat] p2p reachability unknown ({}): {}",
+            status.method,
+            status.detail.unwrap_or_default()
+        ),
+    }
+    if status.mapped.is_none() {
+        return;
+    }
+    while tasks::sleep(UPNP_REFRESH) {
+        if let Err(err) = nat.upnp_map() {
+            warn!("[nat] port mapping refresh: {}", err);
+        }
+    }
+}
@@ -0,0 +1,297 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Receipt push notifications.
+//!
+//! A submitter registers a callback URL for a transaction via the node API,
+//! `POST /api/v1/notify` with `{"hash": "<hex>", "url": "http://..."}`,
+//! usually right after the submission. Once the transaction is executed its
+//! receipt is POSTed to the URL as JSON, sparing the client a polling loop.
+//! A transaction already executed is notified at once.
+//!
+//! Only plain HTTP callbacks are supported. The registrations of the
+//! transactions never executed expire after `NOTIFY_TTL`.
+
+use crate::api::{client, Request, Response, Router};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use trinci_core::{
+    base::Mutex,
+    blockchain::{BlockRequestSender, Event, Message},
+    Hash, Receipt,
+};
+
+/// Max time a registration waits for its transaction.
+const NOTIFY_TTL: Duration = Duration::from_secs(3600);
+
+/// Max pending registrations.
+const MAX_PENDING: usize = 10_000;
+
+/// Delivery attempts of a notification.
+const DELIVERY_ATTEMPTS: u32 = 3;
+
+/// Pause between two delivery attempts.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Blockchain subscription identifier.
+const SUBSCRIPTION_ID: &str = "notify";
+
+/// Registration request, via node API.
+#[derive(Deserialize)]
+struct NotifyRequest {
+    /// Transaction hash (hex).
+    hash: String,
+    /// Callback URL.
+    url: String,
+}
+
+/// Notification POSTed to the callback.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Notification {
+    /// Transaction hash (hex).
+    pub hash: String,
+    pub height: u64,
+    pub index: u32,
+    pub success: bool,
+    pub burned_fuel: u64,
+    /// Contract return value (hex), or error message on failure.
+    pub returns: String,
+}
+
+impl Notification {
+    fn new(hash: &Hash, rx: &Receipt) -> Self {
+        Notification {
+            hash: hex::encode(hash.as_bytes()),
+            height: rx.height,
+            index: rx.index,
+            success: rx.success,
+            burned_fuel: rx.burned_fuel,
+            returns: if rx.success {
+                hex::encode(&rx.returns)
+            } else {
+                String::from_utf8_lossy(&rx.returns).into_owned()
+            },
+        }
+    }
+}
+
+fn receipt(chan: &BlockRequestSender, hash: &Hash) -> Option<Receipt> {
+    let res = chan
+        .send_sync(Message::GetReceiptRequest { hash: *hash })
+        .ok()?
+        .recv_sync()
+        .ok()?;
+    match res {
+        Message::GetReceiptResponse { rx } => Some(rx),
+        _ => None,
+    }
+}
+
+// Posts the notification to the callbacks, in background.
+fn deliver(notification: Notification, urls: Vec<String>) {
+    thread::spawn(move || {
+        let body = match serde_json::to_vec(&notification) {
+            Ok(body) => body,
+            Err(_) => return,
+        };
+        for url in urls {
+            let (addr, path) = match client::split_url(&url) {
+                Some(target) => target,
+                None => continue,
+            };
+            for attempt in 1..=DELIVERY_ATTEMPTS {
+                match client::request(&addr, "POST", &path, Some(&body)) {
+                    Ok((status, _)) if status < 300 => break,
+                    Ok((status, _)) => {
+                        debug!(
+                            "[notify] {} answered {} for {}",
+                            url, status, notification.hash
+                        )
+                    }
+                    Err(err) => debug!("[notify] {}: {}", url, err),
+                }
+                if attempt == DELIVERY_ATTEMPTS {
+                    warn!(
+                        "[notify] receipt {} not delivered to {}",
+                        notification.hash, url
+                    );
+                } else {
+                    thread::sleep(RETRY_DELAY);
+                }
+            }
+        }
+    });
+}
+
+/// Pending callbacks.
+pub struct Notifier {
+    /// Blockchain service channel, to read the receipts.
+    chan: BlockRequestSender,
+    /// Callback URLs and registration time per transaction.
+    pending: Mutex<HashMap<Hash, Vec<(String, Instant)>>>,
+}
+
+impl Notifier {
+    pub fn new(chan: BlockRequestSender) -> Self {
+        Notifier {
+            chan,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Registers a callback for the transaction, the error carries the HTTP
+    /// status and message.
+    pub fn register(&self, hash: Hash, url: String) -> Result<(), (u16, String)> {
+        if client::split_url(&url).is_none() {
+            return Err((
+                400,
+                "only plain `http://` callbacks are supported".to_string(),
+            ));
+        }
+        if let Some(rx) = receipt(&self.chan, &hash) {
+            deliver(Notification::new(&hash, &rx), vec![url]);
+            return Ok(());
+        }
+        let mut pending = self.pending.lock();
+        pending.retain(|_, urls| {
+            urls.retain(|(_, time)| time.elapsed() < NOTIFY_TTL);
+            !urls.is_empty()
+        });
+        if pending.values().map(Vec::len).sum::<usize>() >= MAX_PENDING {
+            return Err((429, "too many pending notifications".to_string()));
+        }
+        pending.entry(hash).or_default().push((url, Instant::now()));
+        Ok(())
+    }
+
+    /// Removes the callbacks of the executed transactions.
+    pub fn take(&self, txs: &[Hash]) -> Vec<(Hash, Vec<String>)> {
+        let mut pending = self.pending.lock();
+        if pending.is_empty() {
+            return Vec::new();
+        }
+        txs.iter()
+            .filter_map(|hash| {
+                let urls = pending.remove(hash)?;
+                Some((*hash, urls.into_iter().map(|(url, _)| url).collect()))
+            })
+            .collect()
+    }
+
+    /// Registers the notification routes within the node API.
+    pub fn routes(notifier: Arc<Self>, router: &mut Router) {
+        router.add("POST", "/api/v1/notify", move |req: &Request| {
+            let notify = match req.json::<NotifyRequest>() {
+                Ok(notify) => notify,
+                Err(res) => return res,
+            };
+            let hash = match Hash::from_hex(&notify.hash) {
+                Ok(hash) => hash,
+                Err(_) => return Response::error(400, "invalid transaction hash"),
+            };
+            match notifier.register(hash, notify.url) {
+                Ok(()) => Response::ok(),
+                Err((status, message)) => Response::error(status, message),
+            }
+        });
+    }
+}
+
+/// Notifies the receipts of the transactions executed, until the blockchain
+/// channel is closed.
+pub fn run(notifier: Arc<Notifier>, chan: BlockRequestSender) {
+    let req = Message::Subscribe {
+        id: SUBSCRIPTION_ID.to_owned(),
+        events: Event::BLOCK,
+    };
+    let rx_chan = match chan.send_sync(req) {
+        Ok(rx_chan) => rx_chan,
+        Err(_) => {
+            warn!("[notify] blockchain channel closed");
+            return;
+        }
+    };
+    while let Ok(msg) = rx_chan.recv_sync() {
+        if let Message::GetBlockResponse { txs: Some(txs), .. } = msg {
+            for (hash, urls) in notifier.take(&txs) {
+                match receipt(&chan, &hash) {
+                    Some(rx) => deliver(Notification::new(&hash, &rx), urls),
+                    None => warn!(
+                        "[notify] receipt {} not found",
+                        hex::encode(hash.as_bytes())
+                    ),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway::service::sink;
+    use crate::tasks::Tasks;
+    use std::{io::Read, net::TcpListener};
+
+    #[test]
+    fn register_and_deliver() {
+        let notifier = Notifier::new(sink(&Tasks::default(), "no receipts"));
+        let hash = Hash::from_data(trinci_core::crypto::HashAlgorithm::Sha256, b"tx");
+
+        assert_eq!(
+            notifier
+                .register(hash, "https://example.com/receipts".to_string())
+                .unwrap_err()
+                .0,
+            400
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/receipts", listener.local_addr().unwrap());
+        notifier.register(hash, url.clone()).unwrap();
+
+        assert!(notifier.take(&[Hash::default()]).is_empty());
+        let due = notifier.take(&[hash]);
+        assert_eq!(due, vec![(hash, vec![url])]);
+        assert!(notifier.take(&[hash]).is_empty());
+
+        let rx = Receipt {
+            height: 3,
+            burned_fuel: 10,
+            index: 0,
+            success: true,
+            returns: vec![0xca, 0xfe],
+            events: None,
+        };
+        deliver(Notification::new(&hash, &rx), due[0].1.clone());
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut req = String::new();
+        let mut buf = [0; 1024];
+        while !req.ends_with('}') {
+            let len = stream.read(&mut buf).unwrap();
+            req.push_str(&String::from_utf8_lossy(&buf[..len]));
+        }
+
+        assert!(req.starts_with("POST /receipts HTTP/1.1"));
+        assert!(req.contains("\"returns\":\"cafe\""));
+    }
+}
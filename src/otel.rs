@@ -0,0 +1,298 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! OpenTelemetry exporter (`otel` feature).
+//!
+//! Exports to an OTLP/HTTP collector, as JSON, every `otel-interval`:
+//! - traces: a `block processing` span per executed block, from the block
+//!   timestamp (set by the validator) to its execution on this node, and the
+//!   request spans when `trace-requests` is enabled (see the `correlation`
+//!   module);
+//! - metrics: the top level contract calls durations, the block height, the
+//!   executed transactions and burned fuel, and the depth of the queues in
+//!   front of the blockchain service (unconfirmed pool, transactions held by
+//!   the priority lanes).
+
+use crate::api::client;
+use crate::correlation::{self, Correlation, Span};
+use crate::gateway::{admission, lanes::Lanes};
+use crate::metrics::BUCKETS;
+use crate::tasks;
+use crate::tracer::Tracer;
+use crate::wm_cache::{CallDurations, WmCache};
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Duration};
+use trinci_core::{
+    base::Mutex,
+    blockchain::{BlockRequestSender, Event, Message},
+};
+
+/// Max spans waiting for the export, the exceeding ones are dropped.
+const MAX_SPANS: usize = 8192;
+
+/// Blockchain subscription identifier.
+const SUBSCRIPTION_ID: &str = "otel";
+
+/// Exporter configuration.
+pub struct OtelConfig {
+    /// Collector base URL.
+    pub endpoint: String,
+    pub interval: Duration,
+}
+
+/// Metrics sampled at every export.
+#[derive(Default)]
+struct Sample {
+    height: u64,
+    txs: u64,
+    fuel: u64,
+    calls: CallDurations,
+    pool: Option<usize>,
+    held: usize,
+}
+
+pub struct Otel {
+    config: OtelConfig,
+    tracer: Arc<Tracer>,
+    wm_cache: Arc<WmCache>,
+    lanes: Arc<Lanes>,
+    spans: Mutex<Vec<Span>>,
+    /// Exporter start, nanoseconds since the epoch.
+    start: u64,
+}
+
+fn int_point(time: u64, start: Option<u64>, value: u64, attributes: Value) -> Value {
+    let mut point = json!({
+        "timeUnixNano": time.to_string(),
+        "asInt": value.to_string(),
+        "attributes": attributes,
+    });
+    if let Some(start) = start {
+        point["startTimeUnixNano"] = json!(start.to_string());
+    }
+    point
+}
+
+fn counter(name: &str, start: u64, time: u64, value: u64) -> Value {
+    json!({
+        "name": name,
+        "sum": {
+            "aggregationTemporality": 2,
+            "isMonotonic": true,
+            "dataPoints": [int_point(time, Some(start), value, json!([]))],
+        }
+    })
+}
+
+impl Otel {
+    pub fn new(
+        config: OtelConfig,
+        tracer: Arc<Tracer>,
+        wm_cache: Arc<WmCache>,
+        lanes: Arc<Lanes>,
+    ) -> Self {
+        Otel {
+            config,
+            tracer,
+            wm_cache,
+            lanes,
+            spans: Mutex::new(Vec::new()),
+            start: correlation::now(),
+        }
+    }
+
+    fn metrics_body(&self, sample: &Sample, time: u64) -> Value {
+        let calls = &sample.calls;
+        let mut queues = vec![int_point(
+            time,
+            None,
+            sample.held as u64,
+            json!([{"key": "queue", "value": {"stringValue": "lanes"}}]),
+        )];
+        if let Some(pool) = sample.pool {
+            queues.push(int_point(
+                time,
+                None,
+                pool as u64,
+                json!([{"key": "queue", "value": {"stringValue": "pool"}}]),
+            ));
+        }
+        json!({
+            "resourceMetrics": [{
+                "resource": correlation::otlp_resource(),
+                "scopeMetrics": [{
+                    "scope": {"name": "trinci-node"},
+                    "metrics": [
+                        {
+                            "name": "trinci.wasm.call.duration",
+                            "unit": "s",
+                            "histogram": {
+                                "aggregationTemporality": 2,
+                                "dataPoints": [{
+                                    "startTimeUnixNano": self.start.to_string(),
+                                    "timeUnixNano": time.to_string(),
+                                    "count": calls.count.to_string(),
+                                    "sum": calls.sum,
+                                    "bucketCounts": calls.buckets.iter().map(u64::to_string).collect::<Vec<_>>(),
+                                    "explicitBounds": BUCKETS,
+                                }],
+                            }
+                        },
+                        {
+                            "name": "trinci.block.height",
+                            "gauge": {"dataPoints": [int_point(time, None, sample.height, json!([]))]},
+                        },
+                        counter("trinci.transactions", self.start, time, sample.txs),
+                        counter("trinci.fuel.burned", self.start, time, sample.fuel),
+                        {
+                            "name": "trinci.queue.depth",
+                            "gauge": {"dataPoints": queues},
+                        },
+                    ],
+                }]
+            }]
+        })
+    }
+
+    fn post(&self, path: &str, body: &Value) {
+        let (addr, base) = match client::split_url(&self.config.endpoint) {
+            Some(target) => target,
+            None => return,
+        };
+        let path = format!("{}{}", base.trim_end_matches('/'), path);
+        match client::request(&addr, "POST", &path, Some(body.to_string().as_bytes())) {
+            Ok((status, _)) if status < 300 => (),
+            Ok((status, body)) => warn!(
+                "[otel] collector answered {}: {}",
+                status,
+                String::from_utf8_lossy(&body)
+            ),
+            Err(err) => warn!("[otel] collector unreachable: {}", err),
+        }
+    }
+
+    /// Exports the spans and the metrics every interval, until the node
+    /// shutdown.
+    pub fn export(otel: Arc<Self>, chan: BlockRequestSender) {
+        if client::split_url(&otel.config.endpoint).is_none() {
+            warn!("[otel] invalid collector URL, only plain `http://` is supported");
+            return;
+        }
+        info!("[otel] exporting to {}", otel.config.endpoint);
+        while tasks::sleep(otel.config.interval) {
+            let spans = std::mem::take(&mut *otel.spans.lock());
+            if !spans.is_empty() {
+                otel.post("/v1/traces", &Correlation::otlp_body(&spans));
+            }
+            let stats = otel.tracer.stats();
+            let sample = Sample {
+                height: stats.totals.height,
+                txs: stats.totals.txs,
+                fuel: stats.totals.fuel,
+                calls: otel.wm_cache.call_durations(),
+                pool: admission::pool_size(&chan),
+                held: otel.lanes.held(),
+            };
+            otel.post(
+                "/v1/metrics",
+                &otel.metrics_body(&sample, correlation::now()),
+            );
+        }
+    }
+
+    /// Records a span per executed block, until the blockchain channel is
+    /// closed.
+    pub fn run(otel: Arc<Self>, chan: BlockRequestSender) {
+        let req = Message::Subscribe {
+            id: SUBSCRIPTION_ID.to_owned(),
+            events: Event::BLOCK,
+        };
+        let rx_chan = match chan.send_sync(req) {
+            Ok(rx_chan) => rx_chan,
+            Err(_) => {
+                warn!("[otel] blockchain channel closed");
+                return;
+            }
+        };
+        while let Ok(msg) = rx_chan.recv_sync() {
+            if let Message::GetBlockResponse { block, .. } = msg {
+                let data = &block.data;
+                let end = correlation::now();
+                let start = data.timestamp.saturating_mul(1_000_000_000).min(end);
+                let mut spans = otel.spans.lock();
+                if spans.len() >= MAX_SPANS {
+                    continue;
+                }
+                spans.push(Span {
+                    trace_id: correlation::random_id(16),
+                    span_id: correlation::random_id(8),
+                    parent_id: None,
+                    name: "block processing".to_string(),
+                    start,
+                    end,
+                    attributes: vec![
+                        ("trinci.height", data.height.to_string()),
+                        ("trinci.block_txs", data.size.to_string()),
+                    ],
+                    error: false,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DEFAULT_OTEL_INTERVAL;
+    use crate::gateway::lanes::LanePolicy;
+    use tempfile::TempDir;
+
+    #[test]
+    fn metrics_body() {
+        let dir = TempDir::new().unwrap();
+        let otel = Otel::new(
+            OtelConfig {
+                endpoint: "http://127.0.0.1:4318".to_string(),
+                interval: Duration::from_secs(DEFAULT_OTEL_INTERVAL),
+            },
+            Arc::new(Tracer::open(dir.path())),
+            Arc::new(WmCache::new(4)),
+            Arc::new(Lanes::new(LanePolicy::Fair, 100)),
+        );
+        let mut sample = Sample {
+            height: 42,
+            pool: Some(7),
+            ..Default::default()
+        };
+        sample.calls.count = 2;
+        sample.calls.buckets[0] = 2;
+
+        let body = otel.metrics_body(&sample, otel.start + 1);
+        let metrics = &body["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+
+        let histogram = &metrics[0]["histogram"]["dataPoints"][0];
+        assert_eq!(histogram["count"], "2");
+        assert_eq!(
+            histogram["bucketCounts"].as_array().unwrap().len(),
+            histogram["explicitBounds"].as_array().unwrap().len() + 1
+        );
+        assert_eq!(metrics[1]["gauge"]["dataPoints"][0]["asInt"], "42");
+        let queues = metrics[4]["gauge"]["dataPoints"].as_array().unwrap();
+        assert_eq!(queues[1]["asInt"], "7");
+    }
+}
@@ -0,0 +1,165 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! P2P service wrapper.
+//!
+//! The core P2P service takes the bootstrap address at creation. The node
+//! resolves the DNS names of the bootstrap address (`/dns`, `/dns4`, `/dns6`)
+//! and resolves them again every `p2p-dns-refresh` seconds: when the address
+//! changes, e.g. a seed node behind dynamic DNS got a new IP, the core service
+//! is rebuilt and restarted to connect to it.
+
+use crate::peers;
+use crate::tasks;
+use std::{sync::Arc, time::Duration};
+use trinci_core::{
+    base::Mutex,
+    blockchain::BlockRequestSender,
+    crypto::ed25519::KeyPair as Ed25519KeyPair,
+    p2p::{service::PeerConfig, PeerService},
+};
+
+/// Default bootstrap DNS names refresh interval in seconds.
+pub const DEFAULT_DNS_REFRESH: u64 = 300;
+
+pub struct P2pService {
+    addr: String,
+    port: u16,
+    /// Identity keypair bytes.
+    keypair: Option<Vec<u8>>,
+    active: bool,
+    network: String,
+    /// Configured bootstrap address, the host may be a DNS name.
+    bootstrap_addr: Option<String>,
+    /// Bootstrap address given to the core service.
+    resolved: Option<String>,
+    chan: BlockRequestSender,
+    svc: PeerService,
+    /// Bootstrap DNS name refresh interval, zero disables.
+    dns_refresh: Duration,
+}
+
+// Resolves a bootstrap address, keeping it as is on failure.
+fn resolve(addr: &str) -> String {
+    peers::resolve_address(addr).unwrap_or_else(|err| {
+        warn!("[p2p] bootstrap address {}: {}", addr, err);
+        addr.to_string()
+    })
+}
+
+impl P2pService {
+    pub fn new(mut config: PeerConfig, chan: BlockRequestSender) -> Self {
+        let resolved = config.bootstrap_addr.as_deref().map(resolve);
+        let bootstrap_addr = std::mem::replace(&mut config.bootstrap_addr, resolved.clone());
+        let keypair = config
+            .p2p_keypair
+            .as_ref()
+            .map(|keypair| keypair.to_bytes());
+        let network = config.network.lock().clone();
+        P2pService {
+            addr: config.addr.clone(),
+            port: config.port,
+            keypair,
+            active: config.active,
+            network,
+            bootstrap_addr,
+            resolved,
+            svc: PeerService::new(config, chan.clone()),
+            chan,
+            dns_refresh: Duration::from_secs(DEFAULT_DNS_REFRESH),
+        }
+    }
+
+    pub fn with_dns_refresh(mut self, interval: Duration) -> Self {
+        self.dns_refresh = interval;
+        self
+    }
+
+    pub fn start(&mut self) {
+        self.svc.start();
+    }
+
+    pub fn stop(&mut self) {
+        self.svc.stop();
+    }
+
+    pub fn set_network_name(&mut self, network: String) {
+        self.network = network.clone();
+        self.svc.set_network_name(network);
+    }
+
+    /// Configured bootstrap address.
+    pub fn bootstrap_addr(&self) -> Option<&str> {
+        self.bootstrap_addr.as_deref()
+    }
+
+    /// Connects to a new resolution of the bootstrap address: the core
+    /// service is rebuilt, and restarted if running. Returns `false` if the
+    /// address did not change.
+    pub fn set_resolved(&mut self, resolved: String) -> bool {
+        if self.resolved.as_deref() == Some(resolved.as_str()) {
+            return false;
+        }
+        info!(
+            "[p2p] bootstrap address {} now resolved to {}, reconnecting",
+            self.bootstrap_addr.as_deref().unwrap_or_default(),
+            resolved
+        );
+        let running = self.svc.is_running();
+        self.svc.stop();
+        self.resolved = Some(resolved);
+        let config = PeerConfig {
+            addr: self.addr.clone(),
+            port: self.port,
+            network: Mutex::new(self.network.clone()),
+            bootstrap_addr: self.resolved.clone(),
+            p2p_keypair: self
+                .keypair
+                .as_ref()
+                .and_then(|buf| Ed25519KeyPair::from_bytes(buf).ok()),
+            active: self.active,
+        };
+        self.svc = PeerService::new(config, self.chan.clone());
+        if running {
+            self.svc.start();
+        }
+        true
+    }
+}
+
+/// Resolves the bootstrap DNS name periodically, reconnecting when the
+/// address changes. Returns at once for the addresses without DNS names.
+pub fn run(p2p: Arc<Mutex<P2pService>>) {
+    let (addr, interval) = {
+        let p2p = p2p.lock();
+        match p2p.bootstrap_addr() {
+            Some(addr) if peers::is_dns_address(addr) && !p2p.dns_refresh.is_zero() => {
+                (addr.to_string(), p2p.dns_refresh)
+            }
+            _ => return,
+        }
+    };
+    while tasks::sleep(interval) {
+        // Resolved out of the lock, the lookup may take a while.
+        match peers::resolve_address(&addr) {
+            Ok(resolved) => {
+                p2p.lock().set_resolved(resolved);
+            }
+            Err(err) => debug!("[p2p] bootstrap address {}: {}", addr, err),
+        }
+    }
+}
@@ -0,0 +1,158 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Blocks production pacing.
+//!
+//! On quiet networks the validators keep building empty blocks at every
+//! block timeout. When the idle skip is enabled the block timeout is raised
+//! while the unconfirmed pool is empty and the last block carries no
+//! transactions, and the network timeout is restored as soon as a
+//! transaction reaches the pool.
+//! The switch restarts the block service, as any other block configuration
+//! change.
+
+use crate::{clock::Clock, tasks, wm_cache::NodeWm};
+use std::{sync::Arc, time::Duration};
+use trinci_core::{
+    base::{serialize::rmp_deserialize, BlockchainSettings, Mutex},
+    blockchain::{BlockRequestSender, BlockService},
+    db::{Db, RocksDb},
+    Message,
+};
+
+/// Default block timeout while the network is idle.
+pub const DEFAULT_BLOCK_IDLE_TIMEOUT: u16 = 60;
+
+/// Seconds between two checks of the pool.
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+pub struct Pacer {
+    block_svc: Arc<Mutex<BlockService<RocksDb, NodeWm>>>,
+    chan: BlockRequestSender,
+    idle_timeout: u16,
+    clock: Clock,
+}
+
+/// Whether the pacing has to switch, given the current state, the pool size
+/// and the last block transactions count.
+fn switch(idle: bool, pool_size: usize, last_block_txs: Option<u32>) -> bool {
+    if idle {
+        pool_size > 0
+    } else {
+        pool_size == 0 && last_block_txs == Some(0)
+    }
+}
+
+impl Pacer {
+    pub fn new(
+        block_svc: Arc<Mutex<BlockService<RocksDb, NodeWm>>>,
+        chan: BlockRequestSender,
+        idle_timeout: u16,
+        clock: Clock,
+    ) -> Self {
+        Pacer {
+            block_svc,
+            chan,
+            idle_timeout,
+            clock,
+        }
+    }
+
+    // Pool size and last block transactions count.
+    fn sample(&self) -> Option<(usize, Option<u32>)> {
+        let rx_chan = self.chan.send_sync(Message::GetCoreStatsRequest).ok()?;
+        match rx_chan.recv_sync().ok()? {
+            Message::GetCoreStatsResponse((_, pool_size, block)) => {
+                Some((pool_size, block.map(|block| block.data.size)))
+            }
+            res => {
+                warn!("[pacer] unexpected message {:?}", res);
+                None
+            }
+        }
+    }
+
+    // Sets the block timeout, or the network one when `None`.
+    // Returns false if the network is not configured yet.
+    fn set_timeout(&self, timeout: Option<u16>) -> bool {
+        let mut block_svc = self.block_svc.lock();
+        let db = block_svc.db_arc();
+        let settings = db
+            .read()
+            .load_configuration("blockchain:settings")
+            .and_then(|buf| rmp_deserialize::<BlockchainSettings>(&buf).ok());
+        let (network_name, settings) = match settings {
+            Some(settings) => match settings.network_name.clone() {
+                Some(network_name) => (network_name, settings),
+                None => return false,
+            },
+            None => return false,
+        };
+        block_svc.stop();
+        block_svc.set_block_config(
+            network_name,
+            settings.block_threshold,
+            timeout.unwrap_or(settings.block_timeout),
+        );
+        block_svc.start();
+        true
+    }
+}
+
+/// Checks the pool every `CHECK_INTERVAL` and switches the block timeout.
+pub fn run(pacer: Arc<Pacer>) {
+    let mut idle = false;
+    loop {
+        pacer.clock.sleep(CHECK_INTERVAL);
+        if tasks::cancelled() {
+            break;
+        }
+        let (pool_size, last_block_txs) = match pacer.sample() {
+            Some(sample) => sample,
+            None => {
+                warn!("[pacer] blockchain channel closed");
+                break;
+            }
+        };
+        if !switch(idle, pool_size, last_block_txs) {
+            continue;
+        }
+        let timeout = (!idle).then_some(pacer.idle_timeout);
+        if pacer.set_timeout(timeout) {
+            idle = !idle;
+            match timeout {
+                Some(timeout) => info!("[pacer] network idle, block timeout {}s", timeout),
+                None => info!("[pacer] network active, block timeout restored"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_switch() {
+        assert!(switch(false, 0, Some(0)));
+        assert!(!switch(false, 0, Some(3)));
+        assert!(!switch(false, 0, None));
+        assert!(!switch(false, 2, Some(0)));
+        assert!(switch(true, 1, Some(0)));
+        assert!(!switch(true, 0, Some(0)));
+    }
+}
@@ -0,0 +1,325 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! P2P peers.
+//!
+//! Allowed and blocked peers lists, to restrict the peers of private
+//! consortium networks. An empty allowed list permits every peer that is not
+//! blocked.
+//!
+//! The core p2p service does not expose the connections, thus the filter is
+//! applied to the p2p messages carrying the remote peer identifier (blocks
+//! and transactions requests and responses) as they pass through the gateway.
+//!
+//! The core p2p service accepts a single bootstrap address, the node picks
+//! the first answering one among the configured addresses.
+
+use crate::api::{Request, Response, Router};
+use crate::utils;
+use serde::Serialize;
+use std::{
+    collections::BTreeSet,
+    net::{IpAddr, TcpStream, ToSocketAddrs},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use trinci_core::{base::RwLock, blockchain::Message};
+
+/// Time given to a bootstrap address before trying the others in parallel,
+/// also used as dial timeout.
+const BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Filter content, as reported by the node API.
+#[derive(Serialize, Clone, Default, Debug, PartialEq, Eq)]
+pub struct PeerFilter {
+    /// Peers allowed, empty to allow every peer not blocked.
+    pub allowed: BTreeSet<String>,
+    /// Peers blocked.
+    pub blocked: BTreeSet<String>,
+    /// Peers running a version not compatible with the blockchain, their
+    /// block requests are refused.
+    pub incompatible: BTreeSet<String>,
+}
+
+/// Remote peer of a p2p message, if carried by the message.
+pub(crate) fn message_peer(msg: &Message) -> Option<&str> {
+    match msg {
+        Message::GetBlockRequest { destination, .. }
+        | Message::GetTransactionRequest { destination, .. } => destination.as_deref(),
+        Message::GetBlockResponse { origin, .. }
+        | Message::GetTransactionResponse { origin, .. } => origin.as_deref(),
+        _ => None,
+    }
+}
+
+/// Peer identifier of a p2p address in the `<peer-id>@<multiaddr>` form.
+pub fn address_peer(addr: &str) -> Option<&str> {
+    addr.split_once('@').map(|(peer, _)| peer)
+}
+
+/// P2P address of a peer, in the `<peer-id>@<multiaddr>` form.
+pub fn peer_address(peer: &str, host: &str, port: u16) -> String {
+    let host = utils::unbracket(host);
+    let protocol = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(_)) => "ip4",
+        Ok(IpAddr::V6(_)) => "ip6",
+        Err(_) => "dns",
+    };
+    format!("{}@/{}/{}/tcp/{}", peer, protocol, host, port)
+}
+
+/// Whether the host of a p2p address is a DNS name.
+pub fn is_dns_address(addr: &str) -> bool {
+    let multiaddr = addr.split_once('@').map(|(_, addr)| addr).unwrap_or(addr);
+    ["/dns/", "/dns4/", "/dns6/"]
+        .iter()
+        .any(|protocol| multiaddr.starts_with(protocol))
+}
+
+/// Resolves the DNS host of a p2p address, in the `<peer-id>@<multiaddr>`
+/// form, to an IP one. The other addresses are returned unchanged.
+pub fn resolve_address(addr: &str) -> Result<String, String> {
+    let (peer, multiaddr) = match addr.split_once('@') {
+        Some(parts) if is_dns_address(addr) => parts,
+        _ => return Ok(addr.to_string()),
+    };
+    let parts: Vec<&str> = multiaddr.split('/').collect();
+    let (protocol, host, port) = match parts.as_slice() {
+        ["", protocol, host, "tcp", port] => (*protocol, *host, *port),
+        _ => return Err("unsupported address".to_string()),
+    };
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| format!("invalid port {}", port))?;
+    let ip = (host, port)
+        .to_socket_addrs()
+        .map_err(|err| format!("{}: {}", host, err))?
+        .map(|addr| addr.ip())
+        .find(|ip| match protocol {
+            "dns4" => ip.is_ipv4(),
+            "dns6" => ip.is_ipv6(),
+            _ => true,
+        })
+        .ok_or_else(|| format!("{} not resolved", host))?;
+    Ok(peer_address(peer, &ip.to_string(), port))
+}
+
+/// TCP `host:port` of a p2p address in the `<peer-id>@<multiaddr>` form.
+pub(crate) fn address_endpoint(addr: &str) -> Option<String> {
+    let multiaddr = addr.split_once('@').map(|(_, addr)| addr).unwrap_or(addr);
+    let parts: Vec<&str> = multiaddr.split('/').collect();
+    match parts.as_slice() {
+        ["", "ip6", host, "tcp", port, ..] => Some(format!("[{}]:{}", host, port)),
+        ["", "ip4" | "dns" | "dns4" | "dns6", host, "tcp", port, ..] => {
+            Some(format!("{}:{}", host, port))
+        }
+        _ => None,
+    }
+}
+
+fn dial(addr: &str) -> Result<(), String> {
+    let endpoint = address_endpoint(addr).ok_or("unsupported address")?;
+    let addr = endpoint
+        .to_socket_addrs()
+        .map_err(|err| err.to_string())?
+        .next()
+        .ok_or("address not resolved")?;
+    TcpStream::connect_timeout(&addr, BOOTSTRAP_TIMEOUT)
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+/// Picks the bootstrap address to hand to the p2p service.
+/// Addresses are dialed in order, moving to the next one as soon as a dial
+/// fails. If a dial does not complete within `BOOTSTRAP_TIMEOUT` the
+/// remaining addresses are dialed in parallel and the first answering wins.
+/// When none answers the first address is returned, leaving the retries to
+/// the p2p service.
+pub fn select_bootstrap(addrs: &[String]) -> Option<String> {
+    if addrs.len() <= 1 {
+        return addrs.first().cloned();
+    }
+    let (tx, rx) = mpsc::channel();
+    let spawn_dial = |index: usize| {
+        let tx = tx.clone();
+        let addr = addrs[index].clone();
+        thread::spawn(move || {
+            let _ = tx.send((index, dial(&addr)));
+        });
+    };
+
+    spawn_dial(0);
+    let (mut next, mut pending) = (1, 1);
+    while pending > 0 {
+        match rx.recv_timeout(BOOTSTRAP_TIMEOUT) {
+            Ok((index, Ok(()))) => {
+                info!("[p2p] bootstrap peer {} answered", addrs[index]);
+                return Some(addrs[index].clone());
+            }
+            Ok((index, Err(err))) => {
+                warn!("[p2p] bootstrap peer {}: {}", addrs[index], err);
+                pending -= 1;
+                if pending == 0 && next < addrs.len() {
+                    spawn_dial(next);
+                    next += 1;
+                    pending += 1;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                while next < addrs.len() {
+                    spawn_dial(next);
+                    next += 1;
+                    pending += 1;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    warn!("[p2p] no bootstrap peer answered, falling back to the first one");
+    addrs.first().cloned()
+}
+
+impl PeerFilter {
+    pub fn new<I: IntoIterator<Item = String>>(allowed: I, blocked: I) -> Self {
+        PeerFilter {
+            allowed: allowed.into_iter().collect(),
+            blocked: blocked.into_iter().collect(),
+            incompatible: BTreeSet::new(),
+        }
+    }
+
+    /// Returns `true` if the peer is not blocked and, when the allowed list
+    /// is not empty, is allowed.
+    pub fn is_permitted(&self, peer: &str) -> bool {
+        !self.blocked.contains(peer) && (self.allowed.is_empty() || self.allowed.contains(peer))
+    }
+
+    /// Checks a p2p message, returns the refused peer: not permitted, or
+    /// incompatible for block requests.
+    pub fn check<'a>(&self, msg: &'a Message) -> Option<&'a str> {
+        message_peer(msg).filter(|peer| {
+            !self.is_permitted(peer)
+                || (matches!(msg, Message::GetBlockRequest { .. })
+                    && self.incompatible.contains(*peer))
+        })
+    }
+
+    /// Marks a peer as compatible or not with the blockchain.
+    pub fn set_compatible(&mut self, peer: String, compatible: bool) {
+        if compatible {
+            self.incompatible.remove(&peer);
+        } else {
+            self.incompatible.insert(peer);
+        }
+    }
+
+    /// Registers the peers filter routes within the node API.
+    /// Runtime changes are not persisted, the config lists are restored at
+    /// the next start.
+    pub fn routes(filter: Arc<RwLock<Self>>, router: &mut Router) {
+        let peers = filter.clone();
+        router.add("GET", "/admin/p2p/peers", move |_: &Request| {
+            Response::json(&*peers.read())
+        });
+        let lists = [
+            ("allowed", "/admin/p2p/peers/allowed/:id"),
+            ("blocked", "/admin/p2p/peers/blocked/:id"),
+        ];
+        for (list, path) in lists {
+            let peers = filter.clone();
+            router.add("POST", path, move |req: &Request| {
+                let id = match req.param::<String>("id") {
+                    Some(id) if !id.is_empty() => id,
+                    _ => return Response::error(400, "invalid peer identifier"),
+                };
+                let mut peers = peers.write();
+                info!("[p2p] peer {} {}", id, list);
+                peers.list_mut(list).insert(id);
+                Response::ok()
+            });
+            let peers = filter.clone();
+            router.add("DELETE", path, move |req: &Request| {
+                let id = req.param::<String>("id").unwrap_or_default();
+                if !peers.write().list_mut(list).remove(&id) {
+                    return Response::error(404, "Not Found");
+                }
+                info!("[p2p] peer {} removed from {}", id, list);
+                Response::ok()
+            });
+        }
+    }
+
+    fn list_mut(&mut self, list: &str) -> &mut BTreeSet<String> {
+        match list {
+            "allowed" => &mut self.allowed,
+            _ => &mut self.blocked,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permitted_peers() {
+        let filter = PeerFilter::new(vec![], vec!["bad".to_string()]);
+        assert!(filter.is_permitted("any"));
+        assert!(!filter.is_permitted("bad"));
+
+        let filter = PeerFilter::new(
+            vec!["good".to_string(), "bad".to_string()],
+            vec!["bad".to_string()],
+        );
+        assert!(filter.is_permitted("good"));
+        assert!(!filter.is_permitted("bad"));
+        assert!(!filter.is_permitted("any"));
+    }
+
+    #[test]
+    fn bootstrap_failover() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed_port = closed.local_addr().unwrap().port();
+        drop(closed);
+        let addrs = vec![
+            format!("peer1@/ip4/127.0.0.1/tcp/{}", closed_port),
+            "peer2@/unsupported".to_string(),
+            format!("peer3@/ip4/127.0.0.1/tcp/{}", port),
+        ];
+
+        assert_eq!(select_bootstrap(&addrs), Some(addrs[2].clone()));
+        assert_eq!(
+            address_endpoint("peer@/ip6/::1/tcp/9006").as_deref(),
+            Some("[::1]:9006")
+        );
+        assert_eq!(
+            peer_address("peer", "[::1]", 9006),
+            "peer@/ip6/::1/tcp/9006"
+        );
+        assert_eq!(
+            resolve_address("peer@/dns4/localhost/tcp/9006").as_deref(),
+            Ok("peer@/ip4/127.0.0.1/tcp/9006")
+        );
+        assert_eq!(resolve_address(&addrs[0]).as_deref(), Ok(addrs[0].as_str()));
+    }
+}
@@ -0,0 +1,305 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! PKCS#11 keys.
+//!
+//! Keys held by an HSM are referenced by a PKCS#11 URI (RFC 7512 subset):
+//!
+//! `pkcs11:token=<label>;object=<label>?module-path=<library>&pin-source=<file>`
+//!
+//! The token defaults to the first one found, the PIN can be given inline with
+//! `pin-value`. Only ECDSA P-256 and P-384 keys are supported: the signature
+//! is computed by the device over the SHA-256/SHA-384 digest, the private key
+//! never leaves it. The device access requires the `pkcs11` feature.
+//!
+//! The node keypair can't be held by an HSM: the core block service signs
+//! with an in-process keypair, so the configuration refuses a PKCS#11 URI
+//! as `keypair-path`.
+
+use crate::utils::Signer;
+use std::collections::HashMap;
+use trinci_core::{Error, ErrorKind, Result};
+
+/// URI scheme of the keys held by an HSM.
+pub const SCHEME: &str = "pkcs11:";
+
+/// Returns whether a keypair path is a PKCS#11 URI.
+pub fn is_uri(path: &str) -> bool {
+    path.starts_with(SCHEME)
+}
+
+/// Parsed PKCS#11 URI.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Pkcs11Uri {
+    /// Token label, the first token found if missing.
+    pub token: Option<String>,
+    /// Key label, shared by the private and the public key objects.
+    pub object: String,
+    /// PKCS#11 module library.
+    pub module_path: String,
+    /// File holding the user PIN.
+    pub pin_source: Option<String>,
+    /// Inline user PIN.
+    pub pin_value: Option<String>,
+}
+
+// Decodes the `%XX` escapes of an URI attribute value.
+fn percent_decode(value: &str) -> std::result::Result<String, String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = value
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("invalid escape in `{}`", value))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| format!("invalid UTF-8 in `{}`", value))
+}
+
+// Attributes of an URI component, separated by `sep`.
+fn attributes(component: &str, sep: char) -> std::result::Result<HashMap<String, String>, String> {
+    let mut attributes = HashMap::new();
+    for attribute in component
+        .split(sep)
+        .filter(|attribute| !attribute.is_empty())
+    {
+        let (name, value) = attribute
+            .split_once('=')
+            .ok_or_else(|| format!("invalid attribute `{}`", attribute))?;
+        attributes.insert(name.to_string(), percent_decode(value)?);
+    }
+    Ok(attributes)
+}
+
+impl Pkcs11Uri {
+    pub fn parse(uri: &str) -> std::result::Result<Self, String> {
+        let uri = uri
+            .strip_prefix(SCHEME)
+            .ok_or_else(|| format!("missing `{}` scheme", SCHEME))?;
+        let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+        let mut path = attributes(path, ';')?;
+        let mut query = attributes(query, '&')?;
+        Ok(Pkcs11Uri {
+            token: path.remove("token"),
+            object: path.remove("object").ok_or("missing `object` attribute")?,
+            module_path: query
+                .remove("module-path")
+                .ok_or("missing `module-path` attribute")?,
+            pin_source: query.remove("pin-source"),
+            pin_value: query.remove("pin-value"),
+        })
+    }
+
+    // User PIN, from the PIN file if any.
+    #[cfg_attr(not(feature = "pkcs11"), allow(dead_code))]
+    fn pin(&self) -> std::result::Result<Option<String>, String> {
+        match &self.pin_source {
+            Some(source) => {
+                let path = source.strip_prefix("file:").unwrap_or(source);
+                std::fs::read_to_string(path)
+                    .map(|pin| Some(pin.trim_end().to_string()))
+                    .map_err(|err| format!("PIN file {}: {}", path, err))
+            }
+            None => Ok(self.pin_value.clone()),
+        }
+    }
+}
+
+// Uncompressed EC point within the DER octet string of the `CKA_EC_POINT`
+// attribute, some modules return the bare point.
+#[cfg_attr(not(feature = "pkcs11"), allow(dead_code))]
+fn ec_point(der: &[u8]) -> &[u8] {
+    match der {
+        [0x04, len, point @ ..] if *len as usize == point.len() && point.first() == Some(&0x04) => {
+            point
+        }
+        [0x04, 0x81, len, point @ ..] if *len as usize == point.len() => point,
+        point => point,
+    }
+}
+
+/// Opens the key referenced by a PKCS#11 URI.
+pub fn open(uri: &str) -> Result<Box<dyn Signer>> {
+    let uri = Pkcs11Uri::parse(uri)
+        .map_err(|err| Error::new_ext(ErrorKind::MalformedData, format!("PKCS#11 URI: {}", err)))?;
+    #[cfg(feature = "pkcs11")]
+    {
+        let key = device::Pkcs11Key::open(&uri)
+            .map_err(|err| Error::new_ext(ErrorKind::Other, format!("PKCS#11: {}", err)))?;
+        Ok(Box::new(key))
+    }
+    #[cfg(not(feature = "pkcs11"))]
+    {
+        let _ = uri;
+        Err(Error::new_ext(
+            ErrorKind::NotImplemented,
+            "PKCS#11 feature not included, for using HSM keys compile with feature=pkcs11",
+        ))
+    }
+}
+
+#[cfg(feature = "pkcs11")]
+mod device {
+    use super::{ec_point, Pkcs11Uri, Result};
+    use crate::utils::Signer;
+    use cryptoki::{
+        context::{CInitializeArgs, Pkcs11},
+        mechanism::Mechanism,
+        object::{Attribute, AttributeType, ObjectClass, ObjectHandle},
+        session::{Session, UserType},
+        types::AuthPin,
+    };
+    use ring::digest;
+    use trinci_core::{base::Mutex, crypto::ecdsa, Error, ErrorKind, PublicKey};
+
+    /// ECDSA key held by a PKCS#11 device.
+    pub struct Pkcs11Key {
+        session: Mutex<Session>,
+        key: ObjectHandle,
+        public_key: PublicKey,
+        digest: &'static digest::Algorithm,
+        // Keeps the module loaded while the session is open.
+        _context: Pkcs11,
+    }
+
+    impl Pkcs11Key {
+        pub fn open(uri: &Pkcs11Uri) -> std::result::Result<Self, String> {
+            let context = Pkcs11::new(&uri.module_path)
+                .map_err(|err| format!("module {}: {}", uri.module_path, err))?;
+            context
+                .initialize(CInitializeArgs::OsThreads)
+                .map_err(|err| err.to_string())?;
+            let slots = context
+                .get_slots_with_token()
+                .map_err(|err| err.to_string())?;
+            let slot = slots
+                .into_iter()
+                .find(|slot| match &uri.token {
+                    Some(token) => context
+                        .get_token_info(*slot)
+                        .map(|info| info.label().trim_end() == token)
+                        .unwrap_or(false),
+                    None => true,
+                })
+                .ok_or("token not found")?;
+            let session = context
+                .open_ro_session(slot)
+                .map_err(|err| err.to_string())?;
+            if let Some(pin) = uri.pin()? {
+                session
+                    .login(UserType::User, Some(&AuthPin::new(pin)))
+                    .map_err(|err| format!("login: {}", err))?;
+            }
+
+            let find = |class| {
+                session
+                    .find_objects(&[
+                        Attribute::Class(class),
+                        Attribute::Label(uri.object.as_bytes().to_vec()),
+                    ])
+                    .map_err(|err| err.to_string())?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| format!("object `{}` not found", uri.object))
+            };
+            let key = find(ObjectClass::PRIVATE_KEY)?;
+            let public = find(ObjectClass::PUBLIC_KEY)?;
+            let point = session
+                .get_attributes(public, &[AttributeType::EcPoint])
+                .map_err(|err| err.to_string())?
+                .into_iter()
+                .find_map(|attribute| match attribute {
+                    Attribute::EcPoint(point) => Some(point),
+                    _ => None,
+                })
+                .ok_or("public key without EC point")?;
+            let point = ec_point(&point).to_vec();
+            let (curve_id, digest) = match point.len() {
+                65 => (ecdsa::CurveId::Secp256R1, &digest::SHA256),
+                97 => (ecdsa::CurveId::Secp384R1, &digest::SHA384),
+                _ => return Err("only ECDSA P-256 and P-384 keys are supported".to_string()),
+            };
+            let public_key = PublicKey::Ecdsa(ecdsa::PublicKey {
+                curve_id,
+                value: point,
+            });
+
+            Ok(Pkcs11Key {
+                session: Mutex::new(session),
+                key,
+                public_key,
+                digest,
+                _context: context,
+            })
+        }
+    }
+
+    impl Signer for Pkcs11Key {
+        fn public_key(&self) -> PublicKey {
+            self.public_key.clone()
+        }
+
+        fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+            let hash = digest::digest(self.digest, data);
+            // CKM_ECDSA returns the fixed size `r || s` form, as the core keys.
+            self.session
+                .lock()
+                .sign(&Mechanism::Ecdsa, self.key, hash.as_ref())
+                .map_err(|err| Error::new_ext(ErrorKind::Other, err.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_uri() {
+        let uri = Pkcs11Uri::parse(
+            "pkcs11:token=trinci;object=node%20key?module-path=/usr/lib/softhsm/libsofthsm2.so&pin-source=file:/etc/trinci/pin",
+        )
+        .unwrap();
+
+        assert_eq!(
+            uri,
+            Pkcs11Uri {
+                token: Some("trinci".to_string()),
+                object: "node key".to_string(),
+                module_path: "/usr/lib/softhsm/libsofthsm2.so".to_string(),
+                pin_source: Some("file:/etc/trinci/pin".to_string()),
+                pin_value: None,
+            }
+        );
+        assert!(Pkcs11Uri::parse("pkcs11:object=node").is_err());
+        assert!(Pkcs11Uri::parse("pkcs11:token=trinci?module-path=lib.so").is_err());
+
+        let point = [4u8; 65];
+        let mut der = vec![0x04, 65];
+        der.extend_from_slice(&point);
+        assert_eq!(ec_point(&der), &point[..]);
+        assert_eq!(ec_point(&point), &point[..]);
+    }
+}
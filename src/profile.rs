@@ -0,0 +1,119 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Configuration profiles.
+//!
+//! Curated bundles of defaults for the common setups, applied before the
+//! configuration file and the command line, which can still override every
+//! setting.
+
+use crate::config::Config;
+use crate::integrity::DbVerify;
+use crate::telemetry::Telemetry;
+
+/// Configuration profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// Local development chain: offline, fast blocks, verbose logs and no
+    /// monitor updates.
+    Dev,
+    /// Public test network.
+    Testnet,
+    /// Public main network.
+    Mainnet,
+}
+
+impl std::str::FromStr for Profile {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "dev" => Ok(Profile::Dev),
+            "testnet" => Ok(Profile::Testnet),
+            "mainnet" => Ok(Profile::Mainnet),
+            _ => Err(format!(
+                "invalid profile `{}` (expected dev, testnet or mainnet)",
+                value
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let profile = match self {
+            Profile::Dev => "dev",
+            Profile::Testnet => "testnet",
+            Profile::Mainnet => "mainnet",
+        };
+        write!(f, "{}", profile)
+    }
+}
+
+impl Profile {
+    /// Settings changed by the profile.
+    pub fn keys(&self) -> &'static [&'static str] {
+        match self {
+            Profile::Dev => &[
+                "log-level",
+                "bootstrap-path",
+                "db-path",
+                "offline",
+                "block-timeout",
+                "block-idle-skip",
+                "ip-discovery",
+                "telemetry",
+            ],
+            Profile::Testnet => &["bootstrap-path", "p2p-addr", "p2p-port"],
+            Profile::Mainnet => &[
+                "bootstrap-path",
+                "p2p-addr",
+                "p2p-port",
+                "db-verify",
+                "tx-journal",
+            ],
+        }
+    }
+
+    /// Applies the profile defaults.
+    pub fn apply(&self, config: &mut Config) {
+        match self {
+            Profile::Dev => {
+                config.log_level = "debug".to_string();
+                config.bootstrap_path = "./data/offline-bootstrap.bin".to_string();
+                config.db_path = "./db/dev".to_string();
+                config.offline = true;
+                config.block_timeout = 1;
+                config.block_idle_skip = true;
+                config.ip_discovery = false;
+                config.telemetry = Telemetry::Off;
+            }
+            Profile::Testnet => {
+                config.bootstrap_path = "./data/testnet-bootstrap.bin".to_string();
+                config.p2p_addr = "0.0.0.0".to_string();
+                config.p2p_port = 9000;
+            }
+            Profile::Mainnet => {
+                config.bootstrap_path = "./data/prod-bootstrap.bin".to_string();
+                config.p2p_addr = "0.0.0.0".to_string();
+                config.p2p_port = 9000;
+                config.db_verify = DbVerify::Quick;
+                config.tx_journal = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,178 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Block propagation latency.
+//!
+//! Compares the time a block is first received from the P2P network with its
+//! production time, the block timestamp, to tune `block-timeout` for
+//! geographically distributed validators.
+//! The block timestamp has a one second resolution and comes from the
+//! validator clock: the latencies are biased up to one second and a skewed
+//! validator clock shows as a constant offset (negative values are clamped to
+//! zero). Blocks produced by the node itself are not received from the
+//! network and are not accounted.
+
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use trinci_core::{
+    base::{serialize::rmp_deserialize, Mutex},
+    Message,
+};
+
+/// Number of blocks the percentiles are computed over.
+const WINDOW_BLOCKS: usize = 100;
+
+/// Propagation latency statistics over the last blocks, in milliseconds.
+#[derive(Serialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct PropagationStats {
+    /// Blocks the statistics are computed over.
+    pub blocks: usize,
+    pub last_ms: u64,
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+    pub max_ms: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Highest block height seen, the following copies are ignored.
+    height: Option<u64>,
+    samples: VecDeque<u64>,
+}
+
+#[derive(Default)]
+pub struct Propagation {
+    inner: Mutex<Inner>,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// Nearest rank percentile of sorted values.
+fn percentile(sorted: &[u64], percent: usize) -> u64 {
+    let rank = (sorted.len() * percent).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+impl Propagation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accounts the blocks carried by a message received from a peer,
+    /// packed messages included.
+    pub fn observe(&self, msg: &Message) {
+        match msg {
+            Message::GetBlockResponse { block, .. } => {
+                self.record(block.data.height, block.data.timestamp, now_ms())
+            }
+            Message::Packed { buf } => {
+                if let Ok(msg) = rmp_deserialize::<Message>(buf) {
+                    self.observe(&msg);
+                } else if let Ok(msgs) = rmp_deserialize::<Vec<Message>>(buf) {
+                    msgs.iter().for_each(|msg| self.observe(msg));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Accounts a block received at `now_ms`, `timestamp` being its
+    /// production time in seconds.
+    fn record(&self, height: u64, timestamp: u64, now_ms: u64) {
+        let mut inner = self.inner.lock();
+        if inner.height >= Some(height) {
+            return;
+        }
+        inner.height = Some(height);
+        if inner.samples.len() == WINDOW_BLOCKS {
+            inner.samples.pop_front();
+        }
+        let produced_ms = timestamp.saturating_mul(1000);
+        inner.samples.push_back(now_ms.saturating_sub(produced_ms));
+    }
+
+    /// Highest block height seen.
+    pub fn height(&self) -> Option<u64> {
+        self.inner.lock().height
+    }
+
+    /// Current statistics, `None` before the first block.
+    pub fn stats(&self) -> Option<PropagationStats> {
+        let inner = self.inner.lock();
+        let last_ms = *inner.samples.back()?;
+        let mut sorted: Vec<u64> = inner.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        Some(PropagationStats {
+            blocks: sorted.len(),
+            last_ms,
+            p50_ms: percentile(&sorted, 50),
+            p95_ms: percentile(&sorted, 95),
+            max_ms: sorted[sorted.len() - 1],
+        })
+    }
+
+    /// Renders the statistics in the Prometheus text format.
+    pub fn render(&self, out: &mut String) {
+        let stats = match self.stats() {
+            Some(stats) => stats,
+            None => return,
+        };
+        let _ = writeln!(out, "# TYPE trinci_block_propagation_seconds gauge");
+        for (quantile, value) in [("0.5", stats.p50_ms), ("0.95", stats.p95_ms)] {
+            let _ = writeln!(
+                out,
+                "trinci_block_propagation_seconds{{quantile=\"{}\"}} {}",
+                quantile,
+                value as f64 / 1000.0
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let propagation = Propagation::new();
+        assert!(propagation.stats().is_none());
+
+        for height in 1..=20 {
+            propagation.record(height, 1000, 1_000_000 + height * 100);
+        }
+        // Copies of the blocks already seen.
+        propagation.record(20, 1000, 9_000_000);
+        propagation.record(3, 1000, 9_000_000);
+
+        let stats = propagation.stats().unwrap();
+        assert_eq!(stats.blocks, 20);
+        assert_eq!(stats.last_ms, 2000);
+        assert_eq!(stats.p50_ms, 1000);
+        assert_eq!(stats.p95_ms, 1900);
+        assert_eq!(stats.max_ms, 2000);
+    }
+}
@@ -0,0 +1,280 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Outbound HTTP proxy.
+//!
+//! Nodes deployed behind a corporate firewall reach the monitor, the alert
+//! webhooks, the public address echo services and the bootstrap nodes
+//! through the `http-proxy`. The isahc requests accept any proxy supported by
+//! libcurl (`http://`, `https://`, `socks5://`, `socks5h://`...); without the
+//! setting they honor the usual `http_proxy`/`https_proxy`/`no_proxy`
+//! environment variables. The bootstrap download opens its own connections,
+//! tunneled through an `http://` proxy with `CONNECT` or through a
+//! `socks5://` proxy.
+
+use isahc::{config::Configurable, http::request::Builder};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::RwLock,
+    time::Duration,
+};
+
+/// Proxy handshake timeout.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+static PROXY: RwLock<Option<String>> = RwLock::new(None);
+
+/// Sets the proxy used by the outbound requests.
+pub fn set(proxy: Option<String>) {
+    *PROXY.write().unwrap_or_else(|err| err.into_inner()) = proxy;
+}
+
+/// Configured proxy.
+pub fn get() -> Option<String> {
+    PROXY.read().unwrap_or_else(|err| err.into_inner()).clone()
+}
+
+/// Routes the request through the configured proxy, if any.
+pub fn apply(builder: Builder) -> Builder {
+    match get().map(|proxy| proxy.parse()) {
+        Some(Ok(uri)) => builder.proxy(Some(uri)),
+        Some(Err(err)) => {
+            warn!("[proxy] invalid proxy address: {}", err);
+            builder
+        }
+        None => builder,
+    }
+}
+
+/// GET request through the configured proxy.
+pub fn http_get(url: &str) -> Result<isahc::Response<isahc::Body>, isahc::Error> {
+    let request = apply(isahc::Request::get(url)).body(())?;
+    isahc::send(request)
+}
+
+/// Proxy address components.
+#[derive(Debug, PartialEq, Eq)]
+struct ProxyAddr {
+    scheme: String,
+    credentials: Option<String>,
+    host: String,
+}
+
+fn parse(proxy: &str) -> Result<ProxyAddr, String> {
+    let (scheme, rest) = proxy.split_once("://").unwrap_or(("http", proxy));
+    let rest = rest.trim_end_matches('/');
+    let (credentials, host) = match rest.rsplit_once('@') {
+        Some((credentials, host)) => (Some(credentials.to_string()), host),
+        None => (None, rest),
+    };
+    let default_port = match scheme {
+        "http" => 80,
+        "socks5" | "socks5h" => 1080,
+        scheme => return Err(format!("proxy scheme `{}` not supported", scheme)),
+    };
+    let has_port = matches!(host.rsplit_once(':'), Some((_, port)) if port.parse::<u16>().is_ok());
+    let host = if has_port {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, default_port)
+    };
+    Ok(ProxyAddr {
+        scheme: scheme.to_string(),
+        credentials,
+        host,
+    })
+}
+
+fn base64(data: &[u8]) -> String {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(CHARS[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// Opens a tunnel to `host` through an HTTP proxy.
+fn http_tunnel(stream: &mut TcpStream, proxy: &ProxyAddr, host: &str) -> io::Result<()> {
+    let auth = match &proxy.credentials {
+        Some(credentials) => format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64(credentials.as_bytes())
+        ),
+        None => String::new(),
+    };
+    write!(
+        stream,
+        "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n{1}\r\n",
+        host, auth
+    )?;
+    // The tunnel starts right after the response head, read it byte by byte.
+    let mut reader = BufReader::with_capacity(1, stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line.split(' ').nth(1).unwrap_or_default().to_string();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+    match status.as_str() {
+        "200" => Ok(()),
+        status => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("proxy CONNECT status {}", status),
+        )),
+    }
+}
+
+// Opens a tunnel to `host` through a SOCKS5 proxy, the name is resolved by the proxy.
+fn socks5_tunnel(stream: &mut TcpStream, proxy: &ProxyAddr, host: &str) -> io::Result<()> {
+    let refused = |msg: &str| io::Error::new(io::ErrorKind::ConnectionRefused, msg.to_string());
+    let credentials = proxy
+        .credentials
+        .as_deref()
+        .map(|credentials| credentials.split_once(':').unwrap_or((credentials, "")));
+    let method = if credentials.is_some() { 2 } else { 0 };
+    stream.write_all(&[5, 1, method])?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply)?;
+    if reply != [5, method] {
+        return Err(refused("SOCKS5 authentication method refused"));
+    }
+    if let Some((user, password)) = credentials {
+        let mut auth = vec![1, user.len() as u8];
+        auth.extend(user.as_bytes());
+        auth.push(password.len() as u8);
+        auth.extend(password.as_bytes());
+        stream.write_all(&auth)?;
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0 {
+            return Err(refused("SOCKS5 authentication failed"));
+        }
+    }
+
+    let (name, port) = host
+        .rsplit_once(':')
+        .and_then(|(name, port)| Some((crate::utils::unbracket(name), port.parse::<u16>().ok()?)))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid address"))?;
+    let mut req = vec![5, 1, 0, 3, name.len() as u8];
+    req.extend(name.as_bytes());
+    req.extend(port.to_be_bytes());
+    stream.write_all(&req)?;
+    let mut head = [0; 4];
+    stream.read_exact(&mut head)?;
+    if head[1] != 0 {
+        return Err(refused(&format!("SOCKS5 connect error {}", head[1])));
+    }
+    // Skips the bound address.
+    let len = match head[3] {
+        1 => 4,
+        4 => 16,
+        _ => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+    };
+    let mut bound = vec![0; len + 2];
+    stream.read_exact(&mut bound)
+}
+
+// Connects to `host` through the `proxy` tunnel.
+fn tunnel(proxy: &str, host: &str) -> io::Result<TcpStream> {
+    let proxy = parse(proxy).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let addr = proxy
+        .host
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "proxy address not found"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, HANDSHAKE_TIMEOUT)?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    match proxy.scheme.as_str() {
+        "http" => http_tunnel(&mut stream, &proxy, host)?,
+        _ => socks5_tunnel(&mut stream, &proxy, host)?,
+    }
+    Ok(stream)
+}
+
+/// Connects to `host` (host:port), through the configured proxy if any.
+pub fn connect(host: &str) -> io::Result<TcpStream> {
+    match get() {
+        Some(proxy) => tunnel(&proxy, host),
+        None => TcpStream::connect(host),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn http_connect_tunnel() {
+        assert_eq!(
+            parse("user:pass@proxy.local").unwrap(),
+            ProxyAddr {
+                scheme: "http".to_string(),
+                credentials: Some("user:pass".to_string()),
+                host: "proxy.local:80".to_string(),
+            }
+        );
+        assert_eq!(parse("socks5://[::1]").unwrap().host, "[::1]:1080");
+        assert!(parse("ftp://proxy.local:21").is_err());
+        assert_eq!(base64(b"user:pass"), "dXNlcjpwYXNz");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let proxy = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\ntunneled")
+                .unwrap();
+            line
+        });
+
+        let proxy_addr = format!("http://127.0.0.1:{}", port);
+        let mut stream = tunnel(&proxy_addr, "bootstrap.local:8000").unwrap();
+        let mut data = String::new();
+        stream.read_to_string(&mut data).unwrap();
+
+        assert_eq!(
+            proxy.join().unwrap(),
+            "CONNECT bootstrap.local:8000 HTTP/1.1\r\n"
+        );
+        assert_eq!(data, "tunneled");
+    }
+}
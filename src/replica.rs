@@ -0,0 +1,433 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Read replica.
+//!
+//! In `replica` role the node takes no part in the P2P network: it tails the
+//! blocks of the `replica-primary` node, via the core REST message route, and
+//! hands them to the local blockchain service, which executes them and checks
+//! the resulting state against the block. The replica then serves the read
+//! APIs (REST, bridge, WebSocket, explorer), an easy way to scale the read
+//! traffic behind a single validator.
+//!
+//! The replica starts from the same bootstrap file as the primary. The
+//! transactions it receives are not relayed anywhere: submit them to the
+//! primary.
+//!
+//! Catching up is pipelined: `replay-threads` threads fetch the following
+//! blocks and verify their transactions signatures while the current ones are
+//! executed. The execution and the database writes are performed by the core
+//! blockchain service, one block at a time.
+//!
+//! The same pipeline catches up the `full` and `api` nodes, from the
+//! `catchup-peer` or else from the REST service of the first P2P bootstrap
+//! peer: the core synchronization from the P2P network fetches and executes
+//! one block at a time and can't be pipelined from the node, it keeps running
+//! alongside. The blocks are executed and checked by the blockchain service as
+//! the ones gossiped by the network, the source does not need to be trusted.
+
+use crate::api::{Request, Response, Router};
+use crate::cmd::rest;
+use crate::tasks::{self, panic_message};
+use serde::Serialize;
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use trinci_core::{
+    base::{Mutex, RwLock},
+    blockchain::{BlockRequestSender, Message},
+    db::{Db, RocksDb},
+    Block, Hash, Transaction,
+};
+
+/// Seconds between two polls of the primary when in sync.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Seconds before polling again an unreachable or failing primary.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Max blocks applied between two polls of the primary.
+const MAX_BATCH: u64 = 100;
+
+/// Max time waited for the local execution of a block.
+const APPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default threads preparing the primary blocks ahead of their execution.
+pub const DEFAULT_REPLAY_THREADS: usize = 4;
+
+/// Blocks prepared by each thread ahead of the execution.
+const BLOCKS_PER_THREAD: usize = 4;
+
+/// Replica status, as reported by the node API.
+#[derive(Serialize, Clone, Default)]
+pub struct ReplicaStatus {
+    /// Primary node REST address.
+    pub primary: String,
+    /// Last block height executed locally.
+    pub height: Option<u64>,
+    /// Last block height of the primary.
+    pub primary_height: Option<u64>,
+    /// Last successful poll of the primary, seconds since the epoch.
+    pub last_sync: Option<u64>,
+    /// Outcome of the last sync.
+    pub last_error: Option<String>,
+}
+
+pub struct Replica {
+    primary: String,
+    db: Arc<RwLock<RocksDb>>,
+    chan: BlockRequestSender,
+    threads: usize,
+    status: Mutex<ReplicaStatus>,
+}
+
+// Primary block ready to be executed, with its verified transactions.
+struct Prepared {
+    block: Block,
+    hashes: Vec<Hash>,
+    txs: Vec<Transaction>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Block with its transactions hashes, from the primary.
+fn primary_block(primary: &str, height: u64) -> Result<(Block, Vec<Hash>), String> {
+    let msg = Message::GetBlockRequest {
+        height,
+        txs: true,
+        destination: None,
+    };
+    match rest::request(primary, msg)? {
+        Message::GetBlockResponse { block, txs, .. } => Ok((block, txs.unwrap_or_default())),
+        msg => Err(format!("unexpected answer: {:?}", msg)),
+    }
+}
+
+fn primary_tx(primary: &str, hash: Hash) -> Result<Transaction, String> {
+    let msg = Message::GetTransactionRequest {
+        hash,
+        destination: None,
+    };
+    match rest::request(primary, msg)? {
+        Message::GetTransactionResponse { tx, .. } => Ok(tx),
+        msg => Err(format!("unexpected answer: {:?}", msg)),
+    }
+}
+
+// Fetches a primary block with its transactions, verifying their signatures.
+fn prepare(primary: &str, height: u64) -> Result<Prepared, String> {
+    let (block, hashes) = primary_block(primary, height)?;
+    if block.data.height != height {
+        return Err(format!(
+            "primary answered block {} for {}",
+            block.data.height, height
+        ));
+    }
+    let txs = hashes
+        .iter()
+        .map(|hash| {
+            let tx = primary_tx(primary, *hash)?;
+            let hex = || hex::encode(hash.as_bytes());
+            if tx.get_primary_hash() != *hash {
+                return Err(format!(
+                    "primary answered a different transaction for {}",
+                    hex()
+                ));
+            }
+            tx.check_integrity()
+                .map_err(|err| format!("transaction {}: {}", hex(), err))?;
+            Ok(tx)
+        })
+        .collect::<Result<_, String>>()?;
+    Ok(Prepared { block, hashes, txs })
+}
+
+// Maps the items over `threads` scoped threads, the results keep the items
+// order. Fails if a thread panicked.
+fn parallel_map<T, R, F>(items: &[T], threads: usize, f: F) -> Result<Vec<R>, String>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let chunk_size = items.len().div_ceil(threads.max(1)).max(1);
+    let f = &f;
+    thread::scope(|scope| {
+        let workers: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|items| scope.spawn(move || items.iter().map(f).collect::<Vec<_>>()))
+            .collect();
+        // Every worker is joined, a panic left to the scope is propagated.
+        let results: Vec<_> = workers.into_iter().map(|worker| worker.join()).collect();
+        results
+            .into_iter()
+            .try_fold(Vec::with_capacity(items.len()), |mut mapped, result| {
+                let results = result.map_err(|payload| {
+                    format!(
+                        "replay thread panicked: {}",
+                        panic_message(payload.as_ref())
+                    )
+                })?;
+                mapped.extend(results);
+                Ok(mapped)
+            })
+    })
+}
+
+impl Replica {
+    pub fn new(primary: String, db: Arc<RwLock<RocksDb>>, chan: BlockRequestSender) -> Self {
+        Replica {
+            status: Mutex::new(ReplicaStatus {
+                primary: primary.clone(),
+                ..Default::default()
+            }),
+            primary,
+            db,
+            chan,
+            threads: DEFAULT_REPLAY_THREADS,
+        }
+    }
+
+    /// Sets the threads preparing the blocks ahead of their execution.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    fn height(&self) -> Option<u64> {
+        self.db
+            .read()
+            .load_block(u64::MAX)
+            .map(|block| block.data.height)
+    }
+
+    // Hands a primary block to the local blockchain service and waits for its
+    // execution, returns its height.
+    fn apply(&self, prepared: Prepared) -> Result<u64, String> {
+        let Prepared { block, hashes, txs } = prepared;
+        let height = block.data.height;
+        for tx in txs {
+            self.chan
+                .send_sync(Message::PutTransactionRequest { confirm: false, tx })
+                .map_err(|err| err.to_string())?;
+        }
+        self.chan
+            .send_sync(Message::GetBlockResponse {
+                block,
+                txs: Some(hashes),
+                origin: None,
+            })
+            .map_err(|err| err.to_string())?;
+
+        let deadline = Instant::now() + APPLY_TIMEOUT;
+        while self.height() < Some(height) {
+            if Instant::now() > deadline {
+                return Err(format!("block {} not executed", height));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        Ok(height)
+    }
+
+    /// Applies the primary blocks missing locally, at most `MAX_BATCH`.
+    /// Returns `true` when in sync.
+    pub fn sync(&self) -> Result<bool, String> {
+        let height = self
+            .height()
+            .ok_or("genesis block not executed yet, bootstrap pending")?;
+        let (last, _) = primary_block(&self.primary, u64::MAX)?;
+        let primary_height = last.data.height;
+        {
+            let mut status = self.status.lock();
+            status.height = Some(height);
+            status.primary_height = Some(primary_height);
+            status.last_sync = Some(now());
+        }
+        let end = primary_height.min(height + MAX_BATCH);
+        let heights: Vec<u64> = (height + 1..=end).collect();
+        let (primary, threads) = (self.primary.as_str(), self.threads);
+        thread::scope(|scope| {
+            // The next window is prepared while the current one is executed.
+            let (sender, receiver) = mpsc::sync_channel(1);
+            scope.spawn(move || {
+                for window in heights.chunks(threads * BLOCKS_PER_THREAD) {
+                    let prepared =
+                        parallel_map(window, threads, |height| prepare(primary, *height));
+                    if sender.send(prepared).is_err() {
+                        break;
+                    }
+                }
+            });
+            for prepared in receiver {
+                for prepared in prepared? {
+                    let height = self.apply(prepared?)?;
+                    self.status.lock().height = Some(height);
+                }
+            }
+            Ok::<_, String>(())
+        })?;
+        Ok(end == primary_height)
+    }
+
+    pub fn status(&self) -> ReplicaStatus {
+        self.status.lock().clone()
+    }
+
+    /// Registers the replica routes within the node API.
+    pub fn routes(replica: Arc<Self>, router: &mut Router) {
+        router.add("GET", "/admin/replica", move |_: &Request| {
+            Response::json(&replica.status())
+        });
+    }
+}
+
+/// Tails the primary blocks.
+pub fn run(replica: Arc<Replica>) {
+    info!("[replica] following the primary node {}", replica.primary);
+    follow(&replica, "replica");
+}
+
+/// Catches up from the `catchup-peer` or bootstrap peer blocks, then keeps
+/// following them along with the P2P network.
+pub fn catch_up(peer: Arc<Replica>) {
+    info!(
+        "[catchup] fetching the missing blocks from {}",
+        peer.primary
+    );
+    follow(&peer, "catchup");
+}
+
+// Applies the source blocks until cancelled, polling it when in sync.
+fn follow(replica: &Replica, label: &str) {
+    loop {
+        let result = replica.sync();
+        let cancelled = match &result {
+            Ok(true) => !tasks::sleep(POLL_INTERVAL),
+            Ok(false) => tasks::cancelled(),
+            Err(err) => {
+                warn!("[{}] sync: {}", label, err);
+                !tasks::sleep(RETRY_INTERVAL)
+            }
+        };
+        replica.status.lock().last_error = result.err();
+        if cancelled {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+    };
+    use trinci_core::{base::serialize::rmp_serialize, BlockData};
+
+    #[test]
+    fn primary_block_request() {
+        let block = Block {
+            data: BlockData {
+                validator: None,
+                height: 7,
+                size: 1,
+                prev_hash: Hash::default(),
+                txs_hash: Hash::default(),
+                rxs_hash: Hash::default(),
+                state_hash: Hash::default(),
+                timestamp: 0,
+            },
+            signature: vec![],
+        };
+        let hashes = vec![Hash::default()];
+        let body = rmp_serialize(&Message::GetBlockResponse {
+            block: block.clone(),
+            txs: Some(hashes.clone()),
+            origin: None,
+        })
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let primary = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut len = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                match line.trim_end().split_once(": ") {
+                    Some(("Content-Length", value)) => len = value.parse().unwrap(),
+                    Some(_) => (),
+                    None => break,
+                }
+            }
+            reader.read_exact(&mut vec![0; len]).unwrap();
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(head.as_bytes()).unwrap();
+            stream.write_all(&body).unwrap();
+            request_line
+        });
+
+        let result = primary_block(&primary, 7).unwrap();
+
+        assert_eq!(result, (block, hashes));
+        assert!(server
+            .join()
+            .unwrap()
+            .starts_with("POST /api/v1/message HTTP/1.1"));
+    }
+
+    #[test]
+    fn parallel_map_keeps_order() {
+        let heights: Vec<u64> = (1..=10).collect();
+
+        let doubled = parallel_map(&heights, 3, |height| height * 2).unwrap();
+
+        assert_eq!(
+            doubled,
+            (1..=10).map(|height| height * 2).collect::<Vec<_>>()
+        );
+        assert!(parallel_map(&[] as &[u64], 3, |height| *height)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn parallel_map_panic() {
+        let heights: Vec<u64> = (1..=10).collect();
+
+        let result = parallel_map(&heights, 3, |height| match height {
+            5 => panic!("boom"),
+            height => *height,
+        });
+
+        assert_eq!(result.unwrap_err(), "replay thread panicked: boom");
+    }
+}
@@ -0,0 +1,352 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! P2P peers reputation.
+//!
+//! The peers messages refused by the blockchain service as invalid (blocks,
+//! transactions, malformed requests) lower the peer score, which recovers
+//! slowly over time. A peer whose score falls to the ban threshold is banned
+//! for a while: its messages are refused by the gateway.
+//!
+//! The core p2p service does not expose the requests sent to the peers,
+//! thus the response times of the peers can't be scored.
+//! The scores are persisted within the database.
+
+use crate::api::{Request, Response, Router};
+use crate::tasks;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use trinci_core::{
+    base::{
+        serialize::{rmp_deserialize, rmp_serialize},
+        RwLock,
+    },
+    blockchain::Message,
+    db::{Db, DbFork, RocksDb},
+    Error, ErrorKind,
+};
+
+/// Default score at which a peer is banned.
+pub const DEFAULT_BAN_SCORE: u64 = 100;
+
+/// Default ban duration in seconds.
+pub const DEFAULT_BAN_DURATION: u64 = 3600;
+
+/// Database configuration key of the scores.
+const DB_KEY: &str = "node:p2p:reputation";
+
+/// Score points recovered every hour.
+const RECOVERY_PER_HOUR: u64 = 10;
+
+/// Seconds between two saves of the scores.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Peer misbehavior.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offense {
+    InvalidBlock,
+    InvalidTransaction,
+    ProtocolError,
+    /// Block conflicting with a trusted checkpoint, banned at once.
+    CheckpointConflict,
+}
+
+impl Offense {
+    /// Score points lost, `ban_score` being the ban threshold.
+    fn penalty(&self, ban_score: u64) -> u64 {
+        match self {
+            Offense::InvalidBlock => 50,
+            Offense::InvalidTransaction => 10,
+            Offense::ProtocolError => 20,
+            Offense::CheckpointConflict => ban_score.max(1),
+        }
+    }
+}
+
+impl fmt::Display for Offense {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Offense::InvalidBlock => "invalid-block",
+            Offense::InvalidTransaction => "invalid-transaction",
+            Offense::ProtocolError => "protocol-error",
+            Offense::CheckpointConflict => "checkpoint-conflict",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Whether a refusal of the blockchain service is the peer fault.
+pub(crate) fn is_peer_fault(err: &Error) -> bool {
+    matches!(
+        err.kind,
+        ErrorKind::MalformedData | ErrorKind::InvalidSignature | ErrorKind::BadNetwork
+    )
+}
+
+/// Offense of a peer whose message is refused as invalid.
+pub(crate) fn offense(req: &Message) -> Option<Offense> {
+    match req {
+        Message::GetBlockResponse { .. } => Some(Offense::InvalidBlock),
+        Message::GetTransactionResponse { .. } => Some(Offense::InvalidTransaction),
+        Message::GetBlockRequest { .. } | Message::GetTransactionRequest { .. } => {
+            Some(Offense::ProtocolError)
+        }
+        _ => None,
+    }
+}
+
+/// Ban thresholds.
+pub struct ReputationConfig {
+    /// Score lost at which a peer is banned, 0 to never ban.
+    pub ban_score: u64,
+    /// Ban duration in seconds.
+    pub ban_duration: u64,
+}
+
+/// Peer reputation, as reported by the node API.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerRecord {
+    /// Score points lost, zero for a well behaving peer.
+    pub penalty: u64,
+    /// Offenses count by kind.
+    pub offenses: BTreeMap<String, u64>,
+    /// Time of the last score change, seconds since the epoch.
+    pub updated: u64,
+    /// Ban expiration time, seconds since the epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub banned_until: Option<u64>,
+}
+
+impl PeerRecord {
+    // Recovers the score points earned since the last change.
+    fn recover(&mut self, now: u64) {
+        let recovered = now.saturating_sub(self.updated) * RECOVERY_PER_HOUR / 3600;
+        if recovered > 0 {
+            self.penalty = self.penalty.saturating_sub(recovered);
+            self.updated = now;
+        }
+    }
+
+    fn is_banned(&self, now: u64) -> bool {
+        self.banned_until.map(|until| until > now).unwrap_or(false)
+    }
+}
+
+/// Peers scores, shared by the gateway taps and the node API.
+pub struct Reputation {
+    config: ReputationConfig,
+    peers: RwLock<BTreeMap<String, PeerRecord>>,
+    db: Option<Arc<RwLock<RocksDb>>>,
+    /// Scores changed since the last save.
+    dirty: AtomicBool,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Reputation {
+    pub fn new(config: ReputationConfig) -> Self {
+        Reputation {
+            config,
+            peers: RwLock::new(BTreeMap::new()),
+            db: None,
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Persists the scores in the database, the stored ones are loaded.
+    pub fn with_db(mut self, db: Arc<RwLock<RocksDb>>) -> Self {
+        let stored = db
+            .read()
+            .load_configuration(DB_KEY)
+            .and_then(|buf| rmp_deserialize::<BTreeMap<String, PeerRecord>>(&buf).ok());
+        if let Some(peers) = stored {
+            *self.peers.write() = peers;
+        }
+        self.db = Some(db);
+        self
+    }
+
+    /// Records a peer offense, returns `true` if the peer gets banned.
+    pub fn report(&self, peer: &str, offense: Offense) -> bool {
+        self.report_at(peer, offense, now())
+    }
+
+    fn report_at(&self, peer: &str, offense: Offense, now: u64) -> bool {
+        let mut peers = self.peers.write();
+        let record = peers.entry(peer.to_string()).or_insert_with(|| PeerRecord {
+            updated: now,
+            ..Default::default()
+        });
+        record.recover(now);
+        record.penalty += offense.penalty(self.config.ban_score);
+        record.updated = now;
+        *record.offenses.entry(offense.to_string()).or_insert(0) += 1;
+        self.dirty.store(true, Ordering::Relaxed);
+        debug!(
+            "[p2p] peer {} {}, penalty {}",
+            peer, offense, record.penalty
+        );
+        if self.config.ban_score == 0
+            || record.penalty < self.config.ban_score
+            || record.is_banned(now)
+        {
+            return false;
+        }
+        record.banned_until = Some(now + self.config.ban_duration);
+        warn!(
+            "[p2p] peer {} banned for {} seconds, penalty {}",
+            peer, self.config.ban_duration, record.penalty
+        );
+        true
+    }
+
+    pub fn is_banned(&self, peer: &str) -> bool {
+        let now = now();
+        self.peers
+            .read()
+            .get(peer)
+            .map(|record| record.is_banned(now))
+            .unwrap_or(false)
+    }
+
+    /// Checks a p2p message, returns the banned peer.
+    pub fn check<'a>(&self, msg: &'a Message) -> Option<&'a str> {
+        crate::peers::message_peer(msg).filter(|peer| self.is_banned(peer))
+    }
+
+    /// Peers reputation, scores recovered up to now.
+    pub fn table(&self) -> BTreeMap<String, PeerRecord> {
+        let now = now();
+        let mut peers = self.peers.read().clone();
+        for record in peers.values_mut() {
+            record.recover(now);
+        }
+        peers
+    }
+
+    /// Forgets a peer, lifting its ban. Returns `false` if not found.
+    pub fn forget(&self, peer: &str) -> bool {
+        let found = self.peers.write().remove(peer).is_some();
+        if found {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        found
+    }
+
+    /// Writes the scores to the database, when changed. The peers back to a
+    /// clean record are dropped.
+    pub fn save(&self) {
+        let db = match &self.db {
+            Some(db) if self.dirty.swap(false, Ordering::Relaxed) => db,
+            _ => return,
+        };
+        let now = now();
+        let peers: BTreeMap<String, PeerRecord> = self
+            .table()
+            .into_iter()
+            .filter(|(_, record)| record.penalty > 0 || record.is_banned(now))
+            .collect();
+        match rmp_serialize(&peers) {
+            Ok(buf) => {
+                let mut fork = db.write().fork_create();
+                fork.store_configuration(DB_KEY, buf);
+                if let Err(err) = db.write().fork_merge(fork) {
+                    warn!("[p2p] error saving the peers reputation: {}", err);
+                }
+            }
+            Err(err) => warn!("[p2p] error saving the peers reputation: {}", err),
+        }
+    }
+
+    /// Registers the reputation routes within the node API.
+    pub fn routes(reputation: Arc<Self>, router: &mut Router) {
+        let table = reputation.clone();
+        router.add("GET", "/admin/p2p/peers/reputation", move |_: &Request| {
+            Response::json(&table.table())
+        });
+        router.add(
+            "DELETE",
+            "/admin/p2p/peers/reputation/:id",
+            move |req: &Request| {
+                let id = req.param::<String>("id").unwrap_or_default();
+                if !reputation.forget(&id) {
+                    return Response::error(404, "Not Found");
+                }
+                info!("[p2p] peer {} reputation reset", id);
+                Response::ok()
+            },
+        );
+    }
+}
+
+/// Saves the scores every `SAVE_INTERVAL`.
+pub fn run(reputation: Arc<Reputation>) {
+    while tasks::sleep(SAVE_INTERVAL) {
+        reputation.save();
+    }
+    reputation.save();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn score_and_ban() {
+        let reputation = Reputation::new(ReputationConfig {
+            ban_score: 100,
+            ban_duration: 600,
+        });
+        let start = now();
+
+        assert!(!reputation.report_at("peer", Offense::InvalidBlock, start));
+        assert!(!reputation.report_at("peer", Offense::ProtocolError, start));
+        // Recovered 10 points in an hour.
+        assert!(!reputation.report_at("peer", Offense::ProtocolError, start + 3600));
+        assert!(reputation.report_at("peer", Offense::InvalidBlock, start + 3600));
+        assert!(reputation.is_banned("peer"));
+        assert!(!reputation.is_banned("other"));
+
+        let record = &reputation.table()["peer"];
+        assert_eq!(record.offenses["invalid-block"], 2);
+        assert_eq!(record.penalty, 130);
+        assert!(reputation.forget("peer"));
+        assert!(!reputation.is_banned("peer"));
+
+        let err = Error::new(ErrorKind::InvalidSignature);
+        let req = Message::GetTransactionRequest {
+            hash: Default::default(),
+            destination: Some("peer".to_string()),
+        };
+        assert_eq!(offense(&req), Some(Offense::ProtocolError));
+        assert!(is_peer_fault(&err));
+        assert!(!is_peer_fault(&Error::new(ErrorKind::ResourceNotFound)));
+    }
+}
@@ -0,0 +1,289 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Node resources guard.
+//!
+//! Samples the process resident memory, the open file descriptors and the
+//! free space of the database disk. Above the thresholds the transactions
+//! admission is throttled; when the disk is about to fill up the blockchain
+//! service is stopped, since RocksDB corrupts its files on a full disk, and
+//! restarted once enough space is freed.
+
+use crate::api::{Request, Response, Router};
+use serde::Serialize;
+#[cfg(unix)]
+use std::{ffi::CString, os::unix::ffi::OsStrExt};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use trinci_core::base::{Mutex, RwLock};
+
+/// Interval between two samples.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Transactions accepted per second while throttled.
+const THROTTLED_RATE: usize = 10;
+
+/// Resources thresholds, the zero ones are disabled.
+#[derive(Debug, Clone, Default)]
+pub struct ResourceConfig {
+    /// Resident memory in MiB.
+    pub max_memory: u64,
+    /// Open file descriptors, percent of the process limit.
+    pub max_open_files: u64,
+    /// Free disk space in MiB throttling the transactions.
+    pub min_disk_free: u64,
+    /// Free disk space in MiB stopping the database writes.
+    pub critical_disk_free: u64,
+}
+
+/// Last sample, via node API.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Usage {
+    /// Resident memory in MiB.
+    pub memory: Option<u64>,
+    pub open_files: Option<u64>,
+    /// Open file descriptors limit of the process.
+    pub max_open_files: Option<u64>,
+    /// Free space of the database disk in MiB.
+    pub disk_free: Option<u64>,
+    /// Transactions admission throttled.
+    pub throttled: bool,
+    /// Blockchain service stopped to protect the database.
+    pub writes_blocked: bool,
+}
+
+/// Thresholds crossed by a sample.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Conditions {
+    memory: bool,
+    open_files: bool,
+    disk_low: bool,
+    disk_full: bool,
+}
+
+// Resident memory in MiB (Linux only).
+fn memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb / 1024)
+}
+
+fn open_files() -> Option<u64> {
+    let dir = ["/proc/self/fd", "/dev/fd"]
+        .into_iter()
+        .find_map(|dir| fs::read_dir(dir).ok())?;
+    Some(dir.count() as u64)
+}
+
+#[cfg(unix)]
+fn max_open_files() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid pointer for the call duration.
+    let res = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+    // The field type depends on the platform.
+    #[allow(clippy::unnecessary_cast)]
+    let max = limit.rlim_cur as u64;
+    (res == 0 && limit.rlim_cur != libc::RLIM_INFINITY).then_some(max)
+}
+
+#[cfg(not(unix))]
+fn max_open_files() -> Option<u64> {
+    None
+}
+
+// Free space in MiB of the disk holding the path, for unprivileged users.
+#[cfg(unix)]
+fn disk_free(path: &Path) -> Option<u64> {
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    // SAFETY: all zeroes is a valid `statvfs`, filled by the call.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is nul terminated and `stat` valid for the call duration.
+    let res = unsafe { libc::statvfs(path.as_ptr(), &mut stat) };
+    // The field types depend on the platform.
+    #[allow(clippy::unnecessary_cast)]
+    let free = stat.f_bavail as u64 * stat.f_frsize as u64;
+    (res == 0).then_some(free / (1024 * 1024))
+}
+
+#[cfg(not(unix))]
+fn disk_free(_path: &Path) -> Option<u64> {
+    None
+}
+
+fn evaluate(config: &ResourceConfig, usage: &Usage, writes_blocked: bool) -> Conditions {
+    let above = |value: Option<u64>, limit: u64| limit != 0 && value.is_some_and(|v| v >= limit);
+    let below = |value: Option<u64>, limit: u64| limit != 0 && value.is_some_and(|v| v < limit);
+    // Writes are resumed with the disk out of the low space zone, to avoid
+    // flapping around the critical threshold.
+    let resume = config.critical_disk_free.max(config.min_disk_free);
+    Conditions {
+        memory: above(usage.memory, config.max_memory),
+        open_files: match usage.max_open_files {
+            Some(max) => above(usage.open_files, max * config.max_open_files / 100),
+            None => false,
+        },
+        disk_low: below(usage.disk_free, config.min_disk_free),
+        disk_full: below(usage.disk_free, config.critical_disk_free)
+            || (writes_blocked && below(usage.disk_free, resume)),
+    }
+}
+
+pub struct ResourceGuard {
+    config: ResourceConfig,
+    db_path: PathBuf,
+    usage: RwLock<Usage>,
+    throttled: AtomicBool,
+    writes_blocked: AtomicBool,
+    /// Transactions admitted in the current second while throttled.
+    window: Mutex<(Instant, usize)>,
+}
+
+impl ResourceGuard {
+    pub fn new(config: ResourceConfig, db_path: &str) -> Self {
+        ResourceGuard {
+            config,
+            db_path: PathBuf::from(db_path),
+            usage: RwLock::new(Usage::default()),
+            throttled: AtomicBool::new(false),
+            writes_blocked: AtomicBool::new(false),
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    /// Database writes must be stopped.
+    pub fn writes_blocked(&self) -> bool {
+        self.writes_blocked.load(Ordering::Relaxed)
+    }
+
+    /// Whether a new transaction can be admitted, while throttled up to
+    /// `THROTTLED_RATE` per second.
+    pub fn admit_tx(&self) -> bool {
+        if !self.throttled.load(Ordering::Relaxed) {
+            return true;
+        }
+        let mut window = self.window.lock();
+        if window.0.elapsed() >= Duration::from_secs(1) {
+            *window = (Instant::now(), 0);
+        }
+        window.1 += 1;
+        window.1 <= THROTTLED_RATE
+    }
+
+    /// Samples the resources and updates the guard state, returns the
+    /// alert conditions: kind, raised and message.
+    pub fn check(&self) -> Vec<(&'static str, bool, String)> {
+        let mut usage = Usage {
+            memory: memory(),
+            open_files: open_files(),
+            max_open_files: max_open_files(),
+            disk_free: disk_free(&self.db_path),
+            ..Default::default()
+        };
+        let conditions = evaluate(&self.config, &usage, self.writes_blocked());
+        usage.throttled = conditions.memory || conditions.open_files || conditions.disk_low;
+        usage.writes_blocked = conditions.disk_full;
+        self.throttled.store(usage.throttled, Ordering::Relaxed);
+        self.writes_blocked
+            .store(usage.writes_blocked, Ordering::Relaxed);
+
+        let alerts = vec![
+            (
+                "memory",
+                conditions.memory,
+                format!("resident memory {} MiB", usage.memory.unwrap_or_default()),
+            ),
+            (
+                "open_files",
+                conditions.open_files,
+                format!(
+                    "{} open files of {}",
+                    usage.open_files.unwrap_or_default(),
+                    usage.max_open_files.unwrap_or_default()
+                ),
+            ),
+            (
+                "disk_space",
+                conditions.disk_low || conditions.disk_full,
+                format!(
+                    "{} MiB free on the database disk{}",
+                    usage.disk_free.unwrap_or_default(),
+                    if conditions.disk_full {
+                        ", blockchain service stopped"
+                    } else {
+                        ""
+                    }
+                ),
+            ),
+        ];
+        *self.usage.write() = usage;
+        alerts
+    }
+
+    /// Registers the resources route within the node API.
+    pub fn routes(guard: Arc<Self>, router: &mut Router) {
+        router.add("GET", "/admin/node/resources", move |_: &Request| {
+            Response::json(&*guard.usage.read())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thresholds() {
+        let config = ResourceConfig {
+            max_memory: 1024,
+            max_open_files: 90,
+            min_disk_free: 1024,
+            critical_disk_free: 256,
+        };
+        let usage = |memory, open_files, disk_free| Usage {
+            memory: Some(memory),
+            open_files: Some(open_files),
+            max_open_files: Some(1000),
+            disk_free: Some(disk_free),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            evaluate(&config, &usage(100, 100, 4096), false),
+            Conditions::default()
+        );
+        let conditions = evaluate(&config, &usage(2048, 950, 100), false);
+        assert!(conditions.memory && conditions.open_files && conditions.disk_full);
+        // Writes resumed out of the low space zone only.
+        assert!(evaluate(&config, &usage(100, 100, 512), true).disk_full);
+        assert!(!evaluate(&config, &usage(100, 100, 512), false).disk_full);
+        assert!(!evaluate(&config, &usage(100, 100, 2048), true).disk_full);
+        // Disabled thresholds.
+        let conditions = evaluate(&ResourceConfig::default(), &usage(2048, 950, 0), true);
+        assert_eq!(conditions, Conditions::default());
+    }
+}
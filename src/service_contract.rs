@@ -0,0 +1,354 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Service contract hot upgrade.
+//!
+//! The service (bootstrap) contract is replaced either on-chain, by a
+//! governance transaction updating the service account, or locally through
+//! the node API. In both cases the new contract is pinned in the wasm machine
+//! cache in place of the old one and the validator check is set again,
+//! without restarting the node.
+//!
+//! The local replacement bypasses the consensus: the node state diverges from
+//! the other nodes unless all of them apply the same binary at the same
+//! height, it is meant for private and test networks.
+//!
+//! The burning fuel method of the blockchain settings is followed as well: a
+//! new method is called first on a fork of the state, dropped afterwards, and
+//! only switched to if the service contract exposes it. Otherwise the old
+//! method is kept and the rejection raises the `burn_method` alert until the
+//! settings or the contract are fixed. The choice is not persisted, a restart
+//! applies the settings method.
+
+use crate::api::{Request, Response, Router};
+use crate::app::ValidatorConfig;
+use crate::config::SERVICE_ACCOUNT_ID;
+use crate::tasks;
+use crate::wm_cache::{NodeWm, WmCache, WmPool};
+use serde::Serialize;
+use serde_json::json;
+use std::{fs, sync::Arc, time::Duration};
+use trinci_core::{
+    base::{
+        serialize::{rmp_deserialize, rmp_serialize},
+        BlockchainSettings, Mutex,
+    },
+    blockchain::BlockService,
+    crypto::{drand::SeedSource, Hash, HashAlgorithm},
+    db::{Db, DbFork, RocksDb},
+    Error, ErrorKind,
+};
+
+/// Seconds between two checks of the on-chain service contract.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Arguments of the burning fuel method.
+#[derive(Serialize)]
+pub(crate) struct BurnFuelArgs<'a> {
+    pub account: &'a str,
+    pub fuel_to_burn: u64,
+    pub fuel_limit: u64,
+}
+
+/// Burning fuel method state.
+#[derive(Default)]
+struct BurnMethod {
+    /// Method in use by the blockchain service.
+    active: String,
+    /// Method of the settings refused by the last check, with the reason.
+    rejected: Option<(String, String)>,
+}
+
+/// Whether the call error means the contract does not expose the method.
+/// Any other error comes from the method itself, refusing the arguments.
+fn is_missing_method(err: &Error) -> bool {
+    matches!(
+        err.kind,
+        ErrorKind::ResourceNotFound | ErrorKind::WasmMachineFault
+    )
+}
+
+pub struct ServiceContract {
+    block_svc: Arc<Mutex<BlockService<RocksDb, NodeWm>>>,
+    wm_cache: Arc<WmCache>,
+    wm_pool: Arc<WmPool>,
+    seed: Arc<SeedSource>,
+    validators: ValidatorConfig,
+    current: Mutex<Option<Hash>>,
+    burn_method: Mutex<BurnMethod>,
+}
+
+impl ServiceContract {
+    pub fn new(
+        block_svc: Arc<Mutex<BlockService<RocksDb, NodeWm>>>,
+        wm_cache: Arc<WmCache>,
+        wm_pool: Arc<WmPool>,
+        seed: Arc<SeedSource>,
+        validators: ValidatorConfig,
+    ) -> Self {
+        let service = ServiceContract {
+            block_svc,
+            wm_cache,
+            wm_pool,
+            seed,
+            validators,
+            current: Mutex::new(None),
+            burn_method: Mutex::new(BurnMethod::default()),
+        };
+        *service.current.lock() = service.load();
+        service
+    }
+
+    // Blockchain settings stored in the database.
+    fn settings(&self) -> Option<BlockchainSettings> {
+        let db = self.block_svc.lock().db_arc();
+        let buf = db.read().load_configuration("blockchain:settings")?;
+        rmp_deserialize(&buf).ok()
+    }
+
+    // Service contract stored in the database.
+    fn load(&self) -> Option<Hash> {
+        let db = self.block_svc.lock().db_arc();
+        let account = db.read().load_account(SERVICE_ACCOUNT_ID);
+        account.and_then(|account| account.contract)
+    }
+
+    /// Active service contract.
+    pub fn current(&self) -> Option<Hash> {
+        *self.current.lock()
+    }
+
+    // Pins the new contract in place of the old one and sets the validator
+    // check again.
+    fn activate(&self, hash: Hash) {
+        if let Some(old) = self.current.lock().replace(hash) {
+            if old != hash {
+                self.wm_cache.unpin(&old);
+            }
+        }
+        self.wm_cache.pin(hash);
+
+        let mut block_svc = self.block_svc.lock();
+        let is_validator = self.validators.is_validator_function(
+            self.wm_pool.clone(),
+            block_svc.db_arc(),
+            self.seed.clone(),
+        );
+        block_svc.stop();
+        block_svc.set_validator(is_validator);
+        block_svc.start();
+        info!("[service] contract {} active", hex::encode(hash));
+    }
+
+    /// Replaces the service contract with the given wasm binary, returns the
+    /// new contract hash.
+    pub fn replace(&self, bin: Vec<u8>) -> Result<Hash, String> {
+        let db = self.block_svc.lock().db_arc();
+        let mut fork = db.write().fork_create();
+        let mut account = fork
+            .load_account(SERVICE_ACCOUNT_ID)
+            .ok_or("service account not found")?;
+        let hash = Hash::from_data(HashAlgorithm::Sha256, &bin);
+        account.contract = Some(hash);
+        fork.store_account(account);
+        let key = format!("contracts:code:{}", hex::encode(hash));
+        fork.store_account_data(SERVICE_ACCOUNT_ID, &key, bin);
+        db.write().fork_merge(fork).map_err(|err| err.to_string())?;
+        warn!(
+            "[service] contract locally replaced with {}, the state diverges from the nodes not applying it",
+            hex::encode(hash)
+        );
+        self.activate(hash);
+        Ok(hash)
+    }
+
+    /// Activates the service contract set on-chain, returns `true` if it
+    /// changed since the last check.
+    pub fn refresh(&self) -> bool {
+        let hash = match self.load() {
+            Some(hash) => hash,
+            None => return false,
+        };
+        let mut current = self.current.lock();
+        match *current {
+            Some(old) if old == hash => false,
+            Some(_) => {
+                drop(current);
+                info!("[service] contract updated on-chain");
+                self.activate(hash);
+                true
+            }
+            // Service account created by the bootstrap, already set up.
+            None => {
+                *current = Some(hash);
+                false
+            }
+        }
+    }
+
+    // Calls the burning fuel method on a fork of the state, never merged.
+    fn dry_run_burn(&self, method: &str, network: &str) -> Result<(), String> {
+        let db = self.block_svc.lock().db_arc();
+        let mut fork = db.write().fork_create();
+        let contract = fork
+            .load_account(SERVICE_ACCOUNT_ID)
+            .and_then(|account| account.contract)
+            .ok_or("service contract not found")?;
+        let calls = &self.validators.calls;
+        let args = rmp_serialize(&BurnFuelArgs {
+            account: &calls.origin,
+            fuel_to_burn: 0,
+            fuel_limit: 0,
+        })
+        .map_err(|err| err.to_string())?;
+        let (burned_fuel, res) = self.wm_pool.with(|wm| {
+            wm.call(
+                &mut fork,
+                calls.depth,
+                network,
+                &calls.origin,
+                SERVICE_ACCOUNT_ID,
+                &calls.origin,
+                contract,
+                method,
+                &args,
+                self.seed.clone(),
+                &mut Vec::new(),
+                #[cfg(feature = "indexer")]
+                &mut Vec::new(),
+                calls.fuel,
+                0,
+            )
+        });
+        calls.account(burned_fuel, res.is_err());
+        match res {
+            Err(err) if is_missing_method(&err) => Err(err.to_string_full()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Follows the burning fuel method of the blockchain settings, switching to
+    /// a new one only if the service contract exposes it.
+    /// Returns `true` if the method changed since the last check.
+    pub fn refresh_burn_method(&self) -> bool {
+        let settings = match self.settings() {
+            Some(settings) => settings,
+            None => return false,
+        };
+        let method = settings.burning_fuel_method;
+        {
+            let burn_method = self.burn_method.lock();
+            let rejected = burn_method.rejected.as_ref().map(|(method, _)| method);
+            if method == burn_method.active || rejected == Some(&method) {
+                return false;
+            }
+        }
+        let network = settings.network_name.unwrap_or_default();
+        // Empty when the burning is disabled.
+        let res = if method.is_empty() {
+            Ok(())
+        } else {
+            self.dry_run_burn(&method, &network)
+        };
+        let mut burn_method = self.burn_method.lock();
+        match res {
+            Ok(()) => {
+                let mut block_svc = self.block_svc.lock();
+                block_svc.stop();
+                block_svc.set_burn_fuel_method(method.clone());
+                block_svc.start();
+                info!("[service] burning fuel method `{}` active", method);
+                burn_method.active = method;
+                burn_method.rejected = None;
+                true
+            }
+            Err(err) => {
+                error!(
+                    "[service] burning fuel method `{}` not exposed by the service contract ({}), keeping `{}`",
+                    method, err, burn_method.active
+                );
+                burn_method.rejected = Some((method, err));
+                false
+            }
+        }
+    }
+
+    /// Sets the burning fuel method the blockchain service started with.
+    pub fn set_burn_method(&self, method: String) {
+        *self.burn_method.lock() = BurnMethod {
+            active: method,
+            rejected: None,
+        };
+    }
+
+    /// Burning fuel method refused by the last check, with the reason.
+    pub fn rejected_burn_method(&self) -> Option<(String, String)> {
+        self.burn_method.lock().rejected.clone()
+    }
+
+    /// Registers the service contract routes within the node API.
+    pub fn routes(service: Arc<Self>, router: &mut Router) {
+        let contract = service.clone();
+        router.add("GET", "/admin/service/contract", move |_: &Request| {
+            let burn_method = contract.burn_method.lock();
+            Response::json(&json!({
+                "hash": contract.current().map(hex::encode),
+                "burn_method": burn_method.active,
+                "rejected_burn_method": burn_method.rejected.as_ref().map(|(method, _)| method),
+            }))
+        });
+        // The binary is read from the node file system, it may exceed the
+        // request body limit.
+        router.add("POST", "/admin/service/contract", move |req: &Request| {
+            let path = match req.query::<String>("path") {
+                Some(path) => path,
+                None => return Response::error(400, "missing `path`"),
+            };
+            let bin = match fs::read(&path) {
+                Ok(bin) => bin,
+                Err(err) => return Response::error(400, format!("{}: {}", path, err)),
+            };
+            match service.replace(bin) {
+                Ok(hash) => Response::json(&json!({ "hash": hex::encode(hash) })),
+                Err(err) => Response::error(500, err),
+            }
+        });
+    }
+}
+
+/// Follows the on-chain service contract updates.
+pub fn run(service: Arc<ServiceContract>) {
+    while tasks::sleep(CHECK_INTERVAL) {
+        service.refresh();
+        service.refresh_burn_method();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_method_errors() {
+        assert!(is_missing_method(&Error::new(ErrorKind::ResourceNotFound)));
+        assert!(is_missing_method(&Error::new(ErrorKind::WasmMachineFault)));
+        assert!(!is_missing_method(&Error::new(
+            ErrorKind::SmartContractFault
+        )));
+        assert!(!is_missing_method(&Error::new(ErrorKind::FuelError)));
+    }
+}
@@ -0,0 +1,232 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Light client snapshots.
+//!
+//! Block headers and data snapshots for light clients (mobile wallets). A
+//! header carries the msgpack encoded block data, whose hash is the block
+//! hash, and the validator signature; consecutive headers are chained by
+//! `prev_hash`, so the headers can be verified.
+//!
+//! The snapshots are not proofs: the core does not expose the Merkle paths of
+//! the block lists and of the state tree. A transaction snapshot carries the
+//! whole lists the block `txs_hash` and `rxs_hash` commit to, in block order,
+//! with the index of the item, checking them against the block requires the
+//! core Merkle tree construction. An account snapshot is the account record
+//! read together with the last block header, it can't be verified against the
+//! block `state_hash`: the client trusts the node for it.
+
+use crate::api::{Request, Response, Router};
+use crate::explorer::{page_size, BlockItem, Page};
+use serde::Serialize;
+use std::sync::Arc;
+use trinci_core::{
+    base::{serialize::rmp_serialize, RwLock},
+    crypto::Hashable,
+    db::{Db, RocksDb},
+    Block, Hash,
+};
+
+/// Block header.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Header {
+    /// Decoded block data.
+    #[serde(flatten)]
+    pub block: BlockItem,
+    /// Msgpack encoded block data, hex.
+    pub data: String,
+    /// Validator signature of the block data, hex.
+    pub signature: String,
+}
+
+impl From<&Block> for Header {
+    fn from(block: &Block) -> Self {
+        Header {
+            block: BlockItem::from(block),
+            data: hex::encode(rmp_serialize(&block.data).unwrap_or_default()),
+            signature: hex::encode(&block.signature),
+        }
+    }
+}
+
+/// Item within a block list, with the whole list.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ListSnapshot {
+    /// Item position.
+    pub index: usize,
+    /// Hashes of the list items, hex.
+    pub leaves: Vec<String>,
+}
+
+impl ListSnapshot {
+    fn new(leaves: &[Hash], index: usize) -> Self {
+        ListSnapshot {
+            index,
+            leaves: leaves
+                .iter()
+                .map(|hash| hex::encode(hash.as_bytes()))
+                .collect(),
+        }
+    }
+}
+
+/// Transaction and receipt with the block lists they are committed in.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TxSnapshot {
+    pub header: Header,
+    /// Msgpack encoded transaction, hex.
+    pub tx: String,
+    /// Transactions list of the block `txs_hash`.
+    pub tx_list: ListSnapshot,
+    /// Msgpack encoded receipt, hex.
+    pub receipt: String,
+    /// Receipts list of the block `rxs_hash`.
+    pub receipt_list: ListSnapshot,
+}
+
+/// Account record read at the last block, not verifiable.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct AccountSnapshot {
+    pub header: Header,
+    /// Msgpack encoded account, hex.
+    pub account: String,
+}
+
+pub struct SnapshotServer {
+    db: Arc<RwLock<RocksDb>>,
+}
+
+impl SnapshotServer {
+    pub fn new(db: Arc<RwLock<RocksDb>>) -> Self {
+        SnapshotServer { db }
+    }
+
+    /// Headers from height `from`, ascending, at most `limit` of them.
+    pub fn headers(&self, from: u64, limit: usize) -> Page<Header> {
+        let db = self.db.read();
+        let last = match db.load_block(u64::MAX) {
+            Some(block) => block.data.height,
+            None => return Page::default(),
+        };
+        if from > last {
+            return Page::default();
+        }
+        let end = last.min(from.saturating_add(limit.max(1) as u64 - 1));
+        Page {
+            items: (from..=end)
+                .filter_map(|height| db.load_block(height))
+                .map(|block| Header::from(&block))
+                .collect(),
+            next: (end < last).then_some(end + 1),
+        }
+    }
+
+    /// Executed transaction with its block lists, `None` if the transaction
+    /// or its receipt is not stored.
+    pub fn tx(&self, hash: &Hash) -> Option<TxSnapshot> {
+        let db = self.db.read();
+        let tx = db.load_transaction(hash)?;
+        let receipt = db.load_receipt(hash)?;
+        let block = db.load_block(receipt.height)?;
+        let hashes = db.load_transactions_hashes(receipt.height)?;
+        let index = hashes.iter().position(|item| item == hash)?;
+        let receipts = hashes
+            .iter()
+            .map(|hash| db.load_receipt(hash).map(|rx| rx.primary_hash()))
+            .collect::<Option<Vec<_>>>()?;
+        Some(TxSnapshot {
+            header: Header::from(&block),
+            tx: hex::encode(rmp_serialize(&tx).ok()?),
+            tx_list: ListSnapshot::new(&hashes, index),
+            receipt: hex::encode(rmp_serialize(&receipt).ok()?),
+            receipt_list: ListSnapshot::new(&receipts, index),
+        })
+    }
+
+    /// Account record with the last block header, `None` if not found.
+    pub fn account(&self, id: &str) -> Option<AccountSnapshot> {
+        let db = self.db.read();
+        let account = db.load_account(id)?;
+        let block = db.load_block(u64::MAX)?;
+        Some(AccountSnapshot {
+            header: Header::from(&block),
+            account: hex::encode(rmp_serialize(&account).ok()?),
+        })
+    }
+
+    /// Registers the light client routes within the node API.
+    pub fn routes(server: Arc<Self>, router: &mut Router) {
+        let snapshots = server.clone();
+        router.add("GET", "/api/v1/light/headers", move |req: &Request| {
+            let from = req.query::<u64>("from").unwrap_or(0);
+            Response::negotiate(req, &snapshots.headers(from, page_size(req)))
+        });
+        let snapshots = server.clone();
+        router.add("GET", "/api/v1/light/tx/:hash", move |req: &Request| {
+            let hash = match req.params.get("hash").map(|hash| Hash::from_hex(hash)) {
+                Some(Ok(hash)) => hash,
+                _ => return Response::error(400, "invalid transaction hash"),
+            };
+            match snapshots.tx(&hash) {
+                Some(snapshot) => Response::negotiate(req, &snapshot),
+                None => Response::error(404, "Not Found"),
+            }
+        });
+        router.add("GET", "/api/v1/light/account/:id", move |req: &Request| {
+            let id = req.params.get("id").cloned().unwrap_or_default();
+            match server.account(&id) {
+                Some(snapshot) => Response::negotiate(req, &snapshot),
+                None => Response::error(404, "Not Found"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trinci_core::{BlockData, HashAlgorithm};
+
+    #[test]
+    fn header_encoding() {
+        let block = Block {
+            data: BlockData {
+                validator: None,
+                height: 3,
+                size: 1,
+                prev_hash: Hash::from_data(HashAlgorithm::Sha256, b"prev"),
+                txs_hash: Hash::default(),
+                rxs_hash: Hash::default(),
+                state_hash: Hash::default(),
+                timestamp: 0,
+            },
+            signature: vec![1, 2],
+        };
+        let header = Header::from(&block);
+
+        assert_eq!(header.block.height, 3);
+        assert_eq!(header.signature, "0102");
+        assert_eq!(
+            hex::decode(&header.data).unwrap(),
+            rmp_serialize(&block.data).unwrap()
+        );
+
+        let leaves = [Hash::default(), block.data.prev_hash];
+        let list = ListSnapshot::new(&leaves, 1);
+        assert_eq!(list.leaves[1], hex::encode(block.data.prev_hash.as_bytes()));
+    }
+}
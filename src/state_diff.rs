@@ -0,0 +1,216 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Accounts state diff.
+//!
+//! For each new block, reports the accounts touched and their changed data
+//! keys, for external indexers maintaining materialized views.
+//! The core does not expose the block execution fork, so the touched accounts
+//! are derived from the block transactions (targets, bulk nodes, callers) and
+//! the contract events emitters, while the changed keys come from a comparison
+//! with the last known snapshot of the account data. Accounts modified by
+//! nested contract calls without emitting events are only reported the next
+//! time they are touched, their diff then spans all the changes since.
+
+use crate::denylist;
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    sync::Arc,
+};
+use trinci_core::{
+    base::RwLock,
+    crypto::HashAlgorithm,
+    db::{Db, RocksDb},
+    Account, Hash,
+};
+
+/// Max number of accounts snapshots kept in memory.
+const MAX_SNAPSHOTS: usize = 1024;
+
+/// Account changes within a block.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AccountDiff {
+    pub account: String,
+    /// Added or updated data keys, not known if the account was never seen
+    /// before: the whole account shall be reloaded.
+    pub keys: Option<Vec<String>>,
+    /// Removed data keys.
+    pub removed: Vec<String>,
+    /// Assets or contract changed.
+    pub assets: bool,
+}
+
+/// Accounts touched by the block transactions: targets, callers and
+/// contract events emitters.
+pub(crate) fn block_accounts(db: &RocksDb, txs: &[Hash]) -> BTreeSet<String> {
+    let mut accounts = BTreeSet::new();
+    for hash in txs {
+        if let Some(tx) = db.load_transaction(hash) {
+            accounts.insert(tx.get_caller().to_account_id());
+            for (account, _) in denylist::targets(&tx) {
+                accounts.insert(account.to_owned());
+            }
+        }
+        let events = db.load_receipt(hash).and_then(|rx| rx.events);
+        for event in events.unwrap_or_default() {
+            accounts.insert(event.emitter_account);
+        }
+    }
+    accounts
+}
+
+struct Snapshot {
+    account: Account,
+    /// Data values hashes.
+    data: HashMap<String, Hash>,
+}
+
+/// Added or updated keys and removed keys between two data snapshots.
+fn diff_keys(
+    old: &HashMap<String, Hash>,
+    new: &HashMap<String, Hash>,
+) -> (Vec<String>, Vec<String>) {
+    let mut keys: Vec<String> = new
+        .iter()
+        .filter(|(key, hash)| old.get(*key) != Some(hash))
+        .map(|(key, _)| key.clone())
+        .collect();
+    let mut removed: Vec<String> = old
+        .keys()
+        .filter(|key| !new.contains_key(*key))
+        .cloned()
+        .collect();
+    keys.sort();
+    removed.sort();
+    (keys, removed)
+}
+
+pub struct StateTracker {
+    db: Arc<RwLock<RocksDb>>,
+    snapshots: HashMap<String, Snapshot>,
+    /// Snapshots insertion order, the oldest are dropped first.
+    order: VecDeque<String>,
+}
+
+impl StateTracker {
+    pub fn new(db: Arc<RwLock<RocksDb>>) -> Self {
+        StateTracker {
+            db,
+            snapshots: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn data(db: &RocksDb, id: &str) -> HashMap<String, Hash> {
+        db.load_account_keys(id)
+            .into_iter()
+            .filter_map(|key| {
+                let value = db.load_account_data(id, &key)?;
+                Some((key, Hash::from_data(HashAlgorithm::Sha256, &value)))
+            })
+            .collect()
+    }
+
+    fn store(&mut self, id: &str, snapshot: Snapshot) {
+        self.snapshots.insert(id.to_owned(), snapshot);
+        self.order.push_back(id.to_owned());
+        if self.order.len() > MAX_SNAPSHOTS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.snapshots.remove(&oldest);
+            }
+        }
+    }
+
+    /// Changes of the accounts touched by a block.
+    pub fn block_diff(&mut self, txs: &[Hash]) -> Vec<AccountDiff> {
+        let accounts = block_accounts(&self.db.read(), txs);
+        self.diff(accounts)
+    }
+
+    /// Changes of the given accounts since their last snapshot, unchanged
+    /// accounts are left out.
+    pub fn diff(&mut self, accounts: BTreeSet<String>) -> Vec<AccountDiff> {
+        let db = self.db.clone();
+        let db = db.read();
+        let mut diffs = Vec::new();
+        for id in accounts {
+            let account = match db.load_account(&id) {
+                Some(account) => account,
+                None => continue,
+            };
+            if let Some(snapshot) = self.snapshots.get_mut(&id) {
+                if snapshot.account == account {
+                    continue;
+                }
+                let assets = snapshot.account.assets != account.assets
+                    || snapshot.account.contract != account.contract;
+                let (keys, removed) = if snapshot.account.data_hash == account.data_hash {
+                    (Vec::new(), Vec::new())
+                } else {
+                    let data = Self::data(&db, &id);
+                    let diff = diff_keys(&snapshot.data, &data);
+                    snapshot.data = data;
+                    diff
+                };
+                snapshot.account = account;
+                diffs.push(AccountDiff {
+                    account: id,
+                    keys: Some(keys),
+                    removed,
+                    assets,
+                });
+            } else {
+                let data = Self::data(&db, &id);
+                self.store(&id, Snapshot { account, data });
+                diffs.push(AccountDiff {
+                    account: id,
+                    keys: None,
+                    removed: Vec::new(),
+                    assets: true,
+                });
+            }
+        }
+        diffs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(value: &str) -> Hash {
+        Hash::from_data(HashAlgorithm::Sha256, value.as_bytes())
+    }
+
+    #[test]
+    fn changed_keys() {
+        let old: HashMap<String, Hash> = [("a", "1"), ("b", "2"), ("c", "3")]
+            .iter()
+            .map(|(key, value)| (key.to_string(), hash(value)))
+            .collect();
+        let new: HashMap<String, Hash> = [("a", "1"), ("b", "4"), ("d", "5")]
+            .iter()
+            .map(|(key, value)| (key.to_string(), hash(value)))
+            .collect();
+
+        let (keys, removed) = diff_keys(&old, &new);
+
+        assert_eq!(keys, ["b", "d"]);
+        assert_eq!(removed, ["c"]);
+    }
+}
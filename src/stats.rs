@@ -0,0 +1,215 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Core statistics.
+//!
+//! Samples the core stats (unconfirmed pool and last block) periodically and
+//! serves them via the node API, for monitoring tools other than the monitor
+//! server. The last samples are kept in memory.
+//! Each sample carries the P2P traffic statistics too.
+
+use crate::api::{Request, Response, Router};
+use crate::clock::Clock;
+use crate::tasks;
+use crate::traffic::{Traffic, TrafficStats};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use trinci_core::{
+    base::Mutex,
+    blockchain::BlockRequestSender,
+    crypto::{HashAlgorithm, Hashable},
+    Block, Message,
+};
+
+/// Default number of samples kept in memory.
+pub const DEFAULT_STATS_HISTORY: usize = 60;
+
+/// Seconds between two samples.
+const SAMPLE_INTERVAL: u64 = 10;
+
+/// Last block summary.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct BlockStats {
+    pub height: u64,
+    /// Block hash (hex).
+    pub hash: String,
+    pub size: u32,
+    pub timestamp: u64,
+    pub prev_hash: String,
+    pub txs_hash: String,
+    pub rxs_hash: String,
+    pub state_hash: String,
+}
+
+impl From<&Block> for BlockStats {
+    fn from(block: &Block) -> Self {
+        let data = &block.data;
+        BlockStats {
+            height: data.height,
+            hash: hex::encode(block.hash(HashAlgorithm::Sha256).as_bytes()),
+            size: data.size,
+            timestamp: data.timestamp,
+            prev_hash: hex::encode(data.prev_hash.as_bytes()),
+            txs_hash: hex::encode(data.txs_hash.as_bytes()),
+            rxs_hash: hex::encode(data.rxs_hash.as_bytes()),
+            state_hash: hex::encode(data.state_hash.as_bytes()),
+        }
+    }
+}
+
+/// Core stats sample, as returned by the `GetCoreStats` request.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct StatsSample {
+    /// Sample time, seconds since the epoch.
+    pub time: u64,
+    /// Unconfirmed pool hash (hex).
+    pub pool_hash: String,
+    /// Unconfirmed pool size.
+    pub pool_size: usize,
+    /// Last block.
+    pub last_block: Option<BlockStats>,
+    /// P2P traffic.
+    pub p2p: TrafficStats,
+}
+
+pub struct CoreStats {
+    chan: BlockRequestSender,
+    history: usize,
+    samples: Mutex<VecDeque<StatsSample>>,
+    traffic: Arc<Traffic>,
+    clock: Clock,
+}
+
+impl CoreStats {
+    pub fn new(
+        chan: BlockRequestSender,
+        history: usize,
+        traffic: Arc<Traffic>,
+        clock: Clock,
+    ) -> Self {
+        CoreStats {
+            chan,
+            history: history.max(1),
+            samples: Mutex::new(VecDeque::new()),
+            traffic,
+            clock,
+        }
+    }
+
+    /// Requests the current stats to the core.
+    pub fn sample(&self) -> Option<StatsSample> {
+        let rx_chan = self.chan.send_sync(Message::GetCoreStatsRequest).ok()?;
+        match rx_chan.recv_sync() {
+            Ok(Message::GetCoreStatsResponse((pool_hash, pool_size, block))) => Some(StatsSample {
+                time: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                pool_hash: hex::encode(pool_hash.as_bytes()),
+                pool_size,
+                last_block: block.as_ref().map(BlockStats::from),
+                p2p: self.traffic.stats(),
+            }),
+            Ok(res) => {
+                warn!("[stats] unexpected message {:?}", res);
+                None
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Records a sample, dropping the oldest one when the history is full.
+    pub fn push(&self, sample: StatsSample) {
+        let mut samples = self.samples.lock();
+        if samples.len() == self.history {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    /// Last `count` samples, oldest first.
+    pub fn history(&self, count: usize) -> Vec<StatsSample> {
+        let samples = self.samples.lock();
+        let skip = samples.len().saturating_sub(count);
+        samples.iter().skip(skip).cloned().collect()
+    }
+
+    /// Registers the stats routes within the node API.
+    pub fn routes(stats: Arc<Self>, router: &mut Router) {
+        let core = stats.clone();
+        router.add("GET", "/api/v1/stats", move |_: &Request| {
+            match core.sample() {
+                Some(sample) => Response::json(&sample),
+                None => Response::error(503, "blockchain service not available"),
+            }
+        });
+        router.add("GET", "/api/v1/stats/history", move |req: &Request| {
+            let count = req.query::<usize>("count").unwrap_or(stats.history);
+            Response::json(&stats.history(count))
+        });
+    }
+}
+
+/// Records a sample every `SAMPLE_INTERVAL` seconds.
+pub fn run(stats: Arc<CoreStats>) {
+    loop {
+        stats.clock.sleep(Duration::from_secs(SAMPLE_INTERVAL));
+        if tasks::cancelled() {
+            break;
+        }
+        match stats.sample() {
+            Some(sample) => stats.push(sample),
+            None => {
+                warn!("[stats] blockchain channel closed");
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(time: u64) -> StatsSample {
+        StatsSample {
+            time,
+            pool_hash: String::new(),
+            pool_size: 0,
+            last_block: None,
+            p2p: TrafficStats::default(),
+        }
+    }
+
+    #[test]
+    fn history_keeps_last_samples() {
+        let (chan, _rx) = trinci_core::channel::confirmed_channel();
+        let stats = CoreStats::new(chan, 3, Arc::new(Traffic::new()), Clock::default());
+        for time in 0..5 {
+            stats.push(sample(time));
+        }
+
+        let times: Vec<u64> = stats.history(10).iter().map(|s| s.time).collect();
+        assert_eq!(times, [2, 3, 4]);
+        let times: Vec<u64> = stats.history(2).iter().map(|s| s.time).collect();
+        assert_eq!(times, [3, 4]);
+    }
+}
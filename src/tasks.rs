@@ -0,0 +1,252 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Node workers supervision.
+//!
+//! The node side workers run on a single tokio runtime shared by the whole
+//! node, spawned through `Tasks`, which catches their panics and reports them
+//! to the application loop, where a failed worker stops the node like a
+//! stopped service. The workers are blocking loops over the core channels,
+//! sockets and timers, so they are run by the runtime blocking pool.
+//! At shutdown the workers are cancelled: those waiting in `sleep` return at
+//! once, the ones blocked on a core channel end with the blockchain service.
+
+use crate::api::{Request, Response, Router};
+use serde::Serialize;
+use std::{
+    any::Any,
+    cell::RefCell,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::{
+    runtime::{Builder, Runtime},
+    task::JoinHandle,
+};
+
+/// Worker state, as reported by the node API.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase", tag = "state", content = "message")]
+pub enum TaskState {
+    Running,
+    Finished,
+    Panicked(String),
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TaskStatus {
+    pub name: &'static str,
+    #[serde(flatten)]
+    pub state: TaskState,
+}
+
+struct Task {
+    status: TaskStatus,
+    handle: Option<JoinHandle<()>>,
+    /// Panic not yet reported to the supervisor.
+    unreported: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    cancelled: Mutex<bool>,
+    wake: Condvar,
+    tasks: Mutex<Vec<Task>>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<Shared>>> = const { RefCell::new(None) };
+}
+
+/// Supervised node workers.
+#[derive(Clone, Default)]
+pub struct Tasks {
+    shared: Arc<Shared>,
+}
+
+// Runtime of the node workers, never dropped.
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("trinci-worker")
+            .build()
+            .expect("workers runtime")
+    })
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => payload
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| "unknown panic".to_string()),
+    }
+}
+
+/// Sleeps for `duration` within a task, returns `false` if the task has been
+/// cancelled meanwhile. A plain sleep out of the tasks.
+pub fn sleep(duration: Duration) -> bool {
+    let shared = match CURRENT.with(|current| current.borrow().clone()) {
+        Some(shared) => shared,
+        None => {
+            thread::sleep(duration);
+            return true;
+        }
+    };
+    let deadline = Instant::now() + duration;
+    let mut cancelled = shared.cancelled.lock().unwrap();
+    while !*cancelled {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        cancelled = shared.wake.wait_timeout(cancelled, left).unwrap().0;
+    }
+    !*cancelled
+}
+
+/// The current task has been cancelled, for the workers waiting on the node
+/// clock.
+pub fn cancelled() -> bool {
+    CURRENT.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .is_some_and(|shared| *shared.cancelled.lock().unwrap())
+    })
+}
+
+impl Tasks {
+    /// Runs `f` on the workers runtime.
+    pub fn spawn<F>(&self, name: &'static str, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let shared = self.shared.clone();
+        let mut tasks = self.shared.tasks.lock().unwrap();
+        let index = tasks.len();
+        // The task updates its state once pushed, the list is locked.
+        let handle = runtime().spawn_blocking(move || {
+            CURRENT.with(|current| *current.borrow_mut() = Some(shared.clone()));
+            let state = match panic::catch_unwind(AssertUnwindSafe(f)) {
+                Ok(()) => TaskState::Finished,
+                Err(payload) => {
+                    let message = panic_message(payload.as_ref());
+                    error!("[tasks] {} panicked: {}", name, message);
+                    TaskState::Panicked(message)
+                }
+            };
+            // The blocking pool threads are reused by the next tasks.
+            CURRENT.with(|current| *current.borrow_mut() = None);
+            let mut tasks = shared.tasks.lock().unwrap();
+            tasks[index].unreported = matches!(state, TaskState::Panicked(_));
+            tasks[index].status.state = state;
+        });
+        tasks.push(Task {
+            unreported: false,
+            status: TaskStatus {
+                name,
+                state: TaskState::Running,
+            },
+            handle: Some(handle),
+        });
+    }
+
+    /// Panics not yet reported, as (task, message).
+    pub fn take_panics(&self) -> Vec<(&'static str, String)> {
+        let mut tasks = self.shared.tasks.lock().unwrap();
+        tasks
+            .iter_mut()
+            .filter(|task| task.unreported)
+            .filter_map(|task| {
+                task.unreported = false;
+                match &task.status.state {
+                    TaskState::Panicked(message) => Some((task.status.name, message.clone())),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    pub fn status(&self) -> Vec<TaskStatus> {
+        let tasks = self.shared.tasks.lock().unwrap();
+        tasks.iter().map(|task| task.status.clone()).collect()
+    }
+
+    /// Cancels the tasks and waits up to `timeout` for them to end.
+    /// Returns the tasks still running.
+    pub fn shutdown(&self, timeout: Duration) -> Vec<&'static str> {
+        *self.shared.cancelled.lock().unwrap() = true;
+        self.shared.wake.notify_all();
+        let deadline = Instant::now() + timeout;
+        loop {
+            let running: Vec<_> = {
+                let mut tasks = self.shared.tasks.lock().unwrap();
+                for task in tasks.iter_mut() {
+                    if task
+                        .handle
+                        .as_ref()
+                        .is_some_and(|handle| handle.is_finished())
+                    {
+                        task.handle = None;
+                    }
+                }
+                tasks
+                    .iter()
+                    .filter(|task| task.handle.is_some())
+                    .map(|task| task.status.name)
+                    .collect()
+            };
+            if running.is_empty() || Instant::now() >= deadline {
+                return running;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Registers the tasks routes within the node API.
+    pub fn routes(tasks: Tasks, router: &mut Router) {
+        router.add("GET", "/admin/tasks", move |_: &Request| {
+            Response::json(&tasks.status())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panics_and_cancellation() {
+        let tasks = Tasks::default();
+        tasks.spawn("faulty", || panic!("boom"));
+        tasks.spawn("worker", || while sleep(Duration::from_secs(60)) {});
+        while tasks.status()[0].state == TaskState::Running {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(tasks.take_panics(), vec![("faulty", "boom".to_string())]);
+        assert!(tasks.take_panics().is_empty());
+        assert!(tasks.shutdown(Duration::from_secs(5)).is_empty());
+        assert_eq!(tasks.status()[1].state, TaskState::Finished);
+    }
+}
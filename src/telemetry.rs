@@ -0,0 +1,172 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Telemetry controls.
+//!
+//! The monitor tracks the node status, records it in the local history and,
+//! when enabled, sends it to the monitor server. The operator selects what
+//! leaves the machine: `telemetry` chooses the destinations, `telemetry-redact`
+//! blanks the sensitive fields of the remote updates.
+
+use serde_json::Value;
+
+/// Node status destinations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Telemetry {
+    /// No history, no remote updates.
+    Off,
+    /// Local history only.
+    File,
+    /// Local history and remote updates to `monitor-addr`.
+    Remote,
+}
+
+impl std::str::FromStr for Telemetry {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(Telemetry::Off),
+            "file" => Ok(Telemetry::File),
+            "remote" => Ok(Telemetry::Remote),
+            _ => Err(format!(
+                "invalid telemetry `{}` (expected off, file or remote)",
+                value
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Telemetry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let telemetry = match self {
+            Telemetry::Off => "off",
+            Telemetry::File => "file",
+            Telemetry::Remote => "remote",
+        };
+        write!(f, "{}", telemetry)
+    }
+}
+
+/// Node status fields blanked in the remote updates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redact {
+    /// Local and public IP, P2P listening and bootstrap addresses.
+    Ip,
+    /// Blockchain seed.
+    Seed,
+}
+
+impl std::str::FromStr for Redact {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "ip" => Ok(Redact::Ip),
+            "seed" => Ok(Redact::Seed),
+            _ => Err(format!(
+                "invalid telemetry redaction `{}` (expected ip or seed)",
+                value
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Redact {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let redact = match self {
+            Redact::Ip => "ip",
+            Redact::Seed => "seed",
+        };
+        write!(f, "{}", redact)
+    }
+}
+
+/// Fields of the serialized node status covered by a redaction.
+#[cfg_attr(not(feature = "monitor"), allow(dead_code))]
+fn fields(redact: Redact) -> &'static [&'static [&'static str]] {
+    match redact {
+        Redact::Ip => &[
+            &["data", "ip_endpoint"],
+            &["data", "pub_ip"],
+            &["data", "p2p_info", "p2p_addr"],
+            &["data", "p2p_info", "p2p_bootstrap_addr"],
+            &["data", "p2p_info", "p2p_bootstrap_peers"],
+        ],
+        Redact::Seed => &[&["data", "seed"]],
+    }
+}
+
+/// Blanks the redacted fields of a serialized node status.
+#[cfg_attr(not(feature = "monitor"), allow(dead_code))]
+pub fn redact(status: &mut Value, redactions: &[Redact]) {
+    for redaction in redactions {
+        for path in fields(*redaction) {
+            let (field, parents) = match path.split_last() {
+                Some(split) => split,
+                None => continue,
+            };
+            let object = parents
+                .iter()
+                .try_fold(&mut *status, |object, parent| object.get_mut(*parent));
+            if let Some(object) = object.and_then(Value::as_object_mut) {
+                if object.contains_key(*field) {
+                    object.insert(field.to_string(), Value::Null);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn redact_fields() {
+        let mut status = json!({
+            "nodeID": "QmNode",
+            "data": {
+                "ip_endpoint": "192.168.1.10",
+                "pub_ip": "203.0.113.10",
+                "seed": 42,
+                "p2p_info": {
+                    "p2p_addr": "0.0.0.0",
+                    "p2p_port": 9000,
+                    "p2p_bootstrap_peers": ["12D3KooW@/ip4/10.0.0.1/tcp/9000"]
+                }
+            }
+        });
+
+        redact(&mut status, &[Redact::Ip, Redact::Seed]);
+
+        assert_eq!(status["nodeID"], "QmNode");
+        assert_eq!(status["data"]["ip_endpoint"], Value::Null);
+        assert_eq!(status["data"]["pub_ip"], Value::Null);
+        assert_eq!(status["data"]["seed"], Value::Null);
+        assert_eq!(status["data"]["p2p_info"]["p2p_addr"], Value::Null);
+        assert_eq!(
+            status["data"]["p2p_info"]["p2p_bootstrap_peers"],
+            Value::Null
+        );
+        assert_eq!(status["data"]["p2p_info"]["p2p_port"], 9000);
+        assert!(status["data"]["p2p_info"]
+            .get("p2p_bootstrap_addr")
+            .is_none());
+    }
+}
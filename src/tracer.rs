@@ -15,40 +15,408 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
 
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use trinci_core::blockchain::{BlockRequestSender, Event, Message};
+//! Blockchain throughput metrics.
+//!
+//! Follows the executed blocks to compute the transactions per second over a
+//! sliding window, the block interval and the fuel usage. Totals are
+//! persisted in the database folder to survive the node restarts.
+//!
+//! The executed transactions are also broken down per target account and per
+//! method since the node start, to show which contract is consuming the chain
+//! capacity.
+
+use crate::api::{Request, Response, Router};
+use crate::denylist;
+use crate::metrics::MetricsSource;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use trinci_core::{
+    base::Mutex,
+    blockchain::{BlockRequestSender, Event, Message},
+    Hash, Transaction,
+};
+
+/// Totals file name, within the database folder.
+const TRACER_FILE: &str = "tracer.json";
+
+/// Statistics sliding window.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Totals are persisted every this number of blocks.
+const SAVE_PERIOD: u64 = 10;
+
+/// Default number of entries of the top tables.
+const DEFAULT_TOP: usize = 10;
+
+/// Max tracked targets per table, beyond it the least consuming half is
+/// dropped.
+const MAX_TARGETS: usize = 4096;
+
+/// Persisted counters.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct Totals {
+    pub blocks: u64,
+    pub txs: u64,
+    pub fuel: u64,
+    /// Last traced block height.
+    pub height: u64,
+}
+
+/// Block interval statistics over the window, in seconds.
+#[derive(Serialize, Default, Clone)]
+pub struct IntervalStats {
+    pub last: f64,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+}
+
+/// Throughput statistics, as reported by the monitor and the node API.
+#[derive(Serialize, Default, Clone)]
+pub struct TracerStats {
+    pub totals: Totals,
+    /// Transactions per second over the window.
+    pub tps: f64,
+    /// Interval between the blocks within the window.
+    pub block_interval: Option<IntervalStats>,
+    /// Transactions in the last block.
+    pub last_block_txs: u64,
+    /// Fuel burned by the last block transactions.
+    pub last_block_fuel: u64,
+}
+
+/// Execution statistics of a target account, or of one of its methods.
+#[derive(Serialize, Default, Clone, Debug, PartialEq)]
+pub struct TargetStats {
+    pub account: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    /// Executed transactions.
+    pub txs: u64,
+    /// Transactions with a failure receipt.
+    pub failed: u64,
+    /// Fuel burned.
+    pub fuel: u64,
+}
+
+/// Top targets tables, as reported by the node API.
+#[derive(Serialize)]
+pub struct TopTargets {
+    pub accounts: Vec<TargetStats>,
+    pub methods: Vec<TargetStats>,
+}
+
+/// Top tables ordering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopOrder {
+    Fuel,
+    Txs,
+}
+
+impl std::str::FromStr for TopOrder {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "fuel" => Ok(TopOrder::Fuel),
+            "txs" => Ok(TopOrder::Txs),
+            _ => Err(format!("invalid order `{}` (expected fuel or txs)", value)),
+        }
+    }
+}
+
+/// Executed transaction target and outcome.
+pub struct TxTrace {
+    pub account: String,
+    pub method: String,
+    pub fuel: u64,
+    pub success: bool,
+}
 
-// Temporary structure to keep track for executed transactions per second.
 #[derive(Default)]
-struct Tracer {
-    begin: Duration,
-    txs: usize,
+struct Targets {
+    accounts: HashMap<String, TargetStats>,
+    methods: HashMap<(String, String), TargetStats>,
+}
+
+// Bounds a table, keeping the most consuming half.
+fn shrink<K: Clone + Eq + std::hash::Hash>(table: &mut HashMap<K, TargetStats>) {
+    if table.len() <= MAX_TARGETS {
+        return;
+    }
+    let mut entries: Vec<_> = table.drain().collect();
+    entries.sort_by(|(_, a), (_, b)| b.fuel.cmp(&a.fuel).then(b.txs.cmp(&a.txs)));
+    entries.truncate(MAX_TARGETS / 2);
+    table.extend(entries);
+}
+
+// Top `count` entries of a table.
+fn top<'a, I>(entries: I, count: usize, order: TopOrder) -> Vec<TargetStats>
+where
+    I: Iterator<Item = &'a TargetStats>,
+{
+    let mut entries: Vec<_> = entries.cloned().collect();
+    entries.sort_by(|a, b| {
+        let (a_key, b_key) = match order {
+            TopOrder::Fuel => ((a.fuel, a.txs), (b.fuel, b.txs)),
+            TopOrder::Txs => ((a.txs, a.fuel), (b.txs, b.fuel)),
+        };
+        b_key
+            .cmp(&a_key)
+            .then_with(|| a.account.cmp(&b.account))
+            .then_with(|| a.method.cmp(&b.method))
+    });
+    entries.truncate(count);
+    entries
+}
+
+struct Sample {
+    at: Instant,
+    txs: u64,
+    fuel: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    totals: Totals,
+    samples: VecDeque<Sample>,
+    /// Blocks recorded since the last save.
+    unsaved: u64,
+    targets: Targets,
+}
+
+pub struct Tracer {
+    path: PathBuf,
+    inner: Mutex<Inner>,
 }
 
 impl Tracer {
-    pub fn new() -> Tracer {
-        Tracer::default()
+    /// Creates a tracer, loading the totals saved in the database folder.
+    pub fn open<P: AsRef<Path>>(db_path: P) -> Self {
+        let path = db_path.as_ref().join(TRACER_FILE);
+        let totals = fs::read(&path)
+            .ok()
+            .and_then(|buf| serde_json::from_slice(&buf).ok())
+            .unwrap_or_default();
+        Tracer {
+            path,
+            inner: Mutex::new(Inner {
+                totals,
+                samples: VecDeque::new(),
+                unsaved: 0,
+                targets: Targets::default(),
+            }),
+        }
+    }
+
+    /// Accounts an executed block.
+    fn record(&self, height: u64, txs: u64, fuel: u64, at: Instant) {
+        let mut inner = self.inner.lock();
+        inner.samples.push_back(Sample { at, txs, fuel });
+        while let Some(sample) = inner.samples.front() {
+            if at.duration_since(sample.at) <= WINDOW {
+                break;
+            }
+            inner.samples.pop_front();
+        }
+        inner.totals.blocks += 1;
+        inner.totals.txs += txs;
+        inner.totals.fuel += fuel;
+        inner.totals.height = height;
+
+        inner.unsaved += 1;
+        if inner.unsaved >= SAVE_PERIOD {
+            inner.unsaved = 0;
+            if let Err(err) = self.save(&inner.totals) {
+                warn!("[tracer] unable to save totals: {}", err);
+            }
+        }
+    }
+
+    /// Accounts the executed transactions to their targets.
+    fn record_targets(&self, traces: &[TxTrace]) {
+        let mut inner = self.inner.lock();
+        let targets = &mut inner.targets;
+        for trace in traces.iter().filter(|trace| !trace.account.is_empty()) {
+            let account = targets
+                .accounts
+                .entry(trace.account.clone())
+                .or_insert_with(|| TargetStats {
+                    account: trace.account.clone(),
+                    ..Default::default()
+                });
+            account.txs += 1;
+            account.failed += !trace.success as u64;
+            account.fuel += trace.fuel;
+            let method = targets
+                .methods
+                .entry((trace.account.clone(), trace.method.clone()))
+                .or_insert_with(|| TargetStats {
+                    account: trace.account.clone(),
+                    method: Some(trace.method.clone()),
+                    ..Default::default()
+                });
+            method.txs += 1;
+            method.failed += !trace.success as u64;
+            method.fuel += trace.fuel;
+        }
+        shrink(&mut targets.accounts);
+        shrink(&mut targets.methods);
     }
 
-    // Ugly method to keep track of transactions per second.
-    // This is only meant to be used during stress tests.
-    fn update(&mut self, height: usize, count: usize) {
-        if self.txs == 0 {
-            self.begin = SystemTime::now().duration_since(UNIX_EPOCH).unwrap(); // Safe
+    /// Most consuming target accounts and methods.
+    pub fn top(&self, count: usize, order: TopOrder) -> TopTargets {
+        let inner = self.inner.lock();
+        TopTargets {
+            accounts: top(inner.targets.accounts.values(), count, order),
+            methods: top(inner.targets.methods.values(), count, order),
+        }
+    }
+
+    /// Registers the per target statistics route within the node API.
+    pub fn routes(tracer: Arc<Self>, router: &mut Router) {
+        router.add("GET", "/api/v1/stats/contracts", move |req: &Request| {
+            let order = match req.query.get("by").map(|by| by.parse::<TopOrder>()) {
+                Some(Ok(order)) => order,
+                Some(Err(err)) => return Response::error(400, err),
+                None => TopOrder::Fuel,
+            };
+            let count = req.query::<usize>("top").unwrap_or(DEFAULT_TOP);
+            Response::json(&tracer.top(count, order))
+        });
+    }
+
+    fn save(&self, totals: &Totals) -> std::io::Result<()> {
+        let buf = serde_json::to_vec(totals)?;
+        fs::write(&self.path, buf)
+    }
+
+    /// Current statistics.
+    pub fn stats(&self) -> TracerStats {
+        let inner = self.inner.lock();
+        let mut stats = TracerStats {
+            totals: inner.totals.clone(),
+            ..Default::default()
+        };
+        let (first, last) = match (inner.samples.front(), inner.samples.back()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return stats,
+        };
+        stats.last_block_txs = last.txs;
+        stats.last_block_fuel = last.fuel;
+
+        // The first sample marks the window start, its transactions were
+        // executed before it.
+        let span = last.at.duration_since(first.at).as_secs_f64();
+        if span > 0.0 {
+            let txs: u64 = inner.samples.iter().skip(1).map(|sample| sample.txs).sum();
+            stats.tps = txs as f64 / span;
+        }
+
+        let intervals: Vec<f64> = inner
+            .samples
+            .iter()
+            .zip(inner.samples.iter().skip(1))
+            .map(|(prev, next)| next.at.duration_since(prev.at).as_secs_f64())
+            .collect();
+        if let Some(last) = intervals.last() {
+            stats.block_interval = Some(IntervalStats {
+                last: *last,
+                min: intervals.iter().cloned().fold(f64::MAX, f64::min),
+                max: intervals.iter().cloned().fold(0.0, f64::max),
+                avg: span / intervals.len() as f64,
+            });
         }
-        self.txs += count;
-        let delta = SystemTime::now().duration_since(UNIX_EPOCH).unwrap() - self.begin; // Safe
-        let tps = self.txs as f64 / delta.as_secs() as f64;
-        info!(
-            "[tracer] height: {}, block-txs: {}, total-txs: {}, ~tps: {}",
-            height, count, self.txs, tps
-        );
+        stats
     }
 }
 
-pub fn run(tx_chan: BlockRequestSender) {
-    let mut tracer = Tracer::new();
+impl MetricsSource for Tracer {
+    fn render(&self, out: &mut String) {
+        let stats = self.stats();
+        let _ = writeln!(out, "# TYPE trinci_blocks_total counter");
+        let _ = writeln!(out, "trinci_blocks_total {}", stats.totals.blocks);
+        let _ = writeln!(out, "# TYPE trinci_transactions_total counter");
+        let _ = writeln!(out, "trinci_transactions_total {}", stats.totals.txs);
+        let _ = writeln!(out, "# TYPE trinci_fuel_burned_total counter");
+        let _ = writeln!(out, "trinci_fuel_burned_total {}", stats.totals.fuel);
+        let _ = writeln!(out, "# TYPE trinci_block_height gauge");
+        let _ = writeln!(out, "trinci_block_height {}", stats.totals.height);
+        let _ = writeln!(out, "# TYPE trinci_tps gauge");
+        let _ = writeln!(out, "trinci_tps {}", stats.tps);
+        if let Some(interval) = stats.block_interval {
+            let _ = writeln!(out, "# TYPE trinci_block_interval_seconds gauge");
+            for (stat, value) in [
+                ("last", interval.last),
+                ("min", interval.min),
+                ("max", interval.max),
+                ("avg", interval.avg),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "trinci_block_interval_seconds{{stat=\"{}\"}} {}",
+                    stat, value
+                );
+            }
+        }
+    }
+}
+
+fn request(chan: &BlockRequestSender, req: Message) -> Option<Message> {
+    chan.send_sync(req)
+        .ok()
+        .and_then(|res| res.recv_sync().ok())
+}
 
+// Targets and outcome of the block transactions, from the transactions and
+// their receipts. The fuel of a bulk transaction is accounted to its root
+// target, the other nodes only count as executed transactions.
+fn block_traces(chan: &BlockRequestSender, txs: &[Hash]) -> Vec<TxTrace> {
+    let mut traces = Vec::new();
+    for hash in txs {
+        let rx = match request(chan, Message::GetReceiptRequest { hash: *hash }) {
+            Some(Message::GetReceiptResponse { rx }) => rx,
+            _ => continue,
+        };
+        let req = Message::GetTransactionRequest {
+            hash: *hash,
+            destination: None,
+        };
+        let tx: Option<Transaction> = match request(chan, req) {
+            Some(Message::GetTransactionResponse { tx, .. }) => Some(tx),
+            _ => None,
+        };
+        let targets = tx.as_ref().map(denylist::targets).unwrap_or_default();
+        if targets.is_empty() {
+            // Only the fuel is known.
+            traces.push(TxTrace {
+                account: String::new(),
+                method: String::new(),
+                fuel: rx.burned_fuel,
+                success: rx.success,
+            });
+            continue;
+        }
+        for (i, (account, method)) in targets.into_iter().enumerate() {
+            traces.push(TxTrace {
+                account: account.to_string(),
+                method: method.to_string(),
+                fuel: if i == 0 { rx.burned_fuel } else { 0 },
+                success: rx.success,
+            });
+        }
+    }
+    traces
+}
+
+pub fn run(tracer: Arc<Tracer>, tx_chan: BlockRequestSender) {
     let msg = Message::Subscribe {
         id: "tracer".to_owned(),
         events: Event::BLOCK,
@@ -65,8 +433,24 @@ pub fn run(tx_chan: BlockRequestSender) {
 
     loop {
         match rx_chan.recv_sync() {
-            Ok(Message::GetBlockResponse { block, .. }) => {
-                tracer.update(block.data.height as usize, block.data.size as usize);
+            Ok(Message::GetBlockResponse { block, txs, .. }) => {
+                let at = Instant::now();
+                let traces = txs
+                    .as_ref()
+                    .map(|txs| block_traces(&tx_chan, txs))
+                    .unwrap_or_default();
+                let fuel = traces.iter().map(|trace| trace.fuel).sum();
+                tracer.record_targets(&traces);
+                let height = block.data.height;
+                let count = block.data.size as u64;
+                tracer.record(height, count, fuel, at);
+                debug!(
+                    "[tracer] height: {}, block-txs: {}, fuel: {}, ~tps: {:.2}",
+                    height,
+                    count,
+                    fuel,
+                    tracer.stats().tps
+                );
             }
             Ok(res) => {
                 info!("[tracer] Subscribe response: {:?}", res);
@@ -78,3 +462,57 @@ pub fn run(tx_chan: BlockRequestSender) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn window_stats() {
+        let tmp = TempDir::new().unwrap();
+        let tracer = Tracer::open(tmp.path());
+        assert_eq!(tracer.stats().tps, 0.0);
+
+        let start = Instant::now();
+        tracer.record(1, 10, 100, start);
+        let stats = tracer.stats();
+        assert_eq!(stats.tps, 0.0);
+        assert!(stats.block_interval.is_none());
+
+        tracer.record(2, 20, 200, start + Duration::from_secs(2));
+        tracer.record(3, 40, 300, start + Duration::from_secs(6));
+        let stats = tracer.stats();
+        assert_eq!(stats.tps, 10.0);
+        let interval = stats.block_interval.unwrap();
+        assert_eq!((interval.last, interval.min, interval.max), (4.0, 2.0, 4.0));
+        assert_eq!(stats.totals.txs, 70);
+
+        // The first sample falls out of the window.
+        tracer.record(4, 0, 0, start + Duration::from_secs(62));
+        assert_eq!(tracer.stats().block_interval.unwrap().max, 56.0);
+
+        let trace = |account: &str, method: &str, fuel, success| TxTrace {
+            account: account.to_string(),
+            method: method.to_string(),
+            fuel,
+            success,
+        };
+        tracer.record_targets(&[
+            trace("asset", "transfer", 10, true),
+            trace("asset", "transfer", 10, false),
+            trace("asset", "mint", 50, true),
+            trace("game", "play", 40, true),
+            trace("game", "play", 40, true),
+            trace("game", "play", 40, true),
+        ]);
+        let top = tracer.top(1, TopOrder::Fuel);
+        assert_eq!(top.accounts[0].account, "game");
+        assert_eq!(top.accounts[0].fuel, 120);
+        assert_eq!(top.methods.len(), 1);
+        assert_eq!(top.methods[0].method.as_deref(), Some("play"));
+        let top = tracer.top(3, TopOrder::Txs);
+        assert_eq!(top.methods[1].method.as_deref(), Some("transfer"));
+        assert_eq!(top.methods[1].failed, 1);
+    }
+}
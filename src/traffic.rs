@@ -0,0 +1,335 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! P2P traffic statistics.
+//!
+//! Accounts the messages exchanged by the P2P service with the blockchain
+//! service, as seen by the gateway: the requests and gossip received from the
+//! peers and the responses and gossip sent back. Sizes are the serialized
+//! message sizes, the transport overhead is not visible to the node.
+//! Gossip messages do not carry the remote peer, they only contribute to
+//! the totals while the per peer rates cover the direct exchanges.
+//! The blocks received are timed to measure their propagation latency.
+
+use crate::metrics::MetricsSource;
+use crate::propagation::{Propagation, PropagationStats};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    time::{Duration, Instant},
+};
+use trinci_core::{
+    base::{serialize::rmp_serialize, Mutex},
+    Message,
+};
+
+/// Per peer rates window.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// A peer with more than this share of the window traffic is reported.
+const DOMINANT_SHARE: f64 = 0.5;
+
+/// Window traffic below this size is not checked for dominant peers.
+const DOMINANT_MIN_BYTES: u64 = 1024 * 1024;
+
+/// Traffic direction, from the node standpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+/// Messages count of a kind.
+#[derive(Serialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct MessageCount {
+    pub received: u64,
+    pub sent: u64,
+}
+
+/// Peer traffic over the last window.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct PeerTraffic {
+    pub peer: String,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    /// Bytes per second received over the last window.
+    pub rate_received: f64,
+    /// Bytes per second sent over the last window.
+    pub rate_sent: f64,
+}
+
+/// Traffic statistics, as reported by the monitor and the node API.
+#[derive(Serialize, Default, Clone, Debug, PartialEq)]
+pub struct TrafficStats {
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    /// Messages by kind, `gossip` for the broadcast ones.
+    pub messages: BTreeMap<&'static str, MessageCount>,
+    /// Peers active in the last window, busiest first.
+    pub peers: Vec<PeerTraffic>,
+    /// Latency of the blocks received from the peers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_propagation: Option<PropagationStats>,
+}
+
+#[derive(Default)]
+struct PeerCounters {
+    /// Current window bytes (received, sent).
+    current: (u64, u64),
+    /// Last complete window bytes.
+    last: (u64, u64),
+}
+
+struct Inner {
+    bytes: (u64, u64),
+    messages: BTreeMap<&'static str, MessageCount>,
+    peers: HashMap<String, PeerCounters>,
+    window_start: Instant,
+}
+
+pub struct Traffic {
+    inner: Mutex<Inner>,
+    propagation: Propagation,
+}
+
+/// Serialized size of a message.
+pub fn message_size(msg: &Message) -> usize {
+    match msg {
+        Message::Packed { buf } => buf.len(),
+        msg => rmp_serialize(msg).map(|buf| buf.len()).unwrap_or_default(),
+    }
+}
+
+impl Inner {
+    // Closes the current window when expired, reporting the dominant peer.
+    fn rotate(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < WINDOW {
+            return;
+        }
+        // A window with no traffic at all leaves nothing to report.
+        let skipped = elapsed >= 2 * WINDOW;
+        self.peers.retain(|_, counters| {
+            counters.last = if skipped { (0, 0) } else { counters.current };
+            counters.current = (0, 0);
+            counters.last != (0, 0)
+        });
+        self.window_start = now;
+
+        let total: u64 = self.peers.values().map(|c| c.last.0 + c.last.1).sum();
+        if self.peers.len() < 2 || total < DOMINANT_MIN_BYTES {
+            return;
+        }
+        if let Some((peer, counters)) = self
+            .peers
+            .iter()
+            .max_by_key(|(_, counters)| counters.last.0 + counters.last.1)
+        {
+            let share = (counters.last.0 + counters.last.1) as f64 / total as f64;
+            if share > DOMINANT_SHARE {
+                warn!(
+                    "[traffic] peer {} took {:.0}% of the P2P traffic in the last {} seconds",
+                    peer,
+                    share * 100.0,
+                    WINDOW.as_secs()
+                );
+            }
+        }
+    }
+}
+
+impl Default for Traffic {
+    fn default() -> Self {
+        Traffic {
+            inner: Mutex::new(Inner {
+                bytes: (0, 0),
+                messages: BTreeMap::new(),
+                peers: HashMap::new(),
+                window_start: Instant::now(),
+            }),
+            propagation: Propagation::new(),
+        }
+    }
+}
+
+impl Traffic {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accounts a message exchanged with a peer, `peer` is `None` when
+    /// unknown.
+    pub fn record(
+        &self,
+        direction: Direction,
+        peer: Option<&str>,
+        kind: &'static str,
+        bytes: usize,
+    ) {
+        self.record_at(direction, peer, kind, bytes, Instant::now());
+    }
+
+    fn record_at(
+        &self,
+        direction: Direction,
+        peer: Option<&str>,
+        kind: &'static str,
+        bytes: usize,
+        now: Instant,
+    ) {
+        let kind = if kind == "packed" { "gossip" } else { kind };
+        let bytes = bytes as u64;
+        let mut guard = self.inner.lock();
+        let inner = &mut *guard;
+        inner.rotate(now);
+        let count = inner.messages.entry(kind).or_default();
+        match direction {
+            Direction::Received => count.received += 1,
+            Direction::Sent => count.sent += 1,
+        }
+        let peer = peer.map(|peer| inner.peers.entry(peer.to_string()).or_default());
+        match direction {
+            Direction::Received => {
+                if let Some(counters) = peer {
+                    counters.current.0 += bytes;
+                }
+                inner.bytes.0 += bytes;
+            }
+            Direction::Sent => {
+                if let Some(counters) = peer {
+                    counters.current.1 += bytes;
+                }
+                inner.bytes.1 += bytes;
+            }
+        }
+    }
+
+    /// Times the blocks carried by a message received from a peer.
+    pub fn observe_blocks(&self, msg: &Message) {
+        self.propagation.observe(msg);
+    }
+
+    /// Highest block height received from the peers.
+    pub fn peers_height(&self) -> Option<u64> {
+        self.propagation.height()
+    }
+
+    /// Current statistics.
+    pub fn stats(&self) -> TrafficStats {
+        self.stats_at(Instant::now())
+    }
+
+    fn stats_at(&self, now: Instant) -> TrafficStats {
+        let mut inner = self.inner.lock();
+        inner.rotate(now);
+        let window = WINDOW.as_secs_f64();
+        let mut peers: Vec<PeerTraffic> = inner
+            .peers
+            .iter()
+            .map(|(peer, counters)| PeerTraffic {
+                peer: peer.clone(),
+                bytes_received: counters.last.0,
+                bytes_sent: counters.last.1,
+                rate_received: counters.last.0 as f64 / window,
+                rate_sent: counters.last.1 as f64 / window,
+            })
+            .filter(|peer| peer.bytes_received + peer.bytes_sent > 0)
+            .collect();
+        peers.sort_by(|a, b| {
+            (b.bytes_received + b.bytes_sent)
+                .cmp(&(a.bytes_received + a.bytes_sent))
+                .then_with(|| a.peer.cmp(&b.peer))
+        });
+        TrafficStats {
+            bytes_received: inner.bytes.0,
+            bytes_sent: inner.bytes.1,
+            messages: inner.messages.clone(),
+            peers,
+            block_propagation: self.propagation.stats(),
+        }
+    }
+}
+
+impl MetricsSource for Traffic {
+    fn render(&self, out: &mut String) {
+        let stats = self.stats();
+        let _ = writeln!(out, "# TYPE trinci_p2p_bytes_total counter");
+        let _ = writeln!(
+            out,
+            "trinci_p2p_bytes_total{{direction=\"received\"}} {}",
+            stats.bytes_received
+        );
+        let _ = writeln!(
+            out,
+            "trinci_p2p_bytes_total{{direction=\"sent\"}} {}",
+            stats.bytes_sent
+        );
+        let _ = writeln!(out, "# TYPE trinci_p2p_messages_total counter");
+        for (kind, count) in &stats.messages {
+            let _ = writeln!(
+                out,
+                "trinci_p2p_messages_total{{kind=\"{}\",direction=\"received\"}} {}",
+                kind, count.received
+            );
+            let _ = writeln!(
+                out,
+                "trinci_p2p_messages_total{{kind=\"{}\",direction=\"sent\"}} {}",
+                kind, count.sent
+            );
+        }
+        self.propagation.render(out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_rates() {
+        let traffic = Traffic::new();
+        let start = traffic.inner.lock().window_start;
+        traffic.record_at(Direction::Received, Some("peer1"), "packed", 600, start);
+        traffic.record_at(Direction::Sent, Some("peer2"), "get_block", 60, start);
+        traffic.record_at(Direction::Sent, None, "packed", 100, start);
+
+        // Rates are only available once the window is complete.
+        let stats = traffic.stats_at(start);
+        assert!(stats.peers.is_empty());
+        assert_eq!(stats.bytes_received, 600);
+        assert_eq!(stats.bytes_sent, 160);
+        assert_eq!(
+            stats.messages["gossip"],
+            MessageCount {
+                received: 1,
+                sent: 1
+            }
+        );
+
+        let stats = traffic.stats_at(start + WINDOW);
+        let peers: Vec<(&str, f64, f64)> = stats
+            .peers
+            .iter()
+            .map(|p| (p.peer.as_str(), p.rate_received, p.rate_sent))
+            .collect();
+        assert_eq!(peers, [("peer1", 10.0, 0.0), ("peer2", 0.0, 1.0)]);
+
+        // Idle peers are dropped.
+        assert!(traffic.stats_at(start + 2 * WINDOW).peers.is_empty());
+    }
+}
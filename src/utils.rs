@@ -15,22 +15,61 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::{pkcs11, proxy};
 use isahc::ReadResponseExt;
+use serde::{Deserialize, Serialize};
 use std::{
-    fs::File,
-    io::{Read, Write},
+    fs::{self, File},
+    io::{self, Read, Write},
+    net::Ipv6Addr,
+    path::Path,
 };
 use trinci_core::{
+    base::serialize::{rmp_deserialize, rmp_serialize},
     crypto::{ecdsa, ed25519, KeyPair},
     rest::service::NodeInfo,
-    Error, ErrorKind, Result,
+    Error, ErrorKind, PublicKey, Result,
 };
 
 use ring::digest;
 
+/// Signing backend of a keypair: a keypair file, the TPM2 device or an HSM.
+pub trait Signer: Send + Sync {
+    fn public_key(&self) -> PublicKey;
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>>;
+}
+
+impl Signer for KeyPair {
+    fn public_key(&self) -> PublicKey {
+        KeyPair::public_key(self)
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        KeyPair::sign(self, data)
+    }
+}
+
+/// Load a keypair signer: PKCS#11 URIs reference a key held by an HSM (see
+/// the `pkcs11` module), the other paths are loaded as by `load_keypair`.
+pub fn load_signer(filename: Option<String>) -> Result<Box<dyn Signer>> {
+    match filename {
+        Some(uri) if pkcs11::is_uri(&uri) => {
+            info!("Opening HSM key: {}", uri);
+            pkcs11::open(&uri)
+        }
+        filename => Ok(Box::new(load_keypair(filename)?)),
+    }
+}
+
 /// Load node account keypair.
 pub fn load_keypair(filename: Option<String>) -> Result<KeyPair> {
     match filename {
+        // The core block service signs with an in-process keypair.
+        Some(filename) if pkcs11::is_uri(&filename) => Err(Error::new_ext(
+            ErrorKind::NotImplemented,
+            "HSM keys can't sign the blocks, the core requires a keypair file or the TPM2 device",
+        )),
         Some(filename) => {
             info!("Loading node keys from: {}", filename);
             if filename.contains("/tpm") {
@@ -68,9 +107,89 @@ pub fn load_keypair(filename: Option<String>) -> Result<KeyPair> {
     }
 }
 
+/// P2P identity file within the database folder, used when no P2P keypair
+/// file is configured.
+pub const P2P_IDENTITY_FILE: &str = "p2p_identity.bin";
+
+/// Persisted P2P identity.
+#[derive(Serialize, Deserialize)]
+struct P2pIdentity {
+    /// Ed25519 keypair bytes.
+    keypair: Vec<u8>,
+    /// Signature of the P2P account identifier by the node keypair.
+    endorsement: Vec<u8>,
+}
+
+/// Loads the P2P keypair persisted within the database folder, generating
+/// it on first use, to keep the same P2P identity across the restarts.
+/// The secret key is stored in clear, readable by the node user only: it is
+/// not sealed by a TPM. When the node keypair is given the identity is
+/// endorsed by it, a replaced identity file or one endorsed by another node
+/// keypair is refused, as a malformed file: remove it to generate a new
+/// identity.
+pub fn load_p2p_identity<P: AsRef<Path>>(
+    db_path: P,
+    node_keypair: Option<&dyn Signer>,
+) -> Result<ed25519::KeyPair> {
+    let path = db_path.as_ref().join(P2P_IDENTITY_FILE);
+    let endorse = |keypair: &ed25519::KeyPair| match node_keypair {
+        Some(node_keypair) => node_keypair.sign(keypair.public_key().to_account_id().as_bytes()),
+        None => Ok(Vec::new()),
+    };
+
+    let refused = |reason: String| {
+        let message = format!(
+            "{:?} {}, remove it to generate a new identity",
+            path, reason
+        );
+        Error::new_ext(ErrorKind::Other, message)
+    };
+    if let Ok(buf) = fs::read(&path) {
+        let (keypair, identity) = rmp_deserialize::<P2pIdentity>(&buf)
+            .and_then(|identity| Ok((ed25519::KeyPair::from_bytes(&identity.keypair)?, identity)))
+            .map_err(|err| refused(format!("malformed ({})", err)))?;
+        let account_id = keypair.public_key().to_account_id();
+        let endorsed = node_keypair
+            .map(|node_keypair| {
+                node_keypair
+                    .public_key()
+                    .verify(account_id.as_bytes(), &identity.endorsement)
+            })
+            .unwrap_or(true);
+        if !endorsed {
+            return Err(refused(format!(
+                "identity {} not endorsed by the node keypair",
+                account_id
+            )));
+        }
+        return Ok(keypair);
+    }
+
+    let keypair = ed25519::KeyPair::from_random();
+    let identity = P2pIdentity {
+        keypair: keypair.to_bytes(),
+        endorsement: endorse(&keypair)?,
+    };
+    let buf = rmp_serialize(&identity)?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&path)
+        .and_then(|mut file| file.write_all(&buf))
+        .map_err(|err| Error::new_ext(ErrorKind::Other, err))?;
+    info!(
+        "[p2p] new identity {} stored in {:?}",
+        keypair.public_key().to_account_id(),
+        path
+    );
+    Ok(keypair)
+}
+
 /// Collects node visa.
 pub fn get_visa(node_address: &str) -> Result<NodeInfo> {
-    match isahc::get(format!("{}/api/v1/visa", node_address)) {
+    match proxy::http_get(&format!("{}/api/v1/visa", node_address)) {
         Ok(mut response) => Ok(response.json().unwrap()),
         Err(_) => Err(Error::new(ErrorKind::Other)),
     }
@@ -78,7 +197,7 @@ pub fn get_visa(node_address: &str) -> Result<NodeInfo> {
 
 /// Collects bootstrap file.
 pub fn get_bootstrap(node_address: &str, bootstrap_path: String) -> String {
-    match isahc::get(format!("{}/api/v1/bootstrap", node_address)) {
+    match proxy::http_get(&format!("{}/api/v1/bootstrap", node_address)) {
         Ok(mut response) => {
             info!("Bootstrap retrieved from relay node ({})", node_address);
 
@@ -106,22 +225,96 @@ pub fn get_bootstrap(node_address: &str, bootstrap_path: String) -> String {
     }
 }
 
-/// Given local and remote node version comunicates
-/// to the user if the local verison conflicts with the remote one.
-pub fn check_version(local_version: (String, String), remote_version: (String, String)) {
-    match (
-        version_compare::compare(local_version.0, remote_version.0).unwrap(),
-        version_compare::compare(remote_version.1, local_version.1).unwrap(),
-    ) {
-        (version_compare::Cmp::Lt, _) => warn!("local node version not up to date"),
-
-        (version_compare::Cmp::Gt, _) => {
-            warn!("local node version more recent than bootstrap node verison")
-        }
-        (_, version_compare::Cmp::Lt) => warn!("local core version not up to date"),
-        (_, version_compare::Cmp::Gt) => {
-            warn!("local core version more recent than bootstrap node verison")
+/// URL with the credentials, if any, masked.
+pub fn hide_credentials(url: &str) -> String {
+    let (scheme, rest) = match url.split_once("://") {
+        Some((scheme, rest)) => (format!("{}://", scheme), rest),
+        None => (String::new(), url),
+    };
+    let authority = rest.split('/').next().unwrap_or_default();
+    match authority.rsplit_once('@') {
+        Some((_, host)) => format!("{}***@{}{}", scheme, host, &rest[authority.len()..]),
+        None => url.to_string(),
+    }
+}
+
+/// Host without the IPv6 brackets.
+pub fn unbracket(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// Whether a host is an IPv6 address, bracketed or not.
+pub fn is_ipv6(host: &str) -> bool {
+    unbracket(host).parse::<Ipv6Addr>().is_ok()
+}
+
+/// `host:port` endpoint, IPv6 addresses are bracketed.
+pub fn host_port(host: &str, port: u16) -> String {
+    if is_ipv6(host) {
+        format!("[{}]:{}", unbracket(host), port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Host to reach locally a service bound to `host`, the unspecified
+/// addresses are replaced by the loopback ones.
+pub fn local_host(host: &str) -> &str {
+    match unbracket(host) {
+        "0.0.0.0" => "127.0.0.1",
+        "::" => "::1",
+        _ => host,
+    }
+}
+
+/// Recursively copies the `from` directory content into `to`.
+pub fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
         }
-        (_, _) => (),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv6_endpoints() {
+        assert_eq!(host_port("10.0.0.1", 8000), "10.0.0.1:8000");
+        assert_eq!(host_port("::1", 8000), "[::1]:8000");
+        assert_eq!(host_port("[fd00::7]", 8000), "[fd00::7]:8000");
+        assert_eq!(host_port("node.example.com", 8000), "node.example.com:8000");
+        assert_eq!(local_host("[::]"), "::1");
+        assert_eq!(local_host("0.0.0.0"), "127.0.0.1");
+        assert!(!is_ipv6("127.0.0.1"));
+    }
+
+    #[test]
+    fn p2p_identity_endorsement() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let node = KeyPair::Ed25519(ed25519::KeyPair::from_random());
+        let other = KeyPair::Ed25519(ed25519::KeyPair::from_random());
+
+        let identity = load_p2p_identity(dir.path(), Some(&node)).unwrap();
+        let reloaded = load_p2p_identity(dir.path(), Some(&node)).unwrap();
+        assert_eq!(
+            reloaded.public_key().to_account_id(),
+            identity.public_key().to_account_id()
+        );
+
+        // Not rotated behind the operator back.
+        assert!(load_p2p_identity(dir.path(), Some(&other)).is_err());
+        fs::write(dir.path().join(P2P_IDENTITY_FILE), b"garbage").unwrap();
+        assert!(load_p2p_identity(dir.path(), Some(&node)).is_err());
     }
 }
@@ -0,0 +1,209 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Node and core versions compatibility with the peers.
+//!
+//! The core p2p handshake carries no version metadata, the peers versions are
+//! read from their visa (`/api/v1/visa` of the REST service, expected on the
+//! local `rest-port`) when the node joins the network through them.
+//! A peer whose core version is below the blockchain `min_node_version` is
+//! incompatible: its block requests are refused by the peers filter.
+
+use crate::api::{client, Request, Response, Router};
+use crate::peers::{self, PeerFilter};
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::Arc};
+use trinci_core::{base::RwLock, rest::service::NodeInfo};
+use version_compare::Cmp;
+
+/// (node, core) versions.
+pub type Versions = (String, String);
+
+/// Versions of the running node.
+pub fn local() -> Versions {
+    (
+        env!("CARGO_PKG_VERSION").to_string(),
+        trinci_core::VERSION.to_string(),
+    )
+}
+
+/// Differences between the local and the remote versions, as actionable
+/// warnings.
+pub fn compare(local: &Versions, remote: &Versions) -> Vec<String> {
+    let components = [("node", &local.0, &remote.0), ("core", &local.1, &remote.1)];
+    components
+        .iter()
+        .filter_map(
+            |(name, local, remote)| match version_compare::compare(local, remote) {
+                Ok(Cmp::Lt) => Some(format!(
+                    "local {} version {} older than the peer {}, consider upgrading",
+                    name, local, remote
+                )),
+                Ok(Cmp::Gt) => Some(format!(
+                    "local {} version {} more recent than the peer {}",
+                    name, local, remote
+                )),
+                Ok(_) => None,
+                Err(_) => Some(format!(
+                    "{} versions {} and {} not comparable",
+                    name, local, remote
+                )),
+            },
+        )
+        .collect()
+}
+
+/// Returns `false` if the peer core version is below the minimum required by
+/// the blockchain.
+pub fn is_compatible(remote: &Versions, min_node_version: Option<&str>) -> bool {
+    match min_node_version {
+        Some(min) => !matches!(
+            version_compare::compare(&remote.1, min),
+            Ok(Cmp::Lt) | Err(_)
+        ),
+        None => true,
+    }
+}
+
+/// Peer versions, as reported by the node API.
+#[derive(Serialize, Clone, Debug)]
+pub struct PeerVersion {
+    pub node_version: String,
+    pub core_version: String,
+    pub compatible: bool,
+}
+
+/// Versions of the peers met by the node.
+pub struct PeerVersions {
+    min_node_version: Option<String>,
+    peers: BTreeMap<String, PeerVersion>,
+    filter: Arc<RwLock<PeerFilter>>,
+    bootstrap_addrs: Vec<String>,
+    rest_port: u16,
+}
+
+impl PeerVersions {
+    pub fn new(
+        filter: Arc<RwLock<PeerFilter>>,
+        bootstrap_addrs: Vec<String>,
+        rest_port: u16,
+    ) -> Self {
+        PeerVersions {
+            min_node_version: None,
+            peers: BTreeMap::new(),
+            filter,
+            bootstrap_addrs,
+            rest_port,
+        }
+    }
+
+    /// Sets the minimum version required by the blockchain settings.
+    pub fn set_min_node_version(&mut self, min_node_version: Option<String>) {
+        self.min_node_version = min_node_version;
+    }
+
+    /// Records the versions of a peer, logging the differences with the
+    /// local ones. Returns `false` if the peer is incompatible.
+    pub fn record(&mut self, peer: &str, versions: Versions) -> bool {
+        for warning in compare(&local(), &versions) {
+            warn!("[p2p] peer {}: {}", peer, warning);
+        }
+        let compatible = is_compatible(&versions, self.min_node_version.as_deref());
+        if !compatible {
+            warn!(
+                "[p2p] peer {} core version {} below the blockchain minimum {}, its block requests are refused",
+                peer,
+                versions.1,
+                self.min_node_version.as_deref().unwrap_or_default()
+            );
+        }
+        self.filter
+            .write()
+            .set_compatible(peer.to_string(), compatible);
+        self.peers.insert(
+            peer.to_string(),
+            PeerVersion {
+                node_version: versions.0,
+                core_version: versions.1,
+                compatible,
+            },
+        );
+        compatible
+    }
+
+    /// Registers the peers versions route within the node API.
+    pub fn routes(versions: Arc<RwLock<Self>>, router: &mut Router) {
+        router.add("GET", "/admin/p2p/versions", move |_: &Request| {
+            Response::json(&versions.read().peers)
+        });
+    }
+}
+
+/// Visa of the node whose REST service listens at `addr` (host:port).
+pub fn get_visa(addr: &str) -> Result<NodeInfo, String> {
+    let (status, body) =
+        client::request(addr, "GET", "/api/v1/visa", None).map_err(|err| err.to_string())?;
+    if status != 200 {
+        return Err(format!("status {}", status));
+    }
+    serde_json::from_slice(&body).map_err(|err| err.to_string())
+}
+
+/// Reads the versions of the bootstrap peers.
+pub fn exchange(versions: Arc<RwLock<PeerVersions>>) {
+    let (bootstrap_addrs, rest_port) = {
+        let versions = versions.read();
+        (versions.bootstrap_addrs.clone(), versions.rest_port)
+    };
+    for addr in &bootstrap_addrs {
+        let host = match peers::address_endpoint(addr) {
+            Some(endpoint) => match endpoint.rsplit_once(':') {
+                Some((host, _)) => format!("{}:{}", host, rest_port),
+                None => continue,
+            },
+            None => continue,
+        };
+        match get_visa(&host) {
+            Ok(visa) => {
+                let peer = peers::address_peer(addr).unwrap_or(&visa.p2p_account_id);
+                versions.write().record(peer, visa.node_version);
+            }
+            Err(err) => debug!("[p2p] visa of {} not available: {}", host, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versions(node: &str, core: &str) -> Versions {
+        (node.to_string(), core.to_string())
+    }
+
+    #[test]
+    fn peer_compatibility() {
+        let local = versions("0.2.10", "0.2.10");
+
+        assert!(compare(&local, &local).is_empty());
+        assert_eq!(compare(&local, &versions("0.2.11", "0.2.9")).len(), 2);
+
+        assert!(is_compatible(&versions("0.2.9", "0.2.7"), Some("0.2.7")));
+        assert!(!is_compatible(&versions("0.2.9", "0.2.6"), Some("0.2.7")));
+        assert!(is_compatible(&versions("0.2.9", "0.2.6"), None));
+    }
+}
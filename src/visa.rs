@@ -0,0 +1,227 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Node visa.
+//!
+//! The core REST service visa (`/api/v1/visa`) only carries what the P2P
+//! bootstrap needs. The node API serves, on the same path, an expanded visa:
+//! versions, network, role, compiled features, public keys and endpoints,
+//! signed by the node keypair over its msgpack serialization. The signature
+//! lets peers and tooling check that the visa was issued by the node owning
+//! `public_key`, see `trinci-node visa`.
+
+use crate::api::{client, Request, Response, Router};
+use crate::utils::Signer;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use trinci_core::{
+    base::{
+        serialize::{rmp_deserialize, rmp_serialize},
+        BlockchainSettings, RwLock,
+    },
+    crypto::KeyPair,
+    db::{Db, RocksDb},
+    PublicKey,
+};
+
+/// Node endpoints, ports as configured.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Endpoints {
+    /// IP seen from the extern.
+    pub public_ip: Option<String>,
+    pub p2p_port: u16,
+    pub rest_port: u16,
+    pub bridge_port: u16,
+    pub ws_port: u16,
+    /// Node API.
+    pub api_port: u16,
+}
+
+/// Node identity and capabilities.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Visa {
+    pub node_version: String,
+    pub core_version: String,
+    /// Network name, once the blockchain settings are stored.
+    pub network: Option<String>,
+    /// Node role: `full`, `api` or `relay`.
+    pub role: String,
+    /// Compiled optional features.
+    pub features: Vec<String>,
+    /// Node public key, signing the blocks and the visa.
+    pub public_key: PublicKey,
+    /// Node account.
+    pub account_id: String,
+    /// P2P identity account.
+    pub p2p_account_id: String,
+    pub endpoints: Endpoints,
+    /// Issue time, seconds since the epoch.
+    pub time: u64,
+}
+
+/// Visa along with its signature.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SignedVisa {
+    pub visa: Visa,
+    /// Signature of the msgpack visa (hex).
+    pub signature: String,
+}
+
+/// Optional features the node has been compiled with.
+pub fn features() -> Vec<String> {
+    [
+        ("monitor", cfg!(feature = "monitor")),
+        ("tpm2", cfg!(feature = "tpm2")),
+        ("pkcs11", cfg!(feature = "pkcs11")),
+        ("rt-monitor", cfg!(feature = "rt-monitor")),
+        ("indexer", cfg!(feature = "indexer")),
+        ("ro-exec", cfg!(feature = "ro-exec")),
+        ("kafka", cfg!(feature = "kafka")),
+    ]
+    .iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(name, _)| name.to_string())
+    .collect()
+}
+
+impl SignedVisa {
+    /// Signs the visa, the signer must own `visa.public_key`.
+    pub fn sign(visa: Visa, signer: &dyn Signer) -> Result<Self, String> {
+        let data = rmp_serialize(&visa).map_err(|err| err.to_string())?;
+        let signature = signer
+            .sign(&data)
+            .map_err(|err| format!("signature failure: {}", err))?;
+        Ok(SignedVisa {
+            visa,
+            signature: hex::encode(signature),
+        })
+    }
+
+    /// Checks the signature against the visa public key.
+    pub fn verify(&self) -> Result<(), String> {
+        let data = rmp_serialize(&self.visa).map_err(|err| err.to_string())?;
+        let signature = hex::decode(&self.signature).map_err(|_| "invalid signature encoding")?;
+        if !self.visa.public_key.verify(&data, &signature) {
+            return Err(format!(
+                "invalid signature, not issued by `{}`",
+                self.visa.public_key.to_account_id()
+            ));
+        }
+        if self.visa.public_key.to_account_id() != self.visa.account_id {
+            return Err(format!(
+                "account `{}` not matching the public key",
+                self.visa.account_id
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Fetches the visa from the node API at `addr` and checks its signature.
+pub fn fetch(addr: &str) -> Result<SignedVisa, String> {
+    let (status, body) =
+        client::request(addr, "GET", "/api/v1/visa", None).map_err(|err| err.to_string())?;
+    if status != 200 {
+        return Err(format!("{} {}", status, String::from_utf8_lossy(&body)));
+    }
+    let visa: SignedVisa = serde_json::from_slice(&body).map_err(|err| err.to_string())?;
+    visa.verify()?;
+    Ok(visa)
+}
+
+/// Visa issuer of the running node.
+pub struct NodeVisa {
+    /// Visa template, network and time are set at every issue.
+    visa: Visa,
+    db: Arc<RwLock<RocksDb>>,
+    keypair: Arc<KeyPair>,
+}
+
+impl NodeVisa {
+    pub fn new(visa: Visa, db: Arc<RwLock<RocksDb>>, keypair: Arc<KeyPair>) -> Self {
+        NodeVisa { visa, db, keypair }
+    }
+
+    /// Issues a visa signed by the node keypair.
+    pub fn issue(&self) -> Result<SignedVisa, String> {
+        let mut visa = self.visa.clone();
+        // Not yet stored during the bootstrap.
+        visa.network = self
+            .db
+            .read()
+            .load_configuration("blockchain:settings")
+            .and_then(|buf| rmp_deserialize::<BlockchainSettings>(&buf).ok())
+            .and_then(|settings| settings.network_name);
+        visa.time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        SignedVisa::sign(visa, &*self.keypair)
+    }
+
+    /// Registers the visa route within the node API.
+    pub fn routes(visa: Arc<Self>, router: &mut Router) {
+        router.add("GET", "/api/v1/visa", move |_: &Request| {
+            match visa.issue() {
+                Ok(visa) => Response::json(&visa),
+                Err(err) => Response::error(500, err),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trinci_core::crypto::ed25519;
+
+    #[test]
+    fn sign_and_verify() {
+        let keypair = KeyPair::Ed25519(ed25519::KeyPair::from_random());
+        let visa = Visa {
+            node_version: "0.2.10".to_string(),
+            core_version: "0.2.10".to_string(),
+            network: Some("skynet".to_string()),
+            role: "full".to_string(),
+            features: features(),
+            public_key: keypair.public_key(),
+            account_id: keypair.public_key().to_account_id(),
+            p2p_account_id: "12D3KooW".to_string(),
+            endpoints: Endpoints {
+                public_ip: None,
+                p2p_port: 9006,
+                rest_port: 8000,
+                bridge_port: 8001,
+                ws_port: 8002,
+                api_port: 8003,
+            },
+            time: 1,
+        };
+        let mut signed = SignedVisa::sign(visa, &keypair).unwrap();
+        assert!(signed.verify().is_ok());
+
+        signed.visa.role = "relay".to_string();
+        assert!(signed.verify().is_err());
+
+        let other = KeyPair::Ed25519(ed25519::KeyPair::from_random());
+        signed.visa.public_key = other.public_key();
+        assert!(signed.verify().is_err());
+    }
+}
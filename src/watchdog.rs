@@ -0,0 +1,226 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Stalled chain watchdog.
+//!
+//! Tracks the time since the last block. Beyond `stall-factor` times the
+//! block timeout the node logs a diagnosis built from its actual state
+//! (unconfirmed pool, P2P traffic, validator status), raises the `stalled`
+//! alert and, if `stall-recover` is set, restarts the P2P service once per
+//! stall. Blocks are only built from pending transactions: with an empty
+//! pool the chain is idle rather than stalled, which is only logged.
+
+use std::time::{Duration, Instant};
+
+/// Default multiple of the block timeout without blocks that makes a stall.
+pub const DEFAULT_STALL_FACTOR: u64 = 10;
+
+/// Watchdog configuration.
+#[derive(Debug, Clone)]
+pub struct WatchdogConfig {
+    /// Multiple of the block timeout without blocks that makes a stall, zero
+    /// disables the watchdog.
+    pub factor: u64,
+    /// Restart the P2P service on stall.
+    pub recover: bool,
+    /// Configured block timeout, used until the blockchain settings are stored.
+    pub block_timeout: u16,
+}
+
+/// Node state at the stall check.
+#[derive(Debug, Clone, Default)]
+pub struct Facts {
+    /// Last block height.
+    pub height: Option<u64>,
+    /// Unconfirmed pool size.
+    pub pool_size: usize,
+    /// Total P2P bytes received.
+    pub p2p_received: u64,
+    /// P2P service active, not in offline mode nor stopped via node API.
+    pub p2p_active: bool,
+}
+
+/// Stall check outcome.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Stall detected, with the diagnosis.
+    Stalled { age: u64, diagnosis: Vec<String> },
+    /// No block but nothing to include.
+    Idle { age: u64 },
+    /// Blocks again after a stall.
+    Resumed,
+}
+
+/// Diagnosis of a chain without new blocks, most likely cause first.
+/// `p2p_received` counts the bytes received since the last block, `validator`
+/// is the validator status of the node or the error of its check.
+pub fn diagnose(facts: &Facts, p2p_received: u64, validator: Result<bool, String>) -> Vec<String> {
+    let mut diagnosis = Vec::new();
+    if facts.pool_size == 0 {
+        diagnosis.push("unconfirmed pool empty, no transaction to include in a block".to_string());
+    }
+    if !facts.p2p_active {
+        diagnosis.push(
+            "P2P service inactive (offline mode or stopped via node API), the blocks can only come from this node"
+                .to_string(),
+        );
+    } else if p2p_received == 0 {
+        diagnosis.push(
+            "no P2P traffic since the last block, check the peers connectivity \
+             (`p2p-bootstrap-addr`, `/admin/p2p/nat`)"
+                .to_string(),
+        );
+    }
+    match validator {
+        Ok(false) => diagnosis.push(
+            "not a validator, the blocks are built by the validators and received via P2P"
+                .to_string(),
+        ),
+        Ok(true) if facts.pool_size > 0 => diagnosis.push(format!(
+            "validator with {} pending transactions, check the block service logs and the other validators",
+            facts.pool_size
+        )),
+        Ok(true) => (),
+        Err(err) => diagnosis.push(format!("validator check failed: {}", err)),
+    }
+    diagnosis
+}
+
+pub struct Watchdog {
+    config: WatchdogConfig,
+    height: Option<u64>,
+    /// Time of the last height change.
+    since: Instant,
+    /// P2P bytes received at the last height change.
+    p2p_received: u64,
+    stalled: bool,
+    idle: bool,
+}
+
+impl Watchdog {
+    pub fn new(config: WatchdogConfig) -> Self {
+        Watchdog {
+            config,
+            height: None,
+            since: Instant::now(),
+            p2p_received: 0,
+            stalled: false,
+            idle: false,
+        }
+    }
+
+    /// Restart the P2P service on stall.
+    pub fn recover(&self) -> bool {
+        self.config.recover
+    }
+
+    /// Configured block timeout.
+    pub fn block_timeout(&self) -> u16 {
+        self.config.block_timeout
+    }
+
+    /// Checks the node state, each condition is reported once.
+    /// The validator status is only requested to diagnose a stall.
+    pub fn check<F>(
+        &mut self,
+        facts: &Facts,
+        block_timeout: u16,
+        now: Instant,
+        validator: F,
+    ) -> Option<Verdict>
+    where
+        F: FnOnce() -> Result<bool, String>,
+    {
+        if facts.height != self.height {
+            self.height = facts.height;
+            self.since = now;
+            self.p2p_received = facts.p2p_received;
+            self.idle = false;
+            let resumed = self.stalled;
+            self.stalled = false;
+            return resumed.then_some(Verdict::Resumed);
+        }
+        // Before the genesis block there is no chain to follow.
+        let limit = Duration::from_secs(self.config.factor * block_timeout.max(1) as u64);
+        let age = now.duration_since(self.since);
+        if self.config.factor == 0 || facts.height.is_none() || age <= limit || self.stalled {
+            return None;
+        }
+        let age = age.as_secs();
+        if facts.pool_size == 0 {
+            if self.idle {
+                return None;
+            }
+            self.idle = true;
+            return Some(Verdict::Idle { age });
+        }
+        self.stalled = true;
+        let p2p_received = facts.p2p_received.saturating_sub(self.p2p_received);
+        Some(Verdict::Stalled {
+            age,
+            diagnosis: diagnose(facts, p2p_received, validator()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stall_and_resume() {
+        let mut watchdog = Watchdog::new(WatchdogConfig {
+            factor: 3,
+            recover: false,
+            block_timeout: 5,
+        });
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut facts = Facts {
+            height: Some(10),
+            pool_size: 0,
+            p2p_received: 500,
+            p2p_active: true,
+        };
+        let validator = || Ok(false);
+
+        assert_eq!(watchdog.check(&facts, 5, at(0), validator), None);
+        assert_eq!(watchdog.check(&facts, 5, at(15), validator), None);
+        // Empty pool: idle, reported once.
+        assert_eq!(
+            watchdog.check(&facts, 5, at(16), validator),
+            Some(Verdict::Idle { age: 16 })
+        );
+        assert_eq!(watchdog.check(&facts, 5, at(20), validator), None);
+
+        facts.pool_size = 3;
+        let verdict = watchdog.check(&facts, 5, at(30), validator);
+        let diagnosis = match verdict {
+            Some(Verdict::Stalled { age: 30, diagnosis }) => diagnosis,
+            verdict => panic!("unexpected verdict {:?}", verdict),
+        };
+        assert!(diagnosis[0].starts_with("no P2P traffic"));
+        assert!(diagnosis[1].starts_with("not a validator"));
+        assert_eq!(watchdog.check(&facts, 5, at(40), validator), None);
+
+        facts.height = Some(11);
+        assert_eq!(
+            watchdog.check(&facts, 5, at(50), validator),
+            Some(Verdict::Resumed)
+        );
+    }
+}
@@ -0,0 +1,133 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Windows service integration.
+//!
+//! With the daemon mode on Windows the node runs under the service control
+//! manager, registered with e.g.
+//! `sc create trinci binPath= "C:\trinci\trinci-node.exe --daemon ..."`.
+//! The service is reported running while the node runs; the stop and system
+//! shutdown controls request the node shutdown, as the admin route does, and
+//! the node exit status is reported as the service specific exit code.
+
+use crate::config::Config;
+use crate::control::NodeControl;
+use std::{
+    ffi::OsString,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult},
+    service_dispatcher,
+};
+
+/// Service name, ignored by the dispatcher for own process services.
+const SERVICE_NAME: &str = "trinci";
+
+/// Node configuration and arguments, taken by the service main.
+static NODE: Mutex<Option<(Config, clap::ArgMatches)>> = Mutex::new(None);
+
+/// Control of the running node.
+static CONTROL: Mutex<Option<Arc<NodeControl>>> = Mutex::new(None);
+
+/// Stop requested, possibly before the node is started.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Node exit status.
+static EXIT_CODE: AtomicI32 = AtomicI32::new(1);
+
+/// Hands the running node control over to the service stop control.
+pub fn attach(control: Arc<NodeControl>) {
+    let mut current = CONTROL.lock().unwrap_or_else(|err| err.into_inner());
+    if STOP.load(Ordering::Relaxed) {
+        control.request_shutdown();
+    }
+    *current = Some(control);
+}
+
+fn stop() {
+    STOP.store(true, Ordering::Relaxed);
+    let control = CONTROL.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(control) = control.as_ref() {
+        warn!("[service] stop requested by the service control manager");
+        control.request_shutdown();
+    }
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn status(state: ServiceState, exit_code: u32) -> ServiceStatus {
+    ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted: match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        },
+        exit_code: ServiceExitCode::ServiceSpecific(exit_code),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    }
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    let handler = |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            stop();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let handle = match service_control_handler::register(SERVICE_NAME, handler) {
+        Ok(handle) => handle,
+        Err(err) => {
+            eprintln!("Error: service control handler: {}", err);
+            return;
+        }
+    };
+    let node = NODE.lock().unwrap_or_else(|err| err.into_inner()).take();
+    let code = match node {
+        Some((config, matches)) => {
+            let _ = handle.set_service_status(status(ServiceState::Running, 0));
+            crate::run(config, &matches)
+        }
+        None => 1,
+    };
+    EXIT_CODE.store(code, Ordering::Relaxed);
+    let _ = handle.set_service_status(status(ServiceState::Stopped, code as u32));
+}
+
+/// Runs the node as a Windows service until it stops, returns the exit
+/// status. Fails if the process was not started by the service control
+/// manager.
+pub fn run(config: Config, matches: &clap::ArgMatches) -> Result<i32, String> {
+    *NODE.lock().unwrap_or_else(|err| err.into_inner()) = Some((config, matches.clone()));
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+        .map_err(|err| format!("not started as a Windows service: {}", err))?;
+    Ok(EXIT_CODE.load(Ordering::Relaxed))
+}
@@ -0,0 +1,476 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! Smart contracts cache accounting and pinning.
+//!
+//! The core wasm machine does not expose its cache, the node wraps it to
+//! track the contracts usage with the same least recently used policy.
+//! Pinned contracts are served by a dedicated wasm machine whose cache is
+//! never full, so they are never evicted. Calls nested within a contract
+//! execution are handled by the wasm machine running the outer contract.
+//!
+//! The wasm machine of the blockchain service is locked for a whole block
+//! execution: the node-initiated calls (validator checks, dry-runs) are served
+//! by a pool of dedicated machines instead.
+
+use crate::api::{Request, Response, Router};
+use crate::metrics::{MetricsSource, BUCKETS};
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, VecDeque},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+#[cfg(feature = "indexer")]
+use trinci_core::blockchain::indexer::StoreAssetDb;
+use trinci_core::{
+    base::{Mutex, RwLock},
+    crypto::{drand::SeedSource, Hash},
+    db::{Db, DbFork, RocksDb},
+    wm::{Wm, WmLocal},
+    Result, SmartContractEvent,
+};
+
+/// Max number of pinned contracts.
+pub const MAX_PINNED: usize = 16;
+
+/// Default number of wasm machines serving the node-initiated calls.
+pub const DEFAULT_WM_POOL_SIZE: usize = 2;
+
+/// Method invoked to load a contract, not meant to be exported.
+const PRELOAD_METHOD: &str = "__preload__";
+
+/// Fuel granted to a preload call.
+const PRELOAD_FUEL: u64 = 1000;
+
+/// Cache statistics, as reported by the node API.
+#[derive(Serialize, Default, Clone)]
+pub struct WmStats {
+    /// Configured cache size.
+    pub cache_max: usize,
+    pub calls: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Loaded contracts (hex), least recently used first.
+    pub loaded: Vec<String>,
+    /// Pinned contracts (hex).
+    pub pinned: Vec<String>,
+}
+
+/// Durations of the top level contract calls, nested calls included.
+#[derive(Default, Clone)]
+pub struct CallDurations {
+    pub count: u64,
+    /// Seconds.
+    pub sum: f64,
+    /// Non cumulative counters of the `metrics::BUCKETS`, the last one is
+    /// `+Inf`.
+    pub buckets: [u64; BUCKETS.len() + 1],
+}
+
+#[derive(Default)]
+struct CacheState {
+    stats: WmStats,
+    durations: CallDurations,
+    /// Contracts loaded in the main wasm machine, by recency.
+    lru: VecDeque<Hash>,
+    /// Pinned contracts.
+    pinned: BTreeSet<Hash>,
+    /// Pinned contracts loaded in the dedicated wasm machine.
+    pinned_loaded: BTreeSet<Hash>,
+}
+
+/// Cache state shared between the wasm machine and the node API.
+#[derive(Default)]
+pub struct WmCache {
+    state: Mutex<CacheState>,
+}
+
+impl WmCache {
+    pub fn new(cache_max: usize) -> Self {
+        let cache = WmCache::default();
+        cache.state.lock().stats.cache_max = cache_max;
+        cache
+    }
+
+    /// Accounts a call, returns `true` if the contract is pinned.
+    fn access(&self, hash: Hash) -> bool {
+        let mut state = self.state.lock();
+        state.stats.calls += 1;
+        if state.pinned.contains(&hash) {
+            if state.pinned_loaded.insert(hash) {
+                state.stats.misses += 1;
+            } else {
+                state.stats.hits += 1;
+            }
+            return true;
+        }
+        match state.lru.iter().position(|loaded| *loaded == hash) {
+            Some(pos) => {
+                state.lru.remove(pos);
+                state.stats.hits += 1;
+            }
+            None => {
+                state.stats.misses += 1;
+                if state.lru.len() >= state.stats.cache_max {
+                    state.lru.pop_front();
+                    state.stats.evictions += 1;
+                }
+            }
+        }
+        state.lru.push_back(hash);
+        false
+    }
+
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(BUCKETS.len());
+        let durations = &mut self.state.lock().durations;
+        durations.count += 1;
+        durations.sum += secs;
+        durations.buckets[bucket] += 1;
+    }
+
+    pub fn call_durations(&self) -> CallDurations {
+        self.state.lock().durations.clone()
+    }
+
+    pub fn stats(&self) -> WmStats {
+        let state = self.state.lock();
+        let mut stats = state.stats.clone();
+        stats.loaded = state
+            .lru
+            .iter()
+            .chain(state.pinned_loaded.iter())
+            .map(hex::encode)
+            .collect();
+        stats.pinned = state.pinned.iter().map(hex::encode).collect();
+        stats
+    }
+
+    /// Pins a contract, returns `false` if the pinned set is full.
+    pub fn pin(&self, hash: Hash) -> bool {
+        let mut state = self.state.lock();
+        if state.pinned.len() >= MAX_PINNED && !state.pinned.contains(&hash) {
+            return false;
+        }
+        state.pinned.insert(hash);
+        true
+    }
+
+    /// Unpins a contract, returns `false` if it was not pinned.
+    pub fn unpin(&self, hash: &Hash) -> bool {
+        let mut state = self.state.lock();
+        state.pinned_loaded.remove(hash);
+        state.pinned.remove(hash)
+    }
+
+    /// Registers the cache routes within the node API.
+    pub fn routes(cache: Arc<Self>, router: &mut Router) {
+        let wm_cache = cache.clone();
+        router.add("GET", "/admin/wm", move |_: &Request| {
+            Response::json(&wm_cache.stats())
+        });
+        let wm_cache = cache.clone();
+        router.add(
+            "POST",
+            "/admin/wm/pin/:hash",
+            move |req: &Request| match req
+                .param::<String>("hash")
+                .map(|hash| Hash::from_hex(&hash))
+            {
+                Some(Ok(hash)) if wm_cache.pin(hash) => Response::ok(),
+                Some(Ok(_)) => Response::error(409, format!("max {} pinned contracts", MAX_PINNED)),
+                _ => Response::error(400, "invalid contract hash"),
+            },
+        );
+        router.add(
+            "DELETE",
+            "/admin/wm/pin/:hash",
+            move |req: &Request| match req
+                .param::<String>("hash")
+                .map(|hash| Hash::from_hex(&hash))
+            {
+                Some(Ok(hash)) if cache.unpin(&hash) => Response::ok(),
+                Some(Ok(_)) => Response::error(404, "Not Found"),
+                _ => Response::error(400, "invalid contract hash"),
+            },
+        );
+    }
+}
+
+impl MetricsSource for WmCache {
+    fn render(&self, out: &mut String) {
+        let durations = self.call_durations();
+        let _ = writeln!(out, "# TYPE trinci_wasm_call_duration_seconds histogram");
+        let mut cumulative = 0;
+        for (i, count) in durations.buckets.iter().enumerate() {
+            cumulative += count;
+            let bound = match BUCKETS.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "trinci_wasm_call_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "trinci_wasm_call_duration_seconds_sum {}",
+            durations.sum
+        );
+        let _ = writeln!(
+            out,
+            "trinci_wasm_call_duration_seconds_count {}",
+            durations.count
+        );
+    }
+}
+
+/// Node wasm machine: the core one plus the cache accounting.
+pub struct NodeWm {
+    main: WmLocal,
+    pinned: WmLocal,
+    cache: Arc<WmCache>,
+}
+
+impl NodeWm {
+    pub fn new(cache: Arc<WmCache>) -> Self {
+        let cache_max = cache.state.lock().stats.cache_max;
+        NodeWm {
+            main: WmLocal::new(cache_max),
+            pinned: WmLocal::new(MAX_PINNED),
+            cache,
+        }
+    }
+}
+
+impl Wm for NodeWm {
+    #[allow(clippy::too_many_arguments)]
+    fn call(
+        &mut self,
+        db: &mut dyn DbFork,
+        depth: u16,
+        network: &str,
+        origin: &str,
+        owner: &str,
+        caller: &str,
+        app_hash: Hash,
+        method: &str,
+        args: &[u8],
+        seed: Arc<SeedSource>,
+        events: &mut Vec<SmartContractEvent>,
+        #[cfg(feature = "indexer")] store_asset_db: &mut Vec<StoreAssetDb>,
+        max_fuel: u64,
+        block_timestamp: u64,
+    ) -> (u64, Result<Vec<u8>>) {
+        let wm = match self.cache.access(app_hash) {
+            true => &mut self.pinned,
+            false => &mut self.main,
+        };
+        let start = Instant::now();
+        let res = wm.call(
+            db,
+            depth,
+            network,
+            origin,
+            owner,
+            caller,
+            app_hash,
+            method,
+            args,
+            seed,
+            events,
+            #[cfg(feature = "indexer")]
+            store_asset_db,
+            max_fuel,
+            block_timestamp,
+        );
+        if depth == 0 {
+            self.cache.observe(start.elapsed());
+        }
+        res
+    }
+
+    fn contract_updatable(
+        &mut self,
+        fork: &mut dyn DbFork,
+        hash_args: Hash,
+        ctx_args: Hash,
+        seed: Arc<SeedSource>,
+        block_timestamp: u64,
+    ) -> bool {
+        self.main
+            .contract_updatable(fork, hash_args, ctx_args, seed, block_timestamp)
+    }
+}
+
+/// Wasm machines serving the node-initiated calls, apart from the blockchain
+/// service one.
+pub struct WmPool {
+    instances: Vec<Mutex<WmLocal>>,
+    next: AtomicUsize,
+}
+
+impl WmPool {
+    pub fn new(size: usize, cache_max: usize) -> Self {
+        WmPool {
+            instances: (0..size.max(1))
+                .map(|_| Mutex::new(WmLocal::new(cache_max)))
+                .collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Runs `f` on an idle machine, on the next one in turn if all are busy.
+    pub fn with<R>(&self, f: impl FnOnce(&mut dyn Wm) -> R) -> R {
+        let len = self.instances.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+        let mut wm = (0..len)
+            .find_map(|i| self.instances[(start + i) % len].try_lock())
+            .unwrap_or_else(|| self.instances[start].lock());
+        f(&mut *wm)
+    }
+
+    /// Loads the contracts into the cache of every machine.
+    pub fn preload(
+        &self,
+        db: &RwLock<RocksDb>,
+        seed: Arc<SeedSource>,
+        service: &str,
+        contracts: &[Hash],
+    ) {
+        for wm in &self.instances {
+            for hash in contracts {
+                let res = load(&mut *wm.lock(), db, seed.clone(), service, *hash);
+                debug!(
+                    "[wm] pool contract {} preload: {:?}",
+                    hex::encode(hash),
+                    res.err()
+                );
+            }
+        }
+    }
+}
+
+// Loads a contract calling a method it does not export, within a discarded
+// database fork.
+fn load(
+    wm: &mut dyn Wm,
+    db: &RwLock<RocksDb>,
+    seed: Arc<SeedSource>,
+    service: &str,
+    hash: Hash,
+) -> Result<Vec<u8>> {
+    let mut fork = db.write().fork_create();
+    let (_, res) = wm.call(
+        &mut fork,
+        0,
+        "preload",
+        service,
+        service,
+        service,
+        hash,
+        PRELOAD_METHOD,
+        &[],
+        seed,
+        &mut Vec::new(),
+        #[cfg(feature = "indexer")]
+        &mut Vec::new(),
+        PRELOAD_FUEL,
+        0,
+    );
+    res
+}
+
+/// Loads the contracts into the wasm machine cache. Each contract is loaded
+/// calling a method it does not export, within a discarded database fork.
+pub fn preload(
+    wm: &Mutex<NodeWm>,
+    db: &RwLock<RocksDb>,
+    seed: Arc<SeedSource>,
+    service: &str,
+    contracts: &[Hash],
+) {
+    let start = Instant::now();
+    for hash in contracts {
+        let res = load(&mut *wm.lock(), db, seed.clone(), service, *hash);
+        debug!(
+            "[wm] contract {} preload: {:?}",
+            hex::encode(hash),
+            res.err()
+        );
+    }
+    if !contracts.is_empty() {
+        info!(
+            "[wm] {} contracts preloaded in {} ms",
+            contracts.len(),
+            start.elapsed().as_millis()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trinci_core::crypto::HashAlgorithm;
+
+    #[test]
+    fn lru_and_pinning() {
+        let cache = WmCache::new(2);
+        let hashes: Vec<Hash> = (0u8..3)
+            .map(|i| Hash::from_data(HashAlgorithm::Sha256, &[i]))
+            .collect();
+        assert!(cache.pin(hashes[2]));
+
+        assert!(!cache.access(hashes[0]));
+        assert!(!cache.access(hashes[1]));
+        assert!(!cache.access(hashes[0]));
+        assert!(cache.access(hashes[2]));
+        assert!(cache.access(hashes[2]));
+
+        let stats = cache.stats();
+        assert_eq!((stats.calls, stats.hits, stats.misses), (5, 2, 3));
+        assert_eq!(stats.evictions, 0);
+        assert_eq!(stats.loaded.len(), 3);
+
+        let other = Hash::from_data(HashAlgorithm::Sha256, &[9]);
+        cache.access(other);
+        let stats = cache.stats();
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.loaded[0], hex::encode(hashes[0]));
+    }
+
+    #[test]
+    fn pool_skips_busy_machines() {
+        let pool = WmPool::new(2, 1);
+        let _busy = pool.instances[0].lock();
+
+        // Served by the idle machine, without waiting for the busy one.
+        assert_eq!(pool.with(|_| 1) + pool.with(|_| 2), 3);
+    }
+}
@@ -0,0 +1,460 @@
+// This file is part of TRINCI.
+//
+// Copyright (C) 2021 Affidaty Spa.
+//
+// TRINCI is free software: you can redistribute it and/or modify it under
+// the terms of the GNU Affero General Public License as published by the
+// Free Software Foundation, either version 3 of the License, or (at your
+// option) any later version.
+//
+// TRINCI is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License
+// for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with TRINCI. If not, see <https://www.gnu.org/licenses/>.
+
+//! WebSocket events service.
+//!
+//! Streams the blockchain events to the connected clients. A client selects
+//! the events sending a JSON subscription, e.g.
+//! `{"events": ["BLOCK", "CONTRACT_EVENTS"], "account": "<id>"}`, that can be
+//! replaced at any time. Until then nothing is sent.
+//!
+//! Subscription fields:
+//! - `events`: any of `BLOCK`, `TRANSACTION`, `CONTRACT_EVENTS` and
+//!   `STATE_DIFF` (accounts and data keys changed by a block, when enabled via
+//!   `ws-state-diff`);
+//! - `account`: transactions target, contract events emitter or changed
+//!   account;
+//! - `event_name`: contract events name.
+//!
+//! Events are sent as JSON text frames, tagged by `type`.
+
+use crate::state_diff::{AccountDiff, StateTracker};
+use crate::utils;
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use trinci_core::{
+    base::{serialize::rmp_deserialize, Mutex},
+    blockchain::{BlockRequestSender, Event, Message},
+    crypto::{HashAlgorithm, Hashable},
+    SmartContractEvent, Transaction, TransactionData,
+};
+use tungstenite::{error::Error as WsError, Message as WsMessage, WebSocket};
+
+/// Max number of connected clients.
+const MAX_CLIENTS: usize = 256;
+
+/// Client socket read timeout, bounds the events delivery latency.
+const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Blockchain subscription identifier.
+const SUBSCRIPTION_ID: &str = "ws";
+
+/// WebSocket service configuration.
+pub struct WsConfig {
+    /// Binding address.
+    pub addr: String,
+    /// Listening port.
+    pub port: u16,
+}
+
+/// Event sent to the clients.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+enum WsEvent {
+    Block {
+        height: u64,
+        hash: String,
+        size: u32,
+        timestamp: u64,
+        txs: Vec<String>,
+    },
+    Transaction {
+        hash: String,
+        account: String,
+        method: String,
+    },
+    ContractEvent {
+        tx: String,
+        account: String,
+        contract: String,
+        name: String,
+        data: String,
+    },
+    StateDiff {
+        height: u64,
+        accounts: Vec<AccountDiff>,
+    },
+}
+
+impl WsEvent {
+    fn from_message(msg: Message) -> Option<Self> {
+        match msg {
+            Message::GetBlockResponse { block, txs, .. } => Some(WsEvent::Block {
+                height: block.data.height,
+                hash: hex::encode(block.hash(HashAlgorithm::Sha256)),
+                size: block.data.size,
+                timestamp: block.data.timestamp,
+                txs: txs.unwrap_or_default().iter().map(hex::encode).collect(),
+            }),
+            Message::GetTransactionResponse { tx, .. } => Some(WsEvent::transaction(&tx)),
+            Message::GetContractEvent { event } => {
+                let event = rmp_deserialize::<SmartContractEvent>(&event).ok()?;
+                Some(WsEvent::ContractEvent {
+                    tx: hex::encode(event.event_tx),
+                    account: event.emitter_account,
+                    contract: hex::encode(event.emitter_smart_contract),
+                    name: event.event_name,
+                    data: hex::encode(event.event_data),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn transaction(tx: &Transaction) -> Self {
+        // Bulk transactions are reported by their root target.
+        let data = match tx {
+            Transaction::UnitTransaction(tx) => &tx.data,
+            Transaction::BulkTransaction(tx) => match &tx.data {
+                TransactionData::BulkV1(bulk) => &bulk.txs.root.data,
+                data => data,
+            },
+        };
+        WsEvent::Transaction {
+            hash: hex::encode(tx.get_primary_hash()),
+            account: data.get_account().unwrap_or_default().to_string(),
+            method: data.get_method().unwrap_or_default().to_string(),
+        }
+    }
+
+    /// Subscription event class.
+    fn class(&self) -> &'static str {
+        match self {
+            WsEvent::Block { .. } => "BLOCK",
+            WsEvent::Transaction { .. } => "TRANSACTION",
+            WsEvent::ContractEvent { .. } => "CONTRACT_EVENTS",
+            WsEvent::StateDiff { .. } => "STATE_DIFF",
+        }
+    }
+}
+
+/// Client subscription.
+#[derive(Deserialize, Default, Debug)]
+struct Filter {
+    #[serde(default)]
+    events: Vec<String>,
+    account: Option<String>,
+    event_name: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, event: &WsEvent) -> bool {
+        if !self.events.iter().any(|class| class == event.class()) {
+            return false;
+        }
+        let (account, name) = match event {
+            WsEvent::Block { .. } => return true,
+            WsEvent::StateDiff { accounts, .. } => {
+                return match &self.account {
+                    Some(target) => accounts.iter().any(|diff| &diff.account == target),
+                    None => true,
+                }
+            }
+            WsEvent::Transaction { account, .. } => (account, None),
+            WsEvent::ContractEvent { account, name, .. } => (account, Some(name)),
+        };
+        let account_matches = match &self.account {
+            Some(target) => target == account,
+            None => true,
+        };
+        let name_matches = match (&self.event_name, name) {
+            (Some(target), Some(name)) => target == name,
+            _ => true,
+        };
+        account_matches && name_matches
+    }
+}
+
+/// Event, serialized once for all the clients.
+struct Outgoing {
+    event: WsEvent,
+    text: String,
+}
+
+type Clients = Arc<Mutex<Vec<Sender<Arc<Outgoing>>>>>;
+
+// Serves a client until it disconnects or the service is stopped.
+fn handle_client(stream: TcpStream, rx: Receiver<Arc<Outgoing>>, stop: Arc<AtomicBool>) {
+    let peer = stream.peer_addr().ok();
+    if stream.set_read_timeout(Some(POLL_TIMEOUT)).is_err() {
+        return;
+    }
+    let mut socket = match tungstenite::accept(stream) {
+        Ok(socket) => socket,
+        Err(err) => {
+            debug!("[ws] {:?} handshake failed: {}", peer, err);
+            return;
+        }
+    };
+    debug!("[ws] {:?} connected", peer);
+    let mut filter = Filter::default();
+    while !stop.load(Ordering::Relaxed) {
+        match socket.read_message() {
+            Ok(WsMessage::Text(text)) => match serde_json::from_str::<Filter>(&text) {
+                Ok(new_filter) => filter = new_filter,
+                Err(err) => {
+                    let reply = serde_json::json!({ "type": "ERROR", "message": err.to_string() });
+                    let _ = socket.write_message(WsMessage::Text(reply.to_string()));
+                }
+            },
+            Ok(WsMessage::Close(_)) => break,
+            Ok(_) => (),
+            Err(WsError::Io(err))
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(_) => break,
+        }
+        if !forward(&mut socket, &rx, &filter) {
+            break;
+        }
+    }
+    let _ = socket.close(None);
+    debug!("[ws] {:?} disconnected", peer);
+}
+
+// Sends the pending events, returns false if the client is gone.
+fn forward(
+    socket: &mut WebSocket<TcpStream>,
+    rx: &Receiver<Arc<Outgoing>>,
+    filter: &Filter,
+) -> bool {
+    loop {
+        match rx.try_recv() {
+            Ok(out) if filter.matches(&out.event) => {
+                if socket
+                    .write_message(WsMessage::Text(out.text.clone()))
+                    .is_err()
+                {
+                    return false;
+                }
+            }
+            Ok(_) => (),
+            Err(TryRecvError::Empty) => return true,
+            Err(TryRecvError::Disconnected) => return false,
+        }
+    }
+}
+
+// Sends an event to all the clients.
+fn broadcast(clients: &Clients, event: WsEvent) {
+    let text = match serde_json::to_string(&event) {
+        Ok(text) => text,
+        Err(_) => return,
+    };
+    let out = Arc::new(Outgoing { event, text });
+    clients
+        .lock()
+        .retain(|client| client.send(out.clone()).is_ok());
+}
+
+// Relays the blockchain events to the clients.
+fn dispatch(
+    bc_chan: BlockRequestSender,
+    clients: Clients,
+    state: Option<Arc<Mutex<StateTracker>>>,
+) {
+    let events = Event::BLOCK | Event::TRANSACTION | Event::CONTRACT_EVENTS;
+    let req = Message::Subscribe {
+        id: SUBSCRIPTION_ID.to_owned(),
+        events,
+    };
+    let rx_chan = match bc_chan.send_sync(req) {
+        Ok(rx_chan) => rx_chan,
+        Err(_) => {
+            warn!("[ws] blockchain channel closed");
+            return;
+        }
+    };
+    while let Ok(msg) = rx_chan.recv_sync() {
+        let diff = match (&state, &msg) {
+            (Some(state), Message::GetBlockResponse { block, txs, .. }) => {
+                Some(WsEvent::StateDiff {
+                    height: block.data.height,
+                    accounts: state.lock().block_diff(txs.as_deref().unwrap_or_default()),
+                })
+            }
+            _ => None,
+        };
+        if let Some(event) = WsEvent::from_message(msg) {
+            broadcast(&clients, event);
+        }
+        if let Some(event) = diff {
+            broadcast(&clients, event);
+        }
+    }
+    debug!("[ws] blockchain subscription closed");
+}
+
+pub struct WsService {
+    /// Service configuration.
+    config: WsConfig,
+    /// Blockchain service channel.
+    bc_chan: BlockRequestSender,
+    /// Accounts state tracker, if the state diff events are enabled.
+    state: Option<Arc<Mutex<StateTracker>>>,
+    /// Bound address, known once started.
+    local_addr: Option<SocketAddr>,
+    /// Worker thread handler
+    handler: Option<JoinHandle<()>>,
+    /// Worker stop flag.
+    stop: Arc<AtomicBool>,
+    /// To check if the worker still alive
+    canary: Arc<()>,
+}
+
+impl WsService {
+    pub fn new(config: WsConfig, bc_chan: BlockRequestSender, state: Option<StateTracker>) -> Self {
+        WsService {
+            config,
+            bc_chan,
+            state: state.map(|state| Arc::new(Mutex::new(state))),
+            local_addr: None,
+            handler: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            canary: Arc::new(()),
+        }
+    }
+
+    /// Start WebSocket service if not already running
+    pub fn start(&mut self) {
+        debug!("Starting WS service");
+        if self.handler.is_some() {
+            warn!("Service was already running");
+            return;
+        }
+
+        let addr = utils::host_port(&self.config.addr, self.config.port);
+        let listener = match TcpListener::bind(&addr) {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("[ws] unable to bind {}: {}", addr, err);
+                return;
+            }
+        };
+        self.local_addr = listener.local_addr().ok();
+        info!("[ws] listening on {}", addr);
+
+        let clients: Clients = Arc::new(Mutex::new(Vec::new()));
+        let bc_chan = self.bc_chan.clone();
+        let dispatch_clients = clients.clone();
+        let state = self.state.clone();
+        thread::spawn(move || dispatch(bc_chan, dispatch_clients, state));
+
+        self.stop.store(false, Ordering::Relaxed);
+        let stop = self.stop.clone();
+        let mut canary = Arc::clone(&self.canary);
+        let handle = thread::spawn(move || {
+            let _ = Arc::get_mut(&mut canary);
+            for stream in listener.incoming() {
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("[ws] accept error: {}", err);
+                        continue;
+                    }
+                };
+                let mut clients = clients.lock();
+                if clients.len() >= MAX_CLIENTS {
+                    warn!("[ws] max clients reached, connection refused");
+                    continue;
+                }
+                let (tx, rx) = mpsc::channel();
+                clients.push(tx);
+                let stop = stop.clone();
+                thread::spawn(move || handle_client(stream, rx, stop));
+            }
+        });
+        self.handler = Some(handle);
+    }
+
+    /// Stop WebSocket service
+    pub fn stop(&mut self) {
+        debug!("Stopping WS service");
+        let handle = match self.handler.take() {
+            Some(handle) => handle,
+            None => return,
+        };
+        self.stop.store(true, Ordering::Relaxed);
+        let req = Message::Unsubscribe {
+            id: SUBSCRIPTION_ID.to_owned(),
+            events: Event::BLOCK | Event::TRANSACTION | Event::CONTRACT_EVENTS,
+        };
+        let _ = self.bc_chan.send_sync(req);
+        // Wake up the worker blocked on accept.
+        if let Some(addr) = self.local_addr {
+            let _ = TcpStream::connect(addr);
+        }
+        let _ = handle.join();
+    }
+
+    /// Check if WebSocket service is running
+    pub fn is_running(&self) -> bool {
+        Arc::strong_count(&self.canary) == 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contract_event(name: &str) -> WsEvent {
+        WsEvent::ContractEvent {
+            tx: String::new(),
+            account: "alice".to_string(),
+            contract: String::new(),
+            name: name.to_string(),
+            data: String::new(),
+        }
+    }
+
+    #[test]
+    fn filter_matches() {
+        let filter: Filter = serde_json::from_str(
+            r#"{"events": ["CONTRACT_EVENTS", "TRANSACTION"], "account": "alice", "event_name": "transfer"}"#,
+        )
+        .unwrap();
+
+        assert!(filter.matches(&contract_event("transfer")));
+        assert!(!filter.matches(&contract_event("mint")));
+        assert!(filter.matches(&WsEvent::Transaction {
+            hash: String::new(),
+            account: "alice".to_string(),
+            method: "mint".to_string(),
+        }));
+        assert!(!filter.matches(&WsEvent::Transaction {
+            hash: String::new(),
+            account: "bob".to_string(),
+            method: "mint".to_string(),
+        }));
+        assert!(!Filter::default().matches(&contract_event("transfer")));
+    }
+}
@@ -52,7 +52,15 @@ find_remote_ip_address() {
 
 negotiate_upnp_port() {
     echo -e "${SUCCESS_CODE}\nHandshaking port for P2P ${CLEAN_CODE}" 
-    endpoint_ip=`./tools/upnp_negotiator/target/release/upnp_negotiator $target_ip $TARGET_PORT`
+    # The negotiator keeps renewing the mapping and removes it when the script exits.
+    endpoint_file=`mktemp`
+    ./tools/upnp_negotiator/target/release/upnp_negotiator --daemon $target_ip $TARGET_PORT > $endpoint_file &
+    negotiator_pid=$!
+    trap "kill $negotiator_pid 2> /dev/null; rm -f $endpoint_file" EXIT
+    while kill -0 $negotiator_pid 2> /dev/null && [ ! -s $endpoint_file ]; do
+        sleep 1
+    done
+    endpoint_ip=`head -n 1 $endpoint_file`
     arrEndpointIp=(${endpoint_ip//:/ })
 
     if [ -z "${endpoint_ip}" ]; then
@@ -146,6 +154,7 @@ start () {
 
 #MAIN
 echo -e "\nTrinci node start script v0.1.0 \n"
+echo -e "${ERROR_CODE}DEPRECATED: use 'trinci-node --profile <network> --auto-network-setup' instead, this script will be removed. \n${CLEAN_CODE}"
 
 unameOut="$(uname -s)"
 case "${unameOut}" in
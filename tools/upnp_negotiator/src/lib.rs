@@ -1,43 +1,331 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+//! Port mapping negotiation.
+//!
+//! Asks the local network gateway to forward a TCP port to the node and to
+//! report the external address the peers can dial. UPnP IGD is tried first,
+//! then PCP and NAT-PMP, the first protocol supported by the gateway wins.
+//! The mapping expires with its lease, a long running [`Mapping`] can be
+//! renewed before the expiry and negotiated again if the gateway forgot it
+//! (e.g. after a reboot).
 
+pub mod pmp;
+
+use std::{
+    fmt,
+    net::{Ipv4Addr, SocketAddrV4},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Default port mapping lease, in seconds.
+pub const DEFAULT_LEASE: u32 = 120;
+
+/// Attempts made before giving up on transient failures.
+const ATTEMPTS: u32 = 3;
+
+/// Delay between two attempts.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Port mapping description shown by the gateway.
+const DESCRIPTION: &str = "node acces point";
+
+/// External address of the mapped port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Address {
-    pub ip: String,
-    pub port: String,
-}
-
-pub fn get_port_and_public_ip() -> Address {
-    match igd::search_gateway(Default::default()) {
-        Err(ref err) => panic!("Error: {}", err),
-        Ok(gateway) => {
-            let local_addr = match std::env::args().nth(1) {
-                Some(local_addr) => local_addr,
-                None => panic!("Expected IP address (cargo run -- <your IP here> <port here>)"),
-            };
-
-            let port = match std::env::args().nth(2) {
-                Some(port) => port,
-                None => panic!("Expected port number (cargo run -- <your IP here> <port here>)"),
-            };
-            let port: u16 = port.parse::<u16>().unwrap();
-            let local_addr = local_addr.parse::<Ipv4Addr>().unwrap();
-            let local_addr = SocketAddrV4::new(local_addr, port);
-
-            let external_ip = gateway.get_external_ip().unwrap();
-
-            match gateway.add_any_port(
-                igd::PortMappingProtocol::TCP,
-                local_addr,
-                120,
-                "node acces point",
-            ) {
-                Err(ref err) => {
-                    panic!("There was an error! {}", err);
+    pub ip: Ipv4Addr,
+    pub port: u16,
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.ip, self.port)
+    }
+}
+
+/// Port mapping protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Upnp,
+    Pcp,
+    NatPmp,
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let method = match self {
+            Method::Upnp => "upnp",
+            Method::Pcp => "pcp",
+            Method::NatPmp => "nat-pmp",
+        };
+        write!(f, "{}", method)
+    }
+}
+
+/// Negotiation failure.
+#[derive(Debug)]
+pub enum UpnpError {
+    /// No UPnP gateway found in the local network.
+    Search(igd::SearchError),
+    /// The gateway did not report its external address.
+    ExternalIp(igd::GetExternalIpError),
+    /// The gateway refused the port mapping.
+    AddPort(igd::AddAnyPortError),
+    /// The gateway refused to remove the port mapping.
+    RemovePort(igd::RemovePortError),
+    /// PCP or NAT-PMP request failure.
+    Pmp(Method, pmp::PmpError),
+    /// None of the protocols is supported, one error per protocol.
+    Unsupported(Vec<UpnpError>),
+}
+
+impl UpnpError {
+    /// Whether a new attempt may succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            UpnpError::Search(_)
+            | UpnpError::ExternalIp(igd::GetExternalIpError::RequestError(_))
+            | UpnpError::AddPort(igd::AddAnyPortError::RequestError(_)) => true,
+            UpnpError::Unsupported(errors) => errors.iter().any(UpnpError::is_transient),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for UpnpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpnpError::Search(err) => write!(f, "gateway search failed: {}", err),
+            UpnpError::ExternalIp(err) => write!(f, "external ip request failed: {}", err),
+            UpnpError::AddPort(err) => write!(f, "port mapping failed: {}", err),
+            UpnpError::RemovePort(err) => write!(f, "port mapping removal failed: {}", err),
+            UpnpError::Pmp(method, err) => write!(f, "{} request failed: {}", method, err),
+            UpnpError::Unsupported(errors) => {
+                write!(f, "no port mapping protocol supported by the gateway")?;
+                for err in errors {
+                    write!(f, "; {}", err)?;
                 }
-                Ok(port) => Address {
-                    ip: external_ip.to_string(),
-                    port: port.to_string(),
-                },
+                Ok(())
             }
         }
     }
 }
+
+impl std::error::Error for UpnpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            UpnpError::Search(err) => Some(err),
+            UpnpError::ExternalIp(err) => Some(err),
+            UpnpError::AddPort(err) => Some(err),
+            UpnpError::RemovePort(err) => Some(err),
+            UpnpError::Pmp(_, err) => Some(err),
+            UpnpError::Unsupported(_) => None,
+        }
+    }
+}
+
+/// Gateway holding the mapping.
+enum Gateway {
+    Upnp(igd::Gateway),
+    Pcp { addr: Ipv4Addr, nonce: [u8; 12] },
+    NatPmp { addr: Ipv4Addr },
+}
+
+/// Port mapping held on the gateway.
+pub struct Mapping {
+    gateway: Gateway,
+    local_addr: SocketAddrV4,
+    lease: u32,
+    /// External address.
+    pub address: Address,
+}
+
+// PCP mapping nonce, only needs to be unpredictable for off-path attackers.
+fn nonce() -> [u8; 12] {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let mut nonce = [0; 12];
+    nonce[..8].copy_from_slice(&time.to_be_bytes());
+    nonce[8..].copy_from_slice(&std::process::id().to_be_bytes());
+    nonce
+}
+
+fn upnp_map(local_addr: SocketAddrV4, lease: u32) -> Result<Mapping, UpnpError> {
+    let gateway = igd::search_gateway(Default::default()).map_err(UpnpError::Search)?;
+    let ip = gateway.get_external_ip().map_err(UpnpError::ExternalIp)?;
+    let port = gateway
+        .add_any_port(
+            igd::PortMappingProtocol::TCP,
+            local_addr,
+            lease,
+            DESCRIPTION,
+        )
+        .map_err(UpnpError::AddPort)?;
+    Ok(Mapping {
+        gateway: Gateway::Upnp(gateway),
+        local_addr,
+        lease,
+        address: Address { ip, port },
+    })
+}
+
+fn pcp_map(local_addr: SocketAddrV4, lease: u32) -> Result<Mapping, UpnpError> {
+    let addr = pmp::default_gateway(*local_addr.ip());
+    let nonce = nonce();
+    let granted = pmp::pcp_map(
+        addr,
+        *local_addr.ip(),
+        local_addr.port(),
+        local_addr.port(),
+        lease,
+        &nonce,
+    )
+    .map_err(|err| UpnpError::Pmp(Method::Pcp, err))?;
+    Ok(Mapping {
+        gateway: Gateway::Pcp { addr, nonce },
+        local_addr,
+        lease: granted.lifetime,
+        address: Address {
+            ip: granted.ip,
+            port: granted.port,
+        },
+    })
+}
+
+fn natpmp_map(local_addr: SocketAddrV4, lease: u32) -> Result<Mapping, UpnpError> {
+    let addr = pmp::default_gateway(*local_addr.ip());
+    let granted = pmp::natpmp_map(addr, local_addr.port(), local_addr.port(), lease)
+        .map_err(|err| UpnpError::Pmp(Method::NatPmp, err))?;
+    Ok(Mapping {
+        gateway: Gateway::NatPmp { addr },
+        local_addr,
+        lease: granted.lifetime,
+        address: Address {
+            ip: granted.ip,
+            port: granted.port,
+        },
+    })
+}
+
+// Tries the protocols in order of preference.
+fn try_map(local_addr: SocketAddrV4, lease: u32) -> Result<Mapping, UpnpError> {
+    let mut errors = Vec::new();
+    for map in [upnp_map, pcp_map, natpmp_map] {
+        match map(local_addr, lease) {
+            Ok(mapping) => return Ok(mapping),
+            Err(err) => errors.push(err),
+        }
+    }
+    Err(UpnpError::Unsupported(errors))
+}
+
+fn with_retries<T>(mut f: impl FnMut() -> Result<T, UpnpError>) -> Result<T, UpnpError> {
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(err) if err.is_transient() && attempt < ATTEMPTS => {
+                attempt += 1;
+                thread::sleep(RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+}
+
+impl Mapping {
+    /// Maps a TCP port of the gateway to `local_ip:port` for `lease` seconds.
+    /// Transient failures are retried.
+    pub fn new(local_ip: Ipv4Addr, port: u16, lease: u32) -> Result<Self, UpnpError> {
+        let local_addr = SocketAddrV4::new(local_ip, port);
+        with_retries(|| try_map(local_addr, lease))
+    }
+
+    /// Lease duration granted by the gateway, in seconds.
+    pub fn lease(&self) -> u32 {
+        self.lease
+    }
+
+    /// Protocol used for the mapping.
+    pub fn method(&self) -> Method {
+        match self.gateway {
+            Gateway::Upnp(_) => Method::Upnp,
+            Gateway::Pcp { .. } => Method::Pcp,
+            Gateway::NatPmp { .. } => Method::NatPmp,
+        }
+    }
+
+    // Requests the same mapping again, returns the external address.
+    fn refresh(&self, lease: u32) -> Option<Address> {
+        let (local_ip, local_port) = (*self.local_addr.ip(), self.local_addr.port());
+        let granted = match &self.gateway {
+            Gateway::Upnp(gateway) => {
+                let renewed = gateway.add_port(
+                    igd::PortMappingProtocol::TCP,
+                    self.address.port,
+                    self.local_addr,
+                    lease,
+                    DESCRIPTION,
+                );
+                return match (renewed, gateway.get_external_ip()) {
+                    (Ok(()), Ok(ip)) => Some(Address {
+                        ip,
+                        port: self.address.port,
+                    }),
+                    _ => None,
+                };
+            }
+            Gateway::Pcp { addr, nonce } => {
+                pmp::pcp_map(*addr, local_ip, local_port, self.address.port, lease, nonce)
+            }
+            Gateway::NatPmp { addr } => {
+                pmp::natpmp_map(*addr, local_port, self.address.port, lease)
+            }
+        };
+        granted.ok().map(|granted| Address {
+            ip: granted.ip,
+            port: granted.port,
+        })
+    }
+
+    /// Extends the lease of the mapping, negotiating a new one if the gateway
+    /// refuses the renewal. Returns `true` if the external address changed.
+    pub fn renew(&mut self) -> Result<bool, UpnpError> {
+        match self.refresh(self.lease) {
+            Some(address) if address == self.address => Ok(false),
+            _ => {
+                let (local_addr, lease) = (self.local_addr, self.lease);
+                let mapping = with_retries(|| try_map(local_addr, lease))?;
+                let changed = mapping.address != self.address;
+                *self = mapping;
+                Ok(changed)
+            }
+        }
+    }
+
+    /// Removes the mapping from the gateway.
+    pub fn remove(self) -> Result<(), UpnpError> {
+        let (local_ip, local_port) = (*self.local_addr.ip(), self.local_addr.port());
+        match &self.gateway {
+            Gateway::Upnp(gateway) => gateway
+                .remove_port(igd::PortMappingProtocol::TCP, self.address.port)
+                .map_err(UpnpError::RemovePort),
+            Gateway::Pcp { addr, nonce } => pmp::pcp_map(*addr, local_ip, local_port, 0, 0, nonce)
+                .map(|_| ())
+                .map_err(|err| UpnpError::Pmp(Method::Pcp, err)),
+            Gateway::NatPmp { addr } => pmp::natpmp_map(*addr, local_port, 0, 0)
+                .map(|_| ())
+                .map_err(|err| UpnpError::Pmp(Method::NatPmp, err)),
+        }
+    }
+}
+
+/// Maps a TCP port of the gateway to `local_ip:port` for `lease` seconds,
+/// returns the external address and the protocol used.
+/// Transient failures are retried.
+pub fn negotiate(
+    local_ip: Ipv4Addr,
+    port: u16,
+    lease: u32,
+) -> Result<(Address, Method), UpnpError> {
+    Mapping::new(local_ip, port, lease).map(|mapping| (mapping.address, mapping.method()))
+}
@@ -1,6 +1,109 @@
-use upnp_negotiator::get_port_and_public_ip;
+use std::{
+    process::exit,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use upnp_negotiator::{negotiate, Mapping, DEFAULT_LEASE};
+
+const USAGE: &str = "usage: upnp_negotiator [--daemon] <IP> <PORT> [LEASE]";
+
+/// Delay before a new renewal attempt after a failure.
+const RETRY_PERIOD: Duration = Duration::from_secs(10);
+
+fn arg<T: std::str::FromStr>(args: &[String], index: usize, name: &str) -> Option<T> {
+    let value = args.get(index)?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            eprintln!("invalid {} `{}`\n{}", name, value, USAGE);
+            exit(2);
+        }
+    }
+}
+
+// Sleeps up to `period`, returns early when the daemon is stopped.
+fn wait(running: &AtomicBool, period: Duration) {
+    let start = Instant::now();
+    while running.load(Ordering::Relaxed) && start.elapsed() < period {
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+/// Keeps the mapping alive until interrupted, then removes it.
+/// The external address is printed again whenever it changes.
+fn daemon(mut mapping: Mapping) {
+    let running = Arc::new(AtomicBool::new(true));
+    let flag = running.clone();
+    if let Err(err) = ctrlc::set_handler(move || flag.store(false, Ordering::Relaxed)) {
+        eprintln!("Error: {}", err);
+        exit(1);
+    }
+
+    // Renew at half lease, leaving room for a failed attempt.
+    let renew_period = Duration::from_secs(u64::from(mapping.lease().max(2) / 2));
+    let mut period = renew_period;
+    loop {
+        wait(&running, period);
+        if !running.load(Ordering::Relaxed) {
+            break;
+        }
+        period = match mapping.renew() {
+            Ok(changed) => {
+                if changed {
+                    println!("{} {}", mapping.address, mapping.method());
+                }
+                renew_period
+            }
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                RETRY_PERIOD
+            }
+        };
+    }
+
+    if let Err(err) = mapping.remove() {
+        eprintln!("Error: {}", err);
+        exit(1);
+    }
+}
 
 fn main() {
-    let info = get_port_and_public_ip();
-    println!("{}:{}", info.ip, info.port);
+    let mut args: Vec<String> = std::env::args().collect();
+    let daemon_mode = args.iter().any(|arg| arg == "--daemon");
+    args.retain(|arg| arg != "--daemon");
+
+    let (local_ip, port) = match (arg(&args, 1, "IP"), arg(&args, 2, "port")) {
+        (Some(local_ip), Some(port)) => (local_ip, port),
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    };
+    let lease = arg(&args, 3, "lease").unwrap_or(DEFAULT_LEASE);
+
+    if !daemon_mode {
+        match negotiate(local_ip, port, lease) {
+            Ok((address, method)) => println!("{} {}", address, method),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                exit(1);
+            }
+        }
+        return;
+    }
+
+    match Mapping::new(local_ip, port, lease) {
+        Ok(mapping) => {
+            println!("{} {}", mapping.address, mapping.method());
+            daemon(mapping);
+        }
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            exit(1);
+        }
+    }
 }
@@ -0,0 +1,265 @@
+//! NAT-PMP (RFC 6886) and PCP (RFC 6887) port mapping clients.
+//!
+//! Both protocols are spoken over UDP with the default gateway, a gateway
+//! that does not support them simply does not answer.
+
+use std::{
+    fmt, fs, io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, UdpSocket},
+    time::Duration,
+};
+
+/// Gateway server port.
+const SERVER_PORT: u16 = 5351;
+
+/// First retransmission timeout, doubled at each attempt.
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Requests sent before giving up.
+const TRANSMISSIONS: u32 = 4;
+
+const NATPMP_VERSION: u8 = 0;
+const NATPMP_OP_EXTERNAL_IP: u8 = 0;
+const NATPMP_OP_MAP_TCP: u8 = 2;
+
+const PCP_VERSION: u8 = 2;
+const PCP_OP_MAP: u8 = 1;
+const PCP_PROTOCOL_TCP: u8 = 6;
+
+/// Gateway answer.
+#[derive(Debug)]
+pub enum PmpError {
+    /// No answer or network failure.
+    Io(io::Error),
+    /// Malformed answer.
+    Invalid,
+    /// Request refused, with the protocol result code.
+    Refused(u16),
+}
+
+impl fmt::Display for PmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PmpError::Io(err) => write!(f, "{}", err),
+            PmpError::Invalid => write!(f, "malformed answer"),
+            PmpError::Refused(code) => write!(f, "refused with result code {}", code),
+        }
+    }
+}
+
+impl std::error::Error for PmpError {}
+
+/// Mapping granted by the gateway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Granted {
+    pub ip: Ipv4Addr,
+    pub port: u16,
+    pub lifetime: u32,
+}
+
+/// Default gateway, read from the routing table.
+/// Falls back on the first address of the local /24 network.
+pub fn default_gateway(local_ip: Ipv4Addr) -> Ipv4Addr {
+    routing_table_gateway().unwrap_or_else(|| {
+        let [a, b, c, _] = local_ip.octets();
+        Ipv4Addr::new(a, b, c, 1)
+    })
+}
+
+// Linux only, entries are little endian hex values.
+fn routing_table_gateway() -> Option<Ipv4Addr> {
+    let table = fs::read_to_string("/proc/net/route").ok()?;
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [_, "00000000", gateway, ..] => u32::from_str_radix(gateway, 16)
+                .ok()
+                .map(|gateway| Ipv4Addr::from(gateway.swap_bytes())),
+            _ => None,
+        }
+    })
+}
+
+// Sends the request with exponential backoff, returns the first answer
+// accepted by `parse`.
+fn transact<T>(
+    gateway: Ipv4Addr,
+    request: &[u8],
+    parse: impl Fn(&[u8]) -> Option<Result<T, PmpError>>,
+) -> Result<T, PmpError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(PmpError::Io)?;
+    socket
+        .connect(SocketAddrV4::new(gateway, SERVER_PORT))
+        .map_err(PmpError::Io)?;
+    let mut timeout = INITIAL_TIMEOUT;
+    let mut buf = [0; 1100];
+    for _ in 0..TRANSMISSIONS {
+        socket.send(request).map_err(PmpError::Io)?;
+        socket
+            .set_read_timeout(Some(timeout))
+            .map_err(PmpError::Io)?;
+        loop {
+            match socket.recv(&mut buf) {
+                Ok(len) => {
+                    if let Some(result) = parse(&buf[..len]) {
+                        return result;
+                    }
+                }
+                Err(err)
+                    if err.kind() == io::ErrorKind::WouldBlock
+                        || err.kind() == io::ErrorKind::TimedOut =>
+                {
+                    break
+                }
+                Err(err) => return Err(PmpError::Io(err)),
+            }
+        }
+        timeout *= 2;
+    }
+    Err(PmpError::Io(io::ErrorKind::TimedOut.into()))
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+// Checks a NAT-PMP response header, `None` if not an answer to `opcode`.
+fn natpmp_header(buf: &[u8], opcode: u8, len: usize) -> Option<Result<(), PmpError>> {
+    if buf.len() < 4 || buf[0] != NATPMP_VERSION || buf[1] != 128 + opcode {
+        return None;
+    }
+    Some(match u16_at(buf, 2) {
+        0 if buf.len() >= len => Ok(()),
+        0 => Err(PmpError::Invalid),
+        code => Err(PmpError::Refused(code)),
+    })
+}
+
+/// Maps the gateway TCP port to `local_port` via NAT-PMP, a zero lifetime
+/// removes the mapping.
+pub fn natpmp_map(
+    gateway: Ipv4Addr,
+    local_port: u16,
+    external_port: u16,
+    lifetime: u32,
+) -> Result<Granted, PmpError> {
+    let ip = transact(gateway, &[NATPMP_VERSION, NATPMP_OP_EXTERNAL_IP], |buf| {
+        natpmp_header(buf, NATPMP_OP_EXTERNAL_IP, 12)
+            .map(|res| res.map(|_| Ipv4Addr::new(buf[8], buf[9], buf[10], buf[11])))
+    })?;
+
+    let mut request = vec![NATPMP_VERSION, NATPMP_OP_MAP_TCP, 0, 0];
+    request.extend_from_slice(&local_port.to_be_bytes());
+    request.extend_from_slice(&external_port.to_be_bytes());
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    transact(gateway, &request, |buf| {
+        natpmp_header(buf, NATPMP_OP_MAP_TCP, 16).map(|res| {
+            res.map(|_| Granted {
+                ip,
+                port: u16_at(buf, 10),
+                lifetime: u32_at(buf, 12),
+            })
+        })
+    })
+}
+
+// PCP MAP request, the client and suggested addresses are IPv4-mapped.
+fn pcp_map_request(
+    local_ip: Ipv4Addr,
+    local_port: u16,
+    external_port: u16,
+    lifetime: u32,
+    nonce: &[u8; 12],
+) -> Vec<u8> {
+    let mut request = vec![PCP_VERSION, PCP_OP_MAP, 0, 0];
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    request.extend_from_slice(&local_ip.to_ipv6_mapped().octets());
+    request.extend_from_slice(nonce);
+    request.extend_from_slice(&[PCP_PROTOCOL_TCP, 0, 0, 0]);
+    request.extend_from_slice(&local_port.to_be_bytes());
+    request.extend_from_slice(&external_port.to_be_bytes());
+    request.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    request
+}
+
+// Parses a PCP MAP response, `None` if not an answer to the request.
+fn pcp_map_response(buf: &[u8], nonce: &[u8; 12]) -> Option<Result<Granted, PmpError>> {
+    if buf.len() < 4 || buf[1] != 128 + PCP_OP_MAP {
+        return None;
+    }
+    if buf[0] != PCP_VERSION {
+        // Version mismatch, e.g. a NAT-PMP only gateway.
+        return Some(Err(PmpError::Refused(u16::from(buf[3]))));
+    }
+    if buf[3] != 0 {
+        return Some(Err(PmpError::Refused(u16::from(buf[3]))));
+    }
+    if buf.len() < 60 {
+        return Some(Err(PmpError::Invalid));
+    }
+    if &buf[24..36] != nonce {
+        return None;
+    }
+    let mut ip = [0; 16];
+    ip.copy_from_slice(&buf[44..60]);
+    Some(
+        Ipv6Addr::from(ip)
+            .to_ipv4_mapped()
+            .map(|ip| Granted {
+                ip,
+                port: u16_at(buf, 42),
+                lifetime: u32_at(buf, 4),
+            })
+            .ok_or(PmpError::Invalid),
+    )
+}
+
+/// Maps the gateway TCP port to `local_ip:local_port` via PCP, a zero
+/// lifetime removes the mapping. The same `nonce` must be used to renew
+/// or remove a mapping.
+pub fn pcp_map(
+    gateway: Ipv4Addr,
+    local_ip: Ipv4Addr,
+    local_port: u16,
+    external_port: u16,
+    lifetime: u32,
+    nonce: &[u8; 12],
+) -> Result<Granted, PmpError> {
+    let request = pcp_map_request(local_ip, local_port, external_port, lifetime, nonce);
+    transact(gateway, &request, |buf| pcp_map_response(buf, nonce))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcp_map_roundtrip() {
+        let nonce = [7; 12];
+        let request = pcp_map_request(Ipv4Addr::new(192, 168, 1, 10), 9006, 9006, 120, &nonce);
+        assert_eq!(request.len(), 60);
+
+        // The gateway echoes the opcode data with the assigned port and address.
+        let mut response = request.clone();
+        response[1] = 128 + PCP_OP_MAP;
+        response[3] = 0;
+        response[8..24].fill(0);
+        response[42..44].copy_from_slice(&40000u16.to_be_bytes());
+        response[44..60].copy_from_slice(&Ipv4Addr::new(203, 0, 113, 10).to_ipv6_mapped().octets());
+
+        let granted = pcp_map_response(&response, &nonce).unwrap().unwrap();
+        assert_eq!(granted.ip, Ipv4Addr::new(203, 0, 113, 10));
+        assert_eq!(granted.port, 40000);
+        assert_eq!(granted.lifetime, 120);
+        assert!(pcp_map_response(&response, &[0; 12]).is_none());
+    }
+}